@@ -0,0 +1,497 @@
+//! Derive and attribute macros for `mut_guard`
+//!
+//! this crate is re-exported by `mut_guard` with the `derive` feature, it
+//! should not be used directly.
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::punctuated::Punctuated;
+use syn::{
+    parse_macro_input, parse_quote, Data, DeriveInput, Error, Expr, Fields, Ident, ItemFn,
+    ReturnType, Token, Type,
+};
+
+/// generates a `<Type>Setters` trait, implemented for `MutGuard<Type>`, with
+/// a `set_<field>` method for each named field. Each method replaces the
+/// field, checks the element like a `MutGuardBorrow` would, and returns the
+/// previous value. Fields marked with `#[guarded_setters(skip)]` get no
+/// setter
+#[proc_macro_derive(GuardedSetters, attributes(guarded_setters))]
+pub fn derive_guarded_setters(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    guarded_setters(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn guarded_setters(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = named_fields(&input, "GuardedSetters")?;
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let trait_name = Ident::new(&format!("{}Setters", name), Span::call_site());
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    // the setters borrow through `MutGuard::guard()`, which needs `Guard`
+    let mut guarded = input.generics.clone();
+    guarded
+        .make_where_clause()
+        .predicates
+        .push(parse_quote!(#name #ty_generics: ::mut_guard::Guard));
+    let guarded_where_clause = &guarded.where_clause;
+
+    let mut signatures = Vec::new();
+    let mut methods = Vec::new();
+    for field in fields.iter() {
+        if is_skipped(field)? {
+            continue;
+        }
+
+        let field_name = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let setter = Ident::new(&format!("set_{}", field_name), field_name.span());
+        let doc = format!(
+            "replaces `{}`, checks the element, and returns the previous value",
+            field_name
+        );
+
+        signatures.push(quote! {
+            #[doc = #doc]
+            fn #setter(&mut self, value: #ty) -> #ty;
+        });
+        methods.push(quote! {
+            fn #setter(&mut self, value: #ty) -> #ty {
+                ::std::mem::replace(&mut self.guard().#field_name, value)
+            }
+        });
+    }
+
+    let doc = format!(
+        "guarded setters for `{}`, generated by `#[derive(GuardedSetters)]`",
+        name
+    );
+
+    Ok(quote! {
+        #[doc = #doc]
+        #vis trait #trait_name #impl_generics #where_clause {
+            #(#signatures)*
+        }
+
+        impl #impl_generics #trait_name #ty_generics for ::mut_guard::MutGuard<#name #ty_generics>
+            #guarded_where_clause
+        {
+            #(#methods)*
+        }
+    })
+}
+
+fn is_skipped(field: &syn::Field) -> Result<bool, Error> {
+    let mut skip = false;
+    for attr in field.attrs.iter() {
+        if !attr.path().is_ident("guarded_setters") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("unknown guarded_setters attribute"))
+            }
+        })?;
+    }
+    Ok(skip)
+}
+
+/// implements `mut_guard::dirty::Fields` and generates a `<Type>FieldsMut`
+/// trait, implemented for `TrackedBorrow<Type>`, with a `<field>_mut()`
+/// method for each named field. Each method marks the field as changed
+/// before returning a mutable reference to it
+#[proc_macro_derive(TrackFields)]
+pub fn derive_track_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    track_fields(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn track_fields(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = named_fields(&input, "TrackFields")?;
+    if fields.len() > 64 {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "TrackFields supports at most 64 fields",
+        ));
+    }
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let trait_name = Ident::new(&format!("{}FieldsMut", name), Span::call_site());
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut borrowed = input.generics.clone();
+    borrowed.params.insert(0, parse_quote!('__tracked));
+    borrowed
+        .make_where_clause()
+        .predicates
+        .push(parse_quote!(#name #ty_generics: ::mut_guard::Guard + '__tracked));
+    let (borrowed_impl_generics, _, borrowed_where_clause) = borrowed.split_for_impl();
+
+    let mut names = Vec::new();
+    let mut signatures = Vec::new();
+    let mut methods = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let field_name = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let accessor = Ident::new(&format!("{}_mut", field_name), field_name.span());
+        let doc = format!("marks `{}` as changed and borrows it mutably", field_name);
+
+        names.push(field_name.to_string());
+        signatures.push(quote! {
+            #[doc = #doc]
+            fn #accessor(&mut self) -> &mut #ty;
+        });
+        methods.push(quote! {
+            fn #accessor(&mut self) -> &mut #ty {
+                self.mark(#index);
+                &mut self.as_mut_unmarked().#field_name
+            }
+        });
+    }
+
+    let doc = format!(
+        "tracked field accessors for `{}`, generated by `#[derive(TrackFields)]`",
+        name
+    );
+
+    Ok(quote! {
+        impl #impl_generics ::mut_guard::dirty::Fields for #name #ty_generics #where_clause {
+            const FIELDS: &'static [&'static str] = &[#(#names),*];
+        }
+
+        #[doc = #doc]
+        #vis trait #trait_name #impl_generics #where_clause {
+            #(#signatures)*
+        }
+
+        impl #borrowed_impl_generics #trait_name #ty_generics
+            for ::mut_guard::dirty::TrackedBorrow<'__tracked, #name #ty_generics>
+            #borrowed_where_clause
+        {
+            #(#methods)*
+        }
+    })
+}
+
+/// implements `mut_guard::violation::CheckFields`, checking the fields
+/// marked with `#[check(...)]`. A failed check panics with the path of the
+/// field in its message, also stored in the `Violation`:
+///
+/// - `#[check(cond)]` or `#[check(cond, "message")]`: `cond` is evaluated
+///   with the field bound by reference under its own name
+/// - `#[check(nested)]`: the field implements `CheckFields`, and is checked
+///   recursively
+/// - `#[check(each)]`: each element of the field (anything iterable by
+///   reference, like a `Vec`) implements `CheckFields`, and is checked
+///   recursively, with its index in the path
+#[proc_macro_derive(CheckFields, attributes(check))]
+pub fn derive_check_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    check_fields(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn check_fields(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = named_fields(&input, "CheckFields")?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut checks = Vec::new();
+    for field in fields.iter() {
+        let field_name = field.ident.as_ref().unwrap();
+        let label = field_name.to_string();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("check"))
+        {
+            let args = attr.parse_args_with(Punctuated::<Expr, Token![,]>::parse_terminated)?;
+            let mut args = args.into_iter();
+            let cond = args
+                .next()
+                .ok_or_else(|| Error::new_spanned(attr, "expected a condition"))?;
+            let message = args.next();
+            if let Some(extra) = args.next() {
+                return Err(Error::new_spanned(extra, "unexpected check argument"));
+            }
+
+            let mode = match cond {
+                Expr::Path(ref path) if message.is_none() => path.path.get_ident().cloned(),
+                _ => None,
+            };
+            checks.push(match mode {
+                Some(ref mode) if mode == "nested" => quote! {
+                    ::mut_guard::violation::CheckFields::check_fields_at(
+                        &self.#field_name,
+                        &path.field(#label),
+                    );
+                },
+                Some(ref mode) if mode == "each" => quote! {
+                    for (index, item) in (&self.#field_name).into_iter().enumerate() {
+                        ::mut_guard::violation::CheckFields::check_fields_at(
+                            item,
+                            &path.field(#label).index(index),
+                        );
+                    }
+                },
+                _ => {
+                    let message = match message {
+                        Some(message) => quote!(#message),
+                        None => {
+                            let message = format!("check failed: {}", quote!(#cond));
+                            quote!(#message)
+                        }
+                    };
+                    quote! {
+                        {
+                            #[allow(unused_variables)]
+                            let #field_name = &self.#field_name;
+                            if !(#cond) {
+                                ::mut_guard::__private::field_failed(&path.field(#label), #message);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::mut_guard::violation::CheckFields for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn check_fields_at(&self, path: &::mut_guard::violation::FieldPath<'_>) {
+                #(#checks)*
+            }
+        }
+    })
+}
+
+/// implements `mut_guard::sql::Row`, mapping each named field to a column,
+/// to generate `UPDATE` statements for the fields changed in a tracked
+/// borrow. The type must also derive `TrackFields`. The table is set with
+/// `#[sql(table = "name")]` (the lowercased type name by default), the
+/// primary key is the field marked `#[sql(key)]`, and columns can be
+/// renamed with `#[sql(column = "name")]`
+#[proc_macro_derive(SqlRow, attributes(sql))]
+pub fn derive_sql_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    sql_row(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn sql_row(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let fields = named_fields(&input, "SqlRow")?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut table = name.to_string().to_lowercase();
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("sql"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = meta.value()?.parse::<syn::LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("unknown sql attribute"))
+            }
+        })?;
+    }
+
+    let mut key = None;
+    let mut columns = Vec::new();
+    let mut binds = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let field_name = field.ident.as_ref().unwrap();
+        let mut column = field_name.to_string();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("sql"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("key") {
+                    if key.is_some() {
+                        return Err(meta.error("only one field can be the key"));
+                    }
+                    key = Some(index);
+                    Ok(())
+                } else if meta.path.is_ident("column") {
+                    column = meta.value()?.parse::<syn::LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown sql attribute"))
+                }
+            })?;
+        }
+
+        columns.push(column);
+        binds.push(quote! {
+            #index => query.bind(&self.#field_name),
+        });
+    }
+
+    let key = key
+        .ok_or_else(|| Error::new_spanned(name, "SqlRow needs a field marked with #[sql(key)]"))?;
+
+    Ok(quote! {
+        impl #impl_generics ::mut_guard::sql::Row for #name #ty_generics #where_clause {
+            const TABLE: &'static str = #table;
+            const COLUMNS: &'static [&'static str] = &[#(#columns),*];
+            const KEY: usize = #key;
+
+            fn bind_field<'q>(
+                &'q self,
+                index: usize,
+                query: ::mut_guard::sql::PgQuery<'q>,
+            ) -> ::mut_guard::sql::PgQuery<'q> {
+                match index {
+                    #(#binds)*
+                    _ => panic!("unknown field index {}", index),
+                }
+            }
+        }
+    })
+}
+
+fn named_fields<'a>(
+    input: &'a DeriveInput,
+    derive: &str,
+) -> Result<&'a Punctuated<syn::Field, Token![,]>, Error> {
+    match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => Ok(&fields.named),
+            _ => Err(Error::new_spanned(
+                &input.ident,
+                format!("{} requires a struct with named fields", derive),
+            )),
+        },
+        _ => Err(Error::new_spanned(
+            &input.ident,
+            format!("{} can only be derived for structs", derive),
+        )),
+    }
+}
+
+/// checks a precondition when the function is called:
+/// `#[requires(amount <= self.balance)]`. Like the other checks, it is
+/// skipped with the `disarm` feature
+#[proc_macro_attribute]
+pub fn requires(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let cond = parse_macro_input!(attr as Expr);
+    let mut f = parse_macro_input!(item as ItemFn);
+
+    let message = format!("precondition failed: {}", quote!(#cond));
+    let block = &f.block;
+    f.block = parse_quote!({
+        if ::mut_guard::__private::ARMED {
+            assert!(#cond, #message);
+        }
+        #block
+    });
+
+    quote!(#f).into()
+}
+
+/// checks a postcondition when the function returns. The returned value
+/// can be used in the condition as `ret`: `#[ensures(ret.len() <= 10)]`
+#[proc_macro_attribute]
+pub fn ensures(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let cond = parse_macro_input!(attr as Expr);
+    let f = parse_macro_input!(item as ItemFn);
+
+    let message = format!("postcondition failed: {}", quote!(#cond));
+    wrap_body(f, |ret| {
+        quote! {
+            if ::mut_guard::__private::ARMED {
+                let ret = &#ret;
+                assert!(#cond, #message);
+            }
+        }
+    })
+    .unwrap_or_else(Error::into_compile_error)
+    .into()
+}
+
+/// checks the type's invariants (`Guard::normalize()` then
+/// `Guard::finish()`) when a method taking `&mut self` returns, along with
+/// its postconditions
+#[proc_macro_attribute]
+pub fn invariant(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return Error::new(Span::call_site(), "#[invariant] takes no arguments")
+            .into_compile_error()
+            .into();
+    }
+    let f = parse_macro_input!(item as ItemFn);
+
+    match f.sig.receiver() {
+        Some(receiver) if receiver.mutability.is_some() => {}
+        _ => {
+            return Error::new_spanned(&f.sig, "#[invariant] requires a method taking `&mut self`")
+                .into_compile_error()
+                .into()
+        }
+    }
+
+    wrap_body(f, |_| {
+        quote! {
+            ::mut_guard::__private::run_guard(self);
+        }
+    })
+    .unwrap_or_else(Error::into_compile_error)
+    .into()
+}
+
+/// moves the body of `f` into a closure, so that `check` runs after it
+/// even if it returns early
+fn wrap_body<F>(mut f: ItemFn, check: F) -> Result<proc_macro2::TokenStream, Error>
+where
+    F: FnOnce(&Ident) -> proc_macro2::TokenStream,
+{
+    if f.sig.asyncness.is_some() {
+        return Err(Error::new_spanned(
+            &f.sig,
+            "contracts are not supported on async functions",
+        ));
+    }
+
+    let ret = Ident::new("__mut_guard_ret", Span::call_site());
+    let check = check(&ret);
+    let block = &f.block;
+    let body = match f.sig.output {
+        ReturnType::Type(_, ref ty) if !matches!(**ty, Type::ImplTrait(_)) => {
+            quote!(let #ret: #ty = (|| -> #ty { #block })();)
+        }
+        ReturnType::Type(..) => quote!(let #ret = (|| #block)();),
+        ReturnType::Default => quote!(let #ret: () = (|| #block)();),
+    };
+
+    f.block = parse_quote!({
+        #[allow(clippy::redundant_closure_call)]
+        #body
+        #check
+        #ret
+    });
+
+    Ok(quote!(#f))
+}
@@ -0,0 +1,732 @@
+//! Guarded state owned by its own thread
+//!
+//! a `GuardActor` moves the value to a dedicated thread, and mutations are
+//! sent to it as closures. They are applied one at a time, the value is
+//! checked after each of them, and the result is sent back to the caller.
+//! No lock is ever exposed.
+//!
+//! A mutation that breaks an invariant (or panics) is reported as an
+//! `ActorError::Violation`, and the actor stops, since its state can no
+//! longer be trusted.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::actor::*;
+//! use std::thread;
+//!
+//! # fn main() {
+//! #[derive(Debug)]
+//! struct Stock(pub u32);
+//!
+//! impl Guard for Stock {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 <= 100, "too many items in stock: {}", self.0);
+//!   }
+//! }
+//!
+//! let stock = GuardActor::spawn(Stock(0));
+//!
+//! let handles: Vec<_> = (0..4).map(|_| {
+//!   let stock = stock.clone();
+//!   thread::spawn(move || stock.mutate(|s| s.0 += 10).unwrap())
+//! }).collect();
+//! for h in handles {
+//!   h.join().unwrap();
+//! }
+//!
+//! assert_eq!(stock.read(|s| s.0), Ok(40));
+//! assert!(stock.mutate(|s| s.0 += 100).is_err());
+//! assert_eq!(stock.read(|s| s.0), Err(ActorError::Stopped));
+//! # }
+//! ```
+//!
+//! With the `tokio` feature, `AsyncGuardActor` does the same in a tokio
+//! task, and lets the value run asynchronous work (persistence,
+//! notifications...) after each mutation, through the `AsyncGuard` trait.
+//! It can also be given an `AsyncValidator`, for checks that need IO, like
+//! asking a remote service whether a name is already taken.
+//!
+//! ## Cancellation
+//!
+//! Dropping the future returned by `AsyncGuardActor::mutate()` (after a
+//! timeout, in a losing `select!` branch...) cannot bypass the checks: the
+//! message is queued before `mutate()` returns, and the task owning the value
+//! processes it to the end. The mutation is applied, checked and validated,
+//! then its asynchronous work runs, and only its result is discarded. The
+//! next messages wait for all of it, so they never see a value whose checks
+//! did not complete. A mutation breaking an invariant stops the actor even if
+//! nobody waits for its result.
+//!
+//! Messages queued before the last `AsyncGuardActor` handle is dropped are
+//! still processed. The task only stops early if the runtime shuts down, and
+//! the value is then dropped with it, so a mutation whose asynchronous checks
+//! were interrupted is never observed.
+//!
+//! `embedded::AsyncMutGuard` gives the same guarantee without a task: it
+//! applies and checks the mutation before returning its future.
+use std::error::Error;
+use std::fmt;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::mpsc::{channel, sync_channel, Sender};
+#[cfg(feature = "tokio")]
+use std::task::{Context, Poll};
+use std::thread;
+
+#[cfg(feature = "tokio")]
+use tokio::sync::{mpsc, oneshot};
+
+use super::violation::Violation;
+#[cfg(feature = "tokio")]
+use super::ARMED;
+use super::{run_guard, Guard};
+
+type Job<T> = Box<dyn FnOnce(&mut T) -> bool + Send>;
+
+/// error returned for a message sent to a `GuardActor`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ActorError {
+    /// the mutation broke an invariant. The actor stopped
+    Violation(Violation),
+    /// the actor stopped after an earlier violation
+    Stopped,
+}
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ActorError::Violation(ref v) => v.fmt(f),
+            ActorError::Stopped => write!(f, "the guard actor stopped"),
+        }
+    }
+}
+
+impl Error for ActorError {}
+
+/// handle to a value owned by a dedicated thread
+///
+/// handles can be cloned and shared between threads. The thread stops once
+/// every handle was dropped, or after a violation
+pub struct GuardActor<T> {
+    sender: Sender<Job<T>>,
+}
+
+impl<T: Guard + Send + 'static> GuardActor<T> {
+    /// moves `value` to a new thread
+    pub fn spawn(value: T) -> GuardActor<T> {
+        let (sender, receiver) = channel::<Job<T>>();
+
+        thread::spawn(move || {
+            let mut value = value;
+            for job in receiver {
+                if !job(&mut value) {
+                    break;
+                }
+            }
+        });
+
+        GuardActor { sender }
+    }
+}
+
+impl<T: 'static> GuardActor<T> {
+    /// applies `f` to the value, checks it, then returns the result of `f`.
+    /// Blocks until the actor has processed the message
+    pub fn mutate<R, F>(&self, f: F) -> Result<R, ActorError>
+    where
+        T: Guard,
+        R: Send + 'static,
+        F: FnOnce(&mut T) -> R + Send + 'static,
+    {
+        self.request(move |value: &mut T| {
+            let res = f(value);
+            run_guard(value);
+            res
+        })
+    }
+
+    /// applies `f` to a shared reference to the value, without running the
+    /// checks
+    pub fn read<R, F>(&self, f: F) -> Result<R, ActorError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&T) -> R + Send + 'static,
+    {
+        self.request(move |value: &mut T| f(value))
+    }
+
+    /// sends `f` without waiting for the result. A violation stops the actor,
+    /// and is reported to the next callers as `ActorError::Stopped`
+    pub fn send<F>(&self, f: F) -> Result<(), ActorError>
+    where
+        T: Guard,
+        F: FnOnce(&mut T) + Send + 'static,
+    {
+        let job: Job<T> = Box::new(move |value: &mut T| {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                f(value);
+                run_guard(value);
+            }))
+            .is_ok()
+        });
+        self.sender.send(job).map_err(|_| ActorError::Stopped)
+    }
+
+    fn request<R, F>(&self, f: F) -> Result<R, ActorError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut T) -> R + Send + 'static,
+    {
+        let (reply, response) = sync_channel(1);
+
+        let job: Job<T> = Box::new(move |value: &mut T| {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| f(value)))
+                .map_err(|payload| Violation::from_panic(payload.as_ref()));
+            let ok = res.is_ok();
+            // the caller may have given up waiting
+            let _ = reply.send(res);
+            ok
+        });
+
+        self.sender.send(job).map_err(|_| ActorError::Stopped)?;
+        match response.recv() {
+            Ok(res) => res.map_err(ActorError::Violation),
+            Err(_) => Err(ActorError::Stopped),
+        }
+    }
+}
+
+impl<T> Clone for GuardActor<T> {
+    fn clone(&self) -> GuardActor<T> {
+        GuardActor {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// asynchronous work returned by `AsyncGuard::finish_async`
+#[cfg(feature = "tokio")]
+pub type FinishFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// values that need asynchronous work after each mutation
+///
+/// `Guard::finish` checks the value synchronously, then `finish_async`
+/// returns a future that owns what it needs (a snapshot to persist, a
+/// channel to notify...). Like `Guard::normalize`, it still runs with the
+/// `disarm` feature
+#[cfg(feature = "tokio")]
+pub trait AsyncGuard: Guard {
+    fn finish_async(&mut self) -> FinishFuture;
+}
+
+/// asynchronous check returned by `AsyncValidator::validate`
+#[cfg(feature = "tokio")]
+pub type ValidateFuture = Pin<Box<dyn Future<Output = Result<(), Violation>> + Send>>;
+
+/// checks that need asynchronous work, like a network round trip, run by
+/// an `AsyncGuardActor` after each mutation
+///
+/// `validate` is called once the mutation passed the synchronous checks,
+/// and returns a future owning what it needs (a key to look up, a client
+/// handle...). The caller gets its result once that future completes. If
+/// it fails, the caller gets an `ActorError::Violation` and the actor
+/// stops, as with the synchronous checks
+#[cfg(feature = "tokio")]
+pub trait AsyncValidator<T> {
+    fn validate(&mut self, value: &T) -> ValidateFuture;
+}
+
+#[cfg(feature = "tokio")]
+impl<T, F: FnMut(&T) -> ValidateFuture> AsyncValidator<T> for F {
+    fn validate(&mut self, value: &T) -> ValidateFuture {
+        self(value)
+    }
+}
+
+/// state owned by the task of an `AsyncGuardActor`
+#[cfg(feature = "tokio")]
+struct State<T> {
+    value: T,
+    validator: Option<Box<dyn AsyncValidator<T> + Send>>,
+}
+
+#[cfg(feature = "tokio")]
+type AsyncJob<T> = Box<dyn FnOnce(&mut State<T>) -> Step<T> + Send>;
+
+/// called with the state once a pending future completes
+#[cfg(feature = "tokio")]
+type Then<T> = Box<dyn FnOnce(&mut State<T>, Result<(), Violation>) -> Step<T> + Send>;
+
+#[cfg(feature = "tokio")]
+enum Step<T> {
+    Continue,
+    /// no other message is processed until the future completes
+    Wait(ValidateFuture, Then<T>),
+    Stop,
+}
+
+/// `FinishFuture` adapter, for futures that cannot fail
+#[cfg(feature = "tokio")]
+struct Completed(FinishFuture);
+
+#[cfg(feature = "tokio")]
+impl Future for Completed {
+    type Output = Result<(), Violation>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.as_mut().poll(cx).map(Ok)
+    }
+}
+
+/// handle to a value owned by a tokio task
+///
+/// messages are processed one at a time, and the future returned by
+/// `AsyncGuard::finish_async` completes before the next message is
+/// processed, and before the caller gets its result. If that future
+/// panics, the task stops
+///
+/// Actors created with `spawn_validated` also run an `AsyncValidator`
+/// after the synchronous checks, and before `finish_async`
+///
+/// ```rust,edition2021
+/// # extern crate mut_guard;
+/// # extern crate tokio;
+/// # use mut_guard::*;
+/// # use mut_guard::actor::*;
+/// #[derive(Debug)]
+/// struct Counter(u32);
+///
+/// impl Guard for Counter {
+///   fn finish(&mut self) {
+///     assert!(self.0 < 10, "counter overflow");
+///   }
+/// }
+///
+/// impl AsyncGuard for Counter {
+///   fn finish_async(&mut self) -> FinishFuture {
+///     let value = self.0;
+///     Box::pin(async move {
+///       // persist `value` somewhere
+///       let _ = value;
+///     })
+///   }
+/// }
+///
+/// # fn main() {
+/// # let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// # rt.block_on(async {
+/// let counter = AsyncGuardActor::spawn(Counter(0));
+///
+/// counter.mutate(|c| c.0 += 1).await.unwrap();
+/// assert_eq!(counter.read(|c| c.0).await, Ok(1));
+/// assert!(counter.mutate(|c| c.0 += 10).await.is_err());
+/// # });
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+pub struct AsyncGuardActor<T> {
+    sender: mpsc::UnboundedSender<AsyncJob<T>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<T: AsyncGuard + Send + 'static> AsyncGuardActor<T> {
+    /// moves `value` to a new task. This must be called from a tokio runtime
+    pub fn spawn(value: T) -> AsyncGuardActor<T> {
+        AsyncGuardActor::start(State {
+            value,
+            validator: None,
+        })
+    }
+
+    /// like `spawn`, with asynchronous checks run by `validator` after each
+    /// mutation
+    pub fn spawn_validated<V>(value: T, validator: V) -> AsyncGuardActor<T>
+    where
+        V: AsyncValidator<T> + Send + 'static,
+    {
+        AsyncGuardActor::start(State {
+            value,
+            validator: Some(Box::new(validator)),
+        })
+    }
+
+    fn start(state: State<T>) -> AsyncGuardActor<T> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(ActorTask {
+            state,
+            receiver,
+            pending: None,
+        });
+
+        AsyncGuardActor { sender }
+    }
+
+    /// applies `f` to the value, checks it (with the validator too, if there
+    /// is one) and runs its asynchronous work, then resolves to the result
+    /// of `f`. All of this happens even if the returned future is dropped,
+    /// see the module documentation
+    pub fn mutate<R, F>(&self, f: F) -> Reply<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut T) -> R + Send + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+
+        let job: AsyncJob<T> = Box::new(move |state: &mut State<T>| {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                let res = f(&mut state.value);
+                run_guard(&mut state.value);
+                res
+            }));
+
+            match res {
+                Ok(res) => {
+                    let finish: Then<T> = Box::new(move |state, validated| match validated {
+                        Ok(()) => {
+                            let work = state.value.finish_async();
+                            Step::Wait(
+                                Box::pin(Completed(work)),
+                                Box::new(move |_, _| {
+                                    let _ = reply.send(Ok(res));
+                                    Step::Continue
+                                }),
+                            )
+                        }
+                        Err(violation) => {
+                            let _ = reply.send(Err(violation));
+                            Step::Stop
+                        }
+                    });
+
+                    match state.validator {
+                        Some(ref mut validator) if ARMED => {
+                            Step::Wait(validator.validate(&state.value), finish)
+                        }
+                        _ => finish(state, Ok(())),
+                    }
+                }
+                Err(payload) => {
+                    let _ = reply.send(Err(Violation::from_panic(payload.as_ref())));
+                    Step::Stop
+                }
+            }
+        });
+
+        self.submit(job, response)
+    }
+
+    /// applies `f` to a shared reference to the value, without running the
+    /// checks
+    pub fn read<R, F>(&self, f: F) -> Reply<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&T) -> R + Send + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+
+        let job: AsyncJob<T> = Box::new(move |state: &mut State<T>| {
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&state.value))) {
+                Ok(res) => {
+                    let _ = reply.send(Ok(res));
+                    Step::Continue
+                }
+                Err(payload) => {
+                    let _ = reply.send(Err(Violation::from_panic(payload.as_ref())));
+                    Step::Stop
+                }
+            }
+        });
+
+        self.submit(job, response)
+    }
+
+    fn submit<R>(
+        &self,
+        job: AsyncJob<T>,
+        response: oneshot::Receiver<Result<R, Violation>>,
+    ) -> Reply<R> {
+        Reply {
+            response: self.sender.send(job).ok().map(|_| response),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T> Clone for AsyncGuardActor<T> {
+    fn clone(&self) -> AsyncGuardActor<T> {
+        AsyncGuardActor {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// future resolving to the result of a message sent to an `AsyncGuardActor`
+#[cfg(feature = "tokio")]
+pub struct Reply<R> {
+    response: Option<oneshot::Receiver<Result<R, Violation>>>,
+}
+
+#[cfg(feature = "tokio")]
+impl<R> Future for Reply<R> {
+    type Output = Result<R, ActorError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let response = match self.response.as_mut() {
+            Some(response) => response,
+            None => return Poll::Ready(Err(ActorError::Stopped)),
+        };
+
+        match Pin::new(response).poll(cx) {
+            Poll::Ready(Ok(res)) => Poll::Ready(res.map_err(ActorError::Violation)),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(ActorError::Stopped)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+struct ActorTask<T> {
+    state: State<T>,
+    receiver: mpsc::UnboundedReceiver<AsyncJob<T>>,
+    pending: Option<(ValidateFuture, Then<T>)>,
+}
+
+// the value is never pinned, only the pending future is, and it is boxed
+#[cfg(feature = "tokio")]
+impl<T> Unpin for ActorTask<T> {}
+
+#[cfg(feature = "tokio")]
+impl<T> Future for ActorTask<T> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+
+        loop {
+            let step = if let Some((ref mut work, _)) = this.pending {
+                let result = match work.as_mut().poll(cx) {
+                    Poll::Ready(result) => result,
+                    Poll::Pending => return Poll::Pending,
+                };
+                let (_, then) = this.pending.take().unwrap();
+                then(&mut this.state, result)
+            } else {
+                match this.receiver.poll_recv(cx) {
+                    Poll::Ready(Some(job)) => job(&mut this.state),
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => return Poll::Pending,
+                }
+            };
+
+            match step {
+                Step::Continue => {}
+                Step::Wait(work, then) => this.pending = Some((work, then)),
+                Step::Stop => return Poll::Ready(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Positive(i32);
+
+    impl Guard for Positive {
+        fn finish(&mut self) {
+            assert!(self.0 >= 0, "value must stay positive: {}", self.0);
+        }
+    }
+
+    #[test]
+    fn mutate() {
+        let actor = GuardActor::spawn(Positive(1));
+        assert_eq!(
+            actor.mutate(|p| {
+                p.0 += 1;
+                p.0
+            }),
+            Ok(2)
+        );
+
+        actor.send(|p| p.0 *= 10).unwrap();
+        assert_eq!(actor.read(|p| p.0), Ok(20));
+    }
+
+    #[test]
+    fn violation_stops_the_actor() {
+        let actor = GuardActor::spawn(Positive(1));
+        assert_eq!(
+            actor.mutate(|p| p.0 -= 2),
+            Err(ActorError::Violation(Violation::new(
+                "value must stay positive: -1"
+            )))
+        );
+        assert_eq!(actor.read(|p| p.0), Err(ActorError::Stopped));
+    }
+
+    #[test]
+    fn send_violation() {
+        let actor = GuardActor::spawn(Positive(1));
+        actor.send(|p| p.0 = -1).unwrap();
+        assert_eq!(actor.read(|p| p.0), Err(ActorError::Stopped));
+    }
+
+    #[cfg(feature = "tokio")]
+    mod tokio_actor {
+        use super::super::*;
+        use super::Positive;
+        use std::future;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct Persisted {
+            value: Positive,
+            writes: Arc<AtomicUsize>,
+        }
+
+        impl Guard for Persisted {
+            fn finish(&mut self) {
+                self.value.finish();
+            }
+        }
+
+        impl AsyncGuard for Persisted {
+            fn finish_async(&mut self) -> FinishFuture {
+                let writes = self.writes.clone();
+                Box::pin(future::poll_fn(move |_| {
+                    writes.fetch_add(1, Ordering::SeqCst);
+                    Poll::Ready(())
+                }))
+            }
+        }
+
+        #[test]
+        fn async_actor() {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let writes = Arc::new(AtomicUsize::new(0));
+
+            let _runtime = rt.enter();
+            let actor = AsyncGuardActor::spawn(Persisted {
+                value: Positive(1),
+                writes: writes.clone(),
+            });
+
+            assert_eq!(rt.block_on(actor.mutate(|p| p.value.0 += 1)), Ok(()));
+            assert_eq!(writes.load(Ordering::SeqCst), 1);
+            assert_eq!(rt.block_on(actor.clone().read(|p| p.value.0)), Ok(2));
+
+            assert_eq!(
+                rt.block_on(actor.mutate(|p| p.value.0 = -1)),
+                Err(ActorError::Violation(Violation::new(
+                    "value must stay positive: -1"
+                )))
+            );
+            assert_eq!(writes.load(Ordering::SeqCst), 1);
+            assert_eq!(
+                rt.block_on(actor.read(|p| p.value.0)),
+                Err(ActorError::Stopped)
+            );
+        }
+
+        #[test]
+        fn async_validator() {
+            use std::collections::HashSet;
+            use std::sync::Mutex;
+
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let writes = Arc::new(AtomicUsize::new(0));
+            // stands for a remote service
+            let taken: Arc<Mutex<HashSet<i32>>> =
+                Arc::new(Mutex::new(vec![3].into_iter().collect()));
+
+            let _runtime = rt.enter();
+            let actor = AsyncGuardActor::spawn_validated(
+                Persisted {
+                    value: Positive(1),
+                    writes: writes.clone(),
+                },
+                move |p: &Persisted| -> ValidateFuture {
+                    let (taken, value) = (taken.clone(), p.value.0);
+                    Box::pin(future::poll_fn(move |_| {
+                        Poll::Ready(if taken.lock().unwrap().contains(&value) {
+                            Err(Violation::new(format!("{} is taken", value)))
+                        } else {
+                            Ok(())
+                        })
+                    }))
+                },
+            );
+
+            assert_eq!(rt.block_on(actor.mutate(|p| p.value.0 = 2)), Ok(()));
+            assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+            assert_eq!(
+                rt.block_on(actor.mutate(|p| p.value.0 = 3)),
+                Err(ActorError::Violation(Violation::new("3 is taken")))
+            );
+            assert_eq!(writes.load(Ordering::SeqCst), 1);
+            assert_eq!(
+                rt.block_on(actor.read(|p| p.value.0)),
+                Err(ActorError::Stopped)
+            );
+        }
+
+        #[test]
+        fn cancelled_callers() {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let writes = Arc::new(AtomicUsize::new(0));
+
+            let _runtime = rt.enter();
+            let actor = AsyncGuardActor::spawn(Persisted {
+                value: Positive(1),
+                writes: writes.clone(),
+            });
+
+            // never polled: the mutation and its asynchronous work still run
+            // before the next message
+            drop(actor.mutate(|p| p.value.0 += 1));
+            assert_eq!(rt.block_on(actor.read(|p| p.value.0)), Ok(2));
+            assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+            // queued before the last handle is dropped
+            let last = actor.clone().mutate(|p| p.value.0 += 1);
+            drop(actor.mutate(|p| p.value.0 = -1));
+            drop(actor);
+            assert_eq!(rt.block_on(last), Ok(()));
+            assert_eq!(writes.load(Ordering::SeqCst), 2);
+        }
+
+        #[test]
+        fn cancelled_violation() {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+
+            let _runtime = rt.enter();
+            let actor = AsyncGuardActor::spawn(Persisted {
+                value: Positive(1),
+                writes: Arc::new(AtomicUsize::new(0)),
+            });
+
+            drop(actor.mutate(|p| p.value.0 = -1));
+            assert_eq!(
+                rt.block_on(actor.read(|p| p.value.0)),
+                Err(ActorError::Stopped)
+            );
+        }
+    }
+}
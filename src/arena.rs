@@ -0,0 +1,351 @@
+//! Guarded arena
+//!
+//! entity stores need both granular and global validation. In a
+//! `GuardedArena`, mutable access to one slot calls `Guard::finish` on that
+//! element only, while operations changing the arena's structure (`insert`,
+//! `remove`, `bulk`) also run a whole-arena check.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::arena::*;
+//! #
+//! # fn main() {
+//! #[derive(Debug)]
+//! struct Entity {
+//!   health: i32,
+//! }
+//!
+//! impl Guard for Entity {
+//!   fn finish(&mut self) {
+//!     assert!(self.health <= 100, "health is too large: {}", self.health);
+//!   }
+//! }
+//!
+//! let mut entities = GuardedArena::new(|arena: &Arena<Entity>| {
+//!   assert!(arena.len() <= 1000, "too many entities");
+//! });
+//!
+//! let player = entities.insert(Entity { health: 100 });
+//!
+//! // only checks this entity
+//! entities.get_mut(player).unwrap().health -= 10;
+//! assert_eq!(entities[player].health, 90);
+//!
+//! // checks every entity, then the whole arena
+//! entities.bulk(|arena| {
+//!   for (_, e) in arena.iter_mut() {
+//!     e.health += 5;
+//!   }
+//! });
+//! # }
+//! ```
+use std::ops::{Deref, DerefMut, Drop, Index};
+
+use super::hold::Unwinding;
+use super::{run_guard, Guard, ARMED};
+
+/// identifies a slot in an `Arena`. Keys of removed elements are never
+/// valid again, even if their slot is reused
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    index: usize,
+    generation: u64,
+}
+
+struct Slot<T> {
+    generation: u64,
+    value: Option<T>,
+}
+
+/// storage for elements indexed by `Key`
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<usize>,
+    len: usize,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Arena<T> {
+        Arena {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        self.len += 1;
+
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.value = Some(value);
+                Key {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                Key {
+                    index: self.slots.len() - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let slot = self.slots.get_mut(key.index)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+
+        let value = slot.value.take()?;
+        slot.generation += 1;
+        self.free.push(key.index);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn contains_key(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        match self.slots.get(key.index) {
+            Some(slot) if slot.generation == key.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        match self.slots.get_mut(key.index) {
+            Some(slot) if slot.generation == key.generation => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|value| {
+                let key = Key {
+                    index,
+                    generation: slot.generation,
+                };
+                (key, value)
+            })
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Key, &mut T)> {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let generation = slot.generation;
+                slot.value
+                    .as_mut()
+                    .map(|value| (Key { index, generation }, value))
+            })
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Arena<T> {
+        Arena::new()
+    }
+}
+
+impl<T> Index<Key> for Arena<T> {
+    type Output = T;
+
+    fn index(&self, key: Key) -> &T {
+        self.get(key).expect("invalid arena key")
+    }
+}
+
+/// stores elements implementing the `Guard` trait in an `Arena`, and a
+/// function checking the whole arena after structural changes
+pub struct GuardedArena<T: Guard, F: FnMut(&Arena<T>)> {
+    arena: Arena<T>,
+    check: F,
+}
+
+impl<T: Guard, F: FnMut(&Arena<T>)> GuardedArena<T, F> {
+    pub fn new(check: F) -> GuardedArena<T, F> {
+        GuardedArena {
+            arena: Arena::new(),
+            check,
+        }
+    }
+
+    /// inserts an element, then checks it and the whole arena
+    pub fn insert(&mut self, mut value: T) -> Key {
+        run_guard(&mut value);
+        let key = self.arena.insert(value);
+        if ARMED {
+            (self.check)(&self.arena);
+        }
+        key
+    }
+
+    /// removes an element, then checks the whole arena
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let value = self.arena.remove(key);
+        if ARMED && value.is_some() {
+            (self.check)(&self.arena);
+        }
+        value
+    }
+
+    /// call this method to get mutable access to one element. Only that
+    /// element is checked when the returned borrow is dropped
+    pub fn get_mut(&mut self, key: Key) -> Option<SlotBorrow<'_, T>> {
+        self.arena.get_mut(key).map(|inner| SlotBorrow {
+            inner,
+            unwinding: Unwinding::start(),
+        })
+    }
+
+    /// gives mutable access to the whole arena, then checks every element
+    /// and the arena itself
+    pub fn bulk<R, G>(&mut self, f: G) -> R
+    where
+        G: FnOnce(&mut Arena<T>) -> R,
+    {
+        let res = f(&mut self.arena);
+        for (_, value) in self.arena.iter_mut() {
+            run_guard(value);
+        }
+        if ARMED {
+            (self.check)(&self.arena);
+        }
+        res
+    }
+
+    /// returns the arena, consuming the GuardedArena
+    pub fn into_inner(self) -> Arena<T> {
+        self.arena
+    }
+}
+
+impl<T: Guard, F: FnMut(&Arena<T>)> Deref for GuardedArena<T, F> {
+    type Target = Arena<T>;
+
+    fn deref(&self) -> &Arena<T> {
+        &self.arena
+    }
+}
+
+/// Structure returned by `GuardedArena::get_mut()`. when this is dropped,
+/// it will call the `Guard::finish()` method of the element
+pub struct SlotBorrow<'a, T: 'a + Guard> {
+    inner: &'a mut T,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Guard> Deref for SlotBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner
+    }
+}
+
+impl<'a, T: Guard> DerefMut for SlotBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.inner
+    }
+}
+
+impl<'a, T: Guard> Drop for SlotBorrow<'a, T> {
+    fn drop(&mut self) {
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(self.inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Account {
+        balance: i32,
+    }
+
+    impl Guard for Account {
+        fn finish(&mut self) {
+            assert!(self.balance >= 0, "balance should not become negative");
+        }
+    }
+
+    fn total(arena: &Arena<Account>) {
+        let total: i32 = arena.iter().map(|(_, a)| a.balance).sum();
+        assert_eq!(total, 100, "money was created or destroyed");
+    }
+
+    #[test]
+    fn keys() {
+        let mut arena = Arena::new();
+        let a = arena.insert(1);
+        let b = arena.insert(2);
+        assert_eq!(arena.remove(a), Some(1));
+        assert_eq!(arena.remove(a), None);
+
+        let c = arena.insert(3);
+        assert!(!arena.contains_key(a));
+        assert_eq!(arena[b], 2);
+        assert_eq!(arena[c], 3);
+        assert_eq!(arena.len(), 2);
+    }
+
+    #[test]
+    fn bulk_checks_arena() {
+        let mut accounts = GuardedArena::new(total);
+        let (a, b) = accounts.bulk(|arena| {
+            let a = arena.insert(Account { balance: 60 });
+            let b = arena.insert(Account { balance: 40 });
+            (a, b)
+        });
+
+        accounts.bulk(|arena| {
+            arena.get_mut(a).unwrap().balance -= 10;
+            arena.get_mut(b).unwrap().balance += 10;
+        });
+        assert_eq!(accounts[a].balance, 50);
+        assert_eq!(accounts[b].balance, 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "money was created or destroyed")]
+    fn bulk_violation() {
+        let mut accounts = GuardedArena::new(total);
+        accounts.bulk(|arena| {
+            arena.insert(Account { balance: 60 });
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "balance should not become negative")]
+    fn slot_violation() {
+        let mut accounts = GuardedArena::new(|_: &Arena<Account>| {});
+        let a = accounts.insert(Account { balance: 60 });
+        accounts.get_mut(a).unwrap().balance -= 70;
+    }
+}
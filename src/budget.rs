@@ -0,0 +1,196 @@
+//! Time budget for checks
+//!
+//! a `MutGuard` configured with `with_budget()` checks the element with
+//! `Guard::finish_within()` instead of `Guard::finish()`. The check gets a
+//! `Budget` it can consult to stop early, and returns
+//! `Progress::Incomplete` if it did. The `MutGuard` then remembers that a
+//! complete check is pending, to run later with `finish_pending()`, from a
+//! background pass for example.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::budget::*;
+//! use std::time::Duration;
+//!
+//! #[derive(Debug)]
+//! struct Samples(Vec<u32>);
+//!
+//! impl Guard for Samples {
+//!   fn finish(&mut self) {
+//!     assert!(self.0.iter().all(|s| *s < 100), "sample out of range");
+//!   }
+//!
+//!   fn finish_within(&mut self, budget: &Budget) -> Progress {
+//!     for chunk in self.0.chunks(1000) {
+//!       if budget.exhausted() {
+//!         return Progress::Incomplete;
+//!       }
+//!       assert!(chunk.iter().all(|s| *s < 100), "sample out of range");
+//!     }
+//!     Progress::Done
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut samples = MutGuard::new(Samples(vec![]));
+//! samples.with_budget(Duration::from_micros(50));
+//!
+//! samples.guard().0.extend(0..100);
+//!
+//! if samples.has_pending_checks() {
+//!   samples.finish_pending();
+//! }
+//! # }
+//! ```
+use std::panic::Location;
+use std::time::{Duration, Instant};
+
+use super::{Guard, MutGuard, ARMED};
+
+/// time available to a check
+#[derive(Clone, Copy, Debug)]
+pub struct Budget {
+    deadline: Instant,
+}
+
+impl Budget {
+    /// budget expiring after `duration`, starting now
+    pub fn new(duration: Duration) -> Budget {
+        Budget {
+            deadline: Instant::now() + duration,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// time left until the deadline
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    pub fn exhausted(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+}
+
+/// result of `Guard::finish_within()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Progress {
+    /// the element was fully checked
+    Done,
+    /// the check stopped early, a complete check is still needed
+    Incomplete,
+}
+
+impl<T> MutGuard<T> {
+    /// checks the element after each borrow with `Guard::finish_within()`,
+    /// giving it `duration` to complete
+    pub fn with_budget(&mut self, duration: Duration) {
+        self.settings.budget = Some(duration);
+    }
+
+    /// checks the element with `Guard::finish()` again
+    pub fn without_budget(&mut self) {
+        self.settings.budget = None;
+    }
+
+    /// returns true if a check ran out of budget since the last complete
+    /// check
+    pub fn has_pending_checks(&self) -> bool {
+        self.settings.pending
+    }
+}
+
+impl<T: Guard> MutGuard<T> {
+    /// runs `Guard::finish()` without a time limit if a check ran out of
+    /// budget
+    #[track_caller]
+    pub fn finish_pending(&mut self) {
+        if !self.settings.pending {
+            return;
+        }
+        self.settings.pending = false;
+        if ARMED {
+            self.finish_with(Location::caller(), T::finish);
+        }
+    }
+
+    /// runs `check` with a budget, if one was set with `with_budget()`,
+    /// and records whether it completed
+    pub(crate) fn finish_budgeted<F: FnOnce(&mut T, Option<&Budget>) -> Progress>(
+        &mut self,
+        location: &'static Location<'static>,
+        check: F,
+    ) {
+        let budget = self.settings.budget.map(Budget::new);
+        let mut progress = Progress::Done;
+        self.finish_with(location, |inner| progress = check(inner, budget.as_ref()));
+        self.settings.pending = progress == Progress::Incomplete;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Chunked {
+        items: Vec<u32>,
+        full_checks: u32,
+    }
+
+    impl Guard for Chunked {
+        fn finish(&mut self) {
+            self.full_checks += 1;
+        }
+
+        fn finish_within(&mut self, budget: &Budget) -> Progress {
+            if budget.exhausted() || self.items.len() > 2 {
+                Progress::Incomplete
+            } else {
+                Progress::Done
+            }
+        }
+    }
+
+    #[test]
+    fn pending_checks() {
+        let mut chunked = MutGuard::new(Chunked::default());
+        chunked.with_budget(Duration::from_secs(10));
+
+        chunked.guard().items.push(1);
+        assert!(!chunked.has_pending_checks());
+        assert_eq!(chunked.full_checks, 0);
+
+        chunked.guard().items.extend(&[2, 3]);
+        assert!(chunked.has_pending_checks());
+
+        chunked.finish_pending();
+        assert!(!chunked.has_pending_checks());
+        assert_eq!(chunked.full_checks, 1);
+
+        chunked.guard().items.push(4);
+        assert!(chunked.has_pending_checks());
+
+        // a later complete check clears it
+        chunked.guard().items.truncate(2);
+        assert!(!chunked.has_pending_checks());
+        assert_eq!(chunked.full_checks, 1);
+
+        chunked.without_budget();
+        chunked.guard().items.push(4);
+        assert_eq!(chunked.full_checks, 2);
+    }
+
+    #[test]
+    fn budget() {
+        let budget = Budget::new(Duration::from_secs(10));
+        assert!(!budget.exhausted());
+        assert!(budget.remaining() > Duration::from_secs(9));
+
+        assert!(Budget::new(Duration::from_secs(0)).exhausted());
+    }
+}
@@ -0,0 +1,801 @@
+//! Checks registered at runtime
+//!
+//! `Guard::finish()` is fixed when the guarded type is written. Code that
+//! only knows about the element at runtime, like plugins, can contribute
+//! its own invariants with `MutGuard::add_check()`: named functions that
+//! run after `Guard::finish()` every time the element is checked, until
+//! they are removed with `remove_check()`.
+//!
+//! Like `Guard::finish()`, a check panics when the invariant is broken. The
+//! panic message is prefixed with the name of the check, to know which one
+//! failed.
+//!
+//! When a mutable borrow ends, the work attached to the guard always runs
+//! in the same order:
+//!
+//! 1. `Guard::normalize()`
+//! 2. `Guard::finish()`, then `Guard::finish_slow()`
+//! 3. the checks added with `add_check()`, by increasing priority (see
+//!    `add_check_with_priority()`), then in the order they were added
+//! 4. the callbacks registered with `defer()`, in the order they were added
+//! 5. the publishers registered with `publish_to()`, by increasing priority
+//!    (see `publish_to_with_priority()`), then in the order they were added
+//! 6. with the `tokio` feature, the futures returned by `notified()` and the
+//!    subscribers of `broadcast_changes()`
+//!
+//! If a check fails, the next steps do not run: a value rejected by a check
+//! is never persisted or sent to other services.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use std::panic::{self, AssertUnwindSafe};
+//! #
+//! #[derive(Debug)]
+//! struct Tags(Vec<String>);
+//!
+//! impl Guard for Tags {
+//!   fn finish(&mut self) {}
+//! }
+//!
+//! # fn main() {
+//! let mut tags = MutGuard::new(Tags(vec![]));
+//! let no_dupes = tags.add_check("no_dupes", |tags: &Tags| {
+//!   let mut sorted = tags.0.clone();
+//!   sorted.sort();
+//!   sorted.dedup();
+//!   assert_eq!(sorted.len(), tags.0.len(), "duplicate tag");
+//! });
+//!
+//! tags.guard().0.push("a".to_string());
+//! let res = panic::catch_unwind(AssertUnwindSafe(|| tags.guard().0.push("a".to_string())));
+//! assert!(res.is_err());
+//!
+//! tags.remove_check(no_dupes);
+//! tags.guard().0.push("a".to_string());
+//! assert_eq!(tags.0.len(), 3);
+//! # }
+//! ```
+//!
+//! Checks can be put in a group with `set_check_group()`, like `"cheap"`,
+//! `"expensive"` or `"debug"`, to turn them on and off while the program
+//! runs: for a single guard with `disable_check_group()` and
+//! `enable_check_group()`, or for every guard with the `disable_group()` and
+//! `enable_group()` functions of this module. What is set on a guard takes
+//! precedence over the global setting. Groups are enabled by default, and
+//! checks without a group always run.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::checks;
+//! #
+//! #[derive(Debug)]
+//! struct Graph {
+//!   edges: Vec<(usize, usize)>,
+//! }
+//!
+//! impl Guard for Graph {
+//!   fn finish(&mut self) {}
+//! }
+//!
+//! fn acyclic(graph: &Graph) {
+//!   // walks the whole graph
+//! }
+//!
+//! # fn main() {
+//! // deep validation is off, unless an operator turns it on
+//! checks::disable_group("expensive");
+//!
+//! let mut graph = MutGuard::new(Graph { edges: vec![] });
+//! let id = graph.add_check("acyclic", acyclic);
+//! graph.set_check_group(id, "expensive");
+//! graph.guard().edges.push((0, 1));
+//!
+//! // while diagnosing an incident
+//! checks::enable_group("expensive");
+//! graph.guard().edges.push((1, 2));
+//! # }
+//! ```
+//!
+//! Each check also has a `CheckLevel`: `Basic` by default, or `Full` with
+//! `set_check_level()` for the checks that are too slow to run all the time.
+//! A guard runs the checks up to its own level, set with
+//! `MutGuard::set_level()`, or up to the default level, read from the
+//! `MUTGUARD_LEVEL` environment variable (`off`, `basic` or `full`) the
+//! first time a guard checks its element, so the validation can be dialed
+//! up or down without a rebuild. `Off` skips every check added with
+//! `add_check()`, but `Guard::finish()` still runs: it is the invariant of
+//! the type, and is only removed with the `disarm` feature.
+//!
+//! ```rust,no_run
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::checks::CheckLevel;
+//! #
+//! # #[derive(Debug)]
+//! # struct Graph {
+//! #   edges: Vec<(usize, usize)>,
+//! # }
+//! #
+//! # impl Guard for Graph {
+//! #   fn finish(&mut self) {}
+//! # }
+//! #
+//! # fn acyclic(graph: &Graph) {}
+//! #
+//! # fn main() {
+//! // MUTGUARD_LEVEL=full ./server
+//! let mut graph = MutGuard::new(Graph { edges: vec![] });
+//! let id = graph.add_check("acyclic", acyclic);
+//! graph.set_check_level(id, CheckLevel::Full);
+//! graph.guard().edges.push((0, 1));
+//! # }
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::RwLock;
+
+use super::violation::{Severity, Violation};
+use super::{MutGuard, WrappedGuard};
+
+/// identifies a check added with `MutGuard::add_check()`, to remove it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CheckId(u64);
+
+/// which checks added with `MutGuard::add_check()` run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CheckLevel {
+    /// none of them
+    Off,
+    /// the checks at the `Basic` level, the default for a check
+    Basic,
+    /// every check
+    Full,
+}
+
+/// returned when parsing a `CheckLevel` that is not `off`, `basic` or
+/// `full`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseLevelError(String);
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown check level \"{}\", expected off, basic or full",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseLevelError {}
+
+impl FromStr for CheckLevel {
+    type Err = ParseLevelError;
+
+    /// case insensitive
+    fn from_str(s: &str) -> Result<CheckLevel, ParseLevelError> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(CheckLevel::Off),
+            "basic" => Ok(CheckLevel::Basic),
+            "full" => Ok(CheckLevel::Full),
+            _ => Err(ParseLevelError(s.to_string())),
+        }
+    }
+}
+
+/// environment variable holding the default `CheckLevel`
+pub const LEVEL_VAR: &str = "MUTGUARD_LEVEL";
+
+/// `UNSET` until the default level is first needed
+static DEFAULT_LEVEL: AtomicU8 = AtomicU8::new(UNSET);
+const UNSET: u8 = u8::MAX;
+
+/// the default level for the value of `MUTGUARD_LEVEL`, if it is set
+fn parse_level(value: Option<String>) -> CheckLevel {
+    value
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(CheckLevel::Basic)
+}
+
+/// the level of the guards that did not call `MutGuard::set_level()`
+///
+/// read from `MUTGUARD_LEVEL` the first time it is needed. If the variable
+/// is not set, or holds an unknown level, the default level is `Basic`
+pub fn default_level() -> CheckLevel {
+    let level = match DEFAULT_LEVEL.load(Ordering::Relaxed) {
+        UNSET => {
+            let level = parse_level(env::var(LEVEL_VAR).ok());
+            // keep the level set by `set_default_level()` meanwhile
+            match DEFAULT_LEVEL.compare_exchange(
+                UNSET,
+                level as u8,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => level as u8,
+                Err(current) => current,
+            }
+        }
+        level => level,
+    };
+    match level {
+        0 => CheckLevel::Off,
+        1 => CheckLevel::Basic,
+        _ => CheckLevel::Full,
+    }
+}
+
+/// replaces the default level, whatever `MUTGUARD_LEVEL` holds
+pub fn set_default_level(level: CheckLevel) {
+    DEFAULT_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+type CheckFn<T> = Box<dyn FnMut(&T) + Send>;
+
+struct Check<T> {
+    id: CheckId,
+    priority: i32,
+    name: String,
+    group: Option<String>,
+    level: CheckLevel,
+    severity: Severity,
+    check: CheckFn<T>,
+}
+
+/// checks registered on a `MutGuard`, in the order they run
+pub(crate) struct Checks<T> {
+    checks: Vec<Check<T>>,
+    next: u64,
+    /// groups enabled (`true`) or disabled on this guard, whatever the
+    /// global setting
+    groups: HashMap<String, bool>,
+    /// set with `MutGuard::set_level()`, replaces the default level
+    level: Option<CheckLevel>,
+    /// failed checks with the `Severity::Warning`, not reported yet
+    warnings: Vec<Violation>,
+}
+
+impl<T> Default for Checks<T> {
+    fn default() -> Checks<T> {
+        Checks {
+            checks: Vec::new(),
+            next: 0,
+            groups: HashMap::new(),
+            level: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// set once a group was disabled globally, so guards skip the lock before
+static ANY_DISABLED: AtomicBool = AtomicBool::new(false);
+static DISABLED: RwLock<Option<HashSet<String>>> = RwLock::new(None);
+
+/// disables the checks of `group` on every guard, except the guards where it
+/// was enabled with `MutGuard::enable_check_group()`
+pub fn disable_group<S: Into<String>>(group: S) {
+    let mut disabled = DISABLED.write().unwrap_or_else(|e| e.into_inner());
+    disabled
+        .get_or_insert_with(HashSet::new)
+        .insert(group.into());
+    ANY_DISABLED.store(true, Ordering::Release);
+}
+
+/// enables the checks of `group` again, except on the guards where it was
+/// disabled with `MutGuard::disable_check_group()`
+pub fn enable_group(group: &str) {
+    let mut disabled = DISABLED.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(ref mut groups) = *disabled {
+        groups.remove(group);
+    }
+}
+
+/// whether `group` is enabled, ignoring what was set on each guard
+pub fn is_group_enabled(group: &str) -> bool {
+    if !ANY_DISABLED.load(Ordering::Acquire) {
+        return true;
+    }
+    let disabled = DISABLED.read().unwrap_or_else(|e| e.into_inner());
+    !disabled
+        .as_ref()
+        .is_some_and(|groups| groups.contains(group))
+}
+
+impl<T> Checks<T> {
+    /// failed checks with the `Severity::Warning`, since the last call
+    pub(crate) fn take_warnings(&mut self) -> Vec<Violation> {
+        mem::take(&mut self.warnings)
+    }
+
+    /// runs every check, adding its name to the message of a failure
+    pub(crate) fn run(&mut self, inner: &T) {
+        if self.checks.is_empty() {
+            return;
+        }
+        let level = self.level.unwrap_or_else(default_level);
+        for check in &mut self.checks {
+            if check.level > level {
+                continue;
+            }
+            if let Some(ref group) = check.group {
+                let enabled = self.groups.get(group).cloned();
+                if !enabled.unwrap_or_else(|| is_group_enabled(group)) {
+                    continue;
+                }
+            }
+            let f = &mut check.check;
+            if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| f(inner))) {
+                let violation = Violation::from_panic(payload.as_ref());
+                let mut failed = Violation::new(format!(
+                    "check \"{}\" failed: {}",
+                    check.name,
+                    violation.message()
+                ));
+                if let Some(field) = violation.field() {
+                    failed = failed.with_field(field);
+                }
+                if check.severity == Severity::Warning {
+                    self.warnings.push(failed.with_severity(Severity::Warning));
+                    continue;
+                }
+                // the panic hook already ran for the original panic
+                failed.resume();
+            }
+        }
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// registers `check`, called after `Guard::finish()` every time the
+    /// element is checked, from the next mutable borrow on, with the
+    /// priority 0
+    pub fn add_check<S, F>(&mut self, name: S, check: F) -> CheckId
+    where
+        S: Into<String>,
+        F: 'static + Send + FnMut(&T),
+    {
+        self.add_check_with_priority(name, 0, check)
+    }
+
+    /// like `add_check()`. Checks with a lower priority run first, and
+    /// checks with the same priority run in the order they were added
+    pub fn add_check_with_priority<S, F>(&mut self, name: S, priority: i32, check: F) -> CheckId
+    where
+        S: Into<String>,
+        F: 'static + Send + FnMut(&T),
+    {
+        let checks = self.checks_mut();
+        let id = CheckId(checks.next);
+        checks.next += 1;
+        let index = checks
+            .checks
+            .partition_point(|check| check.priority <= priority);
+        checks.checks.insert(
+            index,
+            Check {
+                id,
+                priority,
+                name: name.into(),
+                group: None,
+                level: CheckLevel::Basic,
+                severity: Severity::Error,
+                check: Box::new(check),
+            },
+        );
+        id
+    }
+
+    /// removes a check added with `add_check()`. Returns false if it was
+    /// already removed
+    pub fn remove_check(&mut self, id: CheckId) -> bool {
+        let checks = &mut self.checks_mut().checks;
+        let len = checks.len();
+        checks.retain(|check| check.id != id);
+        checks.len() != len
+    }
+
+    /// puts a check added with `add_check()` in `group`, replacing its
+    /// previous group. Returns false if the check was removed
+    pub fn set_check_group<S: Into<String>>(&mut self, id: CheckId, group: S) -> bool {
+        match self
+            .checks_mut()
+            .checks
+            .iter_mut()
+            .find(|check| check.id == id)
+        {
+            Some(check) => {
+                check.group = Some(group.into());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// sets the severity of a check added with `add_check()`. When a check
+    /// with the `Severity::Warning` fails, the violation is reported (see
+    /// the `violation` module) and the borrow ends normally. Returns false
+    /// if the check was removed
+    pub fn set_check_severity(&mut self, id: CheckId, severity: Severity) -> bool {
+        match self
+            .checks_mut()
+            .checks
+            .iter_mut()
+            .find(|check| check.id == id)
+        {
+            Some(check) => {
+                check.severity = severity;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// sets the level of a check added with `add_check()`: it only runs
+    /// when the guard's level is at least `level`. Returns false if the
+    /// check was removed
+    pub fn set_check_level(&mut self, id: CheckId, level: CheckLevel) -> bool {
+        match self
+            .checks_mut()
+            .checks
+            .iter_mut()
+            .find(|check| check.id == id)
+        {
+            Some(check) => {
+                check.level = level;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// runs the checks up to `level` on this guard, whatever the default
+    /// level
+    pub fn set_level(&mut self, level: CheckLevel) {
+        self.checks_mut().level = Some(level);
+    }
+
+    /// follows the default level again, see `checks::default_level()`
+    pub fn reset_level(&mut self) {
+        self.checks_mut().level = None;
+    }
+
+    /// runs the checks of `group` on this guard, even if it was disabled
+    /// globally with `checks::disable_group()`
+    pub fn enable_check_group<S: Into<String>>(&mut self, group: S) {
+        self.checks_mut().groups.insert(group.into(), true);
+    }
+
+    /// skips the checks of `group` on this guard, even if it is enabled
+    /// globally
+    pub fn disable_check_group<S: Into<String>>(&mut self, group: S) {
+        self.checks_mut().groups.insert(group.into(), false);
+    }
+
+    /// follows the global setting for `group` again on this guard
+    pub fn reset_check_group(&mut self, group: &str) {
+        self.checks_mut().groups.remove(group);
+    }
+
+    /// names of the checks added with `add_check()`, in the order they run
+    pub fn check_names(&self) -> Vec<String> {
+        let checks = self.checks.lock().unwrap_or_else(|e| e.into_inner());
+        checks
+            .checks
+            .iter()
+            .map(|check| check.name.clone())
+            .collect()
+    }
+
+    fn checks_mut(&mut self) -> &mut Checks<T> {
+        match self.checks.get_mut() {
+            Ok(checks) => checks,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl<T, F> WrappedGuard<T, F> {
+    /// see `MutGuard::add_check()`
+    pub fn add_check<S, G>(&mut self, name: S, mut check: G) -> CheckId
+    where
+        S: Into<String>,
+        G: 'static + Send + FnMut(&T),
+    {
+        self.guard
+            .add_check(name, move |wrapped| check(&wrapped.inner))
+    }
+
+    /// see `MutGuard::add_check_with_priority()`
+    pub fn add_check_with_priority<S, G>(&mut self, name: S, priority: i32, mut check: G) -> CheckId
+    where
+        S: Into<String>,
+        G: 'static + Send + FnMut(&T),
+    {
+        self.guard
+            .add_check_with_priority(name, priority, move |wrapped| check(&wrapped.inner))
+    }
+
+    /// see `MutGuard::remove_check()`
+    pub fn remove_check(&mut self, id: CheckId) -> bool {
+        self.guard.remove_check(id)
+    }
+
+    /// see `MutGuard::set_check_group()`
+    pub fn set_check_group<S: Into<String>>(&mut self, id: CheckId, group: S) -> bool {
+        self.guard.set_check_group(id, group)
+    }
+
+    /// see `MutGuard::set_check_severity()`
+    pub fn set_check_severity(&mut self, id: CheckId, severity: Severity) -> bool {
+        self.guard.set_check_severity(id, severity)
+    }
+
+    /// see `MutGuard::set_check_level()`
+    pub fn set_check_level(&mut self, id: CheckId, level: CheckLevel) -> bool {
+        self.guard.set_check_level(id, level)
+    }
+
+    /// see `MutGuard::set_level()`
+    pub fn set_level(&mut self, level: CheckLevel) {
+        self.guard.set_level(level);
+    }
+
+    /// see `MutGuard::reset_level()`
+    pub fn reset_level(&mut self) {
+        self.guard.reset_level();
+    }
+
+    /// see `MutGuard::enable_check_group()`
+    pub fn enable_check_group<S: Into<String>>(&mut self, group: S) {
+        self.guard.enable_check_group(group);
+    }
+
+    /// see `MutGuard::disable_check_group()`
+    pub fn disable_check_group<S: Into<String>>(&mut self, group: S) {
+        self.guard.disable_check_group(group);
+    }
+
+    /// see `MutGuard::reset_check_group()`
+    pub fn reset_check_group(&mut self, group: &str) {
+        self.guard.reset_check_group(group);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Levels(Vec<u8>);
+
+    impl Guard for Levels {
+        fn finish(&mut self) {
+            assert!(self.0.len() <= 4, "too many levels");
+        }
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut levels = MutGuard::new(Levels::default());
+
+        let logged = order.clone();
+        let sorted = levels.add_check("sorted", move |l: &Levels| {
+            logged.lock().unwrap().push("sorted");
+            assert!(l.0.windows(2).all(|w| w[0] <= w[1]), "unsorted");
+        });
+        let logged = order.clone();
+        let small = levels.add_check("small", move |l: &Levels| {
+            logged.lock().unwrap().push("small");
+            assert!(l.0.iter().all(|level| *level < 10), "level too high");
+        });
+        assert_ne!(sorted, small);
+        assert_eq!(levels.check_names(), vec!["sorted", "small"]);
+
+        levels.guard().0.push(1);
+        assert_eq!(*order.lock().unwrap(), vec!["sorted", "small"]);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| levels.guard().0.push(0)));
+        assert_eq!(
+            res.unwrap_err()
+                .downcast_ref::<String>()
+                .map(|s| s.as_str()),
+            Some("check \"sorted\" failed: unsorted")
+        );
+
+        assert!(levels.remove_check(sorted));
+        assert!(!levels.remove_check(sorted));
+        // [1, 0] is only checked by "small" now
+        levels.guard().0.push(2);
+        assert_eq!(levels.check_names(), vec!["small"]);
+    }
+
+    #[test]
+    fn order() {
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut levels = MutGuard::new(Levels::default());
+        let log = |name: &'static str| {
+            let order = order.clone();
+            move || order.lock().unwrap().push(name)
+        };
+
+        let (a, b, c, d) = (log("a"), log("b"), log("c"), log("d"));
+        levels.add_check("a", move |_: &Levels| a());
+        levels.add_check_with_priority("b", -1, move |_: &Levels| b());
+        levels.add_check("c", move |_: &Levels| c());
+        levels.add_check_with_priority("d", 10, move |l: &Levels| {
+            d();
+            assert!(l.0.len() < 2, "too long");
+        });
+
+        let (persist, notify) = (log("persist"), log("notify"));
+        levels.publish_to_with_priority(5, move |_: &publish::Change<Levels>| notify());
+        levels.publish_to_with_priority(-5, move |_: &publish::Change<Levels>| persist());
+        let deferred = log("deferred");
+        levels.guard().0.push(1);
+        levels.defer(move |_| deferred());
+        levels.guard().0.clear();
+        // nothing runs after a failed check
+        let res = panic::catch_unwind(AssertUnwindSafe(|| levels.guard().0.extend(&[1, 2])));
+        assert!(res.is_err());
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![
+                "b", "a", "c", "d", "persist", "notify", // first borrow
+                "b", "a", "c", "d", "deferred", "persist", "notify", // second
+                "b", "a", "c", "d",
+            ]
+        );
+    }
+
+    #[test]
+    fn groups() {
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let mut levels = MutGuard::new(Levels::default());
+        for (name, group) in &[
+            ("cheap", Some("test-cheap")),
+            ("deep", Some("test-deep")),
+            ("always", None),
+        ] {
+            let runs = runs.clone();
+            let id = levels.add_check(*name, move |_: &Levels| runs.lock().unwrap().push(*name));
+            if let Some(group) = *group {
+                assert!(levels.set_check_group(id, group));
+            }
+        }
+        let ran = |levels: &mut MutGuard<Levels>| {
+            levels.guard().0.clear();
+            mem::take(&mut *runs.lock().unwrap())
+        };
+        assert_eq!(ran(&mut levels), vec!["cheap", "deep", "always"]);
+
+        disable_group("test-deep");
+        assert!(!is_group_enabled("test-deep"));
+        assert_eq!(ran(&mut levels), vec!["cheap", "always"]);
+
+        levels.enable_check_group("test-deep");
+        levels.disable_check_group("test-cheap");
+        assert_eq!(ran(&mut levels), vec!["deep", "always"]);
+
+        levels.reset_check_group("test-deep");
+        levels.reset_check_group("test-cheap");
+        assert_eq!(ran(&mut levels), vec!["cheap", "always"]);
+
+        enable_group("test-deep");
+        assert!(is_group_enabled("test-deep"));
+        assert_eq!(ran(&mut levels), vec!["cheap", "deep", "always"]);
+    }
+
+    #[test]
+    fn levels() {
+        assert_eq!("off".parse(), Ok(CheckLevel::Off));
+        assert_eq!(" Full\n".parse(), Ok(CheckLevel::Full));
+        assert_eq!(
+            "all".parse::<CheckLevel>().unwrap_err().to_string(),
+            "unknown check level \"all\", expected off, basic or full"
+        );
+
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let mut levels = MutGuard::new(Levels::default());
+        for &(name, level) in &[("basic", CheckLevel::Basic), ("full", CheckLevel::Full)] {
+            let runs = runs.clone();
+            let id = levels.add_check(name, move |_: &Levels| runs.lock().unwrap().push(name));
+            assert!(levels.set_check_level(id, level));
+        }
+        let ran = |levels: &mut MutGuard<Levels>| {
+            levels.guard().0.clear();
+            mem::take(&mut *runs.lock().unwrap())
+        };
+
+        // the default level is global, so the test only sets it on the guard
+        levels.set_level(CheckLevel::Off);
+        assert!(ran(&mut levels).is_empty());
+        levels.set_level(CheckLevel::Basic);
+        assert_eq!(ran(&mut levels), vec!["basic"]);
+        levels.set_level(CheckLevel::Full);
+        assert_eq!(ran(&mut levels), vec!["basic", "full"]);
+
+        levels.reset_level();
+        let expected = match default_level() {
+            CheckLevel::Off => vec![],
+            CheckLevel::Basic => vec!["basic"],
+            CheckLevel::Full => vec!["basic", "full"],
+        };
+        assert_eq!(ran(&mut levels), expected);
+    }
+
+    #[test]
+    fn level_from_env() {
+        assert_eq!(parse_level(Some("off".to_string())), CheckLevel::Off);
+        assert_eq!(parse_level(Some(" Full".to_string())), CheckLevel::Full);
+        assert_eq!(
+            parse_level(Some("everything".to_string())),
+            CheckLevel::Basic
+        );
+        assert_eq!(parse_level(None), CheckLevel::Basic);
+    }
+
+    #[test]
+    fn warnings() {
+        let mut levels = MutGuard::new(Levels::default());
+        let short = levels.add_check("short", |l: &Levels| assert!(l.0.len() <= 2, "long"));
+        assert!(levels.set_check_severity(short, Severity::Warning));
+        levels.add_check("small", |l: &Levels| {
+            assert!(l.0.iter().all(|level| *level < 10), "big")
+        });
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        {
+            let reported = reported.clone();
+            levels.on_violation(move |v| {
+                reported
+                    .lock()
+                    .unwrap()
+                    .push((v.severity(), v.message().to_string()))
+            });
+        }
+        let published = Arc::new(Mutex::new(0));
+        {
+            let published = published.clone();
+            levels.publish_to(move |_: &::publish::Change<Levels>| *published.lock().unwrap() += 1);
+        }
+
+        // the warning does not stop the borrow
+        levels.guard().0.extend(&[1, 2, 3]);
+        assert_eq!(*published.lock().unwrap(), 1);
+        levels.guard().0.push(10);
+        assert_eq!(*published.lock().unwrap(), 1);
+        assert_eq!(levels.violation_count(), 3);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec![
+                (
+                    Severity::Warning,
+                    "check \"short\" failed: long".to_string()
+                ),
+                (
+                    Severity::Warning,
+                    "check \"short\" failed: long".to_string()
+                ),
+                (Severity::Error, "check \"small\" failed: big".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "too many levels")]
+    fn after_finish() {
+        let mut levels = MutGuard::new(Levels::default());
+        levels.add_check("never", |_: &Levels| panic!("should not run"));
+        levels.guard().0.extend(&[1, 2, 3, 4, 5]);
+    }
+}
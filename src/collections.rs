@@ -0,0 +1,683 @@
+//! Guarded collections
+//!
+//! these collections only expose their mutating operations through
+//! methods that check the collection afterwards. Unlike a `MutGuard`, the
+//! check knows which part of the collection changed.
+//!
+//! `GuardedBTreeMap` calls its check with the range of keys affected by
+//! each mutation, so invariants between neighbouring entries only need to
+//! look at that region:
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::collections::*;
+//! use std::collections::BTreeMap;
+//! use std::ops::Bound;
+//!
+//! # fn main() {
+//! // intervals keyed by start, storing their end. They must not overlap
+//! let mut intervals = GuardedBTreeMap::new(|map: &BTreeMap<u32, u32>, affected: &KeyRange<u32>| {
+//!   // the interval preceding the affected range can overlap it too
+//!   let before = match affected.start() {
+//!     Bound::Included(start) | Bound::Excluded(start) => map.range(..start).next_back(),
+//!     Bound::Unbounded => None,
+//!   };
+//!   let mut end = before.map(|(_, end)| *end);
+//!   for (start, next_end) in map.range(affected.clone()) {
+//!     assert!(end.map(|end| end <= *start).unwrap_or(true), "overlapping intervals");
+//!     end = Some(*next_end);
+//!   }
+//! });
+//!
+//! intervals.insert(0, 10);
+//! intervals.insert(20, 30);
+//! intervals.update(&20, |end| *end = 25);
+//! assert_eq!(intervals.get(&20), Some(&25));
+//! # }
+//! ```
+//!
+//! `GuardedVecDeque` is a bounded queue: pushing to a full queue fails, and
+//! hooks can observe every push and pop:
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::collections::*;
+//! # fn main() {
+//! let mut jobs = GuardedVecDeque::with_limit(2);
+//! jobs.on_push(|job: &&str, queue| println!("queued {}, {} pending", job, queue.len()));
+//!
+//! jobs.push_back("build").unwrap();
+//! jobs.push_back("test").unwrap();
+//! let err = jobs.push_back("deploy").unwrap_err();
+//! assert_eq!(err.into_inner(), "deploy");
+//!
+//! assert_eq!(jobs.pop_front(), Some("build"));
+//! # }
+//! ```
+//!
+//! `GuardedString` applies content rules after every mutation:
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::collections::*;
+//! # fn main() {
+//! let rules = StringRules::new()
+//!   .max_len(16)
+//!   .charset(|c| c.is_ascii_alphanumeric() || c == '-')
+//!   .normalize(|s| s.make_ascii_lowercase());
+//!
+//! let mut slug = GuardedString::new("Hello", rules);
+//! slug.push_str("-World");
+//! assert_eq!(slug.as_str(), "hello-world");
+//!
+//! slug.edit().truncate(5);
+//! assert_eq!(slug.as_str(), "hello");
+//! # }
+//! ```
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::ops::{Bound, Deref, DerefMut, RangeBounds};
+
+use super::hold::Unwinding;
+use super::{run_guard, Guard, ARMED};
+
+/// range of keys affected by a mutation of a `GuardedBTreeMap`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyRange<K> {
+    start: Bound<K>,
+    end: Bound<K>,
+}
+
+impl<K: Clone> KeyRange<K> {
+    /// range containing only `key`
+    pub fn key(key: K) -> KeyRange<K> {
+        KeyRange {
+            start: Bound::Included(key.clone()),
+            end: Bound::Included(key),
+        }
+    }
+
+    /// range containing every key
+    pub fn all() -> KeyRange<K> {
+        KeyRange {
+            start: Bound::Unbounded,
+            end: Bound::Unbounded,
+        }
+    }
+
+    pub fn from_bounds<R: RangeBounds<K>>(range: &R) -> KeyRange<K> {
+        KeyRange {
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }
+    }
+
+    pub fn start(&self) -> Bound<&K> {
+        self.start.as_ref()
+    }
+
+    pub fn end(&self) -> Bound<&K> {
+        self.end.as_ref()
+    }
+}
+
+impl<K> RangeBounds<K> for KeyRange<K> {
+    fn start_bound(&self) -> Bound<&K> {
+        self.start.as_ref()
+    }
+
+    fn end_bound(&self) -> Bound<&K> {
+        self.end.as_ref()
+    }
+}
+
+/// `BTreeMap` calling a check with the affected keys after each mutation
+pub struct GuardedBTreeMap<K, V, F: FnMut(&BTreeMap<K, V>, &KeyRange<K>)> {
+    map: BTreeMap<K, V>,
+    check: F,
+}
+
+impl<K: Ord + Clone, V, F: FnMut(&BTreeMap<K, V>, &KeyRange<K>)> GuardedBTreeMap<K, V, F> {
+    pub fn new(check: F) -> GuardedBTreeMap<K, V, F> {
+        GuardedBTreeMap {
+            map: BTreeMap::new(),
+            check,
+        }
+    }
+
+    /// wraps an existing map, checking all of it
+    pub fn from_map(map: BTreeMap<K, V>, check: F) -> GuardedBTreeMap<K, V, F> {
+        let mut guarded = GuardedBTreeMap { map, check };
+        guarded.run_check(&KeyRange::all());
+        guarded
+    }
+
+    fn run_check(&mut self, affected: &KeyRange<K>) {
+        if ARMED {
+            (self.check)(&self.map, affected);
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let affected = KeyRange::key(key.clone());
+        let previous = self.map.insert(key, value);
+        self.run_check(&affected);
+        previous
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let removed = self.map.remove(key);
+        if removed.is_some() {
+            self.run_check(&KeyRange::key(key.clone()));
+        }
+        removed
+    }
+
+    /// modifies the value stored at `key`, if there is one
+    pub fn update<R, U: FnOnce(&mut V) -> R>(&mut self, key: &K, update: U) -> Option<R> {
+        let res = self.map.get_mut(key).map(update);
+        if res.is_some() {
+            self.run_check(&KeyRange::key(key.clone()));
+        }
+        res
+    }
+
+    /// modifies the values stored in `range`
+    pub fn update_range<R: RangeBounds<K>, U: FnMut(&K, &mut V)>(
+        &mut self,
+        range: R,
+        mut update: U,
+    ) {
+        let affected = KeyRange::from_bounds(&range);
+        for (key, value) in self.map.range_mut(range) {
+            update(key, value);
+        }
+        self.run_check(&affected);
+    }
+
+    /// removes the entries in `range`
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) {
+        let affected = KeyRange::from_bounds(&range);
+        let keys: Vec<K> = self.map.range(range).map(|(key, _)| key.clone()).collect();
+        for key in keys.iter() {
+            self.map.remove(key);
+        }
+        self.run_check(&affected);
+    }
+
+    /// keeps only the entries for which `keep` returns true
+    pub fn retain<P: FnMut(&K, &mut V) -> bool>(&mut self, keep: P) {
+        self.map.retain(keep);
+        self.run_check(&KeyRange::all());
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.run_check(&KeyRange::all());
+    }
+
+    /// returns the map, consuming the GuardedBTreeMap
+    pub fn into_inner(self) -> BTreeMap<K, V> {
+        self.map
+    }
+}
+
+impl<K: Ord + Clone, V, F: FnMut(&BTreeMap<K, V>, &KeyRange<K>)> Extend<(K, V)>
+    for GuardedBTreeMap<K, V, F>
+{
+    /// inserts all the entries, then checks the range between the smallest
+    /// and largest inserted keys
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let mut bounds: Option<(K, K)> = None;
+        for (key, value) in iter {
+            bounds = Some(match bounds {
+                None => (key.clone(), key.clone()),
+                Some((min, max)) => {
+                    let min = if key < min { key.clone() } else { min };
+                    let max = if key > max { key.clone() } else { max };
+                    (min, max)
+                }
+            });
+            self.map.insert(key, value);
+        }
+        if let Some((min, max)) = bounds {
+            self.run_check(&KeyRange {
+                start: Bound::Included(min),
+                end: Bound::Included(max),
+            });
+        }
+    }
+}
+
+impl<K, V, F: FnMut(&BTreeMap<K, V>, &KeyRange<K>)> Deref for GuardedBTreeMap<K, V, F> {
+    type Target = BTreeMap<K, V>;
+
+    fn deref(&self) -> &BTreeMap<K, V> {
+        &self.map
+    }
+}
+
+/// error returned when pushing to a full `GuardedVecDeque`. It holds the
+/// rejected element
+pub struct CapacityError<T>(T);
+
+impl<T> CapacityError<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> fmt::Debug for CapacityError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CapacityError(..)")
+    }
+}
+
+impl<T> fmt::Display for CapacityError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the queue is full")
+    }
+}
+
+impl<T> Error for CapacityError<T> {}
+
+/// hook called with an element pushed to or popped from a
+/// `GuardedVecDeque`, and the queue after the operation
+type Hook<T> = Box<dyn FnMut(&T, &VecDeque<T>) + Send>;
+
+/// `VecDeque` holding at most `limit` elements
+pub struct GuardedVecDeque<T> {
+    queue: VecDeque<T>,
+    limit: usize,
+    on_push: Option<Hook<T>>,
+    on_pop: Option<Hook<T>>,
+}
+
+impl<T> GuardedVecDeque<T> {
+    pub fn with_limit(limit: usize) -> GuardedVecDeque<T> {
+        GuardedVecDeque {
+            queue: VecDeque::with_capacity(limit),
+            limit,
+            on_push: None,
+            on_pop: None,
+        }
+    }
+
+    /// calls `hook` after every element pushed
+    pub fn on_push<H: 'static + Send + FnMut(&T, &VecDeque<T>)>(&mut self, hook: H) {
+        self.on_push = Some(Box::new(hook));
+    }
+
+    /// calls `hook` after every element popped
+    pub fn on_pop<H: 'static + Send + FnMut(&T, &VecDeque<T>)>(&mut self, hook: H) {
+        self.on_pop = Some(Box::new(hook));
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.limit
+    }
+
+    pub fn push_back(&mut self, value: T) -> Result<(), CapacityError<T>> {
+        if self.is_full() {
+            return Err(CapacityError(value));
+        }
+        self.queue.push_back(value);
+        if let Some(ref mut hook) = self.on_push {
+            hook(self.queue.back().unwrap(), &self.queue);
+        }
+        Ok(())
+    }
+
+    pub fn push_front(&mut self, value: T) -> Result<(), CapacityError<T>> {
+        if self.is_full() {
+            return Err(CapacityError(value));
+        }
+        self.queue.push_front(value);
+        if let Some(ref mut hook) = self.on_push {
+            hook(self.queue.front().unwrap(), &self.queue);
+        }
+        Ok(())
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let value = self.queue.pop_front()?;
+        self.popped(&value);
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<T> {
+        let value = self.queue.pop_back()?;
+        self.popped(&value);
+        Some(value)
+    }
+
+    fn popped(&mut self, value: &T) {
+        if let Some(ref mut hook) = self.on_pop {
+            hook(value, &self.queue);
+        }
+    }
+
+    /// changes the limit. Elements already in the queue are kept, even if
+    /// there are more than `limit`
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+    }
+
+    /// returns the queue, consuming the GuardedVecDeque
+    pub fn into_inner(self) -> VecDeque<T> {
+        self.queue
+    }
+}
+
+impl<T> Deref for GuardedVecDeque<T> {
+    type Target = VecDeque<T>;
+
+    fn deref(&self) -> &VecDeque<T> {
+        &self.queue
+    }
+}
+
+/// normalization applied by a `GuardedString`
+type Normalize = Box<dyn Fn(&mut String) + Send + Sync>;
+
+/// content rules of a `GuardedString`
+#[derive(Default)]
+pub struct StringRules {
+    max_len: Option<usize>,
+    charset: Option<Box<dyn Fn(char) -> bool + Send + Sync>>,
+    normalize: Option<Normalize>,
+}
+
+impl StringRules {
+    /// rules accepting any string
+    pub fn new() -> StringRules {
+        StringRules::default()
+    }
+
+    /// maximum length in bytes
+    pub fn max_len(mut self, max_len: usize) -> StringRules {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// accepts only the characters for which `allowed` returns true
+    pub fn charset<F: 'static + Send + Sync + Fn(char) -> bool>(
+        mut self,
+        allowed: F,
+    ) -> StringRules {
+        self.charset = Some(Box::new(allowed));
+        self
+    }
+
+    /// applies `normalize` after every mutation, before checking the rules
+    pub fn normalize<F: 'static + Send + Sync + Fn(&mut String)>(
+        mut self,
+        normalize: F,
+    ) -> StringRules {
+        self.normalize = Some(Box::new(normalize));
+        self
+    }
+}
+
+/// `String` normalized and checked against its `StringRules` after every
+/// mutation. It panics when the content breaks a rule
+pub struct GuardedString {
+    value: String,
+    rules: StringRules,
+}
+
+impl GuardedString {
+    pub fn new<S: Into<String>>(value: S, rules: StringRules) -> GuardedString {
+        let mut guarded = GuardedString {
+            value: value.into(),
+            rules,
+        };
+        run_guard(&mut guarded);
+        guarded
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.value.push(c);
+        run_guard(self);
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.value.push_str(s);
+        run_guard(self);
+    }
+
+    pub fn insert(&mut self, index: usize, c: char) {
+        self.value.insert(index, c);
+        run_guard(self);
+    }
+
+    pub fn insert_str(&mut self, index: usize, s: &str) {
+        self.value.insert_str(index, s);
+        run_guard(self);
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        let c = self.value.pop();
+        run_guard(self);
+        c
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.value.truncate(len);
+        run_guard(self);
+    }
+
+    pub fn clear(&mut self) {
+        self.value.clear();
+        run_guard(self);
+    }
+
+    /// replaces the whole content
+    pub fn set<S: Into<String>>(&mut self, value: S) {
+        self.value = value.into();
+        run_guard(self);
+    }
+
+    /// mutable access to the `String`, checked when the borrow is dropped
+    pub fn edit(&mut self) -> StringBorrow<'_> {
+        StringBorrow {
+            inner: self,
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    /// returns the string, consuming the GuardedString
+    pub fn into_inner(self) -> String {
+        self.value
+    }
+}
+
+impl Guard for GuardedString {
+    fn normalize(&mut self) {
+        if let Some(ref normalize) = self.rules.normalize {
+            normalize(&mut self.value);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(max_len) = self.rules.max_len {
+            assert!(
+                self.value.len() <= max_len,
+                "string of {} bytes is longer than {}",
+                self.value.len(),
+                max_len
+            );
+        }
+        if let Some(ref allowed) = self.rules.charset {
+            if let Some(c) = self.value.chars().find(|c| !allowed(*c)) {
+                panic!("forbidden character {:?} in {:?}", c, self.value);
+            }
+        }
+    }
+}
+
+impl Deref for GuardedString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.value
+    }
+}
+
+impl fmt::Debug for GuardedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl fmt::Display for GuardedString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// Structure returned by `GuardedString::edit()`. when this is dropped, the
+/// string is normalized and checked
+pub struct StringBorrow<'a> {
+    inner: &'a mut GuardedString,
+    unwinding: Unwinding,
+}
+
+impl<'a> Deref for StringBorrow<'a> {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.inner.value
+    }
+}
+
+impl<'a> DerefMut for StringBorrow<'a> {
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.inner.value
+    }
+}
+
+impl<'a> Drop for StringBorrow<'a> {
+    fn drop(&mut self) {
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(self.inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn affected_ranges() {
+        let ranges = RefCell::new(Vec::new());
+        let mut map = GuardedBTreeMap::new(|_: &BTreeMap<u32, &str>, affected: &KeyRange<u32>| {
+            ranges.borrow_mut().push(affected.clone());
+        });
+
+        map.insert(1, "a");
+        map.insert(5, "b");
+        map.update(&5, |v| *v = "c");
+        map.update(&6, |v| *v = "d");
+        map.update_range(2..6, |_, v| *v = "e");
+        map.extend(vec![(9, "f"), (7, "g")]);
+        map.remove(&3);
+        map.remove_range(..2);
+        drop(map);
+
+        assert_eq!(
+            ranges.into_inner(),
+            vec![
+                KeyRange::key(1),
+                KeyRange::key(5),
+                KeyRange::key(5),
+                KeyRange::from_bounds(&(2..6)),
+                KeyRange::from_bounds(&(7..=9)),
+                KeyRange::from_bounds(&(..2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn bounded_queue() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut queue = GuardedVecDeque::with_limit(2);
+
+        let pushed = events.clone();
+        queue.on_push(move |v: &u32, q| pushed.lock().unwrap().push(("push", *v, q.len())));
+        let popped = events.clone();
+        queue.on_pop(move |v: &u32, q| popped.lock().unwrap().push(("pop", *v, q.len())));
+
+        queue.push_back(1).unwrap();
+        queue.push_front(2).unwrap();
+        assert!(queue.is_full());
+        assert_eq!(queue.push_back(3).unwrap_err().into_inner(), 3);
+
+        assert_eq!(queue.pop_back(), Some(1));
+        queue.push_back(4).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("push", 1, 1),
+                ("push", 2, 2),
+                ("pop", 1, 1),
+                ("push", 4, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn string_rules() {
+        let rules = StringRules::new()
+            .max_len(5)
+            .charset(|c| c.is_ascii_digit())
+            .normalize(|s| {
+                let trimmed = s.trim().to_string();
+                *s = trimmed;
+            });
+        let mut digits = GuardedString::new(" 12 ", rules);
+        assert_eq!(digits.as_str(), "12");
+
+        digits.insert(0, '0');
+        digits.edit().push_str("34 ");
+        assert_eq!(digits.as_str(), "01234");
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| digits.push('5')));
+        assert!(res.is_err());
+
+        digits.truncate(2);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| digits.insert_str(1, "a")));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn failed_check() {
+        let mut map = GuardedBTreeMap::new(|map: &BTreeMap<u32, u32>, affected: &KeyRange<u32>| {
+            for (k, v) in map.range(affected.clone()) {
+                assert!(v >= k, "value below its key");
+            }
+        });
+
+        map.insert(1, 2);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            map.update(&1, |v| *v = 0);
+        }));
+        assert!(res.is_err());
+    }
+}
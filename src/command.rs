@@ -0,0 +1,177 @@
+//! Event sourcing on top of `MutGuard`
+//!
+//! mutations are expressed as commands that are applied through the guard,
+//! so `Guard::finish` runs after every one of them. A `CommandLog` records
+//! the applied commands, and can replay them to reconstruct the state.
+//!
+//! Commands are plain values: derive `Serialize`/`Deserialize` on them to
+//! store the log.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::command::*;
+//! #
+//! # fn main() {
+//! #[derive(Debug)]
+//! struct Counter(u32);
+//!
+//! impl Guard for Counter {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 <= 10, "counter is too large: {}", self.0);
+//!   }
+//! }
+//!
+//! struct Add(u32);
+//!
+//! impl Command<Counter> for Add {
+//!   fn apply(&self, target: &mut Counter) {
+//!     target.0 += self.0;
+//!   }
+//! }
+//!
+//! let mut log = CommandLog::new(Counter(0));
+//! log.apply_command(Add(2));
+//! log.apply_command(Add(3));
+//! assert_eq!(log.0, 5);
+//!
+//! let (_, commands) = log.into_parts();
+//! let replayed = CommandLog::replay(Counter(0), commands);
+//! assert_eq!(replayed.0, 5);
+//! # }
+//! ```
+use std::ops::Deref;
+
+use super::{Guard, MutGuard};
+
+/// a mutation that can be applied to a `T`
+pub trait Command<T> {
+    fn apply(&self, target: &mut T);
+}
+
+impl<T: Guard> MutGuard<T> {
+    /// applies `command` to the inner element through `guard()`, so
+    /// `Guard::finish` is called once the command is done
+    pub fn apply_command<C: Command<T>>(&mut self, command: &C) {
+        command.apply(&mut self.guard());
+    }
+}
+
+/// stores a `MutGuard` and the list of commands that were applied to it
+pub struct CommandLog<T: Guard, C: Command<T>> {
+    state: MutGuard<T>,
+    commands: Vec<C>,
+}
+
+impl<T: Guard, C: Command<T>> CommandLog<T, C> {
+    pub fn new(initial: T) -> CommandLog<T, C> {
+        CommandLog {
+            state: MutGuard::new(initial),
+            commands: Vec::new(),
+        }
+    }
+
+    /// rebuilds the state by applying every command in order to `initial`.
+    /// `Guard::finish` runs after each of them
+    pub fn replay<I>(initial: T, commands: I) -> CommandLog<T, C>
+    where
+        I: IntoIterator<Item = C>,
+    {
+        let mut log = CommandLog::new(initial);
+        for command in commands {
+            log.apply_command(command);
+        }
+        log
+    }
+
+    /// applies `command` through the guard, then records it.
+    ///
+    /// If `Guard::finish` panics, the command is not recorded
+    pub fn apply_command(&mut self, command: C) {
+        self.state.apply_command(&command);
+        self.commands.push(command);
+    }
+
+    /// returns the commands applied so far, in order
+    pub fn commands(&self) -> &[C] {
+        &self.commands
+    }
+
+    /// returns the current state and the command log, consuming the `CommandLog`
+    pub fn into_parts(self) -> (T, Vec<C>) {
+        (self.state.into_inner(), self.commands)
+    }
+}
+
+impl<T: Guard, C: Command<T>> Deref for CommandLog<T, C> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct Stack {
+        items: Vec<u8>,
+        checks: usize,
+    }
+
+    impl Guard for Stack {
+        fn finish(&mut self) {
+            self.checks += 1;
+            assert!(self.items.len() <= 3, "stack is full");
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Op {
+        Push(u8),
+        Pop,
+    }
+
+    impl Command<Stack> for Op {
+        fn apply(&self, target: &mut Stack) {
+            match *self {
+                Op::Push(i) => target.items.push(i),
+                Op::Pop => {
+                    target.items.pop();
+                }
+            }
+        }
+    }
+
+    fn empty() -> Stack {
+        Stack {
+            items: Vec::new(),
+            checks: 0,
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let mut log = CommandLog::new(empty());
+        log.apply_command(Op::Push(1));
+        log.apply_command(Op::Push(2));
+        log.apply_command(Op::Pop);
+
+        assert_eq!(log.items, vec![1]);
+        assert_eq!(log.checks, 3);
+        assert_eq!(log.commands(), &[Op::Push(1), Op::Push(2), Op::Pop][..]);
+
+        let (state, commands) = log.into_parts();
+        let replayed = CommandLog::replay(empty(), commands);
+        assert_eq!(*replayed, state);
+    }
+
+    #[test]
+    #[should_panic(expected = "stack is full")]
+    fn replay_checks_every_step() {
+        let commands = vec![Op::Push(1), Op::Push(2), Op::Push(3), Op::Push(4), Op::Pop];
+        CommandLog::replay(empty(), commands);
+    }
+}
@@ -0,0 +1,262 @@
+//! Compressed persistence
+//!
+//! *Note*: this module requires the `zstd` or `lz4` feature.
+//!
+//! `CompressedStorage` wraps a `persist::Storage`, and compresses the
+//! snapshots and log entries of a `PersistentMutGuard` or
+//! `JournaledMutGuard` before they reach it. The algorithm is chosen per
+//! storage, so per guard: zstd for a better ratio, lz4 for speed.
+//!
+//! Each stored blob starts with a byte naming its algorithm, so snapshots
+//! written before compression was enabled still load. Combined with
+//! `encrypt::EncryptedStorage`, the `CompressedStorage` must be the outer
+//! one: encrypted data does not compress.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use mut_guard::*;
+//! # use mut_guard::compress::*;
+//! # use mut_guard::persist::*;
+//! #
+//! #[derive(Serialize, Deserialize, Debug)]
+//! struct Catalog {
+//!   names: Vec<String>,
+//! }
+//!
+//! impl Guard for Catalog {
+//!   fn finish(&mut self) {
+//!     assert!(self.names.windows(2).all(|w| w[0] < w[1]), "names should be sorted");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let path = std::env::temp_dir().join(format!("mutguard-doc-compress-{}.json", std::process::id()));
+//! # #[cfg(feature = "zstd")]
+//! let compression = Compression::Zstd { level: 3 };
+//! # #[cfg(not(feature = "zstd"))]
+//! # let compression = Compression::Lz4;
+//! let storage = CompressedStorage::new(FileStorage::new(&path), compression);
+//!
+//! let names = (0..1000).map(|i| format!("product-{:04}", i)).collect();
+//! let catalog = PersistentMutGuard::create_with(storage, Catalog { names }).unwrap();
+//! assert_eq!(catalog.names.len(), 1000);
+//! assert!(std::fs::metadata(&path).unwrap().len() < 5000);
+//! # std::fs::remove_file(&path).unwrap();
+//! # std::fs::remove_file(path.with_extension("json.lock")).unwrap();
+//! # }
+//! ```
+use std::io;
+
+use super::persist::{Journal, Storage};
+
+const ZSTD: u8 = 1;
+const LZ4: u8 = 2;
+
+/// algorithm used by a `CompressedStorage`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// zstd, with a level from 1 (fastest) to 22 (smallest)
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+    /// lz4 block compression
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (tag, compressed) = match *self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level } => (ZSTD, zstd::bulk::compress(data, level)?),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => (LZ4, lz4_flex::compress_prepend_size(data)),
+        };
+
+        let mut tagged = Vec::with_capacity(1 + compressed.len());
+        tagged.push(tag);
+        tagged.extend_from_slice(&compressed);
+        Ok(tagged)
+    }
+}
+
+/// decompresses with the algorithm named by the first byte. Other values
+/// were stored without compression
+fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match data.first() {
+        Some(&ZSTD) => {
+            #[cfg(feature = "zstd")]
+            return zstd::stream::decode_all(&data[1..]);
+            #[cfg(not(feature = "zstd"))]
+            return Err(unsupported("zstd"));
+        }
+        Some(&LZ4) => {
+            #[cfg(feature = "lz4")]
+            return lz4_flex::decompress_size_prepended(&data[1..])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            #[cfg(not(feature = "lz4"))]
+            return Err(unsupported("lz4"));
+        }
+        _ => Ok(data),
+    }
+}
+
+#[cfg(not(all(feature = "zstd", feature = "lz4")))]
+fn unsupported(algorithm: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "compressed with {}, which requires the `{}` feature",
+            algorithm, algorithm
+        ),
+    )
+}
+
+/// `Storage` compressing what it stores in another `Storage`
+///
+/// locking is left to the wrapped storage
+#[derive(Clone, Debug)]
+pub struct CompressedStorage<S> {
+    storage: S,
+    compression: Compression,
+}
+
+impl<S: Storage> CompressedStorage<S> {
+    pub fn new(storage: S, compression: Compression) -> CompressedStorage<S> {
+        CompressedStorage {
+            storage,
+            compression,
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.storage
+    }
+}
+
+impl<S: Storage> Storage for CompressedStorage<S> {
+    type Lock = S::Lock;
+
+    fn lock(&self) -> io::Result<S::Lock> {
+        self.storage.lock()
+    }
+
+    fn lock_shared(&self) -> io::Result<S::Lock> {
+        self.storage.lock_shared()
+    }
+
+    fn load(&self) -> io::Result<Option<Vec<u8>>> {
+        self.storage.load()?.map(decompress).transpose()
+    }
+
+    fn store(&self, snapshot: &[u8]) -> io::Result<()> {
+        self.storage.store(&self.compression.compress(snapshot)?)
+    }
+}
+
+impl<S: Journal> Journal for CompressedStorage<S> {
+    fn append(&self, entry: &[u8]) -> io::Result<()> {
+        self.storage.append(&self.compression.compress(entry)?)
+    }
+
+    fn entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        self.storage
+            .entries()?
+            .into_iter()
+            .map(decompress)
+            .collect()
+    }
+
+    fn truncate(&self) -> io::Result<()> {
+        self.storage.truncate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Command;
+    use super::super::persist::*;
+    use super::super::Guard;
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Log(Vec<String>);
+
+    impl Guard for Log {
+        fn finish(&mut self) {}
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Push(String);
+
+    impl Command<Log> for Push {
+        fn apply(&self, target: &mut Log) {
+            target.0.push(self.0.clone());
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("mutguard-compress-{}-{}.json", process::id(), name))
+    }
+
+    fn cleanup(path: &Path) {
+        for suffix in &["", ".lock", ".wal"] {
+            let mut name = path.as_os_str().to_os_string();
+            name.push(suffix);
+            let _ = fs::remove_file(name);
+        }
+    }
+
+    fn algorithms() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level: 3 },
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for (i, compression) in algorithms().into_iter().enumerate() {
+            let path = temp_path(&format!("round-trip-{}", i));
+            let storage = CompressedStorage::new(FileStorage::new(&path), compression);
+            let mut log = JournaledMutGuard::create_with(storage.clone(), Log(Vec::new())).unwrap();
+            log.apply(Push("a".repeat(1000))).unwrap();
+            log.checkpoint().unwrap();
+            log.apply(Push("b".repeat(1000))).unwrap();
+
+            assert!(fs::metadata(&path).unwrap().len() < 200);
+            assert!(FileStorage::new(&path).entries().unwrap()[0].len() < 200);
+
+            let reopened = JournaledMutGuard::<Log, Push, _>::open_with(storage).unwrap();
+            assert_eq!(reopened.0, vec!["a".repeat(1000), "b".repeat(1000)]);
+            cleanup(&path);
+        }
+    }
+
+    #[test]
+    fn uncompressed_snapshot() {
+        let path = temp_path("uncompressed");
+        PersistentMutGuard::create(&path, Log(vec!["plain".to_string()])).unwrap();
+
+        let compression = algorithms()[0];
+        let storage = CompressedStorage::new(FileStorage::new(&path), compression);
+        let mut log = PersistentMutGuard::<Log, _>::open_with(storage).unwrap();
+        assert_eq!(log.0, vec!["plain"]);
+
+        // rewritten compressed
+        log.guard().unwrap().0.push("compressed".to_string());
+        assert_eq!(
+            fs::read(&path).unwrap()[0],
+            compression.compress(b"").unwrap()[0]
+        );
+        cleanup(&path);
+    }
+}
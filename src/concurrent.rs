@@ -0,0 +1,260 @@
+//! Guarded concurrent map, built on `dashmap`
+//!
+//! *Note*: this module requires the `dashmap` feature.
+//!
+//! `GuardedDashMap` can be shared between threads. The values must
+//! implement the `Guard` trait, and `Guard::finish` is called on an entry
+//! every time a mutable reference to it (from `get_mut()` or `entry()`) is
+//! released.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::concurrent::*;
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! # fn main() {
+//! #[derive(Debug, Default)]
+//! struct Hits(pub u32);
+//!
+//! impl Guard for Hits {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 <= 1000, "too many hits: {}", self.0);
+//!   }
+//! }
+//!
+//! let cache: Arc<GuardedDashMap<&str, Hits>> = Arc::new(GuardedDashMap::new());
+//!
+//! let handles: Vec<_> = (0..4).map(|_| {
+//!   let cache = cache.clone();
+//!   thread::spawn(move || {
+//!     cache.entry("index").or_default().0 += 1;
+//!   })
+//! }).collect();
+//!
+//! for h in handles {
+//!   h.join().unwrap();
+//! }
+//!
+//! assert_eq!(cache.get("index").unwrap().0, 4);
+//! # }
+//! ```
+use std::borrow::Borrow;
+use std::hash::Hash;
+use std::ops::{Deref, DerefMut, Drop};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::{Ref, RefMut};
+use dashmap::try_result::TryResult;
+use dashmap::DashMap;
+
+use super::hold::Unwinding;
+use super::{run_guard, Guard};
+
+/// concurrent map whose values implement the `Guard` trait, and can only
+/// be mutably borrowed through guarded references
+pub struct GuardedDashMap<K: Eq + Hash, V: Guard> {
+    inner: DashMap<K, V>,
+}
+
+impl<K: Eq + Hash, V: Guard> GuardedDashMap<K, V> {
+    pub fn new() -> GuardedDashMap<K, V> {
+        GuardedDashMap {
+            inner: DashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.contains_key(key)
+    }
+
+    /// checks `value`, then inserts it, returning the previous value
+    pub fn insert(&self, key: K, mut value: V) -> Option<V> {
+        run_guard(&mut value);
+        self.inner.insert(key, value)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.remove(key)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.get(key)
+    }
+
+    /// call this method to get mutable access to an entry. The entry is
+    /// checked when the returned reference is dropped
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<GuardedRefMut<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.inner.get_mut(key).map(GuardedRefMut::new)
+    }
+
+    /// like `get_mut()`, but returns `TryResult::Locked` instead of waiting
+    /// if the entry's shard is locked
+    pub fn try_get_mut<Q>(&self, key: &Q) -> TryResult<GuardedRefMut<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        match self.inner.try_get_mut(key) {
+            TryResult::Present(inner) => TryResult::Present(GuardedRefMut::new(inner)),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    pub fn entry(&self, key: K) -> GuardedEntry<'_, K, V> {
+        GuardedEntry {
+            inner: self.inner.entry(key),
+        }
+    }
+
+    /// returns the wrapped map, consuming the GuardedDashMap
+    pub fn into_inner(self) -> DashMap<K, V> {
+        self.inner
+    }
+}
+
+impl<K: Eq + Hash, V: Guard> Default for GuardedDashMap<K, V> {
+    fn default() -> GuardedDashMap<K, V> {
+        GuardedDashMap::new()
+    }
+}
+
+/// entry in a `GuardedDashMap`, returned by `GuardedDashMap::entry()`
+pub struct GuardedEntry<'a, K: 'a + Eq + Hash, V: 'a + Guard> {
+    inner: Entry<'a, K, V>,
+}
+
+impl<'a, K: Eq + Hash, V: Guard> GuardedEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+
+    pub fn or_insert(self, value: V) -> GuardedRefMut<'a, K, V> {
+        GuardedRefMut::new(self.inner.or_insert(value))
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, f: F) -> GuardedRefMut<'a, K, V> {
+        GuardedRefMut::new(self.inner.or_insert_with(f))
+    }
+
+    pub fn or_default(self) -> GuardedRefMut<'a, K, V>
+    where
+        V: Default,
+    {
+        GuardedRefMut::new(self.inner.or_default())
+    }
+}
+
+/// mutable reference to an entry of a `GuardedDashMap`. when this is
+/// dropped, it will call the `Guard::finish()` method of the value, then
+/// release the shard lock
+pub struct GuardedRefMut<'a, K: 'a + Eq + Hash, V: 'a + Guard> {
+    inner: RefMut<'a, K, V>,
+    unwinding: Unwinding,
+}
+
+impl<'a, K: Eq + Hash, V: Guard> GuardedRefMut<'a, K, V> {
+    fn new(inner: RefMut<'a, K, V>) -> GuardedRefMut<'a, K, V> {
+        GuardedRefMut {
+            inner,
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        self.inner.key()
+    }
+}
+
+impl<'a, K: Eq + Hash, V: Guard> Deref for GuardedRefMut<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.inner
+    }
+}
+
+impl<'a, K: Eq + Hash, V: Guard> DerefMut for GuardedRefMut<'a, K, V> {
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.inner
+    }
+}
+
+impl<'a, K: Eq + Hash, V: Guard> Drop for GuardedRefMut<'a, K, V> {
+    fn drop(&mut self) {
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(&mut *self.inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Stock(i32);
+
+    impl Guard for Stock {
+        fn finish(&mut self) {
+            assert!(self.0 >= 0, "stock should not become negative");
+        }
+    }
+
+    #[test]
+    fn get_mut() {
+        let map = GuardedDashMap::new();
+        assert!(map.insert("apples", Stock(3)).is_none());
+
+        map.get_mut("apples").unwrap().0 -= 1;
+        assert_eq!(map.get("apples").unwrap().0, 2);
+        assert!(map.get_mut("pears").is_none());
+    }
+
+    #[test]
+    fn try_get_mut() {
+        let map = GuardedDashMap::new();
+        map.insert("apples", Stock(3));
+
+        let apples = map.get("apples").unwrap();
+        assert!(map.try_get_mut("apples").is_locked());
+        drop(apples);
+
+        map.try_get_mut("apples").unwrap().0 -= 1;
+        assert!(map.try_get_mut("pears").is_absent());
+    }
+
+    #[test]
+    #[should_panic(expected = "stock should not become negative")]
+    fn entry_violation() {
+        let map = GuardedDashMap::new();
+        map.entry("apples").or_insert(Stock(1)).0 -= 2;
+    }
+}
@@ -0,0 +1,254 @@
+//! Guarded configuration
+//!
+//! *Note*: this module requires the `figment` feature.
+//!
+//! Configuration is assembled from several sources (defaults, files,
+//! environment variables, profiles), and pieces that are valid on their own
+//! can combine into an invalid configuration. `GuardedConfig` extracts the
+//! configuration from a `Figment` and checks it, and does it again every
+//! time a provider is merged or joined, or another profile is selected. If
+//! the combined configuration fails to extract or to pass the checks, the
+//! change is rejected, and the previous configuration stays in place.
+//!
+//! ```rust
+//! # extern crate figment;
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use mut_guard::*;
+//! # use mut_guard::config::GuardedConfig;
+//! use figment::providers::Serialized;
+//! use figment::Figment;
+//!
+//! #[derive(Debug, Deserialize, Serialize)]
+//! struct Pool {
+//!   min_idle: u32,
+//!   max_size: u32,
+//! }
+//!
+//! impl Guard for Pool {
+//!   fn finish(&mut self) {
+//!     assert!(self.min_idle <= self.max_size, "min_idle is above max_size");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let defaults = Pool { min_idle: 2, max_size: 10 };
+//! let mut pool: GuardedConfig<Pool> =
+//!   GuardedConfig::new(Figment::from(Serialized::defaults(defaults))).unwrap();
+//!
+//! // valid alone, but not with the default max_size
+//! assert!(pool.merge(Serialized::default("min_idle", 20)).is_err());
+//! assert_eq!(pool.min_idle, 2);
+//!
+//! pool.merge(Serialized::default("max_size", 50)).unwrap();
+//! pool.merge(Serialized::default("min_idle", 20)).unwrap();
+//! assert_eq!((pool.min_idle, pool.max_size), (20, 50));
+//! # }
+//! ```
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+
+use figment::{Figment, Profile, Provider};
+use serde::de::DeserializeOwned;
+
+use super::revalidate;
+use super::violation::Violation;
+use super::Guard;
+
+/// why a configuration change was rejected
+#[derive(Debug)]
+pub enum ConfigError {
+    /// the configuration could not be extracted from the providers
+    Extract(Box<figment::Error>),
+    /// the extracted configuration did not pass the checks
+    Violation(Violation),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Extract(ref e) => write!(f, "invalid configuration: {}", e),
+            ConfigError::Violation(ref v) => write!(f, "invalid configuration: {}", v),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConfigError::Extract(ref e) => Some(&**e),
+            ConfigError::Violation(ref v) => Some(v),
+        }
+    }
+}
+
+impl From<figment::Error> for ConfigError {
+    fn from(e: figment::Error) -> ConfigError {
+        ConfigError::Extract(Box::new(e))
+    }
+}
+
+/// configuration extracted from a `Figment`, and checked every time the
+/// providers or the profile change
+pub struct GuardedConfig<T> {
+    figment: Figment,
+    value: T,
+}
+
+impl<T: Guard + DeserializeOwned> GuardedConfig<T> {
+    /// extracts and checks the configuration
+    pub fn new(figment: Figment) -> Result<GuardedConfig<T>, ConfigError> {
+        let value = extract(&figment)?;
+        Ok(GuardedConfig { figment, value })
+    }
+
+    /// merges `provider`, its values replacing the current ones, if the
+    /// resulting configuration is valid
+    pub fn merge<P: Provider>(&mut self, provider: P) -> Result<(), ConfigError> {
+        self.replace(self.figment.clone().merge(provider))
+    }
+
+    /// joins `provider`, only adding the values that are not set yet, if
+    /// the resulting configuration is valid
+    pub fn join<P: Provider>(&mut self, provider: P) -> Result<(), ConfigError> {
+        self.replace(self.figment.clone().join(provider))
+    }
+
+    /// switches to `profile`, if the configuration of that profile is valid
+    pub fn select<P: Into<Profile>>(&mut self, profile: P) -> Result<(), ConfigError> {
+        self.replace(self.figment.clone().select(profile))
+    }
+
+    /// extracts the configuration again, for providers reading sources that
+    /// changed, like files. The new configuration is only kept if it is
+    /// valid
+    pub fn reload(&mut self) -> Result<(), ConfigError> {
+        self.value = extract(&self.figment)?;
+        Ok(())
+    }
+
+    /// uses `figment` if its configuration is valid
+    pub fn replace(&mut self, figment: Figment) -> Result<(), ConfigError> {
+        self.value = extract(&figment)?;
+        self.figment = figment;
+        Ok(())
+    }
+}
+
+impl<T> GuardedConfig<T> {
+    /// the providers of the current configuration
+    pub fn figment(&self) -> &Figment {
+        &self.figment
+    }
+
+    /// the profile of the current configuration
+    pub fn profile(&self) -> &Profile {
+        self.figment.profile()
+    }
+
+    /// returns the configuration, consuming the GuardedConfig
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for GuardedConfig<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for GuardedConfig<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuardedConfig")
+            .field("profile", self.figment.profile())
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+/// like `run_guard()`, but a failed check is returned instead of panicking
+fn extract<T: Guard + DeserializeOwned>(figment: &Figment) -> Result<T, ConfigError> {
+    let mut value: T = figment.extract()?;
+    value.normalize();
+    revalidate::check(&mut value).map_err(ConfigError::Violation)?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::providers::Serialized;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    struct Retry {
+        attempts: u32,
+        backoff_ms: Vec<u64>,
+    }
+
+    impl Guard for Retry {
+        fn normalize(&mut self) {
+            self.backoff_ms.sort();
+        }
+
+        fn finish(&mut self) {
+            assert!(
+                self.backoff_ms.len() as u32 >= self.attempts,
+                "missing backoff delays"
+            );
+        }
+    }
+
+    fn retry(attempts: u32, backoff_ms: Vec<u64>) -> Serialized<Retry> {
+        Serialized::defaults(Retry {
+            attempts,
+            backoff_ms,
+        })
+    }
+
+    #[test]
+    fn rejected_merge() {
+        let mut config: GuardedConfig<Retry> =
+            GuardedConfig::new(Figment::from(retry(2, vec![200, 100]))).unwrap();
+        assert_eq!(config.backoff_ms, vec![100, 200]);
+
+        match config.merge(Serialized::default("attempts", 3)) {
+            Err(ConfigError::Violation(v)) => assert_eq!(v.message(), "missing backoff delays"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match config.merge(Serialized::default("attempts", "three")) {
+            Err(ConfigError::Extract(_)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(config.attempts, 2);
+
+        // only adds what is missing
+        config.join(Serialized::default("attempts", 5)).unwrap();
+        assert_eq!(config.attempts, 2);
+
+        config
+            .merge(Serialized::default("backoff_ms", vec![100, 200, 400]))
+            .unwrap();
+        config.merge(Serialized::default("attempts", 3)).unwrap();
+        assert_eq!(config.into_inner().attempts, 3);
+    }
+
+    #[test]
+    fn profiles() {
+        let figment = Figment::from(retry(1, vec![100]))
+            .merge(Serialized::default("attempts", 2).profile("staging"))
+            .merge(Serialized::default("attempts", 1).profile("production"));
+        let mut config: GuardedConfig<Retry> = GuardedConfig::new(figment).unwrap();
+
+        assert!(config.select("staging").is_err());
+        assert_eq!(config.profile(), "default");
+        config.select("production").unwrap();
+        assert_eq!(config.profile(), "production");
+        assert_eq!(config.attempts, 1);
+    }
+}
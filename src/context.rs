@@ -0,0 +1,188 @@
+//! Callbacks receiving access metadata
+//!
+//! `MutGuard::wrap_with_context()` works like `MutGuard::wrap()`, but the
+//! function also gets a `GuardContext` describing the borrow that just
+//! ended: how many mutable borrows happened so far, where this one was
+//! acquired, and how long it was held.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! #
+//! # fn main() {
+//! let mut iv = MutGuard::wrap_with_context(Vec::new(), |v, ctx| {
+//!   println!(
+//!     "mutation #{} at {}, held for {:?}: {:?}",
+//!     ctx.mutations(),
+//!     ctx.location(),
+//!     ctx.borrowed_for(),
+//!     v
+//!   );
+//! });
+//!
+//! iv.guard().push(1);
+//! // prints "mutation #1 at src/main.rs:14:4, held for 1.2µs: [1]"
+//! # }
+//! ```
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::time::{Duration, Instant};
+
+use super::{Guard, MutGuard, MutGuardBorrow};
+
+/// metadata about the mutable borrow that just ended
+#[derive(Clone, Copy, Debug)]
+pub struct GuardContext {
+    mutations: u64,
+    location: &'static Location<'static>,
+    borrowed_for: Duration,
+}
+
+impl GuardContext {
+    /// number of mutable borrows of the element so far, including this one
+    pub fn mutations(&self) -> u64 {
+        self.mutations
+    }
+
+    /// where the borrow was acquired
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// how long the borrow was held
+    pub fn borrowed_for(&self) -> Duration {
+        self.borrowed_for
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// like `wrap()`, but `f` also gets metadata about the borrow
+    pub fn wrap_with_context<F>(inner: T, f: F) -> ContextGuard<T, F>
+    where
+        F: FnMut(&mut T, &GuardContext),
+    {
+        ContextGuard::new(inner, f)
+    }
+}
+
+/// stores an inner element and a function that will be called after
+/// every time the element is mutably borrowed through `guard()`, with a
+/// `GuardContext`. Returned by `MutGuard::wrap_with_context()`
+pub struct ContextGuard<T, F> {
+    guard: MutGuard<Contextual<T, F>>,
+}
+
+struct Contextual<T, F> {
+    inner: T,
+    f: F,
+    mutations: u64,
+    // set by `ContextGuard::guard()` for the current borrow
+    acquired: Option<(&'static Location<'static>, Instant)>,
+}
+
+impl<T, F: FnMut(&mut T, &GuardContext)> Guard for Contextual<T, F> {
+    fn finish(&mut self) {
+        if let Some((location, at)) = self.acquired.take() {
+            let ctx = GuardContext {
+                mutations: self.mutations,
+                location,
+                borrowed_for: at.elapsed(),
+            };
+            (self.f)(&mut self.inner, &ctx);
+        }
+    }
+}
+
+impl<T, F: FnMut(&mut T, &GuardContext)> ContextGuard<T, F> {
+    pub fn new(inner: T, f: F) -> ContextGuard<T, F> {
+        ContextGuard {
+            guard: MutGuard::new(Contextual {
+                inner,
+                f,
+                mutations: 0,
+                acquired: None,
+            }),
+        }
+    }
+
+    /// call this method to get mutable access to the underlying element
+    #[track_caller]
+    pub fn guard(&mut self) -> ContextBorrow<'_, T, F> {
+        let contextual = &mut self.guard.inner;
+        contextual.mutations += 1;
+        contextual.acquired = Some((Location::caller(), Instant::now()));
+
+        ContextBorrow {
+            inner: self.guard.guard(),
+        }
+    }
+
+    /// number of mutable borrows of the element so far
+    pub fn mutations(&self) -> u64 {
+        self.guard.inner.mutations
+    }
+
+    /// returns the wrapped element, consuming the ContextGuard
+    pub fn into_inner(self) -> T {
+        self.guard.into_inner().inner
+    }
+}
+
+impl<T, F> Deref for ContextGuard<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.inner.inner
+    }
+}
+
+/// Structure returned by the `ContextGuard::guard()`. when this is dropped,
+/// it will call the function given to `MutGuard::wrap_with_context()`
+pub struct ContextBorrow<'a, T: 'a, F: 'a + FnMut(&mut T, &GuardContext)> {
+    inner: MutGuardBorrow<'a, Contextual<T, F>>,
+}
+
+impl<'a, T, F: FnMut(&mut T, &GuardContext)> Deref for ContextBorrow<'a, T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.inner.inner.inner
+    }
+}
+
+impl<'a, T, F: FnMut(&mut T, &GuardContext)> DerefMut for ContextBorrow<'a, T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner.inner.inner.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn context() {
+        let mut contexts = Vec::new();
+        let line = line!() + 5;
+
+        {
+            let mut iv = MutGuard::wrap_with_context(Vec::new(), |_, ctx| contexts.push(*ctx));
+
+            iv.guard().push(1);
+            {
+                let mut v = iv.guard();
+                v.push(2);
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert_eq!(iv.mutations(), 2);
+        }
+
+        assert_eq!(contexts.len(), 2);
+        assert_eq!(contexts[0].mutations(), 1);
+        assert_eq!(contexts[1].mutations(), 2);
+        assert_eq!(contexts[0].location().file(), file!());
+        assert_eq!(contexts[0].location().line(), line);
+        assert!(contexts[1].borrowed_for() >= Duration::from_millis(5));
+    }
+}
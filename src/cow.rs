@@ -0,0 +1,171 @@
+//! Guarded clone-on-write values
+//!
+//! `GuardedCow` wraps a `Cow`, and calls `Guard::finish` on the owned value
+//! only when it was mutated through `to_mut()`. Values that are only ever
+//! read are never checked.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::cow::*;
+//! #
+//! # fn main() {
+//! #[derive(Clone, Debug)]
+//! struct Config {
+//!   workers: u8,
+//! }
+//!
+//! impl Guard for Config {
+//!   fn finish(&mut self) {
+//!     assert!(self.workers > 0, "at least one worker is needed");
+//!   }
+//! }
+//!
+//! let default = Config { workers: 4 };
+//! let mut config = GuardedCow::borrowed(&default);
+//!
+//! // clones the default configuration, then checks it
+//! config.to_mut().workers = 8;
+//!
+//! assert!(config.is_owned());
+//! assert_eq!(config.workers, 8);
+//! assert_eq!(default.workers, 4);
+//! # }
+//! ```
+use std::borrow::{Borrow, Cow};
+use std::ops::{Deref, DerefMut, Drop};
+
+use super::hold::Unwinding;
+use super::{run_guard, Guard};
+
+/// wraps a `Cow<'a, T>`, and forbids mutable borrows except going through
+/// its `to_mut()` method
+pub struct GuardedCow<'a, T: 'a + ToOwned + ?Sized> {
+    inner: Cow<'a, T>,
+}
+
+impl<'a, T: ToOwned + ?Sized> GuardedCow<'a, T>
+where
+    T::Owned: Guard,
+{
+    pub fn new(inner: Cow<'a, T>) -> GuardedCow<'a, T> {
+        GuardedCow { inner }
+    }
+
+    pub fn borrowed(inner: &'a T) -> GuardedCow<'a, T> {
+        GuardedCow::new(Cow::Borrowed(inner))
+    }
+
+    pub fn owned(inner: T::Owned) -> GuardedCow<'a, T> {
+        GuardedCow::new(Cow::Owned(inner))
+    }
+
+    /// returns true if the value was cloned (or was already owned)
+    pub fn is_owned(&self) -> bool {
+        match self.inner {
+            Cow::Borrowed(_) => false,
+            Cow::Owned(_) => true,
+        }
+    }
+
+    /// call this method to get mutable access to the owned value, cloning
+    /// it if needed. `Guard::finish` is called once the returned borrow
+    /// is dropped
+    pub fn to_mut(&mut self) -> CowBorrow<'_, T::Owned> {
+        CowBorrow {
+            inner: self.inner.to_mut(),
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    /// returns the wrapped `Cow`, consuming the GuardedCow
+    pub fn into_inner(self) -> Cow<'a, T> {
+        self.inner
+    }
+
+    /// returns the owned value (cloning it if needed), consuming the GuardedCow
+    pub fn into_owned(self) -> T::Owned {
+        self.inner.into_owned()
+    }
+}
+
+impl<'a, T: ToOwned + ?Sized> Deref for GuardedCow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self.inner {
+            Cow::Borrowed(b) => b,
+            Cow::Owned(ref o) => o.borrow(),
+        }
+    }
+}
+
+/// Structure returned by `GuardedCow::to_mut()`. when this is dropped, it
+/// will call the `Guard::finish()` method of the owned value
+pub struct CowBorrow<'a, O: 'a + Guard> {
+    inner: &'a mut O,
+    unwinding: Unwinding,
+}
+
+impl<'a, O: Guard> Deref for CowBorrow<'a, O> {
+    type Target = O;
+
+    fn deref(&self) -> &O {
+        self.inner
+    }
+}
+
+impl<'a, O: Guard> DerefMut for CowBorrow<'a, O> {
+    fn deref_mut(&mut self) -> &mut O {
+        self.inner
+    }
+}
+
+impl<'a, O: Guard> Drop for CowBorrow<'a, O> {
+    fn drop(&mut self) {
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(self.inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Limits {
+        min: u32,
+        max: u32,
+    }
+
+    impl Guard for Limits {
+        fn finish(&mut self) {
+            assert!(self.min <= self.max, "min should be lower than max");
+        }
+    }
+
+    #[test]
+    fn read_only() {
+        let limits = Limits { min: 10, max: 1 };
+        // invalid, but never checked since it is not mutated
+        let cow = GuardedCow::borrowed(&limits);
+
+        assert!(!cow.is_owned());
+        assert_eq!(cow.min, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "min should be lower than max")]
+    fn to_mut_checks() {
+        let limits = Limits { min: 1, max: 10 };
+        let mut cow = GuardedCow::borrowed(&limits);
+
+        cow.to_mut().max = 5;
+        assert_eq!(limits.max, 10);
+        assert_eq!(cow.max, 5);
+
+        cow.to_mut().min = 20;
+    }
+}
@@ -0,0 +1,175 @@
+//! Guarded statics shared with interrupt handlers
+//!
+//! *Note*: this module requires the `critical-section` feature.
+//!
+//! `GuardedCriticalCell` holds an element shared between the main loop and
+//! interrupt handlers, in a `static`. Every access runs inside a critical
+//! section, from the `critical-section` crate: the element is mutated in
+//! `with()`, and checked before the critical section ends, so an interrupt
+//! never sees the element in an invalid state.
+//!
+//! The critical section implementation is provided by the application, with
+//! the HAL or `cortex-m` features that enable one, or the `std` feature of
+//! `critical-section` on a host.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::critical::GuardedCriticalCell;
+//! #
+//! #[derive(Debug)]
+//! struct Samples {
+//!   values: [u16; 8],
+//!   len: usize,
+//! }
+//!
+//! impl Guard for Samples {
+//!   fn finish(&mut self) {
+//!     assert!(self.len <= self.values.len(), "sample count out of bounds");
+//!   }
+//! }
+//!
+//! static SAMPLES: GuardedCriticalCell<Samples> =
+//!   GuardedCriticalCell::new(Samples { values: [0; 8], len: 0 });
+//!
+//! // called by the ADC interrupt
+//! fn on_conversion(value: u16) {
+//!   SAMPLES.with(|samples| {
+//!     if samples.len < samples.values.len() {
+//!       samples.values[samples.len] = value;
+//!       samples.len += 1;
+//!     }
+//!   });
+//! }
+//!
+//! # fn main() {
+//! on_conversion(512);
+//! on_conversion(515);
+//!
+//! // in the main loop
+//! let average = SAMPLES.with(|samples| {
+//!   let sum: u32 = samples.values[..samples.len].iter().map(|&v| u32::from(v)).sum();
+//!   let average = sum / samples.len as u32;
+//!   samples.len = 0;
+//!   average
+//! });
+//! assert_eq!(average, 513);
+//! # }
+//! ```
+use std::cell::RefCell;
+
+use critical_section::Mutex;
+
+use super::{logging, run_guard, Guard};
+
+/// stores an element implementing `Guard` in a `static`, accessed inside
+/// critical sections and checked after each mutable access
+pub struct GuardedCriticalCell<T> {
+    inner: Mutex<RefCell<T>>,
+}
+
+impl<T> GuardedCriticalCell<T> {
+    /// `const`, so it can initialize a `static`
+    pub const fn new(inner: T) -> GuardedCriticalCell<T> {
+        GuardedCriticalCell {
+            inner: Mutex::new(RefCell::new(inner)),
+        }
+    }
+
+    /// returns the wrapped element, consuming the GuardedCriticalCell
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().into_inner()
+    }
+
+    /// calls `f` with a shared reference to the element, inside a critical
+    /// section
+    ///
+    /// # Panics
+    ///
+    /// if called from `f` in `with()`, on the same cell
+    pub fn read<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+        critical_section::with(|cs| {
+            let inner = self
+                .inner
+                .borrow(cs)
+                .try_borrow()
+                .expect("GuardedCriticalCell is already mutably borrowed");
+            f(&inner)
+        })
+    }
+}
+
+impl<T: Guard> GuardedCriticalCell<T> {
+    /// calls `f` with a mutable reference to the element, then checks it,
+    /// inside a single critical section. A failed check panics before the
+    /// critical section ends
+    ///
+    /// # Panics
+    ///
+    /// if called from `f` in `read()` or `with()`, on the same cell
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        critical_section::with(|cs| {
+            let mut inner = self
+                .inner
+                .borrow(cs)
+                .try_borrow_mut()
+                .expect("GuardedCriticalCell is already borrowed");
+            logging::acquired::<T>();
+            let result = f(&mut inner);
+            let checking = logging::Checking::start::<T>();
+            run_guard(&mut *inner);
+            checking.passed();
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    #[derive(Debug)]
+    struct Window {
+        low: u8,
+        high: u8,
+    }
+
+    impl Guard for Window {
+        fn finish(&mut self) {
+            assert!(self.low <= self.high, "empty window");
+        }
+    }
+
+    static WINDOW: GuardedCriticalCell<Window> =
+        GuardedCriticalCell::new(Window { low: 10, high: 20 });
+
+    #[test]
+    fn checked_in_critical_section() {
+        WINDOW.with(|w| w.high = 30);
+        assert_eq!(WINDOW.read(|w| w.high), 30);
+
+        let res = panic::catch_unwind(|| WINDOW.with(|w| w.low = 40));
+        assert_eq!(
+            res.unwrap_err().downcast_ref::<&str>(),
+            Some(&"empty window")
+        );
+        // the critical section was released
+        WINDOW.with(|w| w.low = 20);
+        assert_eq!(WINDOW.read(|w| (w.low, w.high)), (20, 30));
+    }
+
+    #[test]
+    #[should_panic(expected = "GuardedCriticalCell is already borrowed")]
+    fn nested() {
+        let cell = GuardedCriticalCell::new(Window { low: 0, high: 0 });
+        cell.read(|_| cell.with(|w| w.high += 1));
+    }
+
+    #[test]
+    fn into_inner() {
+        let cell = GuardedCriticalCell::new(Window { low: 0, high: 1 });
+        cell.with(|w| w.high = 2);
+        assert_eq!(cell.into_inner().high, 2);
+    }
+}
@@ -0,0 +1,175 @@
+//! Deferred validation
+//!
+//! code that mutates its state thousands of times per tick (game loops,
+//! batch imports) does not need a check after every single mutation.
+//! `DeferredMutGuard` only marks the element as dirty when it is borrowed,
+//! and calls `Guard::finish` on `flush()`, or when it is dropped (unless a
+//! panic is unwinding).
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::deferred::*;
+//! #
+//! # fn main() {
+//! #[derive(Debug)]
+//! struct LessThan20(pub u8);
+//!
+//! impl Guard for LessThan20 {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 <= 20, "invariant failed, internal value is too large: {}", self.0);
+//!   }
+//! }
+//!
+//! let mut val = DeferredMutGuard::new(LessThan20(0));
+//!
+//! for _ in 0..30 {
+//!   val.guard().0 += 1;
+//! }
+//! // the value is temporarily invalid, nothing was checked yet
+//! for _ in 0..15 {
+//!   val.guard().0 -= 1;
+//! }
+//!
+//! // the invariant is checked once, here
+//! val.flush();
+//! # }
+//! ```
+use std::ops::{Deref, Drop};
+use std::thread;
+
+use super::{run_guard, Guard};
+
+/// stores an inner element that must implement the `Guard` trait, and
+/// calls `Guard::finish` on `flush()` if it was mutably borrowed since
+/// the last check
+pub struct DeferredMutGuard<T: Guard> {
+    // only `None` after `into_inner()`
+    inner: Option<T>,
+    dirty: bool,
+}
+
+impl<T: Guard> DeferredMutGuard<T> {
+    pub fn new(inner: T) -> DeferredMutGuard<T> {
+        DeferredMutGuard {
+            inner: Some(inner),
+            dirty: false,
+        }
+    }
+
+    /// call this method to get mutable access to the underlying element.
+    /// It will be checked on the next `flush()`
+    pub fn guard(&mut self) -> &mut T {
+        self.dirty = true;
+        self.inner.as_mut().unwrap()
+    }
+
+    /// returns true if the element was mutably borrowed since the last check
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// calls `Guard::normalize` and `Guard::finish` if the element was
+    /// mutably borrowed since the last check
+    pub fn flush(&mut self) {
+        if self.dirty {
+            self.dirty = false;
+            if let Some(inner) = self.inner.as_mut() {
+                run_guard(inner);
+            }
+        }
+    }
+
+    /// flushes pending changes, then returns the wrapped element, consuming
+    /// the DeferredMutGuard
+    pub fn into_inner(mut self) -> T {
+        self.flush();
+        self.inner.take().unwrap()
+    }
+}
+
+impl<T: Guard> Deref for DeferredMutGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.inner.as_ref().unwrap()
+    }
+}
+
+impl<T: Guard> Drop for DeferredMutGuard<T> {
+    fn drop(&mut self) {
+        // the value may be temporarily invalid when a panic interrupted the
+        // mutations, and panicking again would abort
+        if !thread::panicking() {
+            self.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    struct Counted {
+        value: i32,
+        checks: usize,
+    }
+
+    impl Guard for Counted {
+        fn finish(&mut self) {
+            self.checks += 1;
+            assert!(self.value >= 0, "value should not become negative");
+        }
+    }
+
+    #[test]
+    fn coalesce_checks() {
+        let mut val = DeferredMutGuard::new(Counted {
+            value: 0,
+            checks: 0,
+        });
+
+        val.flush();
+        assert_eq!(val.checks, 0);
+
+        val.guard().value -= 10;
+        val.guard().value += 15;
+        assert!(val.is_dirty());
+        val.flush();
+        assert!(!val.is_dirty());
+        assert_eq!(val.checks, 1);
+
+        val.guard().value += 1;
+        let inner = val.into_inner();
+        assert_eq!(inner.value, 6);
+        assert_eq!(inner.checks, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "value should not become negative")]
+    fn flush_on_drop() {
+        let mut val = DeferredMutGuard::new(Counted {
+            value: 0,
+            checks: 0,
+        });
+
+        val.guard().value -= 1;
+    }
+
+    #[test]
+    fn no_flush_while_unwinding() {
+        let result = panic::catch_unwind(|| {
+            let mut val = DeferredMutGuard::new(Counted {
+                value: 0,
+                checks: 0,
+            });
+
+            val.guard().value -= 1;
+            panic!("interrupted");
+        });
+
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"interrupted"));
+    }
+}
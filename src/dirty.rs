@@ -0,0 +1,290 @@
+//! Tracking which fields of an element were modified
+//!
+//! a type implementing `Fields` (usually with `#[derive(TrackFields)]`
+//! from the `derive` feature) can be borrowed with
+//! `MutGuard::guard_tracked()`. The returned borrow gives mutable access to
+//! each field through a `<field>_mut()` method, and records which ones
+//! were accessed in a `FieldSet`. Mutable access to the whole element
+//! marks every field.
+//!
+//! When that borrow is dropped, the element is checked with
+//! `Guard::finish_incremental()` instead of `Guard::finish()`, so large
+//! elements can check only the invariants involving the changed fields.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::dirty::*;
+//! #
+//! #[derive(Debug)]
+//! struct Stock {
+//!   items: Vec<u32>,
+//!   total: u32,
+//! }
+//!
+//! impl Guard for Stock {
+//!   fn finish(&mut self) {
+//!     assert_eq!(self.items.iter().sum::<u32>(), self.total, "bad total");
+//!   }
+//!
+//!   fn finish_incremental(&mut self, changed: &FieldSet) {
+//!     if changed.contains("items") || changed.contains("total") {
+//!       self.finish();
+//!     }
+//!   }
+//! }
+//!
+//! // generated by `#[derive(TrackFields)]`
+//! impl Fields for Stock {
+//!   const FIELDS: &'static [&'static str] = &["items", "total"];
+//! }
+//!
+//! # fn main() {
+//! let mut stock = MutGuard::new(Stock { items: vec![], total: 0 });
+//!
+//! let mut s = stock.guard_tracked();
+//! s.mark(1);
+//! s.as_mut_unmarked().total = 0;
+//! assert!(s.changed().contains("total"));
+//! assert!(!s.changed().contains("items"));
+//! # }
+//! ```
+use std::fmt;
+#[cfg(feature = "std")]
+use std::ops::{Deref, DerefMut};
+
+#[cfg(feature = "std")]
+use super::{Guard, MutGuard, MutGuardBorrow};
+
+/// types whose fields can be tracked by `MutGuard::guard_tracked()`
+pub trait Fields {
+    /// names of the tracked fields, at most 64
+    const FIELDS: &'static [&'static str];
+}
+
+/// set of fields of a type implementing `Fields`
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FieldSet {
+    bits: u64,
+    fields: &'static [&'static str],
+}
+
+impl FieldSet {
+    /// empty set for the fields of `T`
+    pub fn empty<T: Fields + ?Sized>() -> FieldSet {
+        assert!(T::FIELDS.len() <= 64, "at most 64 fields can be tracked");
+        FieldSet {
+            bits: 0,
+            fields: T::FIELDS,
+        }
+    }
+
+    /// set containing every field of `T`
+    pub fn all<T: Fields + ?Sized>() -> FieldSet {
+        let mut set = FieldSet::empty::<T>();
+        set.insert_all();
+        set
+    }
+
+    /// adds the field at `index` in `Fields::FIELDS`
+    pub fn insert(&mut self, index: usize) {
+        assert!(index < self.fields.len(), "unknown field index {}", index);
+        self.bits |= 1 << index;
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if index < 64 {
+            self.bits &= !(1 << index);
+        }
+    }
+
+    pub fn insert_all(&mut self) {
+        self.bits = match self.fields.len() {
+            64 => u64::MAX,
+            n => (1 << n) - 1,
+        };
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.fields
+            .iter()
+            .position(|field| *field == name)
+            .map(|index| self.contains_index(index))
+            .unwrap_or(false)
+    }
+
+    pub fn contains_index(&self, index: usize) -> bool {
+        index < 64 && self.bits & (1 << index) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits.count_ones() as usize
+    }
+
+    /// names of the fields in the set
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.fields
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| self.contains_index(*index))
+            .map(|(_, name)| *name)
+    }
+}
+
+impl fmt::Debug for FieldSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Guard + Fields> MutGuard<T> {
+    /// like `guard()`, but records which fields are mutably accessed
+    #[track_caller]
+    pub fn guard_tracked(&mut self) -> TrackedBorrow<'_, T> {
+        TrackedBorrow {
+            inner: self.guard(),
+            changed: FieldSet::empty::<T>(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+/// calls `Guard::finish_incremental()` if the changed fields are known,
+/// `Guard::finish()` otherwise
+pub(crate) fn finish<T: Guard + ?Sized>(inner: &mut T, changed: Option<&FieldSet>) {
+    match changed {
+        Some(changed) => inner.finish_incremental(changed),
+        None => inner.finish(),
+    }
+}
+
+#[cfg(feature = "std")]
+/// Structure returned by `MutGuard::guard_tracked()`. when this is dropped,
+/// it will call the `Guard::finish_incremental()` method of the element
+pub struct TrackedBorrow<'a, T: 'a + Guard + Fields> {
+    inner: MutGuardBorrow<'a, T>,
+    changed: FieldSet,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard + Fields> TrackedBorrow<'a, T> {
+    /// fields accessed mutably so far
+    pub fn changed(&self) -> &FieldSet {
+        &self.changed
+    }
+
+    /// records the field at `index` as changed. The accessors generated by
+    /// `#[derive(TrackFields)]` call it
+    pub fn mark(&mut self, index: usize) {
+        self.changed.insert(index);
+    }
+
+    /// mutable access to the element without marking any field, for
+    /// accessors that call `mark()` themselves
+    pub fn as_mut_unmarked(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard + Fields> Deref for TrackedBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "std")]
+/// marks every field as changed
+impl<'a, T: Guard + Fields> DerefMut for TrackedBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.changed.insert_all();
+        &mut self.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard + Fields> Drop for TrackedBorrow<'a, T> {
+    fn drop(&mut self) {
+        // the inner borrow runs the checks right after this
+        self.inner.changed = Some(self.changed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    impl Guard for Point {
+        fn finish(&mut self) {
+            panic!("full check");
+        }
+
+        fn finish_incremental(&mut self, changed: &FieldSet) {
+            if changed.contains("x") {
+                assert!(self.x >= 0, "negative x");
+            }
+        }
+    }
+
+    impl Fields for Point {
+        const FIELDS: &'static [&'static str] = &["x", "y"];
+    }
+
+    #[test]
+    fn field_set() {
+        let mut set = FieldSet::empty::<Point>();
+        assert!(set.is_empty());
+
+        set.insert(1);
+        assert!(set.contains("y"));
+        assert!(!set.contains("x"));
+        assert!(!set.contains("z"));
+        assert_eq!(format!("{:?}", set), "{\"y\"}");
+
+        assert_eq!(FieldSet::all::<Point>().len(), 2);
+    }
+
+    #[test]
+    fn tracked_borrow() {
+        let mut point = MutGuard::new(Point::default());
+
+        let mut p = point.guard_tracked();
+        p.mark(0);
+        p.as_mut_unmarked().x = 1;
+        assert_eq!(p.changed().iter().collect::<Vec<_>>(), vec!["x"]);
+
+        p.y = 2;
+        assert_eq!(p.changed().len(), 2);
+    }
+
+    #[test]
+    fn incremental_check() {
+        let mut point = MutGuard::new(Point::default());
+
+        {
+            let mut p = point.guard_tracked();
+            p.mark(1);
+            p.as_mut_unmarked().y = -1;
+        }
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let mut p = point.guard_tracked();
+            p.mark(0);
+            p.as_mut_unmarked().x = -1;
+        }));
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Async guards without `std`
+//!
+//! `actor::AsyncGuardActor` needs tokio, and boxes the futures returned by
+//! `actor::AsyncGuard`. This module only uses `core`, so it is available
+//! without the `std` feature, in firmware running on an embedded executor
+//! like embassy. The asynchronous work done after a mutation (persisting to
+//! flash, signaling another task...) is a future type named by the guarded
+//! type, so it needs neither an allocator nor a lock.
+//!
+//! The mutation and the synchronous checks happen in `AsyncMutGuard::mutate()`
+//! itself, before it returns the future: if that future is dropped before
+//! completion, the element was still checked, and only the asynchronous
+//! work is cancelled.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::embedded::*;
+//! use std::future::{self, Future, Ready};
+//! use std::pin::pin;
+//! use std::task::{Context, Waker};
+//!
+//! #[derive(Debug)]
+//! struct Config {
+//!   brightness: u8,
+//!   saved: u32,
+//! }
+//!
+//! impl Guard for Config {
+//!   fn finish(&mut self) {
+//!     assert!(self.brightness <= 100, "brightness should be a percentage");
+//!   }
+//! }
+//!
+//! impl AsyncFinish for Config {
+//!   // would be a flash write, with a real driver
+//!   type Finish<'a> = Ready<()>;
+//!
+//!   fn finish_async(&mut self) -> Ready<()> {
+//!     self.saved += 1;
+//!     future::ready(())
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut config = AsyncMutGuard::new(Config { brightness: 50, saved: 0 });
+//!
+//! // `.await` in an async task
+//! let previous = pin!(config.mutate(|c| std::mem::replace(&mut c.brightness, 80)));
+//! let res = previous.poll(&mut Context::from_waker(Waker::noop()));
+//! assert!(res.is_ready());
+//! assert_eq!(config.brightness, 80);
+//! assert_eq!(config.saved, 1);
+//! # }
+//! ```
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::{logging, run_guard, Guard};
+
+/// asynchronous work running after the synchronous checks of an
+/// `AsyncMutGuard`, like `actor::AsyncGuard`, without boxing the future
+pub trait AsyncFinish: Guard {
+    type Finish<'a>: Future<Output = ()>
+    where
+        Self: 'a;
+
+    /// called after `Guard::finish()` passed. With the `disarm` feature, it
+    /// still runs after `Guard::normalize()`, like the deferred callbacks of
+    /// a `MutGuard`
+    fn finish_async(&mut self) -> Self::Finish<'_>;
+}
+
+/// stores an element implementing `AsyncFinish`, and only gives mutable
+/// access to it through `mutate()`
+#[derive(Debug, Default)]
+pub struct AsyncMutGuard<T> {
+    inner: T,
+}
+
+impl<T> AsyncMutGuard<T> {
+    /// `const`, so it can initialize a `static`
+    pub const fn new(inner: T) -> AsyncMutGuard<T> {
+        AsyncMutGuard { inner }
+    }
+
+    /// returns the wrapped element, consuming the AsyncMutGuard
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncFinish> AsyncMutGuard<T> {
+    /// applies `f` to the element and checks it right away, then returns a
+    /// future running `AsyncFinish::finish_async()`, that resolves to the
+    /// result of `f`
+    pub fn mutate<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> Mutate<'_, T, R> {
+        logging::acquired::<T>();
+        let result = f(&mut self.inner);
+        let checking = logging::Checking::start::<T>();
+        run_guard(&mut self.inner);
+        checking.passed();
+
+        Mutate {
+            work: self.inner.finish_async(),
+            result: Some(result),
+        }
+    }
+}
+
+impl<T> Deref for AsyncMutGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// future returned by `AsyncMutGuard::mutate()`
+pub struct Mutate<'a, T: 'a + AsyncFinish, R> {
+    work: T::Finish<'a>,
+    result: Option<R>,
+}
+
+impl<'a, T: AsyncFinish, R> Future for Mutate<'a, T, R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        // SAFETY: `work` is never moved out of `self`, and there is no
+        // `Drop` implementation nor `Unpin` implementation that could move it
+        let this = unsafe { self.get_unchecked_mut() };
+        let work = unsafe { Pin::new_unchecked(&mut this.work) };
+
+        match work.poll(cx) {
+            Poll::Ready(()) => {
+                Poll::Ready(this.result.take().expect("Mutate polled after completion"))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::task::Waker;
+
+    /// pending once, so the caller has a chance to drop it
+    struct Flash<'a> {
+        writes: &'a Cell<u32>,
+        yielded: bool,
+    }
+
+    impl<'a> Future for Flash<'a> {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if !self.yielded {
+                self.yielded = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.writes.set(self.writes.get() + 1);
+            Poll::Ready(())
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Counter {
+        value: u8,
+        writes: Cell<u32>,
+    }
+
+    impl Guard for Counter {
+        fn finish(&mut self) {
+            assert!(self.value < 10, "counter is too large: {}", self.value);
+        }
+    }
+
+    impl AsyncFinish for Counter {
+        type Finish<'a> = Flash<'a>;
+
+        fn finish_async(&mut self) -> Flash<'_> {
+            Flash {
+                writes: &self.writes,
+                yielded: false,
+            }
+        }
+    }
+
+    fn poll<F: Future>(f: Pin<&mut F>) -> Poll<F::Output> {
+        f.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn mutate() {
+        let mut counter = AsyncMutGuard::new(Counter::default());
+
+        let mut work = Box::pin(counter.mutate(|c| {
+            c.value += 1;
+            c.value
+        }));
+        assert_eq!(poll(work.as_mut()), Poll::Pending);
+        assert_eq!(poll(work.as_mut()), Poll::Ready(1));
+        drop(work);
+        assert_eq!(counter.writes.get(), 1);
+
+        // cancelled: the element was checked, but not written
+        {
+            let _cancelled = counter.mutate(|c| c.value += 1);
+        }
+        assert_eq!(counter.value, 2);
+        assert_eq!(counter.writes.get(), 1);
+    }
+
+    #[test]
+    fn violation() {
+        let mut counter = AsyncMutGuard::new(Counter::default());
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _work = counter.mutate(|c| c.value = 20);
+        }));
+        assert!(res.is_err());
+        assert_eq!(counter.writes.get(), 0);
+    }
+}
@@ -0,0 +1,280 @@
+//! Encrypted persistence
+//!
+//! *Note*: this module requires the `encryption` feature.
+//!
+//! `EncryptedStorage` wraps a `persist::Storage`, and encrypts the snapshots
+//! and log entries of a `PersistentMutGuard` or `JournaledMutGuard` before
+//! they reach it, with an authenticated cipher implementing `Cipher`. The
+//! key is provided by the application, which is responsible for keeping it
+//! out of the storage.
+//!
+//! `XChaCha20Poly1305Cipher` is provided, and other AEAD constructions can
+//! be plugged in by implementing `Cipher`.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use mut_guard::*;
+//! # use mut_guard::encrypt::*;
+//! # use mut_guard::persist::*;
+//! #
+//! #[derive(Serialize, Deserialize, Debug)]
+//! struct Session {
+//!   token: String,
+//! }
+//!
+//! impl Guard for Session {
+//!   fn finish(&mut self) {
+//!     assert!(!self.token.is_empty(), "missing token");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let path = std::env::temp_dir().join(format!("mutguard-doc-encrypt-{}.json", std::process::id()));
+//! // loaded from a secret manager
+//! let key = [7u8; 32];
+//! let storage = EncryptedStorage::new(FileStorage::new(&path), XChaCha20Poly1305Cipher::new(&key));
+//!
+//! let session = Session { token: "secret".to_string() };
+//! let mut guard = PersistentMutGuard::create_with(storage, session).unwrap();
+//! guard.guard().unwrap().token = "rotated".to_string();
+//!
+//! let stored = std::fs::read(&path).unwrap();
+//! assert!(!String::from_utf8_lossy(&stored).contains("rotated"));
+//! # std::fs::remove_file(&path).unwrap();
+//! # std::fs::remove_file(path.with_extension("json.lock")).unwrap();
+//! # }
+//! ```
+use std::fmt;
+use std::io;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+use super::persist::{Journal, Storage};
+
+/// associated data of snapshots, so they cannot be swapped with log entries
+const SNAPSHOT: &[u8] = b"mut_guard snapshot";
+/// associated data of log entries
+const ENTRY: &[u8] = b"mut_guard journal entry";
+
+/// authenticated encryption used by `EncryptedStorage`
+///
+/// `open()` must fail if the ciphertext or `aad` were modified. Each call
+/// to `seal()` must use a new nonce, usually stored with the ciphertext
+pub trait Cipher {
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>>;
+
+    fn open(&self, ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// XChaCha20-Poly1305, with a random nonce prepended to each ciphertext.
+/// Nonces are large enough to be generated randomly without risking reuse
+#[derive(Clone)]
+pub struct XChaCha20Poly1305Cipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl XChaCha20Poly1305Cipher {
+    pub fn new(key: &[u8; 32]) -> XChaCha20Poly1305Cipher {
+        XChaCha20Poly1305Cipher {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(key)),
+        }
+    }
+}
+
+// the key must not end up in logs
+impl fmt::Debug for XChaCha20Poly1305Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("XChaCha20Poly1305Cipher")
+    }
+}
+
+const NONCE_LEN: usize = 24;
+
+impl Cipher for XChaCha20Poly1305Cipher {
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| io::Error::other("encryption failed"))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, sealed: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| invalid())
+    }
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "could not decrypt: wrong key or modified data",
+    )
+}
+
+/// `Storage` encrypting what it stores in another `Storage` with a `Cipher`
+///
+/// locking is left to the wrapped storage. Data that cannot be decrypted is
+/// reported as an `io::ErrorKind::InvalidData` error
+#[derive(Clone, Debug)]
+pub struct EncryptedStorage<S, C> {
+    storage: S,
+    cipher: C,
+}
+
+impl<S: Storage, C: Cipher> EncryptedStorage<S, C> {
+    pub fn new(storage: S, cipher: C) -> EncryptedStorage<S, C> {
+        EncryptedStorage { storage, cipher }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.storage
+    }
+}
+
+impl<S: Storage, C: Cipher> Storage for EncryptedStorage<S, C> {
+    type Lock = S::Lock;
+
+    fn lock(&self) -> io::Result<S::Lock> {
+        self.storage.lock()
+    }
+
+    fn lock_shared(&self) -> io::Result<S::Lock> {
+        self.storage.lock_shared()
+    }
+
+    fn load(&self) -> io::Result<Option<Vec<u8>>> {
+        match self.storage.load()? {
+            Some(sealed) => self.cipher.open(&sealed, SNAPSHOT).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&self, snapshot: &[u8]) -> io::Result<()> {
+        self.storage.store(&self.cipher.seal(snapshot, SNAPSHOT)?)
+    }
+}
+
+impl<S: Journal, C: Cipher> Journal for EncryptedStorage<S, C> {
+    fn append(&self, entry: &[u8]) -> io::Result<()> {
+        self.storage.append(&self.cipher.seal(entry, ENTRY)?)
+    }
+
+    fn entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        self.storage
+            .entries()?
+            .iter()
+            .map(|sealed| self.cipher.open(sealed, ENTRY))
+            .collect()
+    }
+
+    fn truncate(&self) -> io::Result<()> {
+        self.storage.truncate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::command::Command;
+    use super::super::persist::*;
+    use super::super::Guard;
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Tokens(Vec<String>);
+
+    impl Guard for Tokens {
+        fn finish(&mut self) {
+            assert!(self.0.len() <= 4, "too many tokens");
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Add(String);
+
+    impl Command<Tokens> for Add {
+        fn apply(&self, target: &mut Tokens) {
+            target.0.push(self.0.clone());
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("mutguard-encrypt-{}-{}.json", process::id(), name))
+    }
+
+    fn cleanup(path: &Path) {
+        for suffix in &["", ".lock", ".wal"] {
+            let mut name = path.as_os_str().to_os_string();
+            name.push(suffix);
+            let _ = fs::remove_file(name);
+        }
+    }
+
+    fn storage(path: &Path, key: u8) -> EncryptedStorage<FileStorage, XChaCha20Poly1305Cipher> {
+        EncryptedStorage::new(
+            FileStorage::new(path),
+            XChaCha20Poly1305Cipher::new(&[key; 32]),
+        )
+    }
+
+    #[test]
+    fn journal() {
+        let path = temp_path("journal");
+        let mut tokens =
+            JournaledMutGuard::create_with(storage(&path, 1), Tokens(Vec::new())).unwrap();
+        tokens.apply(Add("abcdef".to_string())).unwrap();
+
+        let raw = FileStorage::new(&path);
+        let entries = raw.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(!String::from_utf8_lossy(&entries[0]).contains("abcdef"));
+
+        let reopened = JournaledMutGuard::<Tokens, Add, _>::open_with(storage(&path, 1)).unwrap();
+        assert_eq!(*reopened, Tokens(vec!["abcdef".to_string()]));
+
+        match JournaledMutGuard::<Tokens, Add, _>::open_with(storage(&path, 2)) {
+            Err(PersistError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("the wrong key should be rejected"),
+        }
+        cleanup(&path);
+    }
+
+    #[test]
+    fn swapped_data() {
+        let cipher = XChaCha20Poly1305Cipher::new(&[3; 32]);
+        let sealed = cipher.seal(b"entry", ENTRY).unwrap();
+        assert_eq!(cipher.open(&sealed, ENTRY).unwrap(), b"entry");
+        assert!(cipher.open(&sealed, SNAPSHOT).is_err());
+        assert!(cipher.open(&sealed[..10], ENTRY).is_err());
+    }
+}
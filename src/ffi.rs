@@ -0,0 +1,190 @@
+//! C interface
+//!
+//! *Note*: this module requires the `ffi` feature.
+//!
+//! exposes the `MutGuard` discipline to a C host: the data is only modified
+//! between `mutguard_lock()` and `mutguard_unlock()`, and the registered
+//! callbacks are called after every unlock.
+//!
+//! ```c
+//! void check(void *data, void *user_data) {
+//!   assert(((struct counter *)data)->value <= 20);
+//! }
+//!
+//! struct counter c = { 0 };
+//! mutguard_handle *h = mutguard_new(&c);
+//! mutguard_register_callback(h, check, NULL);
+//!
+//! struct counter *locked = mutguard_lock(h);
+//! locked->value = 10;
+//! mutguard_unlock(h); // calls check()
+//!
+//! mutguard_free(h);
+//! ```
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+use super::ARMED;
+
+/// function called after every `mutguard_unlock()`, with the guarded data
+/// and the `user_data` pointer given at registration
+pub type MutGuardCallback = extern "C" fn(data: *mut c_void, user_data: *mut c_void);
+
+/// opaque handle returned by `mutguard_new()`
+pub struct MutGuardHandle {
+    data: *mut c_void,
+    locked: bool,
+    callbacks: Vec<(MutGuardCallback, *mut c_void)>,
+}
+
+/// creates a handle guarding `data`. The data is not owned by the handle
+///
+/// # Safety
+///
+/// `data` must stay valid until the handle is freed by `mutguard_free()`
+#[no_mangle]
+pub unsafe extern "C" fn mutguard_new(data: *mut c_void) -> *mut MutGuardHandle {
+    Box::into_raw(Box::new(MutGuardHandle {
+        data,
+        locked: false,
+        callbacks: Vec::new(),
+    }))
+}
+
+/// registers a callback that will be called after every unlock, in
+/// registration order. Returns 0 on success, -1 if the handle is null
+///
+/// # Safety
+///
+/// `handle` must be null or come from `mutguard_new()`, and not be freed
+#[no_mangle]
+pub unsafe extern "C" fn mutguard_register_callback(
+    handle: *mut MutGuardHandle,
+    callback: MutGuardCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    match handle.as_mut() {
+        Some(handle) => {
+            handle.callbacks.push((callback, user_data));
+            0
+        }
+        None => -1,
+    }
+}
+
+/// returns the data for reading. It must not be modified through this pointer
+///
+/// # Safety
+///
+/// `handle` must be null or come from `mutguard_new()`, and not be freed
+#[no_mangle]
+pub unsafe extern "C" fn mutguard_get(handle: *const MutGuardHandle) -> *const c_void {
+    match handle.as_ref() {
+        Some(handle) => handle.data,
+        None => ptr::null(),
+    }
+}
+
+/// returns the data for modification, or null if the handle is null or
+/// already locked. `mutguard_unlock()` must be called once done
+///
+/// # Safety
+///
+/// `handle` must be null or come from `mutguard_new()`, and not be freed
+#[no_mangle]
+pub unsafe extern "C" fn mutguard_lock(handle: *mut MutGuardHandle) -> *mut c_void {
+    match handle.as_mut() {
+        Some(handle) if !handle.locked => {
+            handle.locked = true;
+            handle.data
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+/// ends the modification started by `mutguard_lock()`, then calls the
+/// registered callbacks. Returns 0 on success, -1 if the handle is null or
+/// was not locked
+///
+/// # Safety
+///
+/// `handle` must be null or come from `mutguard_new()`, and not be freed
+#[no_mangle]
+pub unsafe extern "C" fn mutguard_unlock(handle: *mut MutGuardHandle) -> c_int {
+    match handle.as_mut() {
+        Some(handle) if handle.locked => {
+            handle.locked = false;
+            if ARMED {
+                for &(callback, user_data) in &handle.callbacks {
+                    callback(handle.data, user_data);
+                }
+            }
+            0
+        }
+        _ => -1,
+    }
+}
+
+/// frees the handle, and returns the guarded data
+///
+/// # Safety
+///
+/// `handle` must be null or come from `mutguard_new()`, and must not be
+/// used after this call
+#[no_mangle]
+pub unsafe extern "C" fn mutguard_free(handle: *mut MutGuardHandle) -> *mut c_void {
+    if handle.is_null() {
+        return ptr::null_mut();
+    }
+
+    Box::from_raw(handle).data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn count(data: *mut c_void, user_data: *mut c_void) {
+        unsafe {
+            let value = *(data as *mut u32);
+            assert!(value <= 20, "value is too large");
+            *(user_data as *mut u32) += 1;
+        }
+    }
+
+    #[test]
+    fn lock_unlock() {
+        let mut value: u32 = 0;
+        let mut calls: u32 = 0;
+
+        unsafe {
+            let h = mutguard_new(&mut value as *mut u32 as *mut c_void);
+            assert_eq!(
+                mutguard_register_callback(h, count, &mut calls as *mut u32 as *mut c_void),
+                0
+            );
+
+            let locked = mutguard_lock(h) as *mut u32;
+            assert!(!locked.is_null());
+            assert!(mutguard_lock(h).is_null());
+            *locked = 10;
+            assert_eq!(mutguard_unlock(h), 0);
+            assert_eq!(mutguard_unlock(h), -1);
+            assert_eq!(*(mutguard_get(h) as *const u32), 10);
+
+            let data = mutguard_free(h) as *mut u32;
+            assert_eq!(*data, 10);
+        }
+
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn null_handle() {
+        unsafe {
+            assert!(mutguard_lock(ptr::null_mut()).is_null());
+            assert_eq!(mutguard_unlock(ptr::null_mut()), -1);
+            assert!(mutguard_free(ptr::null_mut()).is_null());
+        }
+    }
+}
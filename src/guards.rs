@@ -0,0 +1,1882 @@
+//! Ready-made `Guard` implementations for common invariants
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::guards::*;
+//! #
+//! # fn main() {
+//! let mut val = MutGuard::new(Bounded::<0, 20>::new(0).unwrap());
+//!
+//! val.guard().set(10);
+//! **val.guard() += 5;
+//! assert_eq!(val.get(), 15);
+//!
+//! let mut names = MutGuard::new(NonEmpty::<String>::from("a"));
+//! names.guard().push_str("bc");
+//! assert_eq!(names.as_str(), "abc");
+//! # }
+//! ```
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::ops::{Add, Deref, DerefMut, Range};
+
+#[cfg(feature = "regex")]
+use regex::Regex;
+#[cfg(feature = "checksum")]
+use serde::Serialize;
+
+use super::Guard;
+
+/// integer that must stay between `MIN` and `MAX` (inclusive)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bounded<const MIN: i64, const MAX: i64>(i64);
+
+impl<const MIN: i64, const MAX: i64> Bounded<MIN, MAX> {
+    /// returns `None` if `value` is out of bounds
+    pub fn new(value: i64) -> Option<Bounded<MIN, MAX>> {
+        if (MIN..=MAX).contains(&value) {
+            Some(Bounded(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0
+    }
+
+    pub fn set(&mut self, value: i64) {
+        self.0 = value;
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> Guard for Bounded<MIN, MAX> {
+    fn finish(&mut self) {
+        assert!(
+            (MIN..=MAX).contains(&self.0),
+            "value out of bounds [{}, {}]: {}",
+            MIN,
+            MAX,
+            self.0
+        );
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> Deref for Bounded<MIN, MAX> {
+    type Target = i64;
+
+    fn deref(&self) -> &i64 {
+        &self.0
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> DerefMut for Bounded<MIN, MAX> {
+    fn deref_mut(&mut self) -> &mut i64 {
+        &mut self.0
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> fmt::Display for Bounded<MIN, MAX> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// float that must stay between `MIN` and `MAX` (inclusive). The bounds
+/// are integers, since floats cannot be const generic parameters. `NaN`
+/// is always out of bounds
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct BoundedF64<const MIN: i64, const MAX: i64>(f64);
+
+impl<const MIN: i64, const MAX: i64> BoundedF64<MIN, MAX> {
+    fn in_bounds(value: f64) -> bool {
+        value >= MIN as f64 && value <= MAX as f64
+    }
+
+    /// returns `None` if `value` is out of bounds
+    pub fn new(value: f64) -> Option<BoundedF64<MIN, MAX>> {
+        if BoundedF64::<MIN, MAX>::in_bounds(value) {
+            Some(BoundedF64(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self) -> f64 {
+        self.0
+    }
+
+    pub fn set(&mut self, value: f64) {
+        self.0 = value;
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> Guard for BoundedF64<MIN, MAX> {
+    fn finish(&mut self) {
+        assert!(
+            BoundedF64::<MIN, MAX>::in_bounds(self.0),
+            "value out of bounds [{}, {}]: {}",
+            MIN,
+            MAX,
+            self.0
+        );
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> Deref for BoundedF64<MIN, MAX> {
+    type Target = f64;
+
+    fn deref(&self) -> &f64 {
+        &self.0
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> DerefMut for BoundedF64<MIN, MAX> {
+    fn deref_mut(&mut self) -> &mut f64 {
+        &mut self.0
+    }
+}
+
+impl<const MIN: i64, const MAX: i64> fmt::Display for BoundedF64<MIN, MAX> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// containers that can tell whether they hold anything
+pub trait Emptiness {
+    fn is_empty(&self) -> bool;
+}
+
+impl<T> Emptiness for Vec<T> {
+    fn is_empty(&self) -> bool {
+        Vec::is_empty(self)
+    }
+}
+
+impl<T> Emptiness for VecDeque<T> {
+    fn is_empty(&self) -> bool {
+        VecDeque::is_empty(self)
+    }
+}
+
+impl Emptiness for String {
+    fn is_empty(&self) -> bool {
+        String::is_empty(self)
+    }
+}
+
+impl<K: Eq + Hash, V> Emptiness for HashMap<K, V> {
+    fn is_empty(&self) -> bool {
+        HashMap::is_empty(self)
+    }
+}
+
+impl<T: Eq + Hash> Emptiness for HashSet<T> {
+    fn is_empty(&self) -> bool {
+        HashSet::is_empty(self)
+    }
+}
+
+impl<K, V> Emptiness for BTreeMap<K, V> {
+    fn is_empty(&self) -> bool {
+        BTreeMap::is_empty(self)
+    }
+}
+
+impl<T> Emptiness for BTreeSet<T> {
+    fn is_empty(&self) -> bool {
+        BTreeSet::is_empty(self)
+    }
+}
+
+/// collection or string that must never be left empty
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NonEmpty<C: Emptiness>(C);
+
+impl<C: Emptiness> NonEmpty<C> {
+    /// returns `None` if `container` is empty
+    pub fn new(container: C) -> Option<NonEmpty<C>> {
+        if container.is_empty() {
+            None
+        } else {
+            Some(NonEmpty(container))
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.0
+    }
+}
+
+impl<T> NonEmpty<Vec<T>> {
+    pub fn singleton(value: T) -> NonEmpty<Vec<T>> {
+        NonEmpty(vec![value])
+    }
+
+    /// the first element, which always exists
+    pub fn first(&self) -> &T {
+        &self.0[0]
+    }
+
+    /// the last element, which always exists
+    pub fn last(&self) -> &T {
+        &self.0[self.0.len() - 1]
+    }
+}
+
+impl<'a> From<&'a str> for NonEmpty<String> {
+    /// panics if `s` is empty
+    fn from(s: &'a str) -> NonEmpty<String> {
+        NonEmpty::new(s.to_string()).expect("NonEmpty created from an empty string")
+    }
+}
+
+impl<C: Emptiness> Guard for NonEmpty<C> {
+    fn finish(&mut self) {
+        assert!(!self.0.is_empty(), "container must not be empty");
+    }
+}
+
+impl<C: Emptiness> Deref for NonEmpty<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.0
+    }
+}
+
+impl<C: Emptiness> DerefMut for NonEmpty<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.0
+    }
+}
+
+/// vector that must stay sorted
+///
+/// by default, a mutation leaving the vector unsorted is a violation. With
+/// `Sorted::resorting`, the vector is sorted again in `normalize` instead
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Sorted<T: Ord> {
+    items: Vec<T>,
+    resort: bool,
+}
+
+impl<T: Ord> Sorted<T> {
+    /// returns `None` if `items` is not sorted
+    pub fn new(items: Vec<T>) -> Option<Sorted<T>> {
+        if is_sorted(&items) {
+            Some(Sorted {
+                items,
+                resort: false,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// sorts `items`, then keeps them sorted after every mutation
+    pub fn resorting(mut items: Vec<T>) -> Sorted<T> {
+        items.sort();
+        Sorted {
+            items,
+            resort: true,
+        }
+    }
+
+    /// inserts `value` at its sorted position
+    pub fn insert(&mut self, value: T) {
+        let index = match self.items.binary_search(&value) {
+            Ok(index) | Err(index) => index,
+        };
+        self.items.insert(index, value);
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+}
+
+fn is_sorted<T: Ord>(items: &[T]) -> bool {
+    items.windows(2).all(|w| w[0] <= w[1])
+}
+
+impl<T: Ord> Default for Sorted<T> {
+    fn default() -> Sorted<T> {
+        Sorted {
+            items: Vec::new(),
+            resort: false,
+        }
+    }
+}
+
+impl<T: Ord> Guard for Sorted<T> {
+    fn normalize(&mut self) {
+        if self.resort {
+            self.items.sort();
+        }
+    }
+
+    fn finish(&mut self) {
+        assert!(is_sorted(&self.items), "vector must stay sorted");
+    }
+}
+
+impl<T: Ord> Deref for Sorted<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.items
+    }
+}
+
+impl<T: Ord> DerefMut for Sorted<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.items
+    }
+}
+
+/// what `Unique` does when a mutation introduces duplicates
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DuplicatePolicy {
+    /// the duplicate is an invariant violation
+    Panic,
+    /// later duplicates are removed in `normalize`, keeping the first one
+    Dedup,
+    /// the whole mutation is undone, restoring the last accepted contents
+    Reject,
+}
+
+/// vector that must not contain duplicates
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unique<T: Eq + Hash + Clone> {
+    items: Vec<T>,
+    policy: DuplicatePolicy,
+    accepted: Option<Vec<T>>,
+    rejections: usize,
+}
+
+impl<T: Eq + Hash + Clone> Unique<T> {
+    /// returns `None` if `items` contains duplicates
+    pub fn new(items: Vec<T>, policy: DuplicatePolicy) -> Option<Unique<T>> {
+        if !has_duplicates(&items) {
+            Some(Unique::from_parts(items, policy))
+        } else if policy == DuplicatePolicy::Dedup {
+            let mut unique = Unique::from_parts(items, policy);
+            unique.dedup();
+            Some(unique)
+        } else {
+            None
+        }
+    }
+
+    fn from_parts(items: Vec<T>, policy: DuplicatePolicy) -> Unique<T> {
+        let accepted = if policy == DuplicatePolicy::Reject {
+            Some(items.clone())
+        } else {
+            None
+        };
+
+        Unique {
+            items,
+            policy,
+            accepted,
+            rejections: 0,
+        }
+    }
+
+    pub fn policy(&self) -> DuplicatePolicy {
+        self.policy
+    }
+
+    /// how many mutations were undone by `DuplicatePolicy::Reject`
+    pub fn rejections(&self) -> usize {
+        self.rejections
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+
+    fn dedup(&mut self) {
+        let mut seen = HashSet::with_capacity(self.items.len());
+        self.items.retain(|item| seen.insert(item.clone()));
+    }
+}
+
+fn has_duplicates<T: Eq + Hash>(items: &[T]) -> bool {
+    let mut seen = HashSet::with_capacity(items.len());
+    !items.iter().all(|item| seen.insert(item))
+}
+
+impl<T: Eq + Hash + Clone> Guard for Unique<T> {
+    fn normalize(&mut self) {
+        match self.policy {
+            DuplicatePolicy::Panic => {}
+            DuplicatePolicy::Dedup => self.dedup(),
+            DuplicatePolicy::Reject => {
+                if has_duplicates(&self.items) {
+                    if let Some(ref accepted) = self.accepted {
+                        self.items = accepted.clone();
+                    }
+                    self.rejections += 1;
+                } else {
+                    self.accepted = Some(self.items.clone());
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self) {
+        assert!(
+            !has_duplicates(&self.items),
+            "vector must not contain duplicates"
+        );
+    }
+}
+
+impl<T: Eq + Hash + Clone> Deref for Unique<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.items
+    }
+}
+
+impl<T: Eq + Hash + Clone> DerefMut for Unique<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.items
+    }
+}
+
+/// string that must match a regular expression
+///
+/// the regex usually needs `^` and `$` anchors, otherwise matching any
+/// substring is enough
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    value: String,
+    regex: Regex,
+}
+
+#[cfg(feature = "regex")]
+impl Pattern {
+    /// returns `None` if `value` does not match `regex`
+    pub fn new<S: Into<String>>(regex: Regex, value: S) -> Option<Pattern> {
+        let value = value.into();
+        if regex.is_match(&value) {
+            Some(Pattern { value, regex })
+        } else {
+            None
+        }
+    }
+
+    pub fn regex(&self) -> &Regex {
+        &self.regex
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    pub fn set<S: Into<String>>(&mut self, value: S) {
+        self.value = value.into();
+    }
+
+    pub fn into_inner(self) -> String {
+        self.value
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Guard for Pattern {
+    fn finish(&mut self) {
+        assert!(
+            self.regex.is_match(&self.value),
+            "{:?} does not match {:?}",
+            self.value,
+            self.regex.as_str()
+        );
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Deref for Pattern {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.value
+    }
+}
+
+#[cfg(feature = "regex")]
+impl DerefMut for Pattern {
+    fn deref_mut(&mut self) -> &mut String {
+        &mut self.value
+    }
+}
+
+#[cfg(feature = "regex")]
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+/// value carrying the CRC32 of its JSON serialization, recomputed after
+/// every mutation
+#[cfg(feature = "checksum")]
+#[derive(Clone, Debug)]
+pub struct Checksummed<T: Serialize> {
+    value: T,
+    checksum: u32,
+}
+
+#[cfg(feature = "checksum")]
+impl<T: Serialize> Checksummed<T> {
+    pub fn new(value: T) -> Checksummed<T> {
+        let checksum = compute_checksum(&value);
+        Checksummed { value, checksum }
+    }
+
+    /// checksum of the value as of the end of the last mutation
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// recomputes the checksum and compares it to the stored one
+    pub fn verify(&self) -> bool {
+        compute_checksum(&self.value) == self.checksum
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+#[cfg(feature = "checksum")]
+fn compute_checksum<T: Serialize>(value: &T) -> u32 {
+    let bytes = serde_json::to_vec(value).expect("could not serialize the checksummed value");
+    crc32fast::hash(&bytes)
+}
+
+#[cfg(feature = "checksum")]
+impl<T: Serialize> Guard for Checksummed<T> {
+    fn normalize(&mut self) {
+        self.checksum = compute_checksum(&self.value);
+    }
+
+    fn finish(&mut self) {}
+}
+
+#[cfg(feature = "checksum")]
+impl<T: Serialize> Deref for Checksummed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+#[cfg(feature = "checksum")]
+impl<T: Serialize> DerefMut for Checksummed<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// vector keeping the sum, count, minimum and maximum of a value derived
+/// from its items up to date
+///
+/// the aggregates are computed in `normalize`, so they are in sync with the
+/// items as soon as a `MutGuardBorrow` is dropped
+///
+/// ```rust
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::guards::Aggregated;
+/// #
+/// struct Account {
+///   balance: i64,
+/// }
+///
+/// # fn main() {
+/// let mut bank = MutGuard::new(Aggregated::new(Vec::new(), |a: &Account| a.balance));
+///
+/// bank.guard().push(Account { balance: 10 });
+/// bank.guard().push(Account { balance: 32 });
+/// assert_eq!(bank.sum(), 42);
+///
+/// bank.guard()[0].balance -= 10;
+/// assert_eq!(bank.sum(), 32);
+/// assert_eq!(bank.min(), Some(0));
+/// # }
+/// ```
+pub struct Aggregated<T, V, F> {
+    items: Vec<T>,
+    value: F,
+    sum: V,
+    min: Option<V>,
+    max: Option<V>,
+}
+
+impl<T, V, F> Aggregated<T, V, F>
+where
+    V: Copy + PartialOrd + Add<Output = V> + Default,
+    F: Fn(&T) -> V,
+{
+    /// `value` extracts from each item the value to aggregate
+    pub fn new(items: Vec<T>, value: F) -> Aggregated<T, V, F> {
+        let mut aggregated = Aggregated {
+            items,
+            value,
+            sum: V::default(),
+            min: None,
+            max: None,
+        };
+        aggregated.recompute();
+        aggregated
+    }
+
+    pub fn sum(&self) -> V {
+        self.sum
+    }
+
+    pub fn count(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn min(&self) -> Option<V> {
+        self.min
+    }
+
+    pub fn max(&self) -> Option<V> {
+        self.max
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+
+    fn recompute(&mut self) {
+        let mut sum = V::default();
+        let mut min: Option<V> = None;
+        let mut max: Option<V> = None;
+
+        for item in self.items.iter() {
+            let v = (self.value)(item);
+            sum = sum + v;
+            if min.map(|m| v < m).unwrap_or(true) {
+                min = Some(v);
+            }
+            if max.map(|m| v > m).unwrap_or(true) {
+                max = Some(v);
+            }
+        }
+
+        self.sum = sum;
+        self.min = min;
+        self.max = max;
+    }
+}
+
+impl<T, V, F> Guard for Aggregated<T, V, F>
+where
+    V: Copy + PartialOrd + Add<Output = V> + Default,
+    F: Fn(&T) -> V,
+{
+    fn normalize(&mut self) {
+        self.recompute();
+    }
+
+    fn finish(&mut self) {}
+}
+
+impl<T, V, F> Deref for Aggregated<T, V, F> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.items
+    }
+}
+
+impl<T, V, F> DerefMut for Aggregated<T, V, F> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.items
+    }
+}
+
+impl<T: fmt::Debug, V: fmt::Debug, F> fmt::Debug for Aggregated<T, V, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Aggregated")
+            .field("items", &self.items)
+            .field("sum", &self.sum)
+            .field("min", &self.min)
+            .field("max", &self.max)
+            .finish()
+    }
+}
+
+/// caches that can be emptied or marked stale
+pub trait Invalidate {
+    fn invalidate(&mut self);
+}
+
+impl<T> Invalidate for Option<T> {
+    fn invalidate(&mut self) {
+        *self = None;
+    }
+}
+
+impl<K: Eq + Hash, V> Invalidate for HashMap<K, V> {
+    fn invalidate(&mut self) {
+        self.clear();
+    }
+}
+
+impl<K, V> Invalidate for BTreeMap<K, V> {
+    fn invalidate(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T> Invalidate for OnceCell<T> {
+    fn invalidate(&mut self) {
+        self.take();
+    }
+}
+
+impl<T> Invalidate for Cell<Option<T>> {
+    fn invalidate(&mut self) {
+        self.set(None);
+    }
+}
+
+impl<C: Invalidate> Invalidate for RefCell<C> {
+    fn invalidate(&mut self) {
+        self.get_mut().invalidate();
+    }
+}
+
+/// stale flag, for caches that are more than a collection
+impl Invalidate for bool {
+    fn invalidate(&mut self) {
+        *self = true;
+    }
+}
+
+/// value with an associated cache, invalidated every time the value is
+/// mutated
+///
+/// the cache is only reachable through a shared reference, so it has to use
+/// interior mutability (`RefCell`, `OnceCell`...) to be filled
+///
+/// ```rust
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::guards::Invalidating;
+/// # use std::cell::OnceCell;
+/// #
+/// # fn main() {
+/// let mut val = MutGuard::new(Invalidating::new(vec![1, 2, 3], OnceCell::new()));
+///
+/// let cache = val.cache();
+/// assert_eq!(*cache.get_or_init(|| val.iter().sum::<i32>()), 6);
+///
+/// val.guard().push(4);
+/// assert!(val.cache().get().is_none());
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Invalidating<T, C: Invalidate> {
+    value: T,
+    cache: C,
+}
+
+impl<T, C: Invalidate> Invalidating<T, C> {
+    pub fn new(value: T, cache: C) -> Invalidating<T, C> {
+        Invalidating { value, cache }
+    }
+
+    pub fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    pub fn into_inner(self) -> (T, C) {
+        (self.value, self.cache)
+    }
+}
+
+impl<T, C: Invalidate> Guard for Invalidating<T, C> {
+    fn normalize(&mut self) {
+        self.cache.invalidate();
+    }
+
+    fn finish(&mut self) {}
+}
+
+impl<T, C: Invalidate> Deref for Invalidating<T, C> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, C: Invalidate> DerefMut for Invalidating<T, C> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// set of half-open ranges that must never overlap. The ranges are kept
+/// sorted by start in `normalize`, and in merging mode, overlapping or
+/// adjacent ranges are merged and empty ranges removed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntervalSet<T: Ord + Clone> {
+    ranges: Vec<Range<T>>,
+    merge: bool,
+}
+
+impl<T: Ord + Clone> IntervalSet<T> {
+    /// interval set where overlapping ranges are a violation
+    pub fn new() -> IntervalSet<T> {
+        IntervalSet {
+            ranges: Vec::new(),
+            merge: false,
+        }
+    }
+
+    /// interval set merging overlapping and adjacent ranges
+    pub fn merging() -> IntervalSet<T> {
+        IntervalSet {
+            ranges: Vec::new(),
+            merge: true,
+        }
+    }
+
+    pub fn is_merging(&self) -> bool {
+        self.merge
+    }
+
+    /// adds `range`, at its sorted position
+    pub fn insert(&mut self, range: Range<T>) {
+        let index = self.ranges.partition_point(|r| r.start < range.start);
+        self.ranges.insert(index, range);
+    }
+
+    /// returns the range containing `value`
+    pub fn find(&self, value: &T) -> Option<&Range<T>> {
+        let index = self.ranges.partition_point(|r| r.start <= *value);
+        index
+            .checked_sub(1)
+            .map(|index| &self.ranges[index])
+            .filter(|range| range.contains(value))
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        self.find(value).is_some()
+    }
+
+    /// returns true if `range` overlaps one of the stored ranges
+    pub fn overlaps(&self, range: &Range<T>) -> bool {
+        self.ranges
+            .iter()
+            .any(|r| r.start < range.end && range.start < r.end)
+    }
+
+    pub fn into_inner(self) -> Vec<Range<T>> {
+        self.ranges
+    }
+
+    fn merge_ranges(&mut self) {
+        let mut merged: Vec<Range<T>> = Vec::with_capacity(self.ranges.len());
+        for range in self.ranges.drain(..).filter(|r| r.start < r.end) {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => {
+                    if range.end > last.end {
+                        last.end = range.end;
+                    }
+                }
+                _ => merged.push(range),
+            }
+        }
+        self.ranges = merged;
+    }
+}
+
+impl<T: Ord + Clone> Default for IntervalSet<T> {
+    fn default() -> IntervalSet<T> {
+        IntervalSet::new()
+    }
+}
+
+impl<T: Ord + Clone> Guard for IntervalSet<T> {
+    fn normalize(&mut self) {
+        self.ranges.sort_by(|a, b| a.start.cmp(&b.start));
+        if self.merge {
+            self.merge_ranges();
+        }
+    }
+
+    fn finish(&mut self) {
+        assert!(
+            self.ranges.iter().all(|r| r.start < r.end),
+            "intervals must not be empty"
+        );
+        assert!(
+            self.ranges.windows(2).all(|w| w[0].end <= w[1].start),
+            "intervals must not overlap"
+        );
+    }
+}
+
+impl<T: Ord + Clone> Deref for IntervalSet<T> {
+    type Target = Vec<Range<T>>;
+
+    fn deref(&self) -> &Vec<Range<T>> {
+        &self.ranges
+    }
+}
+
+impl<T: Ord + Clone> DerefMut for IntervalSet<T> {
+    fn deref_mut(&mut self) -> &mut Vec<Range<T>> {
+        &mut self.ranges
+    }
+}
+
+/// directed graph that must stay acyclic. Edges are added through
+/// `add_edge()`, and the check only looks for cycles going through the
+/// edges added since the last check, instead of the whole graph
+#[derive(Clone, Debug)]
+pub struct Dag<N: Eq + Hash + Clone> {
+    edges: HashMap<N, HashSet<N>>,
+    added: Vec<(N, N)>,
+}
+
+impl<N: Eq + Hash + Clone> Dag<N> {
+    pub fn new() -> Dag<N> {
+        Dag {
+            edges: HashMap::new(),
+            added: Vec::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, node: N) {
+        self.edges.entry(node).or_default();
+    }
+
+    /// adds an edge from `from` to `to`, and both nodes if needed. Returns
+    /// false if the edge already existed
+    pub fn add_edge(&mut self, from: N, to: N) -> bool {
+        self.add_node(to.clone());
+        let added = self
+            .edges
+            .entry(from.clone())
+            .or_default()
+            .insert(to.clone());
+        if added {
+            self.added.push((from, to));
+        }
+        added
+    }
+
+    pub fn remove_edge(&mut self, from: &N, to: &N) -> bool {
+        self.edges
+            .get_mut(from)
+            .map(|targets| targets.remove(to))
+            .unwrap_or(false)
+    }
+
+    /// removes `node` and the edges from and to it
+    pub fn remove_node(&mut self, node: &N) -> bool {
+        let removed = self.edges.remove(node).is_some();
+        if removed {
+            for targets in self.edges.values_mut() {
+                targets.remove(node);
+            }
+        }
+        removed
+    }
+
+    pub fn contains_node(&self, node: &N) -> bool {
+        self.edges.contains_key(node)
+    }
+
+    pub fn contains_edge(&self, from: &N, to: &N) -> bool {
+        self.edges
+            .get(from)
+            .map(|targets| targets.contains(to))
+            .unwrap_or(false)
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &N> {
+        self.edges.keys()
+    }
+
+    /// nodes reached by an edge from `node`
+    pub fn successors(&self, node: &N) -> impl Iterator<Item = &N> {
+        self.edges.get(node).into_iter().flatten()
+    }
+
+    /// returns true if there is a path from `from` to `to`
+    pub fn has_path(&self, from: &N, to: &N) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(node) = stack.pop() {
+            if node == to {
+                return true;
+            }
+            if seen.insert(node) {
+                stack.extend(self.successors(node));
+            }
+        }
+        false
+    }
+
+    /// nodes ordered so that every edge goes from an earlier node to a
+    /// later one
+    pub fn topological_order(&self) -> Vec<&N> {
+        let mut incoming: HashMap<&N, usize> = self.edges.keys().map(|n| (n, 0)).collect();
+        for to in self.edges.values().flatten() {
+            *incoming.get_mut(to).unwrap() += 1;
+        }
+
+        let mut ready: Vec<&N> = incoming
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(n, _)| *n)
+            .collect();
+        let mut order = Vec::with_capacity(self.edges.len());
+        while let Some(node) = ready.pop() {
+            order.push(node);
+            for to in self.successors(node) {
+                let count = incoming.get_mut(to).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    ready.push(to);
+                }
+            }
+        }
+        order
+    }
+}
+
+impl<N: Eq + Hash + Clone> Default for Dag<N> {
+    fn default() -> Dag<N> {
+        Dag::new()
+    }
+}
+
+impl<N: Eq + Hash + Clone + fmt::Debug> Guard for Dag<N> {
+    fn finish(&mut self) {
+        // an edge from -> to closes a cycle if `from` can be reached from `to`.
+        // Removing edges or nodes cannot create cycles
+        for (from, to) in self.added.iter() {
+            if self.contains_edge(from, to) && self.has_path(to, from) {
+                panic!("edge {:?} -> {:?} creates a cycle", from, to);
+            }
+        }
+        // only cleared once they were all checked, so a failed check still
+        // sees them next time
+        self.added.clear();
+    }
+}
+
+/// returns true if `items` follows the max-heap order of `BinaryHeap`:
+/// every element is greater or equal to its children
+pub fn is_heap<T: Ord>(items: &[T]) -> bool {
+    (1..items.len()).all(|i| items[(i - 1) / 2] >= items[i])
+}
+
+/// vector that must keep the max-heap order, for code manipulating the
+/// backing vector of a priority queue directly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heap<T: Ord> {
+    items: Vec<T>,
+}
+
+impl<T: Ord> Heap<T> {
+    /// reorders `items` into a heap
+    pub fn new(items: Vec<T>) -> Heap<T> {
+        Heap {
+            items: BinaryHeap::from(items).into_vec(),
+        }
+    }
+
+    /// greatest element
+    pub fn peek(&self) -> Option<&T> {
+        self.items.first()
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.items.push(value);
+        let mut i = self.items.len() - 1;
+        while i > 0 && self.items[(i - 1) / 2] < self.items[i] {
+            self.items.swap(i, (i - 1) / 2);
+            i = (i - 1) / 2;
+        }
+    }
+
+    /// removes the greatest element
+    pub fn pop(&mut self) -> Option<T> {
+        if self.items.is_empty() {
+            return None;
+        }
+        let value = self.items.swap_remove(0);
+
+        let len = self.items.len();
+        let mut i = 0;
+        loop {
+            let (left, right) = (2 * i + 1, 2 * i + 2);
+            let mut largest = i;
+            if left < len && self.items[left] > self.items[largest] {
+                largest = left;
+            }
+            if right < len && self.items[right] > self.items[largest] {
+                largest = right;
+            }
+            if largest == i {
+                break;
+            }
+            self.items.swap(i, largest);
+            i = largest;
+        }
+        Some(value)
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.items
+    }
+
+    pub fn into_binary_heap(self) -> BinaryHeap<T> {
+        BinaryHeap::from(self.items)
+    }
+}
+
+impl<T: Ord> Default for Heap<T> {
+    fn default() -> Heap<T> {
+        Heap { items: Vec::new() }
+    }
+}
+
+impl<T: Ord> Guard for Heap<T> {
+    fn finish(&mut self) {
+        assert!(is_heap(&self.items), "vector must keep the heap order");
+    }
+}
+
+impl<T: Ord> Deref for Heap<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.items
+    }
+}
+
+impl<T: Ord> DerefMut for Heap<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.items
+    }
+}
+
+/// allowed transitions for a `Monotonic` value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// the value can stay the same or grow
+    NonDecreasing,
+    /// every change must make the value grow
+    Increasing,
+    /// the value can stay the same or shrink
+    NonIncreasing,
+    /// every change must make the value shrink
+    Decreasing,
+}
+
+impl Direction {
+    fn allows<T: PartialOrd>(self, previous: &T, next: &T) -> bool {
+        match self {
+            Direction::NonDecreasing => next >= previous,
+            Direction::Increasing => next > previous,
+            Direction::NonIncreasing => next <= previous,
+            Direction::Decreasing => next < previous,
+        }
+    }
+}
+
+/// value that can only change in one `Direction`, like a sequence number or
+/// a watermark. The value is replaced with `set()`, which keeps the
+/// previous value until it is checked, so `T` does not need to be `Clone`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Monotonic<T: PartialOrd> {
+    value: T,
+    direction: Direction,
+    /// oldest value not checked yet
+    previous: Option<T>,
+}
+
+impl<T: PartialOrd> Monotonic<T> {
+    pub fn new(value: T, direction: Direction) -> Monotonic<T> {
+        Monotonic {
+            value,
+            direction,
+            previous: None,
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// replaces the value. The transition is checked when the borrow ends
+    pub fn set(&mut self, value: T) {
+        let previous = mem::replace(&mut self.value, value);
+        if self.previous.is_none() {
+            self.previous = Some(previous);
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: PartialOrd + Clone> Monotonic<T> {
+    /// modifies the value in place
+    pub fn update<F: FnOnce(&mut T)>(&mut self, f: F) {
+        let mut value = self.value.clone();
+        f(&mut value);
+        self.set(value);
+    }
+}
+
+impl<T: PartialOrd + fmt::Debug> Guard for Monotonic<T> {
+    fn finish(&mut self) {
+        if let Some(ref previous) = self.previous {
+            assert!(
+                self.direction.allows(previous, &self.value),
+                "{:?} value went from {:?} to {:?}",
+                self.direction,
+                previous,
+                self.value
+            );
+        }
+        // only reached if the check passed: after a failure, the next
+        // transition is still checked from the last accepted value
+        self.previous = None;
+    }
+}
+
+impl<T: PartialOrd> Deref for Monotonic<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// error returned by `Accounts::transfer()` and `Accounts::withdraw()`.
+/// The accounts are left unchanged
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    UnknownAccount,
+    /// the amount is negative
+    InvalidAmount,
+    InsufficientFunds {
+        balance: i64,
+        amount: i64,
+    },
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransferError::UnknownAccount => write!(f, "unknown account"),
+            TransferError::InvalidAmount => write!(f, "negative amount"),
+            TransferError::InsufficientFunds { balance, amount } => {
+                write!(f, "cannot take {} from a balance of {}", amount, balance)
+            }
+        }
+    }
+}
+
+impl Error for TransferError {}
+
+/// account balances that must never be negative, and whose sum only changes
+/// with `deposit()` and `withdraw()`: transfers and direct changes through
+/// `balance_mut()` must conserve the total
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Accounts<K: Ord> {
+    balances: BTreeMap<K, i64>,
+    total: i64,
+}
+
+impl<K: Ord> Accounts<K> {
+    pub fn new() -> Accounts<K> {
+        Accounts {
+            balances: BTreeMap::new(),
+            total: 0,
+        }
+    }
+
+    /// adds an account with an initial balance and returns true, or returns
+    /// false if it already exists
+    pub fn open(&mut self, account: K, balance: i64) -> bool {
+        if self.balances.contains_key(&account) {
+            return false;
+        }
+        self.total += balance;
+        self.balances.insert(account, balance);
+        true
+    }
+
+    /// removes an account, and its balance from the total
+    pub fn close(&mut self, account: &K) -> Option<i64> {
+        let balance = self.balances.remove(account)?;
+        self.total -= balance;
+        Some(balance)
+    }
+
+    pub fn balance(&self, account: &K) -> Option<i64> {
+        self.balances.get(account).cloned()
+    }
+
+    /// sum of the balances
+    pub fn total(&self) -> i64 {
+        self.total
+    }
+
+    pub fn balances(&self) -> &BTreeMap<K, i64> {
+        &self.balances
+    }
+
+    /// direct access to a balance. The total must be the same once the
+    /// borrow ends
+    pub fn balance_mut(&mut self, account: &K) -> Option<&mut i64> {
+        self.balances.get_mut(account)
+    }
+
+    pub fn deposit(&mut self, account: &K, amount: i64) -> Result<(), TransferError> {
+        if amount < 0 {
+            return Err(TransferError::InvalidAmount);
+        }
+        *self
+            .balances
+            .get_mut(account)
+            .ok_or(TransferError::UnknownAccount)? += amount;
+        self.total += amount;
+        Ok(())
+    }
+
+    pub fn withdraw(&mut self, account: &K, amount: i64) -> Result<(), TransferError> {
+        self.take(account, amount)?;
+        self.total -= amount;
+        Ok(())
+    }
+
+    /// moves `amount` from `from` to `to`, or changes nothing if one of the
+    /// accounts does not exist or `from` does not have enough funds
+    pub fn transfer(&mut self, from: &K, to: &K, amount: i64) -> Result<(), TransferError> {
+        if !self.balances.contains_key(to) {
+            return Err(TransferError::UnknownAccount);
+        }
+        self.take(from, amount)?;
+        *self.balances.get_mut(to).unwrap() += amount;
+        Ok(())
+    }
+
+    fn take(&mut self, account: &K, amount: i64) -> Result<(), TransferError> {
+        if amount < 0 {
+            return Err(TransferError::InvalidAmount);
+        }
+        let balance = self
+            .balances
+            .get_mut(account)
+            .ok_or(TransferError::UnknownAccount)?;
+        if *balance < amount {
+            return Err(TransferError::InsufficientFunds {
+                balance: *balance,
+                amount,
+            });
+        }
+        *balance -= amount;
+        Ok(())
+    }
+}
+
+impl<K: Ord> Default for Accounts<K> {
+    fn default() -> Accounts<K> {
+        Accounts::new()
+    }
+}
+
+impl<K: Ord + fmt::Debug> Guard for Accounts<K> {
+    fn finish(&mut self) {
+        if let Some((account, balance)) = self.balances.iter().find(|(_, b)| **b < 0) {
+            panic!("account {:?} has a negative balance: {}", account, balance);
+        }
+        let sum: i64 = self.balances.values().sum();
+        assert_eq!(sum, self.total, "the total of the balances changed");
+    }
+}
+
+/// scale of the tolerance: `epsilon` is absolute for values below 1, and
+/// relative to the largest magnitude above
+fn tolerance(a: f64, b: f64, epsilon: f64) -> f64 {
+    epsilon * a.abs().max(b.abs()).max(1.0)
+}
+
+/// returns true if `a` and `b` differ by at most `epsilon`, relative to their
+/// magnitude if it is above 1
+pub fn approx_eq(a: f64, b: f64, epsilon: f64) -> bool {
+    (a - b).abs() <= tolerance(a, b, epsilon)
+}
+
+/// returns true if `a` is lower than `b`, or above it by at most `epsilon`
+pub fn approx_le(a: f64, b: f64, epsilon: f64) -> bool {
+    a <= b + tolerance(a, b, epsilon)
+}
+
+/// returns true if `a` is greater than `b`, or below it by at most `epsilon`
+pub fn approx_ge(a: f64, b: f64, epsilon: f64) -> bool {
+    approx_le(b, a, epsilon)
+}
+
+/// returns true if the sum of `values` is within `epsilon` of `expected`.
+/// The sum is compensated (Kahan summation) to limit rounding errors
+pub fn sum_within_epsilon<I: IntoIterator<Item = f64>>(
+    values: I,
+    expected: f64,
+    epsilon: f64,
+) -> bool {
+    let mut sum = 0.0;
+    let mut compensation = 0.0;
+    for value in values {
+        let y = value - compensation;
+        let t = sum + y;
+        compensation = (t - sum) - y;
+        sum = t;
+    }
+    approx_eq(sum, expected, epsilon)
+}
+
+/// element whose floating point quantity must be conserved by mutations,
+/// like the energy or mass of a simulation, within a tolerance
+///
+/// ```rust
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::guards::Conserved;
+/// #
+/// # fn main() {
+/// let masses = vec![0.1, 0.2, 0.3];
+/// let mut system = MutGuard::new(Conserved::new(masses, |m: &Vec<f64>| m.iter().sum(), 1e-9));
+///
+/// {
+///   let mut s = system.guard();
+///   s[0] -= 0.05;
+///   s[2] += 0.05;
+/// }
+/// assert!((system.expected() - 0.6).abs() < 1e-9);
+/// # }
+/// ```
+pub struct Conserved<T, F> {
+    inner: T,
+    quantity: F,
+    expected: f64,
+    epsilon: f64,
+}
+
+impl<T, F: Fn(&T) -> f64> Conserved<T, F> {
+    /// `quantity` computes the conserved quantity, and must stay within
+    /// `epsilon` of its current value, as defined by `approx_eq()`
+    pub fn new(inner: T, quantity: F, epsilon: f64) -> Conserved<T, F> {
+        let expected = quantity(&inner);
+        Conserved {
+            inner,
+            quantity,
+            expected,
+            epsilon,
+        }
+    }
+
+    /// value the quantity must keep
+    pub fn expected(&self) -> f64 {
+        self.expected
+    }
+
+    /// current value of the quantity
+    pub fn current(&self) -> f64 {
+        (self.quantity)(&self.inner)
+    }
+
+    /// accepts the current value as the new expected value, for intended
+    /// changes, like adding energy to the system
+    pub fn rebase(&mut self) {
+        self.expected = self.current();
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, F: Fn(&T) -> f64> Guard for Conserved<T, F> {
+    fn finish(&mut self) {
+        let current = self.current();
+        assert!(
+            approx_eq(current, self.expected, self.epsilon),
+            "conserved quantity changed from {} to {}",
+            self.expected,
+            current
+        );
+    }
+}
+
+impl<T, F> Deref for Conserved<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T, F> DerefMut for Conserved<T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Conserved<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Conserved")
+            .field("inner", &self.inner)
+            .field("expected", &self.expected)
+            .field("epsilon", &self.epsilon)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MutGuard;
+
+    #[test]
+    fn bounded_new() {
+        assert!(Bounded::<-5, 5>::new(-5).is_some());
+        assert!(Bounded::<-5, 5>::new(6).is_none());
+        assert!(BoundedF64::<0, 1>::new(0.5).is_some());
+        assert!(BoundedF64::<0, 1>::new(f64::NAN).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "value out of bounds [0, 20]: 30")]
+    fn bounded() {
+        let mut val = MutGuard::new(Bounded::<0, 20>::new(0).unwrap());
+        val.guard().set(10);
+        **val.guard() += 20;
+    }
+
+    #[test]
+    #[should_panic(expected = "value out of bounds [0, 1]: 1.5")]
+    fn bounded_f64() {
+        let mut val = MutGuard::new(BoundedF64::<0, 1>::new(0.0).unwrap());
+        val.guard().set(0.5);
+        **val.guard() *= 3.0;
+    }
+
+    #[test]
+    fn non_empty_new() {
+        assert!(NonEmpty::new(Vec::<u8>::new()).is_none());
+        assert!(NonEmpty::new(HashSet::<u8>::new()).is_none());
+        let v = NonEmpty::new(vec![1, 2, 3]).unwrap();
+        assert_eq!((*v.first(), *v.last()), (1, 3));
+        assert_eq!(NonEmpty::singleton(4).len(), 1);
+        assert_eq!(&**NonEmpty::<String>::from("abc"), "abc");
+    }
+
+    #[test]
+    #[should_panic(expected = "container must not be empty")]
+    fn non_empty() {
+        let mut val = MutGuard::new(NonEmpty::singleton(1));
+        val.guard().push(2);
+        val.guard().clear();
+    }
+
+    #[test]
+    fn sorted_insert() {
+        assert!(Sorted::new(vec![2, 1]).is_none());
+        let mut val = MutGuard::new(Sorted::new(vec![1, 3]).unwrap());
+        val.guard().insert(2);
+        val.guard().insert(0);
+        assert_eq!(&**val, &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sorted_resorting() {
+        let mut val = MutGuard::new(Sorted::resorting(vec![3, 1]));
+        assert_eq!(&**val, &[1, 3]);
+        val.guard().push(2);
+        assert_eq!(&**val, &[1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "vector must stay sorted")]
+    fn sorted() {
+        let mut val = MutGuard::new(Sorted::new(vec![1, 2]).unwrap());
+        val.guard().push(0);
+    }
+
+    #[test]
+    fn unique_dedup() {
+        let mut val = MutGuard::new(Unique::new(vec![1, 2, 1], DuplicatePolicy::Dedup).unwrap());
+        assert_eq!(&**val, &[1, 2]);
+        val.guard().extend(vec![3, 2, 3]);
+        assert_eq!(&**val, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn unique_reject() {
+        assert!(Unique::new(vec![1, 1], DuplicatePolicy::Reject).is_none());
+        let mut val = MutGuard::new(Unique::new(vec![1], DuplicatePolicy::Reject).unwrap());
+        val.guard().push(2);
+        val.guard().extend(vec![3, 1]);
+        assert_eq!(&**val, &[1, 2]);
+        assert_eq!(val.rejections(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "vector must not contain duplicates")]
+    fn unique() {
+        let mut val = MutGuard::new(Unique::new(vec![1], DuplicatePolicy::Panic).unwrap());
+        val.guard().push(1);
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    #[should_panic(expected = "\"my slug\" does not match \"^[a-z-]+$\"")]
+    fn pattern() {
+        let slug = Regex::new("^[a-z-]+$").unwrap();
+        assert!(Pattern::new(slug.clone(), "Bad").is_none());
+
+        let mut val = MutGuard::new(Pattern::new(slug, "my").unwrap());
+        val.guard().push_str("-slug");
+        assert_eq!(val.as_str(), "my-slug");
+        val.guard().set("my slug");
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn checksummed() {
+        let mut val = MutGuard::new(Checksummed::new(vec![1, 2]));
+        let before = val.checksum();
+        assert!(val.verify());
+
+        val.guard().push(3);
+        assert_ne!(val.checksum(), before);
+        assert!(val.verify());
+
+        val.guard().pop();
+        assert_eq!(val.checksum(), before);
+    }
+
+    #[test]
+    fn aggregated() {
+        let mut val = MutGuard::new(Aggregated::new(vec![3.0, 1.5], |v: &f64| *v));
+        assert_eq!((val.sum(), val.count()), (4.5, 2));
+
+        val.guard().push(-1.0);
+        assert_eq!(val.sum(), 3.5);
+        assert_eq!((val.min(), val.max()), (Some(-1.0), Some(3.0)));
+
+        val.guard().clear();
+        assert_eq!((val.sum(), val.min(), val.max()), (0.0, None, None));
+    }
+
+    #[test]
+    fn invalidating() {
+        let mut val = MutGuard::new(Invalidating::new(2, RefCell::new(HashMap::new())));
+
+        let scale = |val: &Invalidating<u64, RefCell<HashMap<u64, u64>>>, n: u64| {
+            *val.cache().borrow_mut().entry(n).or_insert(n * **val)
+        };
+        assert_eq!(scale(&val, 3), 6);
+        assert_eq!(val.cache().borrow().len(), 1);
+
+        **val.guard() = 5;
+        assert!(val.cache().borrow().is_empty());
+        assert_eq!(scale(&val, 3), 15);
+    }
+
+    #[test]
+    fn interval_set() {
+        let mut slots = MutGuard::new(IntervalSet::new());
+        slots.guard().insert(10..20);
+        slots.guard().push(0..5);
+        assert_eq!(**slots, vec![0..5, 10..20]);
+        assert!(slots.contains(&12));
+        assert!(!slots.contains(&7));
+        assert!(slots.overlaps(&(4..6)));
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            slots.guard().insert(15..25);
+        }));
+        assert!(res.is_err());
+
+        let mut merged = MutGuard::new(IntervalSet::merging());
+        {
+            let mut m = merged.guard();
+            m.insert(0..5);
+            m.insert(5..8);
+            m.insert(3..4);
+            m.insert(20..20);
+            m.insert(10..12);
+        }
+        assert_eq!(**merged, vec![0..8, 10..12]);
+        assert_eq!(merged.find(&6), Some(&(0..8)));
+    }
+
+    #[test]
+    fn dag() {
+        let mut deps = MutGuard::new(Dag::new());
+        {
+            let mut d = deps.guard();
+            d.add_edge("app", "lib");
+            d.add_edge("lib", "core");
+            d.add_edge("app", "core");
+        }
+        assert!(deps.has_path(&"app", &"core"));
+        let order = deps.topological_order();
+        assert_eq!(order.len(), 3);
+        assert_eq!(*order[0], "app");
+        assert_eq!(*order[2], "core");
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            deps.guard().add_edge("core", "app");
+        }));
+        assert!(res.is_err());
+
+        // fixing the graph makes the pending edge check pass
+        deps.guard().remove_edge(&"core", &"app");
+        deps.guard().remove_node(&"lib");
+        assert!(!deps.contains_node(&"lib"));
+    }
+
+    #[test]
+    fn heap() {
+        let mut queue = MutGuard::new(Heap::new(vec![3, 1, 4, 1, 5]));
+        queue.guard().push(9);
+        assert_eq!(queue.peek(), Some(&9));
+        assert_eq!(queue.guard().pop(), Some(9));
+        assert_eq!(queue.guard().pop(), Some(5));
+        assert!(is_heap(&queue));
+
+        // direct changes to the vector are checked too
+        queue.guard().push(0);
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            queue.guard()[0] = 0;
+        }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn monotonic() {
+        let mut seq = MutGuard::new(Monotonic::new(1u64, Direction::Increasing));
+        seq.guard().set(2);
+        seq.guard().update(|v| *v += 3);
+        assert_eq!(**seq, 5);
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            seq.guard().set(5);
+        }));
+        assert!(res.is_err());
+
+        // still compared to 5, the last accepted value
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            seq.guard().set(4);
+        }));
+        assert!(res.is_err());
+        seq.guard().set(6);
+
+        let mut low = MutGuard::new(Monotonic::new(10i32, Direction::NonIncreasing));
+        low.guard().set(10);
+        low.guard().set(-3);
+        assert_eq!(*low.get(), -3);
+    }
+
+    #[test]
+    fn accounts() {
+        let mut bank = MutGuard::new(Accounts::new());
+        {
+            let mut b = bank.guard();
+            b.open("alice", 10);
+            b.open("bob", 0);
+        }
+
+        bank.guard().transfer(&"alice", &"bob", 4).unwrap();
+        assert_eq!(
+            bank.guard().transfer(&"bob", &"alice", 5),
+            Err(TransferError::InsufficientFunds {
+                balance: 4,
+                amount: 5
+            })
+        );
+        assert_eq!(
+            bank.guard().transfer(&"bob", &"carol", 1),
+            Err(TransferError::UnknownAccount)
+        );
+        bank.guard().withdraw(&"alice", 6).unwrap();
+        assert_eq!((bank.balance(&"alice"), bank.total()), (Some(0), 4));
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            *bank.guard().balance_mut(&"bob").unwrap() += 1;
+        }));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn float_tolerance() {
+        assert!(approx_eq(0.1 + 0.2, 0.3, 1e-12));
+        assert!(approx_eq(1e12 + 1.0, 1e12, 1e-9));
+        assert!(!approx_eq(1.0, 1.1, 1e-3));
+        assert!(approx_le(0.3 + 1e-15, 0.3, 1e-12));
+        assert!(!approx_ge(0.2, 0.3, 1e-12));
+        assert!(sum_within_epsilon(vec![0.1; 10], 1.0, 1e-12));
+
+        let mut energy = MutGuard::new(Conserved::new(
+            vec![1.0, 2.0],
+            |e: &Vec<f64>| e.iter().sum(),
+            1e-9,
+        ));
+        {
+            let mut e = energy.guard();
+            e[0] -= 0.3;
+            e[1] += 0.3;
+        }
+
+        assert_eq!(energy.expected(), 3.0);
+
+        {
+            let mut e = energy.guard();
+            e.push(1.0);
+            e.rebase();
+        }
+        assert_eq!(energy.expected(), 4.0);
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            energy.guard()[0] *= 2.0;
+        }));
+        assert!(res.is_err());
+    }
+}
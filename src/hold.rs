@@ -0,0 +1,322 @@
+//! Long-held borrow detection
+//!
+//! validation only happens once a `MutGuardBorrow` is dropped, so a borrow
+//! that is accidentally kept across a long operation delays the check, and
+//! blocks every reader. A `MutGuard` can be configured to report borrows
+//! held longer than a threshold, with the place they were acquired.
+//!
+//! In debug builds, or with the `backtrace` feature, a backtrace is also
+//! captured every time a borrow is acquired, if `RUST_BACKTRACE` or
+//! `RUST_LIB_BACKTRACE` enables it. It is included in long borrow reports,
+//! printed on stderr when `Guard::finish` panics, and stored in the
+//! `Violation` of a failed check, as `Violation::backtrace()`.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use std::sync::Arc;
+//! use std::thread;
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! let long_borrows = Arc::new(AtomicUsize::new(0));
+//! let mut iv = MutGuard::wrap(Vec::new(), |_| {});
+//!
+//! let counter = long_borrows.clone();
+//! iv.on_long_borrow(Duration::from_millis(10), move |borrow| {
+//!   println!("{}", borrow);
+//!   counter.fetch_add(1, Ordering::SeqCst);
+//! });
+//!
+//! iv.guard().push(1);
+//!
+//! {
+//!   let mut v = iv.guard();
+//!   v.push(2);
+//!   thread::sleep(Duration::from_millis(20));
+//! }
+//!
+//! assert_eq!(long_borrows.load(Ordering::SeqCst), 1);
+//! # }
+//! ```
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::cell::RefCell;
+use std::fmt;
+use std::panic::Location;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{GuardId, MutGuard, WrappedGuard};
+
+/// describes a mutable borrow that was held longer than the configured
+/// threshold
+#[derive(Debug)]
+pub struct LongBorrow<'a> {
+    id: GuardId,
+    location: &'static Location<'static>,
+    held: Duration,
+    threshold: Duration,
+    backtrace: Option<&'a Backtrace>,
+}
+
+impl<'a> LongBorrow<'a> {
+    pub fn guard_id(&self) -> GuardId {
+        self.id
+    }
+
+    /// where the borrow was acquired
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// how long the borrow was held
+    pub fn held(&self) -> Duration {
+        self.held
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    /// backtrace captured when the borrow was acquired, if enabled
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace
+    }
+}
+
+impl<'a> fmt::Display for LongBorrow<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "mutable borrow of guard {} acquired at {} was held for {:?} (threshold: {:?})",
+            self.id, self.location, self.held, self.threshold
+        )?;
+        if let Some(backtrace) = self.backtrace {
+            write!(f, "\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+/// captures a backtrace in debug builds or with the `backtrace` feature,
+/// if the environment enables backtraces
+pub(crate) fn capture_backtrace() -> Option<Arc<Backtrace>> {
+    if !cfg!(any(debug_assertions, feature = "backtrace")) {
+        return None;
+    }
+
+    let backtrace = Backtrace::capture();
+    match backtrace.status() {
+        BacktraceStatus::Captured => Some(Arc::new(backtrace)),
+        _ => None,
+    }
+}
+
+thread_local! {
+    /// acquisition backtrace of the borrow whose element is being checked
+    static CHECKED: RefCell<Option<Arc<Backtrace>>> = const { RefCell::new(None) };
+}
+
+/// the acquisition backtrace of the borrow being checked on this thread,
+/// for the `Violation` of a failed check
+pub(crate) fn checked_backtrace() -> Option<String> {
+    CHECKED.with(|checked| checked.borrow().as_ref().map(|b| b.to_string()))
+}
+
+/// prints the acquisition backtrace if it is dropped because a check
+/// panicked. Until then, it is returned by `checked_backtrace()`
+pub(crate) struct PanicReport {
+    backtrace: Option<Arc<Backtrace>>,
+    // from the borrow being checked when this one was dropped
+    previous: Option<Arc<Backtrace>>,
+}
+
+impl PanicReport {
+    pub(crate) fn new(backtrace: Option<&Arc<Backtrace>>) -> PanicReport {
+        // a borrow dropped while unwinding from an unrelated panic should
+        // not print anything
+        let backtrace = backtrace.filter(|_| !thread::panicking()).cloned();
+        let previous = CHECKED.with(|checked| checked.replace(backtrace.clone()));
+        PanicReport {
+            backtrace,
+            previous,
+        }
+    }
+}
+
+impl Drop for PanicReport {
+    fn drop(&mut self) {
+        if let Some(ref backtrace) = self.backtrace {
+            if thread::panicking() {
+                eprintln!("the element was mutably borrowed at:\n{}", backtrace);
+            }
+        }
+        let previous = self.previous.take();
+        CHECKED.with(|checked| *checked.borrow_mut() = previous);
+    }
+}
+
+/// recorded when a borrow is acquired. If the thread started panicking
+/// since, the mutation was interrupted, or an element nested in it failed
+/// its own check: the borrow is dropped without checking or publishing the
+/// element, since a failing check would panic while panicking, and abort
+#[derive(Clone, Copy)]
+pub(crate) struct Unwinding {
+    panicking: bool,
+}
+
+impl Unwinding {
+    pub(crate) fn start() -> Unwinding {
+        Unwinding {
+            panicking: thread::panicking(),
+        }
+    }
+
+    /// true if a panic started after the borrow was acquired. Borrows
+    /// acquired while unwinding from an unrelated panic, like in a `Drop`
+    /// implementation, are still checked
+    pub(crate) fn interrupted(&self) -> bool {
+        !self.panicking && thread::panicking()
+    }
+}
+
+pub(crate) struct HoldCheck {
+    threshold: Duration,
+    handler: Box<dyn Fn(&LongBorrow<'_>) + Send + Sync>,
+}
+
+/// recorded by a borrow when its `MutGuard` checks how long it is held
+pub(crate) struct Acquired {
+    id: GuardId,
+    at: Instant,
+    location: &'static Location<'static>,
+}
+
+impl HoldCheck {
+    pub(crate) fn acquire(&self, id: GuardId, location: &'static Location<'static>) -> Acquired {
+        Acquired {
+            id,
+            at: Instant::now(),
+            location,
+        }
+    }
+
+    pub(crate) fn release(&self, acquired: &Acquired, backtrace: Option<&Backtrace>) {
+        let held = acquired.at.elapsed();
+        if held > self.threshold {
+            (self.handler)(&LongBorrow {
+                id: acquired.id,
+                location: acquired.location,
+                held,
+                threshold: self.threshold,
+                backtrace,
+            });
+        }
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// calls `handler` every time a mutable borrow of the element was held
+    /// longer than `threshold`, before the element is checked
+    pub fn on_long_borrow<F>(&mut self, threshold: Duration, handler: F)
+    where
+        F: 'static + Send + Sync + Fn(&LongBorrow<'_>),
+    {
+        self.settings.hold = Some(HoldCheck {
+            threshold,
+            handler: Box::new(handler),
+        });
+    }
+
+    /// prints a warning on stderr every time a mutable borrow of the element
+    /// was held longer than `threshold`
+    pub fn warn_on_long_borrow(&mut self, threshold: Duration) {
+        self.on_long_borrow(threshold, |borrow| eprintln!("warning: {}", borrow));
+    }
+}
+
+impl<T, F> WrappedGuard<T, F> {
+    /// see `MutGuard::on_long_borrow()`
+    pub fn on_long_borrow<H>(&mut self, threshold: Duration, handler: H)
+    where
+        H: 'static + Send + Sync + Fn(&LongBorrow<'_>),
+    {
+        self.guard.on_long_borrow(threshold, handler);
+    }
+
+    /// see `MutGuard::warn_on_long_borrow()`
+    pub fn warn_on_long_borrow(&mut self, threshold: Duration) {
+        self.guard.warn_on_long_borrow(threshold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn report_location() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let mut iv = MutGuard::wrap(Vec::new(), |_| {});
+
+        let r = reports.clone();
+        iv.on_long_borrow(Duration::from_millis(1), move |borrow| {
+            r.lock().unwrap().push(borrow.location().line());
+        });
+
+        let line = line!() + 1;
+        let mut v = iv.guard();
+        v.push(1);
+        thread::sleep(Duration::from_millis(5));
+        drop(v);
+
+        iv.guard().push(2);
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(*reports, vec![line]);
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn violation_backtrace() {
+        use super::super::violation::DumpOnViolation;
+        use super::super::Guard;
+
+        #[derive(Debug)]
+        struct Short(Vec<u8>);
+
+        impl Guard for Short {
+            fn finish(&mut self) {
+                assert!(self.0.len() < 2, "too long");
+            }
+        }
+
+        let backtraces = Arc::new(Mutex::new(Vec::new()));
+        let mut short = MutGuard::new(DumpOnViolation::new(Short(vec![])));
+        let b = backtraces.clone();
+        short.on_violation(move |v| b.lock().unwrap().push(v.backtrace().map(String::from)));
+
+        let mut borrow = short.guard();
+        // captured whatever the environment says
+        borrow.backtrace = Some(Arc::new(Backtrace::force_capture()));
+        borrow.0.extend_from_slice(&[1, 2]);
+        drop(borrow);
+
+        let mut borrow = short.guard();
+        borrow.backtrace = None;
+        borrow.0.push(3);
+        drop(borrow);
+
+        let backtraces = backtraces.lock().unwrap();
+        assert!(backtraces[0]
+            .as_ref()
+            .unwrap()
+            .contains("violation_backtrace"));
+        assert_eq!(backtraces[1], None);
+        assert_eq!(checked_backtrace(), None);
+    }
+}
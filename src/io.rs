@@ -0,0 +1,189 @@
+//! Guarded streams
+//!
+//! the "run code after mutation" pattern also applies to streams:
+//! `GuardedWriter` wraps a writer and calls a function after every `write`
+//! and `flush`, to update an integrity hash, check a size cap, etc.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::io::*;
+//! use std::io::{self, Write};
+//!
+//! # fn main() {
+//! let mut w = GuardedWriter::new(Vec::new(), |v: &Vec<u8>, _written: &[u8]| {
+//!   if v.len() > 8 {
+//!     return Err(io::Error::other("size cap reached"));
+//!   }
+//!   Ok(())
+//! });
+//!
+//! assert!(w.write_all(b"hello").is_ok());
+//! // the bytes were written, the error is returned by the next call
+//! assert!(w.write_all(b" world").is_ok());
+//! assert!(w.flush().is_err());
+//! # }
+//! ```
+use std::fmt;
+use std::io::{self, Write};
+
+use super::ARMED;
+
+/// wraps a writer and calls a function after every `write` and `flush`.
+///
+/// The function receives the writer and the bytes that were just written
+/// (an empty slice after a `flush`). If it returns an error after a `flush`,
+/// that error is returned by the `flush` call. After a `write`, the bytes
+/// were already consumed by the writer, so `write` returns their count and
+/// the error is returned by the next `write` or `flush` call
+pub struct GuardedWriter<W, F> {
+    inner: W,
+    f: F,
+    error: Option<io::Error>,
+}
+
+impl<W, F> GuardedWriter<W, F>
+where
+    W: Write,
+    F: FnMut(&W, &[u8]) -> io::Result<()>,
+{
+    pub fn new(inner: W, f: F) -> GuardedWriter<W, F> {
+        GuardedWriter {
+            inner,
+            f,
+            error: None,
+        }
+    }
+
+    /// gets a reference to the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// returns the wrapped writer, consuming the GuardedWriter
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W, F> Write for GuardedWriter<W, F>
+where
+    W: Write,
+    F: FnMut(&W, &[u8]) -> io::Result<()>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let written = self.inner.write(buf)?;
+        if ARMED {
+            if let Err(e) = (self.f)(&self.inner, &buf[..written]) {
+                self.error = Some(e);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.inner.flush()?;
+        if ARMED {
+            (self.f)(&self.inner, &[])?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: fmt::Debug, F> fmt::Debug for GuardedWriter<W, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GuardedWriter")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum() {
+        let mut sum = 0u32;
+        let mut flushes = 0;
+
+        {
+            let mut w = GuardedWriter::new(Vec::new(), |_: &Vec<u8>, written: &[u8]| {
+                if written.is_empty() {
+                    flushes += 1;
+                }
+                sum = written
+                    .iter()
+                    .fold(sum, |acc, b| acc.wrapping_add(*b as u32));
+                Ok(())
+            });
+
+            w.write_all(&[1, 2, 3]).unwrap();
+            w.write_all(&[4]).unwrap();
+            w.flush().unwrap();
+            assert_eq!(w.get_ref(), &vec![1, 2, 3, 4]);
+        }
+
+        assert_eq!(sum, 10);
+        assert_eq!(flushes, 1);
+    }
+
+    #[test]
+    fn callback_error() {
+        let mut w = GuardedWriter::new(Vec::new(), |v: &Vec<u8>, _: &[u8]| {
+            if v.len() > 2 {
+                Err(io::Error::other("too large"))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert!(w.write_all(&[1, 2]).is_ok());
+        assert!(w.write_all(&[3]).is_ok());
+        let e = w.write_all(&[4]).unwrap_err();
+        assert_eq!(e.to_string(), "too large");
+        assert_eq!(w.get_ref(), &vec![1, 2, 3]);
+    }
+
+    // accepts at most 2 bytes per write
+    struct Chunked(Vec<u8>);
+
+    impl Write for Chunked {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(2);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_write_all() {
+        let mut seen = 0;
+        {
+            let mut w = GuardedWriter::new(Chunked(Vec::new()), |c: &Chunked, written: &[u8]| {
+                seen += written.len();
+                if c.0.len() > 2 {
+                    Err(io::Error::other("too large"))
+                } else {
+                    Ok(())
+                }
+            });
+
+            let e = w.write_all(&[1, 2, 3, 4, 5]).unwrap_err();
+            assert_eq!(e.to_string(), "too large");
+            // the failing check saw the bytes of the second write, and
+            // write_all stopped before the third one
+            assert_eq!(w.get_ref().0, vec![1, 2, 3, 4]);
+        }
+        assert_eq!(seen, 4);
+    }
+}
@@ -78,6 +78,39 @@
 //! # }
 //! ```
 //!
+//! ### Normalization
+//!
+//! Instead of rejecting a bad value, a guard can fix it up in `normalize()`. It
+//! runs before `finish()`, so validation and persistence in `finish()` always
+//! see the normalized value:
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! #
+//! #[derive(Debug)]
+//! struct SortedSet(pub Vec<u32>);
+//!
+//! impl Guard for SortedSet {
+//!   fn normalize(&mut self) {
+//!     self.0.sort();
+//!     self.0.dedup();
+//!   }
+//!
+//!   fn finish(&mut self) {
+//!     println!("set is now {:?}", self.0);
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut set = MutGuard::new(SortedSet(Vec::new()));
+//!
+//! set.guard().0.extend(&[3, 1, 3, 2]);
+//! // prints "set is now [1, 2, 3]"
+//! assert_eq!(set.0, vec![1, 2, 3]);
+//! # }
+//! ```
+//!
 //! ### Serialization
 //!
 //! The guard function could be used to store the element to a file after every change
@@ -126,14 +159,372 @@
 //! }
 //! ```
 //!
+//! ## Cargo features
+//!
+//! - `backtrace`: capture a backtrace when a `MutGuardBorrow` is created in release
+//!   builds too (it is always done in debug builds), see the `hold` module
+//! - `disarm`: `Guard::finish()` and the other checks (`Veto::decide()`, the
+//!   functions given to `MutGuard::wrap()` or `MutGuard::scoped()`...) are never
+//!   called, while the API stays identical. This is meant to measure the cost of
+//!   the checks in benchmarks. `Guard::normalize()` and the callbacks registered
+//!   with `MutGuard::defer()` still run, since other code relies on their effects
+//! - `dashmap`: `concurrent::GuardedDashMap`, a guarded concurrent map
+//! - `web`: `web::GuardedSignal`, guarded state in a reactive signal
+//! - `ffi`: C interface to guard data owned by a C host
+//! - `test-util`: `test_util::ViolationRecorder` and `assert_violation!`, to test
+//!   that bad mutations are caught, the `test_util::MockGuard` test double, and
+//!   `test_util::replay()` to replay a command log as a regression test
+//! - `regex`: `guards::Pattern`, a string that must match a regular expression
+//! - `checksum`: `guards::Checksummed`, a value carrying an up to date CRC32
+//!   of its serialization
+//! - `arc-swap`: `rcu::Publisher`, to publish snapshots of the state that readers
+//!   load without locking
+//! - `tokio`: `actor::AsyncGuardActor`, guarded state owned by a tokio task,
+//!   `MutGuard::notified()` to wait for the next mutation,
+//!   `MutGuard::broadcast_changes()` to send change events to many subscribers, and
+//!   `MutGuard::guard_owned()`, a `'static` borrow from an `Arc<tokio::sync::Mutex>`
+//!   that can be held across `.await`
+//! - `persist`: `persist::PersistentMutGuard`, guarded state stored in a file that
+//!   several processes can mutate, using advisory locks, and `persist::JournaledMutGuard`,
+//!   mutated with commands appended to a write-ahead log, and
+//!   `remote::AsyncPersistentMutGuard`, stored in an asynchronous `remote::AsyncStorage`
+//! - `memmap`: `shm::SharedRegion`, a guarded `#[repr(C)]` value in a memory mapped
+//!   file shared between processes
+//! - `metrics`: report the durations recorded by `MutGuard::track_stats()` to the
+//!   `metrics` facade, and count the violations handled by `MutGuard::on_violation()`
+//! - `serde`: serializable `violation::Violation`, and `violation::set_json_sink()` to
+//!   report failed checks as JSON lines. With `tokio`, `MutGuard::broadcast_diffs()`
+//!   sends the serialized element before and after each mutation
+//! - `derive`: `#[derive(GuardedSetters)]`, generating `set_<field>()` methods
+//!   on `MutGuard` that assign a single field, then check the element
+//!   and the `#[requires]`, `#[ensures]` and `#[invariant]` contract attributes
+//!   for methods of guarded types. `#[derive(TrackFields)]` generates the
+//!   `<field>_mut()` accessors used by `MutGuard::guard_tracked()`,
+//!   and `#[derive(CheckFields)]` checks each field, reporting the path of the
+//!   field that broke an invariant in `violation::Violation::field()`
+//! - `sqlx`: `sql::Row` and `#[derive(SqlRow)]`, generating `UPDATE` statements
+//!   for the fields changed in a `MutGuard::guard_tracked()` borrow (implies `derive`)
+//! - `std` (enabled by default): everything except the `Guard` trait, `dirty::Fields`
+//!   and the `embedded` and `critical` modules, which only need `core`. Without it,
+//!   the crate is `no_std`, and `embedded::AsyncMutGuard` runs asynchronous work
+//!   after mutations on executors like embassy, without allocating
+//! - `encryption`: `encrypt::EncryptedStorage`, encrypting the snapshots and write-ahead
+//!   logs of persisted guards with a pluggable AEAD (XChaCha20-Poly1305 is provided)
+//!   and a key given by the application (implies `persist`)
+//! - `zstd`, `lz4`: `compress::CompressedStorage`, compressing the snapshots and
+//!   write-ahead logs of persisted guards (implies `persist`)
+//! - `object-storage`: `remote::HttpStorage`, keeping the snapshot of a
+//!   `remote::AsyncPersistentMutGuard` in an S3 compatible object store, with
+//!   conditional writes (implies `persist` and `tokio`)
+//! - `opentelemetry`: `otel`, OpenTelemetry spans for the borrows of guards, the
+//!   outcome of their checks and the operations of persisted guards, with the
+//!   guard labels as attributes
+//! - `redis`: `pubsub::RedisPublisher`, publishing the checked changes of a
+//!   guard to a Redis channel (implies `serde`)
+//! - `mqtt`: `mqtt::MqttPublisher`, publishing the checked state of a guard, or
+//!   what changed, to an MQTT topic (implies `serde`)
+//! - `critical-section`: `critical::GuardedCriticalCell`, a guarded `static` shared
+//!   with interrupt handlers, checked inside critical sections (works without
+//!   `std`)
+//! - `defmt`: log the mutable borrows of guards (at the `trace` level) and their
+//!   failed checks (at the `error` level) with defmt, in `MutGuard`,
+//!   `embedded::AsyncMutGuard` and `critical::GuardedCriticalCell` (works without
+//!   `std`)
+//! - `axum`: `service::GuardedState`, an extractor sharing a `sync::GuardedRwLock`
+//!   between the handlers of an axum service
+//! - `actix`: `service::GuardedData`, registered with `App::app_data()` in actix-web
+//!   services, giving handlers checked mutable access with `guard()`
+//! - `figment`: `config::GuardedConfig`, configuration extracted from a `Figment` and checked
+//!   again when providers are merged or another profile is selected, rejecting
+//!   the changes that make it invalid (implies `serde`)
+//! - `stable-deref`: `stable::StableGuard`, a guard around a `Box` (or any
+//!   `StableDeref` pointer) implementing `StableDeref`, to own guarded elements
+//!   in self-referential structures
+//!
+#![cfg_attr(not(feature = "std"), no_std)]
+
+// the `Guard` trait and the `embedded` module only need `core`
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+
+#[cfg(feature = "actix")]
+extern crate actix_web;
+#[cfg(feature = "arc-swap")]
+extern crate arc_swap;
+#[cfg(feature = "axum")]
+extern crate axum;
+#[cfg(feature = "encryption")]
+extern crate chacha20poly1305;
+#[cfg(feature = "checksum")]
+extern crate crc32fast;
+#[cfg(feature = "critical-section")]
+extern crate critical_section;
+#[cfg(feature = "dashmap")]
+extern crate dashmap;
+#[cfg(feature = "defmt")]
+extern crate defmt;
+#[cfg(feature = "figment")]
+extern crate figment;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "memmap")]
+extern crate memmap2;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "derive")]
+extern crate mut_guard_derive;
+#[cfg(feature = "opentelemetry")]
+extern crate opentelemetry;
+#[cfg(feature = "web")]
+extern crate reactive_graph;
+#[cfg(feature = "redis")]
+extern crate redis;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "mqtt")]
+extern crate rumqttc;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "sqlx")]
+extern crate sqlx;
+#[cfg(feature = "stable-deref")]
+extern crate stable_deref_trait;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
+use std::cell::RefCell;
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
+use std::mem;
+#[cfg(feature = "std")]
 use std::ops::{Deref, DerefMut, Drop};
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe, Location};
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use std::backtrace::Backtrace;
+
+#[cfg(feature = "std")]
+use budget::{Budget, Progress};
+use dirty::FieldSet;
+#[cfg(feature = "std")]
+use hold::{capture_backtrace, Acquired, HoldCheck, PanicReport, Unwinding};
+
+#[cfg(feature = "sqlx")]
+pub use mut_guard_derive::SqlRow;
+#[cfg(feature = "derive")]
+pub use mut_guard_derive::{
+    ensures, invariant, requires, CheckFields, GuardedSetters, TrackFields,
+};
+
+/// used by the code generated by the macros from the `derive` feature
+#[doc(hidden)]
+pub mod __private {
+    use super::Guard;
+
+    pub const ARMED: bool = super::ARMED;
+
+    pub fn run_guard<T: Guard + ?Sized>(value: &mut T) {
+        super::run_guard(value);
+    }
+
+    #[cfg(feature = "std")]
+    pub fn field_failed(path: &super::violation::FieldPath<'_>, message: &str) -> ! {
+        super::violation::fail_field(path, message)
+    }
+}
+
+// lets the code generated by the derive macros refer to `::mut_guard` in
+// this crate's tests
+#[cfg(all(test, feature = "derive"))]
+extern crate self as mut_guard;
 
+#[cfg(feature = "std")]
+pub mod actor;
+#[cfg(feature = "std")]
+pub mod arena;
+#[cfg(feature = "std")]
+pub mod budget;
+#[cfg(feature = "std")]
+pub mod checks;
+#[cfg(feature = "std")]
+pub mod collections;
+#[cfg(feature = "std")]
+pub mod command;
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+pub mod compress;
+#[cfg(feature = "dashmap")]
+pub mod concurrent;
+#[cfg(feature = "figment")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod context;
+#[cfg(feature = "std")]
+pub mod cow;
+#[cfg(feature = "critical-section")]
+pub mod critical;
+#[cfg(feature = "std")]
+pub mod deferred;
+pub mod dirty;
+pub mod embedded;
+#[cfg(feature = "encryption")]
+pub mod encrypt;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod guards;
+#[cfg(feature = "std")]
+pub mod hold;
+#[cfg(feature = "std")]
+pub mod io;
+mod logging;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "tokio")]
+pub mod notify;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+#[cfg(feature = "tokio")]
+pub mod owned;
+#[cfg(feature = "persist")]
+pub mod persist;
+#[cfg(feature = "std")]
+pub mod publish;
+#[cfg(feature = "redis")]
+pub mod pubsub;
+#[cfg(feature = "arc-swap")]
+pub mod rcu;
+#[cfg(feature = "persist")]
+pub mod remote;
+#[cfg(feature = "std")]
+pub mod revalidate;
+#[cfg(feature = "std")]
+pub mod revert;
+#[cfg(feature = "std")]
+pub mod sample;
+#[cfg(any(feature = "axum", feature = "actix"))]
+pub mod service;
+#[cfg(feature = "memmap")]
+pub mod shm;
+#[cfg(feature = "sqlx")]
+pub mod sql;
+#[cfg(feature = "stable-deref")]
+pub mod stable;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod strict;
+#[cfg(feature = "std")]
+pub mod sync;
+#[cfg(feature = "test-util")]
+#[macro_use]
+pub mod test_util;
+#[cfg(feature = "std")]
+pub mod tier;
+#[cfg(feature = "std")]
+pub mod violation;
+#[cfg(feature = "web")]
+pub mod web;
+
+/// false with the `disarm` feature: checks are then skipped
+const ARMED: bool = cfg!(not(feature = "disarm"));
+
+/// calls `Guard::normalize()`, then `Guard::finish()` and
+/// `Guard::finish_slow()` unless the `disarm` feature is enabled
+fn run_guard<T: Guard + ?Sized>(value: &mut T) {
+    value.normalize();
+    if ARMED {
+        value.finish();
+        value.finish_slow();
+    }
+}
+
+#[cfg(feature = "std")]
 /// stores an inner element that must implement the `Guard` trait,
 /// and forbids mutable borrows except going through its `guard()` method.
 pub struct MutGuard<T> {
     inner: T,
+    // only accessed through `&mut self`, with `Mutex::get_mut()`. The mutex
+    // keeps `MutGuard<T>` `Sync` when `T` is
+    deferred: Mutex<Vec<Deferred<T>>>,
+    // same as `deferred`
+    publishers: Mutex<publish::Publishers<T>>,
+    checks: Mutex<checks::Checks<T>>,
+    settings: Settings,
+    #[cfg(feature = "tokio")]
+    changed: notify::Changed,
+    #[cfg(feature = "tokio")]
+    events: Option<notify::Events<T>>,
 }
 
+#[cfg(feature = "std")]
+/// configuration of a `MutGuard`, independent of the element's type, so
+/// that `map_value()` can keep it
+#[derive(Default)]
+struct Settings {
+    hold: Option<HoldCheck>,
+    stats: Option<stats::FinishStats>,
+    slow: Option<stats::SlowCheck>,
+    label: Option<String>,
+    tier: Option<tier::Schedule>,
+    sample: Option<sample::Sampling>,
+    budget: Option<Duration>,
+    violations: Option<violation::Handler>,
+    /// a check ran out of budget
+    pending: bool,
+    /// assigned on first use
+    id: OnceLock<GuardId>,
+}
+
+#[cfg(feature = "std")]
+/// identifies a `MutGuard` in its reports, events and metrics, to correlate
+/// them when several guards have the same type or label. It is never reused
+/// in the same process, and `map_value()` keeps it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GuardId(u64);
+
+#[cfg(feature = "std")]
+impl GuardId {
+    fn next() -> GuardId {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        GuardId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for GuardId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Settings {
+    fn id(&self) -> GuardId {
+        *self.id.get_or_init(GuardId::next)
+    }
+}
+
+#[cfg(feature = "std")]
+/// callback registered with `MutGuard::defer()`
+type Deferred<T> = Box<dyn FnOnce(&mut T) + Send>;
+
+#[cfg(feature = "std")]
 impl<T> Deref for MutGuard<T> {
     type Target = T;
 
@@ -142,104 +533,1027 @@ impl<T> Deref for MutGuard<T> {
     }
 }
 
+#[cfg(feature = "std")]
+impl<'a, T> IntoIterator for &'a MutGuard<T>
+where
+    &'a T: IntoIterator,
+{
+    type Item = <&'a T as IntoIterator>::Item;
+    type IntoIter = <&'a T as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.inner.into_iter()
+    }
+}
+
 /// Specifies a method that will be called after every time an element
 /// protected by a `Mutguard` will be mutably borrowed
 pub trait Guard {
+    /// fixes up the element (clamping, sorting, deduplicating...) once the
+    /// mutable borrow ends. It always runs before `finish()`
+    fn normalize(&mut self) {}
+
+    /// checks or observes the element once the mutable borrow ends, after
+    /// `normalize()`. This should not modify the element: fixups belong in
+    /// `normalize()`
     fn finish(&mut self);
+
+    /// checks the element after a borrow from `MutGuard::guard_tracked()`,
+    /// instead of `finish()`. `changed` holds the fields that were mutably
+    /// accessed, so only the invariants involving them need to be checked.
+    /// By default, this calls `finish()`
+    fn finish_incremental(&mut self, changed: &FieldSet) {
+        let _ = changed;
+        self.finish();
+    }
+
+    /// expensive checks, running after `finish()` or `finish_incremental()`.
+    /// They run after every borrow, unless `MutGuard::slow_checks_every()`
+    /// was called
+    fn finish_slow(&mut self) {}
+
+    /// checks the element instead of `finish()`, if `MutGuard::with_budget()`
+    /// was called. If the check stops early because `budget` is exhausted,
+    /// it returns `Progress::Incomplete`, and the full check runs in
+    /// `MutGuard::finish_pending()`. By default, this calls `finish()`
+    #[cfg(feature = "std")]
+    fn finish_within(&mut self, budget: &Budget) -> Progress {
+        let _ = budget;
+        self.finish();
+        Progress::Done
+    }
 }
 
-impl<T: Guard> MutGuard<T> {
-    pub fn new(inner: T) -> MutGuard<T> {
-        MutGuard { inner }
+// interior mutability containers delegate to their content, so guarded
+// elements nested in them still take part in the parent's validation.
+// `&mut self` gives direct access, without borrowing or locking
+impl<T: Guard + ?Sized> Guard for RefCell<T> {
+    fn normalize(&mut self) {
+        self.get_mut().normalize();
     }
 
-    /// call this method to get mutable access to the underlying element
-    pub fn guard(&mut self) -> MutGuardBorrow<T> {
-        MutGuardBorrow { inner: self }
+    fn finish(&mut self) {
+        self.get_mut().finish();
     }
 
-    /// returns the wrapped element, consuming the MutGuard
-    pub fn into_inner(self) -> T {
-        self.inner
+    fn finish_slow(&mut self) {
+        self.get_mut().finish_slow();
+    }
+
+    #[cfg(feature = "std")]
+    fn finish_within(&mut self, budget: &Budget) -> Progress {
+        self.get_mut().finish_within(budget)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Guard + ?Sized> Guard for Mutex<T> {
+    fn normalize(&mut self) {
+        match self.get_mut() {
+            Ok(inner) => inner.normalize(),
+            Err(poisoned) => poisoned.into_inner().normalize(),
+        }
+    }
+
+    fn finish(&mut self) {
+        match self.get_mut() {
+            Ok(inner) => inner.finish(),
+            Err(poisoned) => poisoned.into_inner().finish(),
+        }
+    }
+
+    fn finish_slow(&mut self) {
+        match self.get_mut() {
+            Ok(inner) => inner.finish_slow(),
+            Err(poisoned) => poisoned.into_inner().finish_slow(),
+        }
+    }
+
+    fn finish_within(&mut self, budget: &Budget) -> Progress {
+        match self.get_mut() {
+            Ok(inner) => inner.finish_within(budget),
+            Err(poisoned) => poisoned.into_inner().finish_within(budget),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Guard + ?Sized> Guard for RwLock<T> {
+    fn normalize(&mut self) {
+        match self.get_mut() {
+            Ok(inner) => inner.normalize(),
+            Err(poisoned) => poisoned.into_inner().normalize(),
+        }
+    }
+
+    fn finish(&mut self) {
+        match self.get_mut() {
+            Ok(inner) => inner.finish(),
+            Err(poisoned) => poisoned.into_inner().finish(),
+        }
+    }
+
+    fn finish_slow(&mut self) {
+        match self.get_mut() {
+            Ok(inner) => inner.finish_slow(),
+            Err(poisoned) => poisoned.into_inner().finish_slow(),
+        }
+    }
+
+    fn finish_within(&mut self, budget: &Budget) -> Progress {
+        match self.get_mut() {
+            Ok(inner) => inner.finish_within(budget),
+            Err(poisoned) => poisoned.into_inner().finish_within(budget),
+        }
     }
 }
 
-impl<'a, T> MutGuard<MutGuardWrapper<'a, T>> {
+#[cfg(feature = "std")]
+impl<T> MutGuard<T> {
+    pub fn new(inner: T) -> MutGuard<T> {
+        MutGuard {
+            inner,
+            deferred: Mutex::new(Vec::new()),
+            publishers: Mutex::default(),
+            checks: Mutex::default(),
+            settings: Settings::default(),
+            #[cfg(feature = "tokio")]
+            changed: Default::default(),
+            #[cfg(feature = "tokio")]
+            events: None,
+        }
+    }
+
+    /// registers a callback that will run once, after the next mutable
+    /// borrow of the element ends (after `Guard::finish()`), then discarded
+    pub fn defer<F>(&mut self, f: F)
+    where
+        F: 'static + Send + FnOnce(&mut T),
+    {
+        self.deferred_mut().push(Box::new(f));
+    }
+
+    fn deferred_mut(&mut self) -> &mut Vec<Deferred<T>> {
+        match self.deferred.get_mut() {
+            Ok(deferred) => deferred,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn run_deferred(&mut self) {
+        for f in mem::take(self.deferred_mut()) {
+            f(&mut self.inner);
+        }
+    }
+
+    /// calls `publisher` after each mutable borrow of the element ends, once
+    /// it was checked, with the priority 0. See the `publish` module
+    pub fn publish_to<P>(&mut self, publisher: P)
+    where
+        P: 'static + publish::Publisher<T>,
+    {
+        self.publish_to_with_priority(0, publisher);
+    }
+
+    /// like `publish_to()`. Publishers with a lower priority are called
+    /// first, and publishers with the same priority are called in the order
+    /// they were added. The order of everything that runs when a borrow
+    /// ends is described in the `checks` module
+    pub fn publish_to_with_priority<P>(&mut self, priority: i32, publisher: P)
+    where
+        P: 'static + publish::Publisher<T>,
+    {
+        self.publishers_mut().insert(priority, Box::new(publisher));
+    }
+
+    fn publishers_mut(&mut self) -> &mut publish::Publishers<T> {
+        match self.publishers.get_mut() {
+            Ok(publishers) => publishers,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn publish(&mut self, location: &'static Location<'static>) {
+        let publishers = match self.publishers.get_mut() {
+            Ok(publishers) => publishers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let label = self.settings.label.as_deref();
+        publishers.publish(self.settings.id(), label, location, &self.inner);
+    }
+
     /// This method automatically generates a `Guard` implementation that will
     /// call `f` after every time the inner element is mutably borrowed
-    pub fn wrap<F>(inner: T, f: F) -> MutGuard<MutGuardWrapper<'a, T>>
+    pub fn wrap<F>(inner: T, f: F) -> WrappedGuard<T, F>
     where
-        F: 'a + for<'r> FnMut(&'r mut T),
+        F: FnMut(&mut T),
     {
-        let wrapper = MutGuardWrapper {
+        WrappedGuard::new(inner, f)
+    }
+
+    /// like `wrap()`, but `f` only gets a shared reference to the element,
+    /// for callbacks observing it (logging, metrics...) that should not
+    /// modify it
+    pub fn wrap_inspect<F>(inner: T, mut f: F) -> WrappedGuard<T, impl FnMut(&mut T)>
+    where
+        F: FnMut(&T),
+    {
+        WrappedGuard::new(inner, move |inner: &mut T| f(inner))
+    }
+
+    /// like `wrap()`, but `f` can fail. The error is returned by
+    /// `TryWrappedBorrow::commit()`, or stored until
+    /// `TryWrappedGuard::take_error()` if the borrow was just dropped.
+    /// A failure is handled like a violation by the `on_violation()`
+    /// handler, without panicking: deferred callbacks and publishers are
+    /// skipped
+    pub fn try_wrap<F, E>(inner: T, f: F) -> TryWrappedGuard<T, F, E>
+    where
+        F: FnMut(&mut T) -> Result<(), E>,
+    {
+        TryWrappedGuard::new(inner, f)
+    }
+
+    /// like `wrap()`, but `f` also gets mutable access to `state`, stored
+    /// in the returned guard. It can be read with `StatefulGuard::state()`
+    pub fn wrap_with_state<S, F>(inner: T, state: S, f: F) -> StatefulGuard<T, S, F>
+    where
+        F: FnMut(&mut T, &mut S),
+    {
+        StatefulGuard::new(inner, state, f)
+    }
+
+    /// like `wrap()`, but boxes a `Send` closure, so the returned guard can
+    /// be stored in a struct, moved to another thread or placed in a `Mutex`
+    pub fn wrap_send<F>(inner: T, f: F) -> SendWrappedGuard<T>
+    where
+        F: 'static + Send + FnMut(&mut T),
+    {
+        WrappedGuard::new(inner, Box::new(f))
+    }
+
+    /// imposes `check` on a value this code does not own: the returned
+    /// guard gives mutable access to `value`, and calls `check` when it
+    /// is dropped
+    pub fn scoped<F>(value: &mut T, check: F) -> ScopedGuard<'_, T, F>
+    where
+        F: FnMut(&T),
+    {
+        ScopedGuard {
+            value,
+            check,
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    /// transforms the element into another representation, then checks the
+    /// result with `Guard::finish()`. The configuration (like
+    /// `on_long_borrow()`) is kept, callbacks registered with `defer()` or
+    /// `publish_to()` and checks added with `add_check()` are discarded
+    pub fn map_value<U, F>(self, f: F) -> MutGuard<U>
+    where
+        U: Guard,
+        F: FnOnce(T) -> U,
+    {
+        let MutGuard {
+            inner, settings, ..
+        } = self;
+        MutGuard::mapped(f(inner), settings)
+    }
+
+    /// like `map_value()`, but the transformation can fail. The error is
+    /// returned, and the element is lost
+    pub fn try_map_value<U, E, F>(self, f: F) -> Result<MutGuard<U>, E>
+    where
+        U: Guard,
+        F: FnOnce(T) -> Result<U, E>,
+    {
+        let MutGuard {
+            inner, settings, ..
+        } = self;
+        Ok(MutGuard::mapped(f(inner)?, settings))
+    }
+
+    /// names the guard in reports, like the ones from `on_slow_finish()`
+    pub fn set_label<S: Into<String>>(&mut self, label: S) {
+        self.settings.label = Some(label.into());
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.settings.label.as_deref()
+    }
+
+    /// identifier of this guard, included in its reports
+    pub fn id(&self) -> GuardId {
+        self.settings.id()
+    }
+
+    /// returns the wrapped element, consuming the MutGuard
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Guard> MutGuard<T> {
+    fn mapped(inner: T, settings: Settings) -> MutGuard<T> {
+        let mut guard = MutGuard {
             inner,
-            f: Box::new(f),
+            deferred: Mutex::new(Vec::new()),
+            publishers: Mutex::default(),
+            checks: Mutex::default(),
+            settings,
+            #[cfg(feature = "tokio")]
+            changed: Default::default(),
+            #[cfg(feature = "tokio")]
+            events: None,
         };
-        MutGuard::new(wrapper)
+        run_guard(&mut guard.inner);
+        guard
+    }
+
+    /// runs the checks, passing a failure to the handler set with
+    /// `on_violation()` if there is one. Returns false if the failure was
+    /// handled
+    fn run_checks(
+        &mut self,
+        location: &'static Location<'static>,
+        changed: Option<&FieldSet>,
+    ) -> bool {
+        let res = if self.settings.violations.is_none() {
+            self.run_checks_unhandled(location, changed);
+            Ok(())
+        } else {
+            panic::catch_unwind(AssertUnwindSafe(|| {
+                self.run_checks_unhandled(location, changed)
+            }))
+        };
+        // the warnings came from the checks before the failed one
+        self.report_warnings(location);
+        match res {
+            Ok(()) => true,
+            Err(payload) => {
+                let id = self.settings.id();
+                let settings = &mut self.settings;
+                if let Some(ref mut handler) = settings.violations {
+                    let label = settings.label.as_deref();
+                    handler.handle::<T>(payload.as_ref(), id, label, location);
+                }
+                false
+            }
+        }
+    }
+
+    /// reports the checks with `Severity::Warning` that failed
+    fn report_warnings(&mut self, location: &'static Location<'static>) {
+        let warnings = match self.checks.get_mut() {
+            Ok(checks) => checks.take_warnings(),
+            Err(poisoned) => poisoned.into_inner().take_warnings(),
+        };
+        if warnings.is_empty() {
+            return;
+        }
+        let id = self.settings.id();
+        let settings = &mut self.settings;
+        violation::report_warnings::<T>(
+            settings.violations.as_mut(),
+            warnings,
+            id,
+            settings.label.as_deref(),
+            location,
+        );
+    }
+
+    /// like `run_guard()`, skipping the checks for the borrows left out by
+    /// `sample_every()` or `sample_randomly()`
+    fn run_checks_unhandled(
+        &mut self,
+        location: &'static Location<'static>,
+        changed: Option<&FieldSet>,
+    ) {
+        self.inner.normalize();
+        if !ARMED || !self.sampled() {
+            return;
+        }
+        self.timed_finish(location, changed);
+    }
+
+    /// calls `finish()`, timing it if `track_stats()` or `on_slow_finish()`
+    /// was called
+    fn timed_finish(&mut self, location: &'static Location<'static>, changed: Option<&FieldSet>) {
+        if self.settings.stats.is_none() && self.settings.slow.is_none() {
+            self.finish(location, changed);
+            return;
+        }
+
+        let start = Instant::now();
+        self.finish(location, changed);
+        let elapsed = start.elapsed();
+
+        let settings = &mut self.settings;
+        if let Some(ref mut stats) = settings.stats {
+            stats.record(elapsed);
+            #[cfg(feature = "metrics")]
+            stats::report::<T>(settings.id(), elapsed);
+        }
+        if let Some(ref slow) = settings.slow {
+            slow.check(settings.id(), settings.label.as_deref(), location, elapsed);
+        }
+    }
+
+    /// calls `Guard::finish()`, or `Guard::finish_incremental()` if the
+    /// changed fields are known, or `Guard::finish_within()` if a budget was
+    /// set, reporting a failure to the JSON sink if one
+    /// was set with `violation::set_json_sink()`
+    fn finish(&mut self, location: &'static Location<'static>, changed: Option<&FieldSet>) {
+        let slow = self.slow_checks_due();
+        self.finish_budgeted(location, |inner, budget| {
+            let progress = match (changed, budget) {
+                (None, Some(budget)) => inner.finish_within(budget),
+                _ => {
+                    dirty::finish(inner, changed);
+                    Progress::Done
+                }
+            };
+            if slow {
+                inner.finish_slow();
+            }
+            progress
+        });
+    }
+
+    /// runs `check`, reporting a failure to the JSON sink if one was set
+    /// with `violation::set_json_sink()`
+    fn finish_with<F: FnOnce(&mut T)>(&mut self, location: &'static Location<'static>, check: F) {
+        let checks = match self.checks.get_mut() {
+            Ok(checks) => checks,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let check = |inner: &mut T| {
+            check(inner);
+            checks.run(inner);
+        };
+        #[cfg(feature = "opentelemetry")]
+        let check = |inner: &mut T| otel::check(inner, check);
+        #[cfg(feature = "serde")]
+        violation::finish_reported(
+            &mut self.inner,
+            check,
+            self.settings.id(),
+            self.settings.label.as_deref(),
+            location,
+        );
+        #[cfg(not(feature = "serde"))]
+        {
+            let _ = location;
+            check(&mut self.inner);
+        }
+    }
+
+    /// call this method to get mutable access to the underlying element
+    #[track_caller]
+    pub fn guard(&mut self) -> MutGuardBorrow<'_, T> {
+        self.borrow_at(Location::caller())
+    }
+
+    pub(crate) fn borrow_at(
+        &mut self,
+        location: &'static Location<'static>,
+    ) -> MutGuardBorrow<'_, T> {
+        let id = self.settings.id();
+        let acquired = self
+            .settings
+            .hold
+            .as_ref()
+            .map(|hold| hold.acquire(id, location));
+        #[cfg(feature = "opentelemetry")]
+        let span = otel::BorrowSpan::start::<T>(id, self.settings.label.as_deref(), location);
+        logging::acquired::<T>();
+        MutGuardBorrow {
+            inner: self,
+            location,
+            acquired,
+            backtrace: capture_backtrace(),
+            changed: None,
+            unwinding: Unwinding::start(),
+            failed: false,
+            #[cfg(feature = "opentelemetry")]
+            span: Some(span),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 /// Structure returned by the `MutGuard::guard()`. when this is dropped, it
 /// will call the `Guard::finish()` method of the wrapped element
 pub struct MutGuardBorrow<'a, T: 'a + Guard> {
     inner: &'a mut MutGuard<T>,
+    location: &'static Location<'static>,
+    acquired: Option<Acquired>,
+    backtrace: Option<Arc<Backtrace>>,
+    changed: Option<FieldSet>,
+    unwinding: Unwinding,
+    // set by `fail()`
+    failed: bool,
+    #[cfg(feature = "opentelemetry")]
+    span: Option<otel::BorrowSpan>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard> MutGuardBorrow<'a, T> {
+    /// backtrace captured when the borrow was acquired, in debug builds or
+    /// with the `backtrace` feature, if enabled by `RUST_BACKTRACE` or
+    /// `RUST_LIB_BACKTRACE`
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        self.backtrace.as_deref()
+    }
+
+    /// reports a failure detected without panicking to the handler set with
+    /// `on_violation()`, if there is one. When the borrow is dropped, the
+    /// checks, deferred callbacks, publishers and subscribers are then
+    /// skipped, like after a handled violation
+    fn fail(&mut self, message: &'static str) {
+        self.failed = true;
+        let _report = PanicReport::new(self.backtrace.as_ref());
+        let id = self.inner.settings.id();
+        let settings = &mut self.inner.settings;
+        if let Some(ref mut handler) = settings.violations {
+            handler.handle::<T>(&message, id, settings.label.as_deref(), self.location);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard> Deref for MutGuardBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard> DerefMut for MutGuardBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T: Guard> Drop for MutGuardBorrow<'a, T> {
+    fn drop(&mut self) {
+        // ends the span once everything below ran, or panicked
+        #[cfg(feature = "opentelemetry")]
+        let _span = self.span.take().map(otel::BorrowSpan::enter);
+        if let (Some(hold), Some(acquired)) =
+            (self.inner.settings.hold.as_ref(), self.acquired.as_ref())
+        {
+            hold.release(acquired, self.backtrace.as_deref());
+        }
+        // a panic interrupted the mutation: deferred callbacks, publishers
+        // and subscribers are skipped along with the checks
+        if self.unwinding.interrupted() {
+            return;
+        }
+        let _report = PanicReport::new(self.backtrace.as_ref());
+        let checking = logging::Checking::start::<T>();
+        if self.failed || !self.inner.run_checks(self.location, self.changed.as_ref()) {
+            // the violation was handled, and is logged when dropping checking
+            return;
+        }
+        checking.passed();
+        self.inner.run_deferred();
+        self.inner.publish(self.location);
+        #[cfg(feature = "tokio")]
+        self.inner.notify_changed();
+        #[cfg(feature = "tokio")]
+        self.inner.broadcast_change(self.location);
+    }
+}
+
+#[cfg(feature = "std")]
+/// Structure returned by `MutGuard::scoped()`. when this is dropped, it
+/// will call the check function on the borrowed element
+pub struct ScopedGuard<'a, T: 'a, F: FnMut(&T)> {
+    value: &'a mut T,
+    check: F,
+    unwinding: Unwinding,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F: FnMut(&T)> Deref for ScopedGuard<'a, T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F: FnMut(&T)> DerefMut for ScopedGuard<'a, T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F: FnMut(&T)> Drop for ScopedGuard<'a, T, F> {
+    fn drop(&mut self) {
+        if ARMED && !self.unwinding.interrupted() {
+            (self.check)(self.value);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+/// stores an inner element and a function that will be called after every
+/// time the element is mutably borrowed through `guard()`. Returned by
+/// `MutGuard::wrap()`
+pub struct WrappedGuard<T, F = Box<dyn FnMut(&mut T)>> {
+    guard: MutGuard<Wrapped<T, F>>,
+}
+
+#[cfg(feature = "std")]
+/// `WrappedGuard` that is `Send` when `T` is. Returned by `MutGuard::wrap_send()`
+pub type SendWrappedGuard<T> = WrappedGuard<T, Box<dyn FnMut(&mut T) + Send>>;
+
+#[cfg(feature = "std")]
+struct Wrapped<T, F> {
+    inner: T,
+    f: F,
+}
+
+#[cfg(feature = "std")]
+impl<T, F: FnMut(&mut T)> Guard for Wrapped<T, F> {
+    fn finish(&mut self) {
+        (self.f)(&mut self.inner);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F: FnMut(&mut T)> WrappedGuard<T, F> {
+    pub fn new(inner: T, f: F) -> WrappedGuard<T, F> {
+        WrappedGuard {
+            guard: MutGuard::new(Wrapped { inner, f }),
+        }
+    }
+
+    /// call this method to get mutable access to the underlying element
+    #[track_caller]
+    pub fn guard(&mut self) -> WrappedBorrow<'_, T, F> {
+        WrappedBorrow {
+            inner: self.guard.guard(),
+        }
+    }
+
+    /// registers a callback that will run once, after the next mutable
+    /// borrow of the element ends, then discarded
+    pub fn defer<G>(&mut self, g: G)
+    where
+        G: 'static + Send + FnOnce(&mut T),
+    {
+        self.guard.defer(move |wrapped| g(&mut wrapped.inner));
+    }
+
+    /// see `MutGuard::set_label()`
+    pub fn set_label<S: Into<String>>(&mut self, label: S) {
+        self.guard.set_label(label);
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.guard.label()
+    }
+
+    /// see `MutGuard::id()`
+    pub fn id(&self) -> GuardId {
+        self.guard.id()
+    }
+
+    /// returns the wrapped element, consuming the WrappedGuard
+    pub fn into_inner(self) -> T {
+        self.guard.into_inner().inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F> Deref for WrappedGuard<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.inner.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F> IntoIterator for &'a WrappedGuard<T, F>
+where
+    &'a T: IntoIterator,
+{
+    type Item = <&'a T as IntoIterator>::Item;
+    type IntoIter = <&'a T as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.guard.inner.inner.into_iter()
+    }
+}
+
+#[cfg(feature = "std")]
+/// Structure returned by the `WrappedGuard::guard()`. when this is dropped,
+/// it will call the function given to `MutGuard::wrap()`
+pub struct WrappedBorrow<'a, T: 'a, F: 'a + FnMut(&mut T)> {
+    inner: MutGuardBorrow<'a, Wrapped<T, F>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F: FnMut(&mut T)> Deref for WrappedBorrow<'a, T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.inner.inner.inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F: FnMut(&mut T)> DerefMut for WrappedBorrow<'a, T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner.inner.inner.inner
+    }
+}
+
+#[cfg(feature = "std")]
+/// stores an inner element and a fallible function that will be called
+/// after every time the element is mutably borrowed through `guard()`.
+/// Returned by `MutGuard::try_wrap()`
+pub struct TryWrappedGuard<T, F, E> {
+    guard: MutGuard<TryWrapped<T, F, E>>,
+}
+
+#[cfg(feature = "std")]
+struct TryWrapped<T, F, E> {
+    inner: T,
+    f: F,
+    error: Option<E>,
+}
+
+#[cfg(feature = "std")]
+impl<T, F, E> TryWrapped<T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
+    fn call(&mut self) -> Result<(), E> {
+        if ARMED {
+            (self.f)(&mut self.inner)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F, E> Guard for TryWrapped<T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
+    // `f` is called by `TryWrappedBorrow`, before the borrow of the
+    // `MutGuard` ends, so a failure can skip what runs after the checks
+    fn finish(&mut self) {}
+}
+
+#[cfg(feature = "std")]
+impl<T, F, E> TryWrappedGuard<T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
+    pub fn new(inner: T, f: F) -> TryWrappedGuard<T, F, E> {
+        TryWrappedGuard {
+            guard: MutGuard::new(TryWrapped {
+                inner,
+                f,
+                error: None,
+            }),
+        }
+    }
+
+    /// call this method to get mutable access to the underlying element
+    #[track_caller]
+    pub fn guard(&mut self) -> TryWrappedBorrow<'_, T, F, E> {
+        TryWrappedBorrow {
+            inner: self.guard.guard(),
+            committed: false,
+        }
+    }
+
+    /// registers a callback that will run once, after the next mutable
+    /// borrow of the element for which the function succeeds, then discarded
+    pub fn defer<G>(&mut self, g: G)
+    where
+        G: 'static + Send + FnOnce(&mut T),
+    {
+        self.guard.defer(move |wrapped| g(&mut wrapped.inner));
+    }
+
+    /// see `MutGuard::publish_to()`. Changes for which the function failed
+    /// are not published
+    pub fn publish_to<P>(&mut self, mut publisher: P)
+    where
+        P: 'static + publish::Publisher<T>,
+    {
+        self.guard
+            .publish_to(move |change: &publish::Change<'_, TryWrapped<T, F, E>>| {
+                publisher.publish(&publish::Change {
+                    id: change.id,
+                    sequence: change.sequence,
+                    label: change.label,
+                    location: change.location,
+                    value: &change.value.inner,
+                })
+            });
+    }
+
+    /// returns the error from a borrow that was dropped without calling
+    /// `TryWrappedBorrow::commit()`, if the function failed. The first error
+    /// is kept until it is taken, later ones only reach the `on_violation()`
+    /// handler
+    pub fn take_error(&mut self) -> Option<E> {
+        self.guard.inner.error.take()
+    }
+
+    /// returns the wrapped element, consuming the TryWrappedGuard
+    pub fn into_inner(self) -> T {
+        self.guard.into_inner().inner
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, F, E> Deref for TryWrappedGuard<T, F, E> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.inner.inner
+    }
+}
+
+#[cfg(feature = "std")]
+/// Structure returned by the `TryWrappedGuard::guard()`. when this is
+/// dropped, it will call the function given to `MutGuard::try_wrap()`
+pub struct TryWrappedBorrow<'a, T: 'a, F, E>
+where
+    F: 'a + FnMut(&mut T) -> Result<(), E>,
+    E: 'a,
+{
+    inner: MutGuardBorrow<'a, TryWrapped<T, F, E>>,
+    // set by `commit()`, that already called `f`
+    committed: bool,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F, E> TryWrappedBorrow<'a, T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
+    /// ends the borrow now, returning the result of the function
+    pub fn commit(mut self) -> Result<(), E> {
+        self.committed = true;
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<(), E> {
+        if self.inner.unwinding.interrupted() {
+            return Ok(());
+        }
+        let res = self.inner.call();
+        if res.is_err() {
+            self.inner
+                .fail("the function given to MutGuard::try_wrap() failed");
+        }
+        res
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, F, E> Drop for TryWrappedBorrow<'a, T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        if let Err(e) = self.call() {
+            let wrapped = &mut *self.inner;
+            if wrapped.error.is_none() {
+                wrapped.error = Some(e);
+            }
+        }
+    }
 }
 
-impl<'a, T: Guard> Deref for MutGuardBorrow<'a, T> {
+#[cfg(feature = "std")]
+impl<'a, T, F, E> Deref for TryWrappedBorrow<'a, T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.inner.inner
+        &self.inner.inner.inner.inner
     }
 }
 
-impl<'a, T: Guard> DerefMut for MutGuardBorrow<'a, T> {
+#[cfg(feature = "std")]
+impl<'a, T, F, E> DerefMut for TryWrappedBorrow<'a, T, F, E>
+where
+    F: FnMut(&mut T) -> Result<(), E>,
+{
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.inner.inner
+        &mut self.inner.inner.inner.inner
     }
 }
 
-impl<'a, T: Guard> Drop for MutGuardBorrow<'a, T> {
-    fn drop(&mut self) {
-        self.inner.inner.finish();
-    }
+#[cfg(feature = "std")]
+/// stores an inner element, some auxiliary state, and a function that will
+/// be called with both after every time the element is mutably borrowed
+/// through `guard()`. Returned by `MutGuard::wrap_with_state()`
+pub struct StatefulGuard<T, S, F> {
+    guard: MutGuard<Stateful<T, S, F>>,
 }
 
-/// `Guard` implementation returned by `MutGuard::wrap()`
-pub struct MutGuardWrapper<'a, T> {
+#[cfg(feature = "std")]
+struct Stateful<T, S, F> {
     inner: T,
-    f: Box<'a + FnMut(&mut T)>,
+    state: S,
+    f: F,
 }
 
-impl<'a, T: 'a> MutGuardWrapper<'a, T> {
-    pub fn new<F>(inner: T, f: F) -> MutGuardWrapper<'a, T>
-    where
-        F: 'a + for<'r> FnMut(&'r mut T),
-    {
-        MutGuardWrapper {
-            inner,
-            f: Box::new(f),
+#[cfg(feature = "std")]
+impl<T, S, F: FnMut(&mut T, &mut S)> Guard for Stateful<T, S, F> {
+    fn finish(&mut self) {
+        (self.f)(&mut self.inner, &mut self.state);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, S, F: FnMut(&mut T, &mut S)> StatefulGuard<T, S, F> {
+    pub fn new(inner: T, state: S, f: F) -> StatefulGuard<T, S, F> {
+        StatefulGuard {
+            guard: MutGuard::new(Stateful { inner, state, f }),
+        }
+    }
+
+    /// call this method to get mutable access to the underlying element
+    #[track_caller]
+    pub fn guard(&mut self) -> StatefulBorrow<'_, T, S, F> {
+        StatefulBorrow {
+            inner: self.guard.guard(),
         }
     }
+
+    pub fn state(&self) -> &S {
+        &self.guard.inner.state
+    }
+
+    /// gives mutable access to the auxiliary state. This does not call
+    /// the function
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.guard.inner.state
+    }
+
+    /// returns the wrapped element and the state, consuming the StatefulGuard
+    pub fn into_inner(self) -> (T, S) {
+        let stateful = self.guard.into_inner();
+        (stateful.inner, stateful.state)
+    }
 }
 
-impl<'a, T> Guard for MutGuardWrapper<'a, T> {
-    fn finish(&mut self) {
-        (self.f)(&mut self.inner);
+#[cfg(feature = "std")]
+impl<T, S, F> Deref for StatefulGuard<T, S, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.inner.inner
     }
 }
 
-impl<'a, T> Deref for MutGuardWrapper<'a, T> {
+#[cfg(feature = "std")]
+/// Structure returned by the `StatefulGuard::guard()`. when this is
+/// dropped, it will call the function given to `MutGuard::wrap_with_state()`
+pub struct StatefulBorrow<'a, T: 'a, S: 'a, F: 'a + FnMut(&mut T, &mut S)> {
+    inner: MutGuardBorrow<'a, Stateful<T, S, F>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, T, S, F: FnMut(&mut T, &mut S)> Deref for StatefulBorrow<'a, T, S, F> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &self.inner
+        &self.inner.inner.inner.inner
     }
 }
 
-impl<'a, T> DerefMut for MutGuardWrapper<'a, T> {
+#[cfg(feature = "std")]
+impl<'a, T, S, F: FnMut(&mut T, &mut S)> DerefMut for StatefulBorrow<'a, T, S, F> {
     fn deref_mut(&mut self) -> &mut T {
-        &mut self.inner
+        &mut self.inner.inner.inner.inner
     }
 }
 
@@ -373,7 +1687,7 @@ mod tests {
 
         let mut val = MutGuard::new(LessThan20(0));
 
-        let v = val.guard();
+        let _v = val.guard();
         panic!("other panic");
     }
 
@@ -403,4 +1717,626 @@ mod tests {
         // with mem::forget, drop() will not be called on the guard
         assert_eq!(counter, 2);
     }
+
+    #[test]
+    fn normalize_before_finish() {
+        struct Clamped(pub u8);
+
+        impl Guard for Clamped {
+            fn normalize(&mut self) {
+                if self.0 > 20 {
+                    self.0 = 20;
+                }
+            }
+
+            fn finish(&mut self) {
+                assert!(self.0 <= 20, "finish saw a value that was not normalized");
+            }
+        }
+
+        let mut val = MutGuard::new(Clamped(0));
+        val.guard().0 = 30;
+        assert_eq!(val.0, 20);
+    }
+
+    #[test]
+    fn defer_runs_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut iv = MutGuard::wrap(Vec::new(), |_| {});
+
+        let c = calls.clone();
+        iv.defer(move |v| {
+            assert_eq!(*v, vec![1]);
+            c.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        iv.guard().push(1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        iv.guard().push(2);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn into_iter() {
+        fn sum<'a, I: IntoIterator<Item = &'a i32>>(i: I) -> i32 {
+            i.into_iter().sum()
+        }
+
+        let accounts = MutGuard::new(vec![10, 0, 20, 50]);
+        assert_eq!(sum(&accounts), 80);
+
+        let mut iv = MutGuard::wrap(Vec::new(), |_| {});
+        iv.guard().push(1);
+        iv.guard().push(2);
+
+        let mut v = Vec::new();
+        for i in &iv {
+            v.push(*i);
+        }
+        assert_eq!(v, vec![1, 2]);
+        assert_eq!(sum(&*iv), 3);
+    }
+
+    #[test]
+    fn wrap_derefs_to_inner() {
+        fn len<D: Deref<Target = Vec<i32>>>(d: &D) -> usize {
+            d.len()
+        }
+
+        struct Holder {
+            values: WrappedGuard<Vec<i32>>,
+        }
+
+        let mut h = Holder {
+            values: MutGuard::wrap(Vec::new(), Box::new(|v: &mut Vec<i32>| v.sort())),
+        };
+
+        h.values.guard().extend(vec![3, 1, 2]);
+        assert_eq!(len(&h.values), 3);
+        assert_eq!(len(&h.values.guard()), 3);
+        assert_eq!(h.values.into_inner(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn wrap_send() {
+        use std::sync::mpsc::channel;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+
+        let (tx, rx) = channel();
+        let iv = Arc::new(Mutex::new(MutGuard::wrap_send(Vec::new(), move |v| {
+            tx.send(v.len()).unwrap();
+        })));
+
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let iv = iv.clone();
+                thread::spawn(move || iv.lock().unwrap().guard().push(i))
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let mut sizes: Vec<usize> = rx.try_iter().collect();
+        sizes.sort();
+        assert_eq!(sizes, vec![1, 2, 3, 4]);
+        assert_eq!(iv.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "accounts should not become negative")]
+    fn scoped() {
+        fn transfer_all(bank: &mut Bank) {
+            let mut b = MutGuard::scoped(bank, |b: &Bank| {
+                assert!(
+                    b.accounts.iter().all(|v| *v >= 0),
+                    "accounts should not become negative"
+                );
+            });
+
+            b.transfer(0, 1, 5);
+            b.transfer(2, 3, 30);
+        }
+
+        let mut bank = Bank::new(vec![10, 0, 20, 50]);
+        transfer_all(&mut bank);
+    }
+
+    #[test]
+    fn scoped_interrupted() {
+        let mut checks = 0;
+        let mut bank = Bank::new(vec![10, 0]);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut b = MutGuard::scoped(&mut bank, |b: &Bank| {
+                checks += 1;
+                assert!(b.accounts[0] >= 0, "accounts should not become negative");
+            });
+            b.transfer(0, 1, 20);
+            panic!("interrupted");
+        }));
+        assert!(res.is_err());
+        assert_eq!(checks, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "disarm")]
+    fn disarmed() {
+        let mut counter = 0;
+
+        {
+            let mut iv = MutGuard::wrap(Vec::new(), |_| counter += 1);
+            iv.guard().push(1);
+        }
+
+        let mut val = MutGuard::new(Bank::new(vec![10]));
+        val.guard().transfer(0, 0, 1);
+
+        assert_eq!(counter, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "invariant failed, internal value is too large: 30")]
+    fn nested_containers() {
+        use std::cell::RefCell;
+        use std::sync::Mutex;
+
+        struct LessThan20(pub u8);
+
+        impl Guard for LessThan20 {
+            fn finish(&mut self) {
+                assert!(
+                    self.0 <= 20,
+                    "invariant failed, internal value is too large: {}",
+                    self.0
+                );
+            }
+        }
+
+        struct Parent {
+            a: RefCell<LessThan20>,
+            b: Mutex<LessThan20>,
+        }
+
+        impl Guard for Parent {
+            fn finish(&mut self) {
+                self.a.finish();
+                self.b.finish();
+            }
+        }
+
+        let mut val = MutGuard::new(Parent {
+            a: RefCell::new(LessThan20(0)),
+            b: Mutex::new(LessThan20(0)),
+        });
+
+        val.guard().a.borrow_mut().0 = 10;
+        val.b.lock().unwrap().0 = 30;
+        val.guard();
+    }
+
+    #[test]
+    fn wrap_inspect() {
+        let mut sizes = Vec::new();
+
+        {
+            let mut iv = MutGuard::wrap_inspect(Vec::new(), |v: &Vec<i32>| sizes.push(v.len()));
+            iv.guard().push(1);
+            iv.guard().push(2);
+        }
+
+        assert_eq!(sizes, vec![1, 2]);
+    }
+
+    #[test]
+    fn try_wrap() {
+        let mut iv = MutGuard::try_wrap(Vec::new(), |v: &mut Vec<i32>| {
+            if v.len() > 2 {
+                Err(format!("too many elements: {}", v.len()))
+            } else {
+                Ok(())
+            }
+        });
+
+        let mut v = iv.guard();
+        v.push(1);
+        assert_eq!(v.commit(), Ok(()));
+
+        iv.guard().push(2);
+        assert_eq!(iv.take_error(), None);
+
+        iv.guard().push(3);
+        assert_eq!(iv.take_error(), Some("too many elements: 3".to_string()));
+        assert_eq!(iv.take_error(), None);
+
+        let mut v = iv.guard();
+        v.push(4);
+        assert_eq!(v.commit(), Err("too many elements: 4".to_string()));
+        assert_eq!(iv.take_error(), None);
+        assert_eq!(*iv, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn try_wrap_failure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let mut iv = MutGuard::try_wrap(Vec::new(), |v: &mut Vec<i32>| match v.len() {
+            0 | 1 => Ok(()),
+            len => Err(len),
+        });
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let p = published.clone();
+        iv.publish_to(move |change: &publish::Change<Vec<i32>>| {
+            p.lock().unwrap().push(change.value().clone());
+        });
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let v = violations.clone();
+        iv.on_violation(move |violation| v.lock().unwrap().push(violation.message().to_string()));
+        let deferred = Arc::new(AtomicUsize::new(0));
+        let d = deferred.clone();
+        iv.defer(move |_| {
+            d.fetch_add(1, Ordering::SeqCst);
+        });
+
+        iv.guard().push(1);
+        assert_eq!(deferred.load(Ordering::SeqCst), 1);
+        let d = deferred.clone();
+        iv.defer(move |_| {
+            d.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // neither published nor running the deferred callback
+        let mut v = iv.guard();
+        v.push(2);
+        assert_eq!(v.commit(), Err(2));
+        iv.guard().push(3);
+        iv.guard().push(4);
+        assert_eq!(*published.lock().unwrap(), vec![vec![1]]);
+        assert_eq!(deferred.load(Ordering::SeqCst), 1);
+        assert_eq!(iv.violation_count(), 3);
+        assert_eq!(
+            violations.lock().unwrap()[0],
+            "the function given to MutGuard::try_wrap() failed"
+        );
+        // the first error is kept
+        assert_eq!(iv.take_error(), Some(3));
+        assert_eq!(iv.take_error(), None);
+
+        iv.guard().truncate(1);
+        assert_eq!(*published.lock().unwrap(), vec![vec![1], vec![1]]);
+        assert_eq!(deferred.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn wrap_with_state() {
+        // the guard outlives the scope creating it, so the counter cannot
+        // be a captured local
+        fn counted() -> StatefulGuard<Vec<i32>, usize, impl FnMut(&mut Vec<i32>, &mut usize)> {
+            MutGuard::wrap_with_state(Vec::new(), 0, |_, counter| *counter += 1)
+        }
+
+        let mut iv = counted();
+        iv.guard().push(1);
+        iv.guard().push(2);
+        iv.guard().push(3);
+        assert_eq!(*iv.state(), 3);
+
+        *iv.state_mut() = 0;
+        iv.guard().push(4);
+
+        assert_eq!(iv.into_inner(), (vec![1, 2, 3, 4], 1));
+    }
+
+    #[test]
+    fn map_value() {
+        let bank = MutGuard::new(Bank::new(vec![10, -1]));
+
+        struct Total(i32);
+
+        impl Guard for Total {
+            fn finish(&mut self) {
+                assert!(self.0 >= 0, "total should not be negative");
+            }
+        }
+
+        let total = bank.map_value(|b| Total(b.accounts.iter().sum()));
+        assert_eq!(total.0, 9);
+
+        let res: Result<MutGuard<Total>, String> = total.try_map_value(|t| {
+            if t.0 > 5 {
+                Err("too large".to_string())
+            } else {
+                Ok(t)
+            }
+        });
+        assert_eq!(res.err(), Some("too large".to_string()));
+    }
+
+    #[test]
+    fn guard_ids() {
+        struct Count(u32);
+
+        impl Guard for Count {
+            fn finish(&mut self) {}
+        }
+
+        let a = MutGuard::new(Count(0));
+        let b = MutGuard::new(Count(0));
+        assert_ne!(a.id(), b.id());
+        assert_eq!(a.id(), a.id());
+
+        let id = a.id();
+        let doubled = a.map_value(|c| Count(c.0 * 2));
+        assert_eq!(doubled.id(), id);
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn guarded_setters() {
+        #[derive(Debug, GuardedSetters)]
+        struct Range {
+            start: u32,
+            end: u32,
+            #[guarded_setters(skip)]
+            cached_len: u32,
+        }
+
+        impl Guard for Range {
+            fn normalize(&mut self) {
+                self.cached_len = self.end - self.start;
+            }
+
+            fn finish(&mut self) {
+                assert!(self.start <= self.end, "invalid range");
+            }
+        }
+
+        let mut range = MutGuard::new(Range {
+            start: 0,
+            end: 10,
+            cached_len: 10,
+        });
+        assert_eq!(range.set_end(20), 10);
+        assert_eq!(range.set_start(5), 0);
+        assert_eq!((range.start, range.end, range.cached_len), (5, 20, 15));
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            range.set_start(30);
+        }));
+        assert!(res.is_err());
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn contracts() {
+        #[derive(Debug)]
+        struct Account {
+            balance: u32,
+            limit: u32,
+        }
+
+        impl Guard for Account {
+            fn finish(&mut self) {
+                assert!(self.balance <= self.limit, "balance above the limit");
+            }
+        }
+
+        impl Account {
+            #[requires(amount <= self.balance)]
+            #[ensures(*ret == self.balance)]
+            #[invariant]
+            fn withdraw(&mut self, amount: u32) -> u32 {
+                if amount == 0 {
+                    return self.balance;
+                }
+                self.balance -= amount;
+                self.balance
+            }
+
+            #[invariant]
+            fn deposit(&mut self, amount: u32) {
+                self.balance += amount;
+            }
+        }
+
+        let mut account = Account {
+            balance: 10,
+            limit: 20,
+        };
+        assert_eq!(account.withdraw(0), 10);
+        assert_eq!(account.withdraw(4), 6);
+        account.deposit(4);
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            account.withdraw(11);
+        }));
+        assert!(res.is_err());
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            account.deposit(11);
+        }));
+        assert!(res.is_err());
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn check_fields() {
+        use violation::CheckFields;
+
+        #[derive(Debug, CheckFields)]
+        struct Account {
+            #[check(*balance >= 0, "negative balance")]
+            balance: i64,
+            #[check(!name.is_empty())]
+            name: String,
+        }
+
+        #[derive(Debug, CheckFields)]
+        struct Bank {
+            #[check(each)]
+            accounts: Vec<Account>,
+            #[check(nested)]
+            reserve: Account,
+        }
+
+        impl Guard for Bank {
+            fn finish(&mut self) {
+                self.check_fields();
+            }
+        }
+
+        let account = |balance| Account {
+            balance,
+            name: "a".to_string(),
+        };
+        let mut bank = MutGuard::new(Bank {
+            accounts: vec![account(1), account(2), account(3)],
+            reserve: account(100),
+        });
+        bank.on_violation(|_| {});
+
+        bank.guard().accounts[2].balance = -1;
+        let violation = bank.last_violation().unwrap();
+        assert_eq!(violation.field(), Some("accounts[2].balance"));
+        assert_eq!(violation.message(), "accounts[2].balance: negative balance");
+
+        bank.guard().accounts[2].balance = 0;
+        bank.guard().reserve.name.clear();
+        let violation = bank.last_violation().unwrap();
+        assert_eq!(violation.field(), Some("reserve.name"));
+        assert_eq!(
+            violation.message(),
+            "reserve.name: check failed: ! name.is_empty()"
+        );
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn track_fields() {
+        use dirty::TrackedBorrow;
+
+        #[derive(Debug, TrackFields)]
+        struct Order {
+            items: Vec<u32>,
+            total: u32,
+            note: String,
+        }
+
+        impl Guard for Order {
+            fn finish(&mut self) {
+                assert_eq!(self.items.iter().sum::<u32>(), self.total);
+            }
+        }
+
+        let mut order = MutGuard::new(Order {
+            items: vec![],
+            total: 0,
+            note: String::new(),
+        });
+
+        let mut o: TrackedBorrow<Order> = order.guard_tracked();
+        o.items_mut().push(3);
+        *o.total_mut() += 3;
+        assert_eq!(
+            o.changed().iter().collect::<Vec<_>>(),
+            vec!["items", "total"]
+        );
+        assert!(!o.changed().contains("note"));
+        assert!(o.note.is_empty());
+    }
+
+    #[test]
+    fn unwinding() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        struct Cell(u8);
+
+        impl Guard for Cell {
+            fn finish(&mut self) {
+                assert!(self.0 < 5, "cell overflow");
+            }
+        }
+
+        struct Grid {
+            cells: Vec<MutGuard<Cell>>,
+        }
+
+        impl Guard for Grid {
+            fn finish(&mut self) {
+                assert!(
+                    self.cells.iter().all(|c| c.0 < 5),
+                    "invalid cell in the grid"
+                );
+            }
+        }
+
+        fn message(res: std::thread::Result<()>) -> String {
+            match res.unwrap_err().downcast::<String>() {
+                Ok(s) => *s,
+                Err(payload) => payload.downcast_ref::<&str>().unwrap().to_string(),
+            }
+        }
+
+        let published = Arc::new(AtomicUsize::new(0));
+        let mut grid = MutGuard::new(Grid {
+            cells: vec![MutGuard::new(Cell(0))],
+        });
+        {
+            let published = published.clone();
+            grid.publish_to(move |_: &publish::Change<Grid>| {
+                published.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // the grid is dropped while unwinding from the failed check of the
+        // cell, and does not check it again
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut g = grid.guard();
+            g.cells[0].guard().0 = 9;
+        }));
+        assert_eq!(message(res), "cell overflow");
+        assert_eq!(published.load(Ordering::SeqCst), 0);
+
+        grid.guard().cells[0].guard().0 = 1;
+        assert_eq!(published.load(Ordering::SeqCst), 1);
+
+        // interrupted mutation
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut g = grid.guard();
+            g.cells.push(MutGuard::new(Cell(7)));
+            panic!("interrupted");
+        }));
+        assert_eq!(message(res), "interrupted");
+        assert_eq!(published.load(Ordering::SeqCst), 1);
+
+        // borrows acquired while unwinding from an unrelated panic are
+        // checked and published
+        struct Cleanup<'a>(&'a mut MutGuard<Grid>);
+
+        impl<'a> Drop for Cleanup<'a> {
+            fn drop(&mut self) {
+                let invalid = panic::catch_unwind(AssertUnwindSafe(|| {
+                    self.0.guard().cells.push(MutGuard::new(Cell(9)));
+                }));
+                assert!(invalid.is_err());
+                self.0.guard().cells.retain(|c| c.0 < 5);
+            }
+        }
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _cleanup = Cleanup(&mut grid);
+            panic!("unrelated");
+        }));
+        assert_eq!(message(res), "unrelated");
+        assert_eq!(grid.cells.len(), 1);
+        assert_eq!(published.load(Ordering::SeqCst), 2);
+    }
 }
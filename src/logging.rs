@@ -0,0 +1,97 @@
+//! defmt logging, with the `defmt` feature
+//!
+//! the functions here do nothing without the feature, so the guards call
+//! them unconditionally
+#[cfg(feature = "defmt")]
+use std::any::type_name;
+
+/// logs the start of a mutable borrow
+#[inline(always)]
+pub(crate) fn acquired<T: ?Sized>() {
+    #[cfg(feature = "defmt")]
+    ::defmt::trace!(
+        "mut_guard: mutable borrow of {=str} acquired",
+        type_name::<T>()
+    );
+}
+
+/// created before the checks that end a mutable borrow run. Unless
+/// `passed()` is called, the checks panicked, and the violation is logged
+/// while unwinding
+pub(crate) struct Checking {
+    #[cfg(feature = "defmt")]
+    name: &'static str,
+    #[cfg(feature = "defmt")]
+    passed: bool,
+}
+
+impl Checking {
+    #[inline(always)]
+    #[cfg_attr(not(feature = "defmt"), allow(clippy::extra_unused_type_parameters))]
+    pub(crate) fn start<T: ?Sized>() -> Checking {
+        Checking {
+            #[cfg(feature = "defmt")]
+            name: type_name::<T>(),
+            #[cfg(feature = "defmt")]
+            passed: false,
+        }
+    }
+
+    /// logs the end of the borrow
+    #[inline(always)]
+    pub(crate) fn passed(self) {
+        #[cfg(feature = "defmt")]
+        {
+            let mut checking = self;
+            checking.passed = true;
+            ::defmt::trace!(
+                "mut_guard: mutable borrow of {=str} released",
+                checking.name
+            );
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl Drop for Checking {
+    fn drop(&mut self) {
+        if !self.passed {
+            ::defmt::error!("mut_guard: invariant violated in {=str}", self.name);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "defmt", feature = "std"))]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[derive(Debug)]
+    struct Odd(u32);
+
+    impl Guard for Odd {
+        fn finish(&mut self) {
+            assert!(self.0 % 2 == 1, "should be odd");
+        }
+    }
+
+    fn logged(name: &str) -> bool {
+        // the test build of defmt keeps the logs of each thread
+        let bytes = ::defmt::export::fetch_bytes();
+        bytes.windows(name.len()).any(|w| w == name.as_bytes())
+    }
+
+    #[test]
+    fn violation() {
+        let name = type_name::<Odd>();
+        let mut val = MutGuard::new(Odd(1));
+        val.guard().0 += 2;
+        // borrows are only logged with `DEFMT_LOG=trace`
+        logged(name);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| val.guard().0 += 1));
+        assert!(res.is_err());
+        assert!(logged(name));
+    }
+}
@@ -0,0 +1,506 @@
+//! MQTT publishing
+//!
+//! *Note*: this module requires the `mqtt` feature.
+//!
+//! `MqttPublisher` is a `publish::Publisher` sending each checked change of
+//! a `MutGuard` to an MQTT topic, serialized as JSON: the element by
+//! default, a `publish::Payload` with `payload()`, or only what changed
+//! with `deltas()`.
+//!
+//! ```rust,no_run
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate rumqttc;
+//! # use mut_guard::*;
+//! # use mut_guard::mqtt::MqttPublisher;
+//! use rumqttc::{MqttOptions, QoS};
+//!
+//! #[derive(Serialize, Debug)]
+//! struct Valve {
+//!   open: bool,
+//!   pressure: u32,
+//! }
+//!
+//! impl Guard for Valve {
+//!   fn finish(&mut self) {
+//!     assert!(!self.open || self.pressure < 300, "open valve under high pressure");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut options = MqttOptions::new("valve-42", "broker.local", 1883);
+//! options.set_clean_session(false);
+//!
+//! let mut valve = MutGuard::new(Valve { open: false, pressure: 120 });
+//! valve.publish_to(
+//!   MqttPublisher::new(options, "devices/valve-42/state")
+//!     .qos(QoS::AtLeastOnce)
+//!     .deltas(),
+//! );
+//!
+//! // publishes {"open":false,"pressure":120}, then {"open":true}
+//! valve.guard().pressure = 120;
+//! valve.guard().open = true;
+//! # }
+//! ```
+//!
+//! Messages are queued, then sent by a background thread, started on the
+//! first change, which keeps the connection to the broker. When the
+//! connection is lost, it reconnects after `reconnect_delay()`, while new
+//! messages wait in the queue. With QoS 1 or 2, messages that were not
+//! acknowledged yet are sent again after reconnecting, if the broker kept
+//! the session: this requires `MqttOptions::set_clean_session(false)`.
+//!
+//! Errors, like a lost connection or a full queue, are passed to the
+//! `on_error()` handler, which prints a warning by default. A message that
+//! does not fit in the queue is dropped: the mutation was already checked,
+//! so it is not undone.
+//!
+//! Deltas are JSON merge patches (RFC 7386) from the previously published
+//! state, starting with the whole element. Like in merge patches, `null`
+//! fields cannot be told apart from removed ones. When a delta does not fit
+//! in the queue, the next one is computed from the last queued state, so it
+//! includes the dropped changes. A delta lost by the broker makes the
+//! receivers diverge, so deltas should be sent with QoS 1 or 2, and not
+//! retained.
+use std::error::Error;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, Connection, MqttOptions, QoS};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::publish::{Change, Payload, Publisher};
+
+/// default delay before reconnecting to the broker
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// messages waiting to be sent
+const QUEUE_CAPACITY: usize = 64;
+
+type ErrorHandler = Arc<Mutex<dyn FnMut(&dyn Error) + Send>>;
+/// a serialized change, and the state it leads to with deltas
+type Message = (Option<Vec<u8>>, Option<Value>);
+
+enum Format {
+    Json(Payload),
+    /// last published state
+    Delta(Option<Value>),
+}
+
+/// `Publisher` sending changes to an MQTT topic
+pub struct MqttPublisher<T: ?Sized> {
+    client: Client,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    format: Format,
+    reconnect_delay: Duration,
+    on_error: ErrorHandler,
+    // taken by the background thread, on the first change
+    connection: Option<Connection>,
+    closed: Arc<AtomicBool>,
+    element: PhantomData<fn(&T)>,
+}
+
+impl<T: ?Sized + Serialize> MqttPublisher<T> {
+    pub fn new<S: Into<String>>(options: MqttOptions, topic: S) -> MqttPublisher<T> {
+        let (client, connection) = Client::new(options, QUEUE_CAPACITY);
+        MqttPublisher {
+            client,
+            topic: topic.into(),
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            format: Format::Json(Payload::Snapshot),
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            on_error: Arc::new(Mutex::new(|e: &dyn Error| {
+                eprintln!("mut_guard: could not publish to mqtt: {}", e)
+            })),
+            connection: Some(connection),
+            closed: Arc::new(AtomicBool::new(false)),
+            element: PhantomData,
+        }
+    }
+
+    /// `QoS::AtLeastOnce` by default
+    pub fn qos(mut self, qos: QoS) -> MqttPublisher<T> {
+        self.qos = qos;
+        self
+    }
+
+    /// asks the broker to keep the last message for new subscribers
+    pub fn retain(mut self, retain: bool) -> MqttPublisher<T> {
+        self.retain = retain;
+        self
+    }
+
+    /// what is sent for each change, `Payload::Snapshot` by default
+    pub fn payload(mut self, payload: Payload) -> MqttPublisher<T> {
+        self.format = Format::Json(payload);
+        self
+    }
+
+    /// only sends what changed since the previous message, see the module
+    /// documentation
+    pub fn deltas(mut self) -> MqttPublisher<T> {
+        self.format = Format::Delta(None);
+        self
+    }
+
+    /// time to wait after a connection error before reconnecting, one
+    /// second by default
+    pub fn reconnect_delay(mut self, delay: Duration) -> MqttPublisher<T> {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    /// called with the connection errors, and the errors that made a
+    /// change be dropped
+    pub fn on_error<F: FnMut(&dyn Error) + Send + 'static>(mut self, f: F) -> MqttPublisher<T> {
+        self.on_error = Arc::new(Mutex::new(f));
+        self
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    fn report(&self, error: &dyn Error) {
+        report(&self.on_error, error);
+    }
+
+    fn start(&mut self) {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let on_error = self.on_error.clone();
+        let closed = self.closed.clone();
+        let delay = self.reconnect_delay;
+
+        let res = thread::Builder::new()
+            .name("mut_guard-mqtt".to_string())
+            .spawn(move || {
+                // ends once the publisher is dropped and the queue is empty
+                for event in connection.iter() {
+                    if let Err(e) = event {
+                        if closed.load(Ordering::Acquire) {
+                            return;
+                        }
+                        report(&on_error, &e);
+                        thread::sleep(delay);
+                    }
+                }
+            });
+        if let Err(e) = res {
+            self.report(&e);
+        }
+    }
+
+    /// the message sent for `change`, if something changed, and with
+    /// deltas, the state to keep once it is queued
+    fn message(&self, change: &Change<'_, T>) -> serde_json::Result<Message> {
+        match self.format {
+            Format::Json(payload) => Ok((Some(change.to_json(payload)?), None)),
+            Format::Delta(ref previous) => {
+                let state = serde_json::to_value(change.value())?;
+                let patch = match *previous {
+                    Some(ref previous) => merge_patch(previous, &state),
+                    None => Some(state.clone()),
+                };
+                let message = patch.map(|patch| serde_json::to_vec(&patch)).transpose()?;
+                Ok((message, Some(state)))
+            }
+        }
+    }
+}
+
+impl<T: ?Sized + Serialize> Publisher<T> for MqttPublisher<T> {
+    fn publish(&mut self, change: &Change<'_, T>) {
+        self.start();
+        let (message, state) = match self.message(change) {
+            Ok((Some(message), state)) => (message, state),
+            // nothing changed
+            Ok((None, _)) => return,
+            Err(e) => return self.report(&e),
+        };
+
+        let res = self
+            .client
+            .try_publish(self.topic.as_str(), self.qos, self.retain, message);
+        match res {
+            // the next delta is computed from the state of the last queued
+            // message, so it also carries the changes of dropped ones
+            Ok(()) => {
+                if let Format::Delta(ref mut previous) = self.format {
+                    *previous = state;
+                }
+            }
+            Err(e) => self.report(&e),
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for MqttPublisher<T> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Release);
+    }
+}
+
+fn report(on_error: &ErrorHandler, error: &dyn Error) {
+    let mut on_error = on_error.lock().unwrap_or_else(|e| e.into_inner());
+    (*on_error)(error);
+}
+
+/// JSON merge patch turning `before` into `after`, or `None` if they are
+/// equal
+fn merge_patch(before: &Value, after: &Value) -> Option<Value> {
+    match (before, after) {
+        (Value::Object(before), Value::Object(after)) => {
+            let mut patch = Map::new();
+            for key in before.keys() {
+                if !after.contains_key(key) {
+                    patch.insert(key.clone(), Value::Null);
+                }
+            }
+            for (key, value) in after {
+                let changed = match before.get(key) {
+                    Some(previous) => merge_patch(previous, value),
+                    None => Some(value.clone()),
+                };
+                if let Some(changed) = changed {
+                    patch.insert(key.clone(), changed);
+                }
+            }
+
+            if patch.is_empty() {
+                None
+            } else {
+                Some(Value::Object(patch))
+            }
+        }
+        _ if before == after => None,
+        _ => Some(after.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use serde_json::json;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::panic;
+    use std::sync::mpsc;
+
+    #[derive(Serialize, Debug)]
+    struct Sensor {
+        celsius: i32,
+        alarm: bool,
+    }
+
+    impl Guard for Sensor {
+        fn finish(&mut self) {
+            assert!(!self.alarm || self.celsius > 80, "alarm without reason");
+        }
+    }
+
+    #[test]
+    fn patches() {
+        let before = json!({"a": 1, "b": {"c": 2, "d": 3}, "e": [1]});
+        assert_eq!(merge_patch(&before, &before), None);
+        assert_eq!(
+            merge_patch(
+                &before,
+                &json!({"a": 1, "b": {"c": 4, "d": 3}, "e": [1, 2]})
+            ),
+            Some(json!({"b": {"c": 4}, "e": [1, 2]}))
+        );
+        assert_eq!(
+            merge_patch(&before, &json!({"b": {"d": 3}, "f": true})),
+            Some(json!({"a": null, "b": {"c": null}, "e": null, "f": true}))
+        );
+        assert_eq!(merge_patch(&json!(1), &json!("1")), Some(json!("1")));
+    }
+
+    /// reads an MQTT packet: its type and flags, and its body
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            stream.read_exact(&mut byte).ok()?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).ok()?;
+        Some((header, body))
+    }
+
+    /// fake broker keeping the sessions, sending the topic and payload of
+    /// each publish. Unless acknowledged, only the first publish of a
+    /// connection is received, then the connection is closed. With a
+    /// `gate`, connections are only answered once it receives a message
+    fn broker(
+        ack: bool,
+        gate: Option<mpsc::Receiver<()>>,
+    ) -> (u16, mpsc::Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            if let Some(gate) = gate {
+                gate.recv().unwrap();
+            }
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                while let Some((header, body)) = read_packet(&mut stream) {
+                    match header >> 4 {
+                        // CONNECT, answered with a present session
+                        1 => stream.write_all(&[0x20, 2, 1, 0]).unwrap(),
+                        // PUBLISH with QoS 1
+                        3 => {
+                            let len = usize::from(body[0]) << 8 | usize::from(body[1]);
+                            let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                            let id = &body[2 + len..4 + len];
+                            let payload = String::from_utf8(body[4 + len..].to_vec()).unwrap();
+                            sender.send((topic, payload)).unwrap();
+                            if !ack {
+                                break;
+                            }
+                            stream.write_all(&[0x40, 2, id[0], id[1]]).unwrap();
+                        }
+                        // PINGREQ
+                        12 => stream.write_all(&[0xd0, 0]).unwrap(),
+                        _ => {}
+                    }
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    fn received(
+        receiver: &mpsc::Receiver<(String, String)>,
+        count: usize,
+    ) -> Vec<(String, String)> {
+        (0..count)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn publish() {
+        let (port, receiver) = broker(true, None);
+        let options = MqttOptions::new("publish", "127.0.0.1", port);
+
+        let mut sensor = MutGuard::new(Sensor {
+            celsius: 20,
+            alarm: false,
+        });
+        sensor.publish_to(MqttPublisher::new(options, "sensors/1").deltas());
+
+        sensor.guard().celsius = 90;
+        // no change
+        sensor.guard().celsius = 90;
+        sensor.guard().alarm = true;
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| sensor.guard().celsius = 20));
+        assert!(res.is_err());
+
+        let messages = received(&receiver, 2);
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    "sensors/1".to_string(),
+                    r#"{"alarm":false,"celsius":90}"#.to_string()
+                ),
+                ("sensors/1".to_string(), r#"{"alarm":true}"#.to_string()),
+            ]
+        );
+        assert!(receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn reconnect() {
+        let (port, receiver) = broker(false, None);
+        let mut options = MqttOptions::new("reconnect", "127.0.0.1", port);
+        options.set_clean_session(false);
+        let errors = Arc::new(Mutex::new(0));
+
+        let mut sensor = MutGuard::new(Sensor {
+            celsius: 20,
+            alarm: false,
+        });
+        {
+            let errors = errors.clone();
+            let publisher = MqttPublisher::new(options, "sensors/2")
+                .payload(Payload::Event)
+                .reconnect_delay(Duration::from_millis(10))
+                .on_error(move |_| *errors.lock().unwrap() += 1);
+            sensor.publish_to(publisher);
+        }
+
+        sensor.guard().celsius += 1;
+        // sent again on each new connection, since it is never acknowledged
+        let messages = received(&receiver, 3);
+        for (topic, payload) in messages {
+            assert_eq!(topic, "sensors/2");
+            let event: Value = serde_json::from_str(&payload).unwrap();
+            assert_eq!(event["sequence"], 1);
+            assert_eq!(event["value"]["celsius"], 21);
+        }
+        assert!(*errors.lock().unwrap() >= 2);
+    }
+
+    #[test]
+    fn full_queue() {
+        let (open, gate) = mpsc::channel();
+        let (port, receiver) = broker(true, Some(gate));
+        let options = MqttOptions::new("full-queue", "127.0.0.1", port);
+        let errors = Arc::new(Mutex::new(0));
+
+        let mut sensor = MutGuard::new(Sensor {
+            celsius: 0,
+            alarm: false,
+        });
+        {
+            let errors = errors.clone();
+            let publisher = MqttPublisher::new(options, "sensors/3")
+                .deltas()
+                .on_error(move |_| *errors.lock().unwrap() += 1);
+            sensor.publish_to(publisher);
+        }
+
+        // nothing is sent until the broker answers
+        for _ in 0..QUEUE_CAPACITY {
+            sensor.guard().celsius += 1;
+        }
+        sensor.guard().celsius = 90;
+        assert_eq!(*errors.lock().unwrap(), 1);
+
+        open.send(()).unwrap();
+        let messages = received(&receiver, QUEUE_CAPACITY);
+        assert_eq!(
+            messages.last().unwrap().1,
+            format!(r#"{{"celsius":{}}}"#, QUEUE_CAPACITY)
+        );
+
+        // includes the dropped change
+        sensor.guard().alarm = true;
+        let messages = received(&receiver, 1);
+        assert_eq!(messages[0].1, r#"{"alarm":true,"celsius":90}"#);
+    }
+}
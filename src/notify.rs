@@ -0,0 +1,687 @@
+//! Waiting for guarded mutations from async code
+//!
+//! *Note*: this module requires the `tokio` feature.
+//!
+//! `MutGuard::notified()` returns a future that resolves once the next
+//! mutable borrow of the element ends and the element was checked. It owns
+//! what it needs, so it can be awaited while the `MutGuard` is mutated
+//! elsewhere, like in another task behind a mutex.
+//!
+//! ```rust,edition2021
+//! # extern crate mut_guard;
+//! # extern crate tokio;
+//! # use mut_guard::*;
+//! use std::sync::{Arc, Mutex};
+//!
+//! #[derive(Debug, Default)]
+//! struct Jobs(Vec<u32>);
+//!
+//! impl Guard for Jobs {
+//!   fn finish(&mut self) {
+//!     assert!(self.0.len() <= 8, "too many pending jobs");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+//! # rt.block_on(async {
+//! let jobs = Arc::new(Mutex::new(MutGuard::new(Jobs::default())));
+//! let changed = jobs.lock().unwrap().notified();
+//!
+//! let producer = {
+//!   let jobs = jobs.clone();
+//!   tokio::spawn(async move {
+//!     jobs.lock().unwrap().guard().0.push(1);
+//!   })
+//! };
+//!
+//! changed.await;
+//! assert_eq!(jobs.lock().unwrap().0.len(), 1);
+//! # producer.await.unwrap();
+//! # });
+//! # }
+//! ```
+//!
+//! When several components observe the same state, `broadcast_changes()` or
+//! `broadcast_values()` send a `ChangeEvent` to every subscriber after each
+//! checked mutation, through a `tokio::sync::broadcast` channel. It only
+//! keeps the last `capacity` events: a subscriber that falls behind gets
+//! `RecvError::Lagged` with the number of events it missed, then resumes
+//! from the oldest event still retained, and can reload the full state if it
+//! cannot work with gaps.
+//!
+//! ```rust,edition2021
+//! # extern crate mut_guard;
+//! # extern crate tokio;
+//! # use mut_guard::*;
+//! use tokio::sync::broadcast::error::RecvError;
+//!
+//! #[derive(Clone, Debug, Default)]
+//! struct Counter(u32);
+//!
+//! impl Guard for Counter {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 < 100, "counter overflow");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+//! # rt.block_on(async {
+//! let mut counter = MutGuard::new(Counter::default());
+//! let mut fast = counter.broadcast_values(2);
+//! let mut slow = counter.subscribe().unwrap();
+//!
+//! counter.guard().0 += 1;
+//! assert_eq!(fast.recv().await.unwrap().value().unwrap().0, 1);
+//!
+//! counter.guard().0 += 1;
+//! counter.guard().0 += 1;
+//! assert_eq!(slow.recv().await.unwrap_err(), RecvError::Lagged(1));
+//! let event = slow.recv().await.unwrap();
+//! assert_eq!(event.sequence(), 2);
+//! assert_eq!(event.value().unwrap().0, 2);
+//! # });
+//! # }
+//! ```
+//!
+//! With the `serde` feature, `broadcast_diffs()` adds the serialized
+//! element before and after the mutation to each event, with the list of
+//! values that changed between them, for consumers that replicate or audit
+//! the state.
+//!
+#![cfg_attr(feature = "serde", doc = "```rust,edition2021")]
+#![cfg_attr(not(feature = "serde"), doc = "```ignore")]
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate serde_json;
+//! # use mut_guard::*;
+//! use serde_json::json;
+//!
+//! #[derive(Serialize, Debug)]
+//! struct Limits {
+//!   max_connections: u32,
+//!   max_body: u32,
+//! }
+//!
+//! impl Guard for Limits {
+//!   fn finish(&mut self) {
+//!     assert!(self.max_connections > 0, "no connection allowed");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut limits = MutGuard::new(Limits { max_connections: 10, max_body: 1024 });
+//! let mut changes = limits.broadcast_diffs(16);
+//!
+//! limits.guard().max_connections = 20;
+//!
+//! let event = changes.try_recv().unwrap();
+//! let diff = event.diff().unwrap();
+//! assert_eq!(diff.before()["max_connections"], json!(10));
+//! assert_eq!(diff.after()["max_connections"], json!(20));
+//!
+//! let changed = diff.changes();
+//! assert_eq!(changed.len(), 1);
+//! assert_eq!(changed[0].path(), "/max_connections");
+//! # }
+//! ```
+use std::fmt;
+use std::panic::Location;
+use std::sync::{Arc, OnceLock};
+
+use tokio::sync::broadcast;
+use tokio::sync::futures::OwnedNotified;
+use tokio::sync::Notify;
+
+use super::{GuardId, MutGuard, WrappedGuard};
+
+/// created on the first call to `MutGuard::notified()`, so that guards
+/// nobody waits on do not allocate
+pub(crate) type Changed = OnceLock<Arc<Notify>>;
+
+impl<T> MutGuard<T> {
+    /// resolves after the next mutable borrow of the element ends, once the
+    /// element was checked. It is not woken up if the checks panic
+    pub fn notified(&self) -> OwnedNotified {
+        self.changed
+            .get_or_init(|| Arc::new(Notify::new()))
+            .clone()
+            .notified_owned()
+    }
+
+    pub(crate) fn notify_changed(&self) {
+        if let Some(changed) = self.changed.get() {
+            changed.notify_waiters();
+        }
+    }
+}
+
+/// sent to the subscribers of a `MutGuard` after a checked mutation
+pub struct ChangeEvent<T> {
+    id: GuardId,
+    sequence: u64,
+    location: &'static Location<'static>,
+    value: Option<Arc<T>>,
+    #[cfg(feature = "serde")]
+    diff: Option<Arc<diff::Diff>>,
+}
+
+impl<T> ChangeEvent<T> {
+    /// the guard that was mutated
+    pub fn guard_id(&self) -> GuardId {
+        self.id
+    }
+
+    /// number of the mutation, starting at 1. Consecutive events have
+    /// consecutive numbers, so gaps show how many were missed
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// where the borrow was acquired
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// the element after the mutation, if enabled with `broadcast_values()`
+    pub fn value(&self) -> Option<&T> {
+        self.value.as_deref()
+    }
+
+    /// shared copy of the element, if enabled with `broadcast_values()`
+    pub fn shared_value(&self) -> Option<Arc<T>> {
+        self.value.clone()
+    }
+
+    /// the serialized element before and after the mutation, if enabled with
+    /// `broadcast_diffs()`
+    #[cfg(feature = "serde")]
+    pub fn diff(&self) -> Option<&diff::Diff> {
+        self.diff.as_deref()
+    }
+}
+
+// not derived, to clone events of any `T`
+impl<T> Clone for ChangeEvent<T> {
+    fn clone(&self) -> ChangeEvent<T> {
+        ChangeEvent {
+            id: self.id,
+            sequence: self.sequence,
+            location: self.location,
+            value: self.value.clone(),
+            #[cfg(feature = "serde")]
+            diff: self.diff.clone(),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ChangeEvent<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("ChangeEvent");
+        debug
+            .field("guard_id", &self.id)
+            .field("sequence", &self.sequence)
+            .field("location", &self.location)
+            .field("value", &self.value);
+        #[cfg(feature = "serde")]
+        debug.field("diff", &self.diff);
+        debug.finish()
+    }
+}
+
+/// created by `MutGuard::broadcast_changes()` or `broadcast_values()`. The
+/// channel is only `Send` and `Sync` if `T` is, so it is hidden behind a
+/// trait object to keep `MutGuard<T>` `Send` for other `T`
+pub(crate) type Events<T> = Box<dyn Broadcast<T> + Send + Sync>;
+
+pub(crate) trait Broadcast<T> {
+    fn send(&mut self, id: GuardId, value: &T, location: &'static Location<'static>);
+    fn subscribe(&self) -> broadcast::Receiver<ChangeEvent<T>>;
+}
+
+struct Channel<T> {
+    sender: broadcast::Sender<ChangeEvent<T>>,
+    /// set by `broadcast_values()`, which requires `T: Clone`
+    snapshot: Option<fn(&T) -> T>,
+    #[cfg(feature = "serde")]
+    diffs: Option<diff::Diffs<T>>,
+    sequence: u64,
+}
+
+impl<T> Broadcast<T> for Channel<T> {
+    fn send(&mut self, id: GuardId, value: &T, location: &'static Location<'static>) {
+        self.sequence += 1;
+        // the state is kept as the next event's previous state, even if
+        // nobody receives this one
+        #[cfg(feature = "serde")]
+        let diff = self.diffs.as_mut().and_then(|diffs| diffs.next(value));
+        // nobody would receive it
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let value = self.snapshot.map(|snapshot| Arc::new(snapshot(value)));
+        let _ = self.sender.send(ChangeEvent {
+            id,
+            sequence: self.sequence,
+            location,
+            value,
+            #[cfg(feature = "serde")]
+            diff,
+        });
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ChangeEvent<T>> {
+        self.sender.subscribe()
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// sends a `ChangeEvent` to every subscriber after each mutable borrow of
+    /// the element, once it was checked, keeping the last `capacity` events
+    /// for subscribers that fall behind. Returns a first subscriber, others
+    /// are created with `subscribe()`
+    ///
+    /// Calling it again replaces the channel: existing subscribers get
+    /// `RecvError::Closed`
+    ///
+    /// # Panics
+    ///
+    /// if `capacity` is 0
+    pub fn broadcast_changes(&mut self, capacity: usize) -> broadcast::Receiver<ChangeEvent<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.start_broadcast(capacity, None)
+    }
+
+    /// like `broadcast_changes()`, with a copy of the element in each event.
+    /// It is cloned once per mutation, and shared between subscribers
+    pub fn broadcast_values(&mut self, capacity: usize) -> broadcast::Receiver<ChangeEvent<T>>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        self.start_broadcast(capacity, Some(T::clone))
+    }
+
+    fn start_broadcast(
+        &mut self,
+        capacity: usize,
+        snapshot: Option<fn(&T) -> T>,
+    ) -> broadcast::Receiver<ChangeEvent<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        self.start_channel(Channel {
+            sender: broadcast::channel(capacity).0,
+            snapshot,
+            #[cfg(feature = "serde")]
+            diffs: None,
+            sequence: 0,
+        })
+    }
+
+    /// like `broadcast_changes()`, with the element serialized before and
+    /// after the mutation in each event, see `ChangeEvent::diff()`. The
+    /// element is serialized after each mutation, even without subscribers,
+    /// to keep the previous state of the next one
+    #[cfg(feature = "serde")]
+    pub fn broadcast_diffs(&mut self, capacity: usize) -> broadcast::Receiver<ChangeEvent<T>>
+    where
+        T: serde::Serialize + Send + Sync + 'static,
+    {
+        self.start_channel(Channel {
+            sender: broadcast::channel(capacity).0,
+            snapshot: None,
+            diffs: Some(diff::Diffs::new(&self.inner)),
+            sequence: 0,
+        })
+    }
+
+    fn start_channel(&mut self, channel: Channel<T>) -> broadcast::Receiver<ChangeEvent<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let receiver = channel.sender.subscribe();
+        self.events = Some(Box::new(channel));
+        receiver
+    }
+
+    /// returns a new subscriber, receiving the events sent after this call,
+    /// or `None` if `broadcast_changes()` or `broadcast_values()` was not
+    /// called
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<ChangeEvent<T>>> {
+        self.events.as_ref().map(|events| events.subscribe())
+    }
+
+    pub(crate) fn broadcast_change(&mut self, location: &'static Location<'static>) {
+        if let Some(ref mut events) = self.events {
+            events.send(self.settings.id(), &self.inner, location);
+        }
+    }
+}
+
+impl<T, F> WrappedGuard<T, F> {
+    /// see `MutGuard::notified()`
+    pub fn notified(&self) -> OwnedNotified {
+        self.guard.notified()
+    }
+}
+
+#[cfg(feature = "serde")]
+pub mod diff {
+    //! Serialized state carried by change events
+    //!
+    //! *Note*: this module requires the `tokio` and `serde` features.
+    use std::fmt::Write;
+    use std::sync::Arc;
+
+    use serde::Serialize;
+    use serde_json::Value;
+
+    /// the element serialized before and after a mutation, sent by
+    /// `MutGuard::broadcast_diffs()`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Diff {
+        before: Arc<Value>,
+        after: Arc<Value>,
+    }
+
+    impl Diff {
+        pub fn before(&self) -> &Value {
+            &self.before
+        }
+
+        pub fn after(&self) -> &Value {
+            &self.after
+        }
+
+        /// the values that differ between `before()` and `after()`. Objects
+        /// are compared key by key and arrays index by index, so an element
+        /// inserted in an array changes all the following indices
+        pub fn changes(&self) -> Vec<ValueChange<'_>> {
+            let mut changes = Vec::new();
+            compare(
+                &mut String::new(),
+                Some(&self.before),
+                Some(&self.after),
+                &mut changes,
+            );
+            changes
+        }
+    }
+
+    /// a value that was added, removed or replaced, see `Diff::changes()`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ValueChange<'a> {
+        path: String,
+        before: Option<&'a Value>,
+        after: Option<&'a Value>,
+    }
+
+    impl<'a> ValueChange<'a> {
+        /// JSON pointer (RFC 6901) to the value, like `/users/0/name`. Empty
+        /// if the whole element was replaced
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+
+        /// `None` if the value was added
+        pub fn before(&self) -> Option<&'a Value> {
+            self.before
+        }
+
+        /// `None` if the value was removed
+        pub fn after(&self) -> Option<&'a Value> {
+            self.after
+        }
+    }
+
+    fn compare<'a>(
+        path: &mut String,
+        before: Option<&'a Value>,
+        after: Option<&'a Value>,
+        changes: &mut Vec<ValueChange<'a>>,
+    ) {
+        match (before, after) {
+            (Some(Value::Object(before)), Some(Value::Object(after))) => {
+                for (key, value) in before {
+                    nested(path, &escape(key), Some(value), after.get(key), changes);
+                }
+                for (key, value) in after {
+                    if !before.contains_key(key) {
+                        nested(path, &escape(key), None, Some(value), changes);
+                    }
+                }
+            }
+            (Some(Value::Array(before)), Some(Value::Array(after))) => {
+                for i in 0..before.len().max(after.len()) {
+                    nested(path, &i.to_string(), before.get(i), after.get(i), changes);
+                }
+            }
+            (before, after) => {
+                if before != after {
+                    changes.push(ValueChange {
+                        path: path.clone(),
+                        before,
+                        after,
+                    });
+                }
+            }
+        }
+    }
+
+    fn nested<'a>(
+        path: &mut String,
+        token: &str,
+        before: Option<&'a Value>,
+        after: Option<&'a Value>,
+        changes: &mut Vec<ValueChange<'a>>,
+    ) {
+        let len = path.len();
+        let _ = write!(path, "/{}", token);
+        compare(path, before, after, changes);
+        path.truncate(len);
+    }
+
+    fn escape(key: &str) -> String {
+        key.replace('~', "~0").replace('/', "~1")
+    }
+
+    /// state of a channel created by `broadcast_diffs()`
+    pub(crate) struct Diffs<T> {
+        serialize: fn(&T) -> Option<Value>,
+        previous: Option<Arc<Value>>,
+    }
+
+    impl<T: Serialize> Diffs<T> {
+        pub(crate) fn new(value: &T) -> Diffs<T> {
+            let serialize = |value: &T| serde_json::to_value(value).ok();
+            Diffs {
+                serialize,
+                previous: serialize(value).map(Arc::new),
+            }
+        }
+    }
+
+    impl<T> Diffs<T> {
+        /// `None` if the element could not be serialized, now or before the
+        /// mutation
+        pub(crate) fn next(&mut self, value: &T) -> Option<Arc<Diff>> {
+            let after = (self.serialize)(value).map(Arc::new);
+            let before = std::mem::replace(&mut self.previous, after.clone());
+            Some(Arc::new(Diff {
+                before: before?,
+                after: after?,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Wake, Waker};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[derive(Debug)]
+    struct Small(u8);
+
+    impl Guard for Small {
+        fn finish(&mut self) {
+            assert!(self.0 < 10, "value is too large: {}", self.0);
+        }
+    }
+
+    fn poll<F: Future + Unpin>(f: &mut F) -> bool {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        Pin::new(f)
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+    }
+
+    #[test]
+    fn notified_after_mutation() {
+        let mut val = MutGuard::new(Small(0));
+        let mut changed = Box::pin(val.notified());
+        assert!(!poll(&mut changed));
+
+        val.guard().0 += 1;
+        assert!(poll(&mut changed));
+
+        // a future created after the mutation waits for the next one
+        let mut next = Box::pin(val.notified());
+        assert!(!poll(&mut next));
+    }
+
+    #[test]
+    fn not_notified_on_violation() {
+        let mut val = MutGuard::new(Small(0));
+        let mut changed = Box::pin(val.notified());
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            val.guard().0 = 20;
+        }));
+        assert!(res.is_err());
+        assert!(!poll(&mut changed));
+    }
+
+    #[test]
+    fn broadcast() {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        let mut val = MutGuard::new(Small(0));
+        assert!(val.subscribe().is_none());
+
+        let mut first = val.broadcast_changes(2);
+        let mut second = val.subscribe().unwrap();
+
+        let line = line!() + 1;
+        val.guard().0 = 1;
+        let event = first.try_recv().unwrap();
+        assert_eq!(event.sequence(), 1);
+        assert_eq!(event.guard_id(), val.id());
+        assert_eq!(event.location().line(), line);
+        assert!(event.value().is_none());
+
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            val.guard().0 = 20;
+        }));
+        assert!(res.is_err());
+        val.guard().0 = 2;
+        val.guard().0 = 3;
+
+        // the violation sent nothing, and the oldest event was dropped
+        assert_eq!(second.try_recv().unwrap_err(), TryRecvError::Lagged(1));
+        assert_eq!(second.try_recv().unwrap().sequence(), 2);
+        assert_eq!(second.try_recv().unwrap().sequence(), 3);
+        assert_eq!(first.try_recv().unwrap().sequence(), 2);
+        assert_eq!(first.try_recv().unwrap().sequence(), 3);
+        assert_eq!(first.try_recv().unwrap_err(), TryRecvError::Empty);
+
+        drop(val);
+        assert_eq!(first.try_recv().unwrap_err(), TryRecvError::Closed);
+    }
+
+    #[derive(Clone, Debug)]
+    struct Names(Vec<&'static str>);
+
+    impl Guard for Names {
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn broadcast_values() {
+        let mut val = MutGuard::new(Names(Vec::new()));
+        let mut changes = val.broadcast_values(8);
+
+        val.guard().0.push("a");
+        val.guard().0.push("b");
+
+        let first = changes.try_recv().unwrap();
+        let second = changes.try_recv().unwrap();
+        assert_eq!(first.value().unwrap().0, vec!["a"]);
+        assert_eq!(second.shared_value().unwrap().0, vec!["a", "b"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn broadcast_diffs() {
+        use serde::Serialize;
+        use serde_json::json;
+
+        #[derive(Serialize)]
+        struct Team {
+            lead: String,
+            members: Vec<String>,
+        }
+
+        impl Guard for Team {
+            fn finish(&mut self) {
+                assert!(
+                    self.members.contains(&self.lead),
+                    "the lead should be a member"
+                );
+            }
+        }
+
+        let mut team = MutGuard::new(Team {
+            lead: "a".to_string(),
+            members: vec!["a".to_string()],
+        });
+        let mut changes = team.broadcast_diffs(8);
+
+        team.guard().members.push("b".to_string());
+        let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            team.guard().lead = "c".to_string();
+        }));
+        assert!(res.is_err());
+        team.guard().lead = "b".to_string();
+
+        let first = changes.try_recv().unwrap();
+        let diff = first.diff().unwrap();
+        assert_eq!(diff.before()["members"], json!(["a"]));
+        let changed = diff.changes();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].path(), "/members/1");
+        assert_eq!(changed[0].before(), None);
+        assert_eq!(changed[0].after(), Some(&json!("b")));
+
+        // compared to the last broadcast state, not to the violation
+        let second = changes.try_recv().unwrap();
+        let changed = second.diff().unwrap().changes();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].path(), "/lead");
+        assert_eq!(changed[0].before(), Some(&json!("a")));
+        assert_eq!(changed[0].after(), Some(&json!("b")));
+    }
+}
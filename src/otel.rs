@@ -0,0 +1,343 @@
+//! OpenTelemetry traces
+//!
+//! *Note*: this module requires the `opentelemetry` feature.
+//!
+//! guards report to the global tracer provider, set by the application with
+//! `opentelemetry::global::set_tracer_provider()`, through the `mut_guard`
+//! tracer. Without a provider, nothing is recorded.
+//!
+//! Every mutable borrow of a `MutGuard` is a `mut_guard.borrow` span, from
+//! `guard()` until the element was checked, child of the span active when
+//! the borrow was acquired. It has the `TYPE`, `LABEL` and `ID` attributes,
+//! and the location of the borrow as `code.file.path` and
+//! `code.line.number`.
+//! The span is active while the checks run, so spans they create are nested
+//! under it. Once checked, `OUTCOME` is `passed` or `violated`, and a failed
+//! check adds a `mut_guard.violation` event with the panic message and sets
+//! the span's status to an error. Borrows skipped by `sample_every()` have
+//! no outcome.
+//!
+//! With the `persist` feature, the operations of `persist::PersistentMutGuard`
+//! and `persist::JournaledMutGuard` are spans too, with the `TYPE`
+//! attribute: `mut_guard.persist.load`, `mut_guard.persist.store`,
+//! `mut_guard.journal.recover`, `mut_guard.journal.append` and
+//! `mut_guard.journal.checkpoint`. Their status is an error if they fail.
+use std::any::type_name;
+use std::panic::{self, AssertUnwindSafe, Location};
+
+use opentelemetry::trace::{get_active_span, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, ContextGuard, KeyValue};
+
+use super::violation::Violation;
+use super::GuardId;
+
+/// type name of the guarded element
+pub const TYPE: &str = "mut_guard.type";
+/// label set with `MutGuard::set_label()`
+pub const LABEL: &str = "mut_guard.label";
+/// `MutGuard::id()`
+pub const ID: &str = "mut_guard.id";
+/// result of the checks of a borrow: `passed` or `violated`
+pub const OUTCOME: &str = "mut_guard.outcome";
+
+const TRACER: &str = "mut_guard";
+
+/// context holding the span of a borrow, until its checks ran
+pub(crate) struct BorrowSpan(Context);
+
+impl BorrowSpan {
+    pub(crate) fn start<T: ?Sized>(
+        id: GuardId,
+        label: Option<&str>,
+        location: &'static Location<'static>,
+    ) -> BorrowSpan {
+        let mut attributes = vec![
+            KeyValue::new(TYPE, type_name::<T>()),
+            KeyValue::new(ID, id.as_u64() as i64),
+            KeyValue::new("code.file.path", location.file()),
+            KeyValue::new("code.line.number", i64::from(location.line())),
+        ];
+        if let Some(label) = label {
+            attributes.push(KeyValue::new(LABEL, label.to_string()));
+        }
+
+        let span = global::tracer(TRACER)
+            .span_builder("mut_guard.borrow")
+            .with_attributes(attributes)
+            .start(&global::tracer(TRACER));
+        BorrowSpan(Context::current_with_span(span))
+    }
+
+    /// makes the span active, and ends it when the returned guard is
+    /// dropped
+    pub(crate) fn enter(self) -> Active {
+        Active {
+            _attached: self.0.clone().attach(),
+            context: self.0,
+        }
+    }
+}
+
+pub(crate) struct Active {
+    context: Context,
+    _attached: ContextGuard,
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.context.span().end();
+    }
+}
+
+/// runs the checks, recording their outcome on the active span
+pub(crate) fn check<T: ?Sized, F: FnOnce(&mut T)>(inner: &mut T, check: F) {
+    match panic::catch_unwind(AssertUnwindSafe(|| check(inner))) {
+        Ok(()) => get_active_span(|span| span.set_attribute(KeyValue::new(OUTCOME, "passed"))),
+        Err(payload) => {
+            let violation = Violation::from_panic(payload.as_ref());
+            get_active_span(|span| {
+                span.set_attribute(KeyValue::new(OUTCOME, "violated"));
+                span.add_event(
+                    "mut_guard.violation",
+                    vec![KeyValue::new(
+                        "exception.message",
+                        violation.message().to_string(),
+                    )],
+                );
+                span.set_status(Status::error(violation.message().to_string()));
+            });
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+/// runs `f` in a span named `name`, with an error status if it fails
+#[cfg(feature = "persist")]
+pub(crate) fn traced<T: ?Sized, R, E: std::fmt::Display, F: FnOnce() -> Result<R, E>>(
+    name: &'static str,
+    f: F,
+) -> Result<R, E> {
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(vec![KeyValue::new(TYPE, type_name::<T>())])
+        .start(&tracer);
+    let _active = BorrowSpan(Context::current_with_span(span)).enter();
+
+    let res = f();
+    if let Err(ref e) = res {
+        get_active_span(|span| span.set_status(Status::error(e.to_string())));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use opentelemetry::trace::{Span, SpanBuilder, SpanContext, TracerProvider};
+    use opentelemetry::InstrumentationScope;
+    use std::borrow::Cow;
+    use std::sync::{Arc, Mutex, Once};
+    use std::time::SystemTime;
+
+    #[derive(Clone, Debug, Default)]
+    struct Recorded {
+        name: String,
+        attributes: Vec<KeyValue>,
+        events: Vec<String>,
+        status: Option<Status>,
+    }
+
+    impl Recorded {
+        fn attribute(&self, key: &str) -> Option<String> {
+            self.attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        }
+    }
+
+    type Spans = Arc<Mutex<Vec<Recorded>>>;
+
+    struct Recording {
+        span: Recorded,
+        context: SpanContext,
+        spans: Spans,
+        ended: bool,
+    }
+
+    impl Span for Recording {
+        fn add_event_with_timestamp<N: Into<Cow<'static, str>>>(
+            &mut self,
+            name: N,
+            _timestamp: SystemTime,
+            _attributes: Vec<KeyValue>,
+        ) {
+            self.span.events.push(name.into().into_owned());
+        }
+
+        fn span_context(&self) -> &SpanContext {
+            &self.context
+        }
+
+        fn is_recording(&self) -> bool {
+            !self.ended
+        }
+
+        fn set_attribute(&mut self, attribute: KeyValue) {
+            self.span.attributes.push(attribute);
+        }
+
+        fn set_status(&mut self, status: Status) {
+            self.span.status = Some(status);
+        }
+
+        fn update_name<N: Into<Cow<'static, str>>>(&mut self, name: N) {
+            self.span.name = name.into().into_owned();
+        }
+
+        fn add_link(&mut self, _context: SpanContext, _attributes: Vec<KeyValue>) {}
+
+        fn end_with_timestamp(&mut self, _timestamp: SystemTime) {
+            if !self.ended {
+                self.ended = true;
+                self.spans.lock().unwrap().push(self.span.clone());
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct Recorder(Spans);
+
+    impl Tracer for Recorder {
+        type Span = Recording;
+
+        fn build_with_context(&self, builder: SpanBuilder, _parent: &Context) -> Recording {
+            Recording {
+                span: Recorded {
+                    name: builder.name.into_owned(),
+                    attributes: builder.attributes.unwrap_or_default(),
+                    ..Recorded::default()
+                },
+                context: SpanContext::empty_context(),
+                spans: self.0.clone(),
+                ended: false,
+            }
+        }
+    }
+
+    impl TracerProvider for Recorder {
+        type Tracer = Recorder;
+
+        fn tracer_with_scope(&self, _scope: InstrumentationScope) -> Recorder {
+            self.clone()
+        }
+    }
+
+    /// the provider is global, so each test filters the spans by label or
+    /// type
+    fn recorded() -> Spans {
+        static INIT: Once = Once::new();
+        static SPANS: Mutex<Option<Spans>> = Mutex::new(None);
+        INIT.call_once(|| {
+            let spans = Spans::default();
+            global::set_tracer_provider(Recorder(spans.clone()));
+            *SPANS.lock().unwrap() = Some(spans);
+        });
+        SPANS.lock().unwrap().clone().unwrap()
+    }
+
+    #[derive(Debug)]
+    struct Small(u32);
+
+    impl Guard for Small {
+        fn finish(&mut self) {
+            assert!(self.0 < 3, "value is too large: {}", self.0);
+        }
+    }
+
+    #[test]
+    fn borrow_spans() {
+        let spans = recorded();
+        let mut val = MutGuard::new(Small(0));
+        val.set_label("otel-borrow");
+
+        let line = line!() + 1;
+        val.guard().0 += 2;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| val.guard().0 += 1));
+        assert!(res.is_err());
+
+        let spans: Vec<_> = spans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|span| span.attribute(LABEL).as_deref() == Some("otel-borrow"))
+            .cloned()
+            .collect();
+        assert_eq!(spans.len(), 2);
+
+        let passed = &spans[0];
+        assert_eq!(passed.name, "mut_guard.borrow");
+        assert_eq!(
+            passed.attribute(TYPE),
+            Some(type_name::<Small>().to_string())
+        );
+        assert_eq!(passed.attribute("code.line.number"), Some(line.to_string()));
+        assert_eq!(passed.attribute(ID), Some(val.id().to_string()));
+        assert_eq!(passed.attribute(OUTCOME).as_deref(), Some("passed"));
+        assert!(passed.status.is_none());
+
+        let violated = &spans[1];
+        assert_eq!(violated.attribute(OUTCOME).as_deref(), Some("violated"));
+        assert_eq!(violated.events, vec!["mut_guard.violation"]);
+        assert_eq!(
+            violated.status,
+            Some(Status::error("value is too large: 3"))
+        );
+    }
+
+    #[cfg(feature = "persist")]
+    #[test]
+    fn persist_spans() {
+        use super::super::persist::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Serialize, Deserialize, Debug)]
+        struct Traced(u32);
+
+        impl Guard for Traced {
+            fn finish(&mut self) {}
+        }
+
+        let spans = recorded();
+        let path = std::env::temp_dir().join(format!("mutguard-otel-{}.json", std::process::id()));
+        let mut val = PersistentMutGuard::create(&path, Traced(0)).unwrap();
+        val.guard().unwrap().0 += 1;
+        std::fs::write(&path, b"{").unwrap();
+        assert!(val.refresh().is_err());
+
+        let spans: Vec<_> = spans
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|span| span.attribute(TYPE).is_some_and(|t| t.ends_with("Traced")))
+            .cloned()
+            .collect();
+        let names: Vec<_> = spans.iter().map(|span| span.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "mut_guard.persist.store",
+                "mut_guard.persist.load",
+                "mut_guard.persist.store",
+                "mut_guard.persist.load",
+            ]
+        );
+        assert!(spans[..3].iter().all(|span| span.status.is_none()));
+        assert!(matches!(spans[3].status, Some(Status::Error { .. })));
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(path.with_extension("json.lock"));
+    }
+}
@@ -0,0 +1,158 @@
+//! Owned borrows
+//!
+//! *Note*: this module requires the `tokio` feature.
+//!
+//! `MutGuardBorrow` borrows the `MutGuard`, so it cannot be stored in a
+//! struct or held by a task across an `.await`. When the guard is shared
+//! through an `Arc<tokio::sync::Mutex<MutGuard<T>>>`, `MutGuard::guard_owned()`
+//! turns the `OwnedMutexGuard` of that mutex into an `OwnedMutGuardBorrow`,
+//! which is `'static`, and `Send` when `T` is. When it is dropped, the
+//! element is checked like at the end of `guard()`, then the mutex is
+//! unlocked. Unlike `std::sync::Mutex`, the tokio mutex is not poisoned by a
+//! failed check.
+//!
+//! ```rust,edition2021
+//! # extern crate mut_guard;
+//! # extern crate tokio;
+//! # use mut_guard::*;
+//! use std::sync::Arc;
+//! use tokio::sync::Mutex;
+//!
+//! #[derive(Debug, Default)]
+//! struct Inventory {
+//!   reserved: u32,
+//!   stock: u32,
+//! }
+//!
+//! impl Guard for Inventory {
+//!   fn finish(&mut self) {
+//!     assert!(self.reserved <= self.stock, "reserved more than the stock");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+//! # rt.block_on(async {
+//! let inventory = Arc::new(Mutex::new(MutGuard::new(Inventory::default())));
+//!
+//! let restock = tokio::spawn({
+//!   let inventory = inventory.clone();
+//!   async move {
+//!     let mut borrow = MutGuard::guard_owned(inventory.lock_owned().await);
+//!     borrow.stock += 10;
+//!     // the borrow is held across an await point
+//!     tokio::task::yield_now().await;
+//!     borrow.reserved += 4;
+//!   }
+//! });
+//!
+//! restock.await.unwrap();
+//! assert_eq!(inventory.lock().await.reserved, 4);
+//! # });
+//! # }
+//! ```
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+
+use tokio::sync::OwnedMutexGuard;
+
+use super::{Guard, MutGuard, MutGuardBorrow};
+
+impl<T: Guard + 'static> MutGuard<T> {
+    /// mutable access to the element of a locked `Arc<Mutex<MutGuard<T>>>`,
+    /// keeping the lock until the returned borrow is dropped
+    #[track_caller]
+    pub fn guard_owned(lock: OwnedMutexGuard<MutGuard<T>>) -> OwnedMutGuardBorrow<T> {
+        OwnedMutGuardBorrow::new(lock, Location::caller())
+    }
+}
+
+/// Structure returned by `MutGuard::guard_owned()`. When this is dropped,
+/// the element is checked, then the mutex is unlocked
+pub struct OwnedMutGuardBorrow<T: Guard + 'static> {
+    // declared first, so it is dropped, and the element checked, before
+    // the lock is released, even if the checks panic
+    borrow: MutGuardBorrow<'static, T>,
+    _lock: OwnedMutexGuard<MutGuard<T>>,
+}
+
+impl<T: Guard + 'static> OwnedMutGuardBorrow<T> {
+    fn new(
+        mut lock: OwnedMutexGuard<MutGuard<T>>,
+        location: &'static Location<'static>,
+    ) -> OwnedMutGuardBorrow<T> {
+        let guard: *mut MutGuard<T> = &mut *lock;
+        // the `MutGuard` lives in the allocation of the `Arc` held by the
+        // lock, so it does not move with the `OwnedMutGuardBorrow`, and the
+        // lock gives exclusive access to it until it is dropped, after the
+        // borrow. The lock is not used to access the `MutGuard` meanwhile
+        let borrow = unsafe { &mut *guard }.borrow_at(location);
+        OwnedMutGuardBorrow {
+            borrow,
+            _lock: lock,
+        }
+    }
+}
+
+impl<T: Guard + 'static> Deref for OwnedMutGuardBorrow<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.borrow
+    }
+}
+
+impl<T: Guard + 'static> DerefMut for OwnedMutGuardBorrow<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.borrow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[derive(Debug)]
+    struct Window {
+        start: u32,
+        end: u32,
+    }
+
+    impl Guard for Window {
+        fn finish(&mut self) {
+            assert!(self.start <= self.end, "empty window");
+        }
+    }
+
+    struct Session {
+        window: OwnedMutGuardBorrow<Window>,
+    }
+
+    fn send<T: Send + 'static>(value: T) -> T {
+        value
+    }
+
+    #[test]
+    fn owned_borrow() {
+        let window = Arc::new(Mutex::new(MutGuard::new(Window { start: 0, end: 4 })));
+
+        let mut session = send(Session {
+            window: MutGuard::guard_owned(window.clone().try_lock_owned().unwrap()),
+        });
+        session.window.end = 8;
+        assert!(window.try_lock().is_err());
+        drop(session);
+        assert_eq!(window.try_lock().unwrap().end, 8);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut borrow = MutGuard::guard_owned(window.clone().try_lock_owned().unwrap());
+            borrow.start = 10;
+        }));
+        assert!(res.is_err());
+        // unlocked while unwinding
+        window.try_lock().unwrap().guard().start = 2;
+    }
+}
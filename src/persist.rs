@@ -0,0 +1,1245 @@
+//! Guarded state persisted to a file shared between processes
+//!
+//! *Note*: this module requires the `persist` feature.
+//!
+//! a `PersistentMutGuard` keeps its element in a JSON snapshot. Every
+//! mutable borrow takes an exclusive advisory lock, loads the latest
+//! snapshot (another process may have written it), gives access to the
+//! element, checks it, then writes it back atomically before releasing the
+//! lock. Processes using the same file never lose each other's changes.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use mut_guard::*;
+//! # use mut_guard::persist::*;
+//! #
+//! #[derive(Serialize, Deserialize, Debug)]
+//! struct Counter {
+//!   runs: u32,
+//! }
+//!
+//! impl Guard for Counter {
+//!   fn finish(&mut self) {
+//!     assert!(self.runs < 1000, "too many runs");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let path = std::env::temp_dir().join(format!("mutguard-doc-{}.json", std::process::id()));
+//! let mut counter = PersistentMutGuard::create(&path, Counter { runs: 0 }).unwrap();
+//! counter.guard().unwrap().runs += 1;
+//!
+//! // as seen by another process
+//! let other = PersistentMutGuard::<Counter>::open(&path).unwrap();
+//! assert_eq!(other.runs, 1);
+//! # std::fs::remove_file(&path).unwrap();
+//! # std::fs::remove_file(path.with_extension("json.lock")).unwrap();
+//! # }
+//! ```
+//!
+//! Storing the whole snapshot after every mutation is slow for large
+//! elements. A `JournaledMutGuard` is mutated through `command::Command`s
+//! instead: each one is checked, then appended to a write-ahead log before
+//! the mutation is acknowledged, and `checkpoint()` folds the log into a new
+//! snapshot. Loading the element replays the log over the last snapshot, and
+//! discards an entry left incomplete by a crash. With `compact_after()` or
+//! `compact_after_bytes()`, checkpoints happen automatically once the log
+//! grows past a threshold.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use mut_guard::*;
+//! # use mut_guard::command::Command;
+//! # use mut_guard::persist::*;
+//! #
+//! #[derive(Serialize, Deserialize, Debug)]
+//! struct Counter {
+//!   runs: u32,
+//! }
+//!
+//! impl Guard for Counter {
+//!   fn finish(&mut self) {
+//!     assert!(self.runs < 1000, "too many runs");
+//!   }
+//! }
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Run;
+//!
+//! impl Command<Counter> for Run {
+//!   fn apply(&self, target: &mut Counter) {
+//!     target.runs += 1;
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let path = std::env::temp_dir().join(format!("mutguard-doc-wal-{}.json", std::process::id()));
+//! let mut counter = JournaledMutGuard::create(&path, Counter { runs: 0 }).unwrap();
+//! counter.compact_after(100);
+//! counter.apply(Run).unwrap();
+//! counter.apply(Run).unwrap();
+//!
+//! // replayed from the log
+//! let other = JournaledMutGuard::<Counter, Run>::open(&path).unwrap();
+//! assert_eq!(other.runs, 2);
+//! assert_eq!(other.sequence(), 2);
+//! assert_eq!(other.log_entries(), 2);
+//! # std::fs::remove_file(&path).unwrap();
+//! # std::fs::remove_file(path.with_extension("json.lock")).unwrap();
+//! # std::fs::remove_file(path.with_extension("json.wal")).unwrap();
+//! # }
+//! ```
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut, Drop};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::command::Command;
+use super::hold::Unwinding;
+#[cfg(feature = "opentelemetry")]
+use super::otel::traced;
+use super::violation::Violation;
+use super::{run_guard, Guard, ARMED};
+
+/// runs `f` in a span with the `opentelemetry` feature
+#[cfg(not(feature = "opentelemetry"))]
+#[allow(clippy::extra_unused_type_parameters)]
+fn traced<T: ?Sized, R, E, F: FnOnce() -> Result<R, E>>(_name: &'static str, f: F) -> Result<R, E> {
+    f()
+}
+
+/// where a `PersistentMutGuard` keeps its snapshot
+pub trait Storage {
+    /// held while the storage is locked, released when dropped
+    type Lock;
+
+    /// exclusive lock, taken for mutations
+    fn lock(&self) -> io::Result<Self::Lock>;
+
+    /// shared lock, taken to load the snapshot
+    fn lock_shared(&self) -> io::Result<Self::Lock>;
+
+    /// returns `None` if nothing was stored yet
+    fn load(&self) -> io::Result<Option<Vec<u8>>>;
+
+    /// replaces the snapshot. Readers must see either the old or the new
+    /// one, never a partial write
+    fn store(&self, snapshot: &[u8]) -> io::Result<()>;
+}
+
+/// `Storage` with a write-ahead log, used by `JournaledMutGuard`. Entries
+/// are only appended or read while the storage is locked
+pub trait Journal: Storage {
+    /// appends `entry` to the log. It must be durable once this returns
+    fn append(&self, entry: &[u8]) -> io::Result<()>;
+
+    /// returns the entries appended since the last `truncate()`, in order.
+    /// An entry whose `append()` was interrupted is left out
+    fn entries(&self) -> io::Result<Vec<Vec<u8>>>;
+
+    /// removes every entry
+    fn truncate(&self) -> io::Result<()>;
+}
+
+/// stores the snapshot in a file, and locks a `.lock` file next to it
+///
+/// the snapshot is written to a temporary file that is then renamed, so it
+/// cannot be left half written. The log of a `JournaledMutGuard` is kept
+/// in a `.wal` file, each entry prefixed by its length
+#[derive(Clone, Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    lock_path: PathBuf,
+    journal_path: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileStorage {
+        let path = path.as_ref().to_path_buf();
+        let lock_path = with_suffix(&path, ".lock");
+        let journal_path = with_suffix(&path, ".wal");
+        FileStorage {
+            path,
+            lock_path,
+            journal_path,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open_lock_file(&self) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_path)
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+impl Storage for FileStorage {
+    type Lock = File;
+
+    fn lock(&self) -> io::Result<File> {
+        let file = self.open_lock_file()?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    fn lock_shared(&self) -> io::Result<File> {
+        let file = self.open_lock_file()?;
+        file.lock_shared()?;
+        Ok(file)
+    }
+
+    fn load(&self) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(&self.path) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn store(&self, snapshot: &[u8]) -> io::Result<()> {
+        let tmp = with_suffix(&self.path, ".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(snapshot)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+impl Journal for FileStorage {
+    fn append(&self, entry: &[u8]) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&self.journal_path)?;
+
+        // drops an entry left incomplete by a crash, instead of writing
+        // after it
+        let log = fs::read(&self.journal_path)?;
+        let (_, complete) = split_entries(&log);
+        if complete != log.len() {
+            file.set_len(complete as u64)?;
+        }
+
+        let mut frame = Vec::with_capacity(4 + entry.len());
+        frame.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        frame.extend_from_slice(entry);
+        io::Seek::seek(&mut file, io::SeekFrom::Start(complete as u64))?;
+        file.write_all(&frame)?;
+        file.sync_data()
+    }
+
+    fn entries(&self) -> io::Result<Vec<Vec<u8>>> {
+        match fs::read(&self.journal_path) {
+            Ok(log) => Ok(split_entries(&log).0),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn truncate(&self) -> io::Result<()> {
+        match File::options().write(true).open(&self.journal_path) {
+            Ok(file) => {
+                file.set_len(0)?;
+                file.sync_data()
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// returns the complete entries of a log, and the length they cover
+fn split_entries(mut log: &[u8]) -> (Vec<Vec<u8>>, usize) {
+    let mut entries = Vec::new();
+    let mut complete = 0;
+
+    while log.len() >= 4 {
+        let len = u32::from_le_bytes([log[0], log[1], log[2], log[3]]) as usize;
+        if log.len() - 4 < len {
+            break;
+        }
+        entries.push(log[4..4 + len].to_vec());
+        log = &log[4 + len..];
+        complete += 4 + len;
+    }
+
+    (entries, complete)
+}
+
+/// error while loading or storing a persisted element
+#[derive(Debug)]
+pub enum PersistError {
+    Io(io::Error),
+    /// the snapshot could not be serialized or deserialized
+    Format(serde_json::Error),
+    /// the loaded element did not pass the checks
+    Violation(Violation),
+    /// `PersistentMutGuard::open()` found no snapshot
+    Missing,
+    /// the snapshot kept being modified by other writers, see
+    /// `remote::AsyncPersistentMutGuard::max_retries()`
+    Conflict,
+    /// the journal skips from the entry before `expected` to `found`
+    Gap {
+        expected: u64,
+        found: u64,
+    },
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PersistError::Io(ref e) => write!(f, "storage error: {}", e),
+            PersistError::Format(ref e) => write!(f, "invalid snapshot: {}", e),
+            PersistError::Violation(ref v) => write!(f, "invalid snapshot: {}", v),
+            PersistError::Missing => write!(f, "no snapshot was stored"),
+            PersistError::Conflict => write!(f, "the snapshot was modified concurrently"),
+            PersistError::Gap { expected, found } => write!(
+                f,
+                "the journal is missing entry {}, found entry {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl Error for PersistError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PersistError::Io(ref e) => Some(e),
+            PersistError::Format(ref e) => Some(e),
+            PersistError::Violation(ref v) => Some(v),
+            PersistError::Missing | PersistError::Conflict | PersistError::Gap { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for PersistError {
+    fn from(e: io::Error) -> PersistError {
+        PersistError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PersistError {
+    fn from(e: serde_json::Error) -> PersistError {
+        PersistError::Format(e)
+    }
+}
+
+/// guarded element persisted in a `Storage`, by default a file
+///
+/// the element can be read through `Deref`, as of the last load or
+/// mutation. `refresh()` loads changes made by other processes
+pub struct PersistentMutGuard<T, S: Storage = FileStorage> {
+    inner: T,
+    storage: S,
+}
+
+impl<T: Guard + Serialize + DeserializeOwned> PersistentMutGuard<T> {
+    /// writes `inner` to the file at `path`, replacing its content
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        inner: T,
+    ) -> Result<PersistentMutGuard<T>, PersistError> {
+        PersistentMutGuard::create_with(FileStorage::new(path), inner)
+    }
+
+    /// loads and checks the element stored in the file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PersistentMutGuard<T>, PersistError> {
+        PersistentMutGuard::open_with(FileStorage::new(path))
+    }
+
+    /// loads the element stored in the file at `path`, or stores the one
+    /// returned by `init` if the file does not exist
+    pub fn load_or_init<P, F>(path: P, init: F) -> Result<PersistentMutGuard<T>, PersistError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce() -> T,
+    {
+        PersistentMutGuard::load_or_init_with(FileStorage::new(path), init)
+    }
+}
+
+impl<T: Guard + Serialize + DeserializeOwned, S: Storage> PersistentMutGuard<T, S> {
+    /// checks `inner`, then stores it, replacing what `storage` contained
+    pub fn create_with(storage: S, mut inner: T) -> Result<PersistentMutGuard<T, S>, PersistError> {
+        validate(&mut inner)?;
+        let _lock = storage.lock()?;
+        store(&storage, &inner)?;
+        drop(_lock);
+
+        Ok(PersistentMutGuard { inner, storage })
+    }
+
+    /// loads and checks the element stored in `storage`
+    pub fn open_with(storage: S) -> Result<PersistentMutGuard<T, S>, PersistError> {
+        let inner = {
+            let _lock = storage.lock_shared()?;
+            load(&storage)?.ok_or(PersistError::Missing)?
+        };
+
+        Ok(PersistentMutGuard { inner, storage })
+    }
+
+    /// loads and checks the element stored in `storage`, or checks and
+    /// stores the one returned by `init` if nothing was stored. The storage
+    /// stays locked in between, so concurrent callers initialize it once
+    ///
+    /// A corrupt snapshot is left untouched and returned as an error:
+    /// `PersistError::Format` with the line and column of the syntax or type
+    /// error, or `PersistError::Violation` with the failed check
+    pub fn load_or_init_with<F>(
+        storage: S,
+        init: F,
+    ) -> Result<PersistentMutGuard<T, S>, PersistError>
+    where
+        F: FnOnce() -> T,
+    {
+        let inner = {
+            let _lock = storage.lock()?;
+            match load(&storage)? {
+                Some(inner) => inner,
+                None => {
+                    let mut inner = init();
+                    validate(&mut inner)?;
+                    store(&storage, &inner)?;
+                    inner
+                }
+            }
+        };
+
+        Ok(PersistentMutGuard { inner, storage })
+    }
+
+    /// loads the latest snapshot, which other processes may have written
+    pub fn refresh(&mut self) -> Result<(), PersistError> {
+        let _lock = self.storage.lock_shared()?;
+        if let Some(inner) = load(&self.storage)? {
+            self.inner = inner;
+        }
+        Ok(())
+    }
+
+    /// locks the storage, loads the latest snapshot and gives mutable access
+    /// to it. When the returned borrow is dropped, the element is checked,
+    /// stored and the lock is released. Use `PersistentBorrow::commit()` to
+    /// handle storage errors, otherwise they panic
+    pub fn guard(&mut self) -> Result<PersistentBorrow<'_, T, S>, PersistError> {
+        let lock = self.storage.lock()?;
+        if let Some(inner) = load(&self.storage)? {
+            self.inner = inner;
+        }
+
+        Ok(PersistentBorrow {
+            guard: self,
+            lock: Some(lock),
+            unwinding: Unwinding::start(),
+        })
+    }
+
+    /// applies `f` through `guard()`, and commits the change
+    pub fn update<R, F>(&mut self, f: F) -> Result<R, PersistError>
+    where
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut borrow = self.guard()?;
+        let res = f(&mut borrow);
+        borrow.commit()?;
+        Ok(res)
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// returns the element, consuming the PersistentMutGuard
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, S: Storage> Deref for PersistentMutGuard<T, S> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// decodes, fixes up and checks a snapshot
+fn load<T, S>(storage: &S) -> Result<Option<T>, PersistError>
+where
+    T: Guard + DeserializeOwned,
+    S: Storage,
+{
+    traced::<T, _, _, _>("mut_guard.persist.load", || match storage.load()? {
+        None => Ok(None),
+        Some(snapshot) => {
+            let mut inner: T = serde_json::from_slice(&snapshot)?;
+            validate(&mut inner)?;
+            Ok(Some(inner))
+        }
+    })
+}
+
+fn store<T: Serialize, S: Storage>(storage: &S, inner: &T) -> Result<(), PersistError> {
+    traced::<T, _, _, _>("mut_guard.persist.store", || {
+        storage.store(&serde_json::to_vec(inner)?)?;
+        Ok(())
+    })
+}
+
+/// like `run_guard()`, but a failed check is returned instead of panicking
+pub(crate) fn validate<T: Guard>(inner: &mut T) -> Result<(), PersistError> {
+    inner.normalize();
+    if ARMED {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            inner.finish();
+            inner.finish_slow();
+        }))
+        .map_err(|payload| PersistError::Violation(Violation::from_panic(payload.as_ref())))?;
+    }
+    Ok(())
+}
+
+/// mutable borrow of a `PersistentMutGuard`'s element, holding the storage
+/// lock
+pub struct PersistentBorrow<'a, T: 'a + Guard + Serialize, S: 'a + Storage> {
+    guard: &'a mut PersistentMutGuard<T, S>,
+    lock: Option<S::Lock>,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Guard + Serialize, S: Storage> PersistentBorrow<'a, T, S> {
+    /// checks and stores the element now, returning storage errors. A failed
+    /// check panics, like with `MutGuard`, and nothing is stored
+    pub fn commit(mut self) -> Result<(), PersistError> {
+        self.store()
+    }
+
+    fn store(&mut self) -> Result<(), PersistError> {
+        let lock = match self.lock.take() {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+
+        run_guard(&mut self.guard.inner);
+        store(&self.guard.storage, &self.guard.inner)?;
+        drop(lock);
+        Ok(())
+    }
+}
+
+impl<'a, T: Guard + Serialize, S: Storage> Deref for PersistentBorrow<'a, T, S> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard.inner
+    }
+}
+
+impl<'a, T: Guard + Serialize, S: Storage> DerefMut for PersistentBorrow<'a, T, S> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard.inner
+    }
+}
+
+impl<'a, T: Guard + Serialize, S: Storage> Drop for PersistentBorrow<'a, T, S> {
+    fn drop(&mut self) {
+        // a change interrupted by a panic may be incomplete, it is not
+        // stored. The next borrow reloads the stored element
+        if self.unwinding.interrupted() {
+            return;
+        }
+
+        if let Err(e) = self.store() {
+            panic!("could not persist the element: {}", e);
+        }
+    }
+}
+
+/// snapshot of a `JournaledMutGuard`, including the log entries up to
+/// `sequence`
+#[derive(Deserialize)]
+struct Checkpoint<T> {
+    sequence: u64,
+    value: T,
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a, T: 'a> {
+    sequence: u64,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Entry<C> {
+    sequence: u64,
+    command: C,
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a, C: 'a> {
+    sequence: u64,
+    command: &'a C,
+}
+
+/// guarded element persisted in a `Journal`, mutated with commands that are
+/// appended to a write-ahead log
+///
+/// the element can be read through `Deref`, as of the last load or
+/// command. The snapshot and log it writes are not compatible with
+/// `PersistentMutGuard`
+pub struct JournaledMutGuard<T, C, S: Journal = FileStorage> {
+    inner: T,
+    storage: S,
+    /// sequence number of the last entry applied to `inner`
+    sequence: u64,
+    log: LogSize,
+    compaction: Compaction,
+    command: PhantomData<fn(C)>,
+}
+
+/// size of the log, as of the last load
+#[derive(Clone, Copy, Debug, Default)]
+struct LogSize {
+    entries: usize,
+    bytes: usize,
+}
+
+/// thresholds set by `compact_after()` and `compact_after_bytes()`
+#[derive(Clone, Copy, Debug, Default)]
+struct Compaction {
+    entries: Option<usize>,
+    bytes: Option<usize>,
+}
+
+impl Compaction {
+    fn due(&self, log: &LogSize) -> bool {
+        self.entries.is_some_and(|entries| log.entries >= entries)
+            || self.bytes.is_some_and(|bytes| log.bytes >= bytes)
+    }
+}
+
+impl<T, C> JournaledMutGuard<T, C>
+where
+    T: Guard + Serialize + DeserializeOwned,
+    C: Command<T> + Serialize + DeserializeOwned,
+{
+    /// writes `inner` to the file at `path`, replacing its content and
+    /// removing its log
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        inner: T,
+    ) -> Result<JournaledMutGuard<T, C>, PersistError> {
+        JournaledMutGuard::create_with(FileStorage::new(path), inner)
+    }
+
+    /// loads the snapshot stored in the file at `path`, and replays its log
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<JournaledMutGuard<T, C>, PersistError> {
+        JournaledMutGuard::open_with(FileStorage::new(path))
+    }
+}
+
+impl<T, C, S> JournaledMutGuard<T, C, S>
+where
+    T: Guard + Serialize + DeserializeOwned,
+    C: Command<T> + Serialize + DeserializeOwned,
+    S: Journal,
+{
+    /// checks `inner`, then stores it, replacing what `storage` contained
+    pub fn create_with(
+        storage: S,
+        mut inner: T,
+    ) -> Result<JournaledMutGuard<T, C, S>, PersistError> {
+        validate(&mut inner)?;
+        let _lock = storage.lock()?;
+        // in this order, a crash in between cannot replay the old log over
+        // the new snapshot
+        storage.truncate()?;
+        store_checkpoint(&storage, &inner, 0)?;
+        drop(_lock);
+
+        Ok(JournaledMutGuard {
+            inner,
+            storage,
+            sequence: 0,
+            log: LogSize::default(),
+            compaction: Compaction::default(),
+            command: PhantomData,
+        })
+    }
+
+    /// loads and checks the snapshot stored in `storage`, then replays the
+    /// commands of its log, checking the element after each one
+    pub fn open_with(storage: S) -> Result<JournaledMutGuard<T, C, S>, PersistError> {
+        let (inner, sequence, log) = {
+            let _lock = storage.lock_shared()?;
+            recover::<T, C, S>(&storage)?
+        };
+
+        Ok(JournaledMutGuard {
+            inner,
+            storage,
+            sequence,
+            log,
+            compaction: Compaction::default(),
+            command: PhantomData,
+        })
+    }
+
+    /// loads the latest snapshot and log, which other processes may have
+    /// written
+    pub fn refresh(&mut self) -> Result<(), PersistError> {
+        let _lock = self.storage.lock_shared()?;
+        self.reload()
+    }
+
+    fn reload(&mut self) -> Result<(), PersistError> {
+        let (inner, sequence, log) = recover::<T, C, S>(&self.storage)?;
+        self.inner = inner;
+        self.sequence = sequence;
+        self.log = log;
+        Ok(())
+    }
+
+    /// makes `apply()` fold the log into a new snapshot once it holds
+    /// `entries` entries. Without a threshold, the log is only emptied by
+    /// `checkpoint()`
+    pub fn compact_after(&mut self, entries: usize) {
+        self.compaction.entries = Some(entries);
+    }
+
+    /// makes `apply()` fold the log into a new snapshot once its entries
+    /// take `bytes` bytes
+    pub fn compact_after_bytes(&mut self, bytes: usize) {
+        self.compaction.bytes = Some(bytes);
+    }
+
+    /// removes the thresholds set by `compact_after()` and
+    /// `compact_after_bytes()`
+    pub fn without_compaction(&mut self) {
+        self.compaction = Compaction::default();
+    }
+
+    /// locks the storage, loads the latest state, applies `command` and
+    /// checks the element, then appends `command` to the log. The mutation
+    /// is durable once this returns `Ok`
+    ///
+    /// If a compaction threshold is reached, a checkpoint is stored too. As
+    /// the command is already logged, failing to store it does not make
+    /// `apply()` fail: the next call tries again
+    ///
+    /// A failed check panics, like with `MutGuard`, and nothing is written
+    pub fn apply(&mut self, command: C) -> Result<(), PersistError> {
+        traced::<T, _, _, _>("mut_guard.journal.append", || self.append(command))
+    }
+
+    fn append(&mut self, command: C) -> Result<(), PersistError> {
+        let _lock = self.storage.lock()?;
+        self.reload()?;
+
+        command.apply(&mut self.inner);
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| run_guard(&mut self.inner))) {
+            // the element does not match the storage anymore
+            let _ = self.reload();
+            panic::resume_unwind(payload);
+        }
+
+        let entry = EntryRef {
+            sequence: self.sequence + 1,
+            command: &command,
+        };
+        let entry = serde_json::to_vec(&entry)?;
+        self.storage.append(&entry)?;
+        self.sequence += 1;
+        self.log.entries += 1;
+        self.log.bytes += entry.len();
+
+        if self.compaction.due(&self.log) {
+            let _ = self.compact();
+        }
+        Ok(())
+    }
+
+    /// stores a snapshot of the latest state, then empties the log
+    pub fn checkpoint(&mut self) -> Result<(), PersistError> {
+        let _lock = self.storage.lock()?;
+        self.reload()?;
+        self.compact()
+    }
+
+    /// must be called with the exclusive lock held
+    fn compact(&mut self) -> Result<(), PersistError> {
+        traced::<T, _, PersistError, _>("mut_guard.journal.checkpoint", || {
+            store_checkpoint(&self.storage, &self.inner, self.sequence)?;
+            // entries up to `sequence` are skipped if this is interrupted
+            self.storage.truncate()?;
+            Ok(())
+        })?;
+        self.log = LogSize::default();
+        Ok(())
+    }
+
+    /// number of commands applied since the element was created
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// number of entries in the log, as of the last load or command
+    pub fn log_entries(&self) -> usize {
+        self.log.entries
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// returns the element, consuming the JournaledMutGuard
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, C, S: Journal> Deref for JournaledMutGuard<T, C, S> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+fn store_checkpoint<T: Serialize, S: Storage>(
+    storage: &S,
+    inner: &T,
+    sequence: u64,
+) -> Result<(), PersistError> {
+    let checkpoint = CheckpointRef {
+        sequence,
+        value: inner,
+    };
+    storage.store(&serde_json::to_vec(&checkpoint)?)?;
+    Ok(())
+}
+
+/// loads the last snapshot, and replays the log entries it does not include
+fn recover<T, C, S>(storage: &S) -> Result<(T, u64, LogSize), PersistError>
+where
+    T: Guard + DeserializeOwned,
+    C: Command<T> + DeserializeOwned,
+    S: Journal,
+{
+    traced::<T, _, _, _>("mut_guard.journal.recover", || replay::<T, C, S>(storage))
+}
+
+fn replay<T, C, S>(storage: &S) -> Result<(T, u64, LogSize), PersistError>
+where
+    T: Guard + DeserializeOwned,
+    C: Command<T> + DeserializeOwned,
+    S: Journal,
+{
+    let snapshot = storage.load()?.ok_or(PersistError::Missing)?;
+    let checkpoint: Checkpoint<T> = serde_json::from_slice(&snapshot)?;
+    let (mut inner, mut sequence) = (checkpoint.value, checkpoint.sequence);
+    validate(&mut inner)?;
+
+    let entries = storage.entries()?;
+    let log = LogSize {
+        entries: entries.len(),
+        bytes: entries.iter().map(Vec::len).sum(),
+    };
+    for entry in entries {
+        let entry: Entry<C> = serde_json::from_slice(&entry)?;
+        if entry.sequence <= sequence {
+            continue;
+        }
+        if entry.sequence != sequence + 1 {
+            return Err(PersistError::Gap {
+                expected: sequence + 1,
+                found: entry.sequence,
+            });
+        }
+        entry.command.apply(&mut inner);
+        validate(&mut inner)?;
+        sequence = entry.sequence;
+    }
+
+    Ok((inner, sequence, log))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Accounts {
+        balances: Vec<i64>,
+    }
+
+    impl Guard for Accounts {
+        fn finish(&mut self) {
+            assert_eq!(
+                self.balances.iter().sum::<i64>(),
+                100,
+                "money was created or destroyed"
+            );
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("mutguard-{}-{}.json", process::id(), name))
+    }
+
+    fn cleanup(path: &Path) {
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(with_suffix(path, ".lock"));
+        let _ = fs::remove_file(with_suffix(path, ".wal"));
+    }
+
+    #[test]
+    fn persist() {
+        let path = temp_path("persist");
+        let mut a = PersistentMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+        let mut b = PersistentMutGuard::<Accounts>::open(&path).unwrap();
+
+        a.update(|acc| {
+            acc.balances[0] -= 10;
+            acc.balances[1] += 10;
+        })
+        .unwrap();
+        assert_eq!(b.balances, vec![100, 0]);
+
+        // b sees a's change before applying its own
+        {
+            let mut acc = b.guard().unwrap();
+            acc.balances[1] -= 5;
+            acc.balances[0] += 5;
+        }
+        assert_eq!(b.balances, vec![95, 5]);
+
+        a.refresh().unwrap();
+        assert_eq!(a.balances, vec![95, 5]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let path = temp_path("concurrent");
+        PersistentMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+        let barrier = Arc::new(Barrier::new(4));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let path = path.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut accounts = PersistentMutGuard::<Accounts>::open(&path).unwrap();
+                    barrier.wait();
+                    for _ in 0..5 {
+                        accounts
+                            .update(|acc| {
+                                acc.balances[0] -= 1;
+                                acc.balances[1] += 1;
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let accounts = PersistentMutGuard::<Accounts>::open(&path).unwrap();
+        assert_eq!(accounts.balances, vec![80, 20]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn invalid_snapshot() {
+        let path = temp_path("invalid");
+        assert!(matches!(
+            PersistentMutGuard::<Accounts>::open(&path),
+            Err(PersistError::Missing)
+        ));
+
+        fs::write(&path, b"{\"balances\":[1]}").unwrap();
+        match PersistentMutGuard::<Accounts>::open(&path) {
+            Err(PersistError::Violation(v)) => {
+                assert!(v.message().contains("money was created or destroyed"))
+            }
+            _ => panic!("the snapshot should be rejected"),
+        }
+
+        fs::write(&path, b"{").unwrap();
+        assert!(matches!(
+            PersistentMutGuard::<Accounts>::open(&path),
+            Err(PersistError::Format(_))
+        ));
+        cleanup(&path);
+    }
+
+    #[test]
+    fn violation_is_not_stored() {
+        let path = temp_path("violation");
+        let mut accounts = PersistentMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100],
+            },
+        )
+        .unwrap();
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            accounts.guard().unwrap().balances[0] += 1;
+        }));
+        assert!(res.is_err());
+
+        let stored = PersistentMutGuard::<Accounts>::open(&path).unwrap();
+        assert_eq!(stored.balances, vec![100]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn load_or_init() {
+        let path = temp_path("load-or-init");
+        let init = || Accounts {
+            balances: vec![100, 0],
+        };
+
+        let mut accounts = PersistentMutGuard::load_or_init(&path, init).unwrap();
+        accounts.update(|acc| acc.balances.swap(0, 1)).unwrap();
+
+        let accounts =
+            PersistentMutGuard::<Accounts>::load_or_init(&path, || unreachable!()).unwrap();
+        assert_eq!(accounts.balances, vec![0, 100]);
+
+        fs::write(&path, b"{\n  \"balances\": [100, 0,]\n}").unwrap();
+        match PersistentMutGuard::load_or_init(&path, init) {
+            Err(PersistError::Format(e)) => assert_eq!((e.line(), e.column()), (2, 23)),
+            _ => panic!("the corrupt snapshot should be rejected"),
+        }
+        assert!(fs::read_to_string(&path).unwrap().contains("0,]"));
+        cleanup(&path);
+
+        let res = PersistentMutGuard::load_or_init(&path, || Accounts { balances: vec![1] });
+        assert!(matches!(res, Err(PersistError::Violation(_))));
+        assert!(!path.exists());
+        cleanup(&path);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Change {
+        Transfer(usize, usize, i64),
+        /// breaks the invariant
+        Mint(usize, i64),
+    }
+
+    impl Command<Accounts> for Change {
+        fn apply(&self, target: &mut Accounts) {
+            match *self {
+                Change::Transfer(from, to, amount) => {
+                    target.balances[from] -= amount;
+                    target.balances[to] += amount;
+                }
+                Change::Mint(to, amount) => target.balances[to] += amount,
+            }
+        }
+    }
+
+    fn transfer(from: usize, to: usize, amount: i64) -> Change {
+        Change::Transfer(from, to, amount)
+    }
+
+    #[test]
+    fn journal() {
+        let path = temp_path("journal");
+        let mut a = JournaledMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+        a.apply(transfer(0, 1, 10)).unwrap();
+
+        let mut b = JournaledMutGuard::<Accounts, Change>::open(&path).unwrap();
+        assert_eq!(b.balances, vec![90, 10]);
+        b.apply(transfer(1, 0, 5)).unwrap();
+        assert_eq!(b.sequence(), 2);
+
+        // a replays b's entry before appending its own
+        a.apply(transfer(0, 1, 1)).unwrap();
+        assert_eq!(a.balances, vec![94, 6]);
+        assert_eq!(a.sequence(), 3);
+        assert_eq!(a.storage().entries().unwrap().len(), 3);
+
+        a.checkpoint().unwrap();
+        assert!(a.storage().entries().unwrap().is_empty());
+        b.refresh().unwrap();
+        assert_eq!(b.balances, vec![94, 6]);
+        assert_eq!(b.sequence(), 3);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn journal_recovery() {
+        let path = temp_path("journal-recovery");
+        let mut accounts = JournaledMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+        accounts.apply(transfer(0, 1, 10)).unwrap();
+        accounts.apply(transfer(0, 1, 10)).unwrap();
+        let log = fs::read(with_suffix(&path, ".wal")).unwrap();
+
+        // an append interrupted by a crash
+        fs::write(with_suffix(&path, ".wal"), &log[..log.len() - 3]).unwrap();
+        let mut recovered = JournaledMutGuard::<Accounts, Change>::open(&path).unwrap();
+        assert_eq!(recovered.balances, vec![90, 10]);
+        assert_eq!(recovered.sequence(), 1);
+
+        // the incomplete entry is replaced
+        recovered.apply(transfer(1, 0, 10)).unwrap();
+        let reopened = JournaledMutGuard::<Accounts, Change>::open(&path).unwrap();
+        assert_eq!(reopened.balances, vec![100, 0]);
+        assert_eq!(reopened.storage().entries().unwrap().len(), 2);
+
+        // a checkpoint interrupted before the log was emptied
+        let log = fs::read(with_suffix(&path, ".wal")).unwrap();
+        recovered.checkpoint().unwrap();
+        fs::write(with_suffix(&path, ".wal"), &log).unwrap();
+        let reopened = JournaledMutGuard::<Accounts, Change>::open(&path).unwrap();
+        assert_eq!(reopened.balances, vec![100, 0]);
+        assert_eq!(reopened.sequence(), 2);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn journal_violation_is_not_logged() {
+        let path = temp_path("journal-violation");
+        let mut accounts = JournaledMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            accounts.apply(Change::Mint(0, 10)).unwrap();
+        }));
+        assert!(res.is_err());
+        assert_eq!(accounts.balances, vec![100, 0]);
+        assert!(accounts.storage().entries().unwrap().is_empty());
+        cleanup(&path);
+    }
+
+    #[test]
+    fn journal_gap() {
+        let path = temp_path("journal-gap");
+        let mut accounts = JournaledMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+        for _ in 0..3 {
+            accounts.apply(transfer(0, 1, 1)).unwrap();
+        }
+
+        // the second entry was lost
+        let storage = accounts.storage();
+        let entries = storage.entries().unwrap();
+        storage.truncate().unwrap();
+        storage.append(&entries[0]).unwrap();
+        storage.append(&entries[2]).unwrap();
+        match JournaledMutGuard::<Accounts, Change>::open(&path) {
+            Err(PersistError::Gap { expected, found }) => assert_eq!((expected, found), (2, 3)),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("the gap was not detected"),
+        }
+        cleanup(&path);
+    }
+
+    #[test]
+    fn journal_compaction() {
+        let path = temp_path("journal-compaction");
+        let mut accounts = JournaledMutGuard::create(
+            &path,
+            Accounts {
+                balances: vec![100, 0],
+            },
+        )
+        .unwrap();
+        accounts.compact_after(3);
+
+        accounts.apply(transfer(0, 1, 1)).unwrap();
+        accounts.apply(transfer(0, 1, 1)).unwrap();
+        assert_eq!(accounts.log_entries(), 2);
+        accounts.apply(transfer(0, 1, 1)).unwrap();
+        assert_eq!(accounts.log_entries(), 0);
+        assert!(accounts.storage().entries().unwrap().is_empty());
+
+        let entry_len = {
+            accounts.apply(transfer(0, 1, 1)).unwrap();
+            accounts.storage().entries().unwrap()[0].len()
+        };
+        accounts.without_compaction();
+        accounts.compact_after_bytes(entry_len * 2);
+        accounts.apply(transfer(0, 1, 1)).unwrap();
+        assert_eq!(accounts.log_entries(), 0);
+
+        let reopened = JournaledMutGuard::<Accounts, Change>::open(&path).unwrap();
+        assert_eq!(reopened.balances, vec![95, 5]);
+        assert_eq!(reopened.sequence(), 5);
+        assert_eq!(reopened.log_entries(), 0);
+        cleanup(&path);
+    }
+}
@@ -0,0 +1,268 @@
+//! Publishing checked changes
+//!
+//! `MutGuard::publish_to()` registers a `Publisher`, called after each
+//! mutable borrow of the element ends, once the element was checked. Unlike
+//! the channels of the `notify` module, publishers run synchronously in the
+//! `Drop` of the borrow, to forward the state to other processes or
+//! services. They are not called if the checks panic, so only valid states
+//! are published.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::publish::Change;
+//! use std::sync::mpsc;
+//!
+//! #[derive(Debug)]
+//! struct Temperature(i32);
+//!
+//! impl Guard for Temperature {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 > -273, "below absolute zero");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let (sender, receiver) = mpsc::channel();
+//! let mut temperature = MutGuard::new(Temperature(20));
+//! temperature.publish_to(move |change: &Change<Temperature>| {
+//!   sender.send((change.sequence(), change.value().0)).unwrap();
+//! });
+//!
+//! temperature.guard().0 += 1;
+//! temperature.guard().0 -= 5;
+//! assert_eq!(receiver.try_iter().collect::<Vec<_>>(), vec![(1, 21), (2, 16)]);
+//! # }
+//! ```
+//!
+//! With the `serde` feature, `Change::to_json()` serializes a change as a
+//! `Payload`, the format of the publishers of the `pubsub` module.
+use std::fmt;
+use std::panic::Location;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use super::GuardId;
+
+/// a checked mutation, passed to each `Publisher`
+pub struct Change<'a, T: ?Sized> {
+    pub(crate) id: GuardId,
+    pub(crate) sequence: u64,
+    pub(crate) label: Option<&'a str>,
+    pub(crate) location: &'static Location<'static>,
+    pub(crate) value: &'a T,
+}
+
+impl<'a, T: ?Sized> Change<'a, T> {
+    /// the guard that was mutated
+    pub fn guard_id(&self) -> GuardId {
+        self.id
+    }
+
+    /// number of the mutation since the publishers were first registered,
+    /// starting at 1
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// label set with `MutGuard::set_label()`
+    pub fn label(&self) -> Option<&'a str> {
+        self.label
+    }
+
+    /// where the borrow was acquired
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// the element after the mutation
+    pub fn value(&self) -> &'a T {
+        self.value
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for Change<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Change")
+            .field("guard_id", &self.id)
+            .field("sequence", &self.sequence)
+            .field("label", &self.label)
+            .field("location", &self.location)
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+/// receives the changes of a `MutGuard`, see `MutGuard::publish_to()`
+///
+/// publishing happens while the borrow is dropped, so errors cannot be
+/// returned to the code that mutated the element: publishers handle them
+/// themselves
+pub trait Publisher<T: ?Sized>: Send {
+    fn publish(&mut self, change: &Change<'_, T>);
+}
+
+impl<T: ?Sized, F: FnMut(&Change<'_, T>) + Send> Publisher<T> for F {
+    fn publish(&mut self, change: &Change<'_, T>) {
+        self(change)
+    }
+}
+
+/// publishers registered on a `MutGuard`
+pub(crate) struct Publishers<T> {
+    /// sorted by priority
+    publishers: Vec<(i32, Box<dyn Publisher<T>>)>,
+    sequence: u64,
+}
+
+impl<T> Default for Publishers<T> {
+    fn default() -> Publishers<T> {
+        Publishers {
+            publishers: Vec::new(),
+            sequence: 0,
+        }
+    }
+}
+
+impl<T> Publishers<T> {
+    /// publishers with the same priority keep the order they were added in
+    pub(crate) fn insert(&mut self, priority: i32, publisher: Box<dyn Publisher<T>>) {
+        let index = self.publishers.partition_point(|&(p, _)| p <= priority);
+        self.publishers.insert(index, (priority, publisher));
+    }
+
+    pub(crate) fn publish(
+        &mut self,
+        id: GuardId,
+        label: Option<&str>,
+        location: &'static Location<'static>,
+        value: &T,
+    ) {
+        if self.publishers.is_empty() {
+            return;
+        }
+
+        self.sequence += 1;
+        let change = Change {
+            id,
+            sequence: self.sequence,
+            label,
+            location,
+            value,
+        };
+        for &mut (_, ref mut publisher) in &mut self.publishers {
+            publisher.publish(&change);
+        }
+    }
+}
+
+/// what `Change::to_json()` serializes
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Payload {
+    /// the element only
+    Snapshot,
+    /// an object with the `guard_id`, `sequence`, `label` (if set) and
+    /// `location` of the change, and the element as `value`
+    #[default]
+    Event,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct Event<'a, T: ?Sized> {
+    guard_id: u64,
+    sequence: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<&'a str>,
+    location: String,
+    value: &'a T,
+}
+
+#[cfg(feature = "serde")]
+impl<'a, T: ?Sized + Serialize> Change<'a, T> {
+    pub fn to_json(&self, payload: Payload) -> serde_json::Result<Vec<u8>> {
+        match payload {
+            Payload::Snapshot => serde_json::to_vec(self.value),
+            Payload::Event => serde_json::to_vec(&Event {
+                guard_id: self.id.as_u64(),
+                sequence: self.sequence,
+                label: self.label,
+                location: self.location.to_string(),
+                value: self.value,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize))]
+    struct Ports(Vec<u16>);
+
+    impl Guard for Ports {
+        fn finish(&mut self) {
+            assert!(self.0.iter().all(|p| *p >= 1024), "privileged port");
+        }
+    }
+
+    #[test]
+    fn publish_checked() {
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let mut ports = MutGuard::new(Ports(vec![]));
+        ports.set_label("ports");
+        {
+            let published = published.clone();
+            ports.publish_to(move |change: &Change<Ports>| {
+                assert_eq!(change.label(), Some("ports"));
+                published
+                    .lock()
+                    .unwrap()
+                    .push((change.sequence(), change.value().clone()));
+            });
+        }
+
+        ports.guard().0.push(8080);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| ports.guard().0.push(80)));
+        assert!(res.is_err());
+        ports.guard().0.retain(|p| *p != 80);
+
+        let published = published.lock().unwrap();
+        let values: Vec<_> = published.iter().map(|(s, p)| (*s, p.0.clone())).collect();
+        assert_eq!(values, vec![(1, vec![8080]), (2, vec![8080])]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json() {
+        let ports = Ports(vec![8080]);
+        let location = Location::caller();
+        let change = Change {
+            id: GuardId(7),
+            sequence: 3,
+            label: None,
+            location,
+            value: &ports,
+        };
+
+        assert_eq!(change.to_json(Payload::Snapshot).unwrap(), b"[8080]");
+        let event: serde_json::Value =
+            serde_json::from_slice(&change.to_json(Payload::Event).unwrap()).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "guard_id": 7,
+                "sequence": 3,
+                "location": location.to_string(),
+                "value": [8080],
+            })
+        );
+    }
+}
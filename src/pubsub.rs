@@ -0,0 +1,296 @@
+//! Redis pub/sub
+//!
+//! *Note*: this module requires the `redis` feature.
+//!
+//! `RedisPublisher` is a `publish::Publisher` sending each checked change of
+//! a `MutGuard` to a Redis channel with `PUBLISH`, serialized as JSON: the
+//! whole event by default, or the element only with `Payload::Snapshot`.
+//!
+//! ```rust,no_run
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # extern crate redis;
+//! # use mut_guard::*;
+//! # use mut_guard::publish::Payload;
+//! # use mut_guard::pubsub::RedisPublisher;
+//! use std::time::Duration;
+//!
+//! #[derive(Serialize, Debug)]
+//! struct Inventory {
+//!   stock: u32,
+//!   reserved: u32,
+//! }
+//!
+//! impl Guard for Inventory {
+//!   fn finish(&mut self) {
+//!     assert!(self.reserved <= self.stock, "reserved more than the stock");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+//! let mut inventory = MutGuard::new(Inventory { stock: 10, reserved: 0 });
+//! inventory.publish_to(
+//!   RedisPublisher::new(client, "inventory")
+//!     .payload(Payload::Snapshot)
+//!     .timeout(Duration::from_millis(200)),
+//! );
+//!
+//! // publishes {"stock":10,"reserved":2} on the "inventory" channel
+//! inventory.guard().reserved += 2;
+//! # }
+//! ```
+//!
+//! The connection is opened on the first change. If sending fails because
+//! the connection was lost, the publisher reconnects and tries again once.
+//! Other errors are passed to the `on_error()` handler, which prints a
+//! warning by default, and the change is dropped: the mutation was already
+//! checked, so it is not undone.
+use std::error::Error;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use redis::{Client, Connection, RedisResult};
+use serde::Serialize;
+
+use super::publish::{Change, Payload, Publisher};
+
+/// default timeout to connect to the server, and of each `PUBLISH`
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+type ErrorHandler = Box<dyn FnMut(&dyn Error) + Send>;
+
+/// `Publisher` sending changes to a Redis channel
+pub struct RedisPublisher<T: ?Sized> {
+    client: Client,
+    channel: String,
+    payload: Payload,
+    timeout: Duration,
+    on_error: ErrorHandler,
+    connection: Option<Connection>,
+    element: PhantomData<fn(&T)>,
+}
+
+impl<T: ?Sized + Serialize> RedisPublisher<T> {
+    pub fn new<S: Into<String>>(client: Client, channel: S) -> RedisPublisher<T> {
+        RedisPublisher {
+            client,
+            channel: channel.into(),
+            payload: Payload::default(),
+            timeout: DEFAULT_TIMEOUT,
+            on_error: Box::new(|e| eprintln!("mut_guard: could not publish to redis: {}", e)),
+            connection: None,
+            element: PhantomData,
+        }
+    }
+
+    /// what is sent for each change, `Payload::Event` by default
+    pub fn payload(mut self, payload: Payload) -> RedisPublisher<T> {
+        self.payload = payload;
+        self
+    }
+
+    /// maximum time to connect, and to send each change. One second by
+    /// default
+    pub fn timeout(mut self, timeout: Duration) -> RedisPublisher<T> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// called with the errors that made a change be dropped
+    pub fn on_error<F: FnMut(&dyn Error) + Send + 'static>(mut self, f: F) -> RedisPublisher<T> {
+        self.on_error = Box::new(f);
+        self
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    fn connection(&mut self) -> RedisResult<&mut Connection> {
+        if self.connection.is_none() {
+            let connection = self.client.get_connection_with_timeout(self.timeout)?;
+            connection.set_read_timeout(Some(self.timeout))?;
+            connection.set_write_timeout(Some(self.timeout))?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    fn send(&mut self, message: &[u8]) -> RedisResult<()> {
+        let channel = self.channel.clone();
+        let res = self.connection().and_then(|connection| {
+            redis::cmd("PUBLISH")
+                .arg(&channel)
+                .arg(message)
+                .query::<i64>(connection)
+        });
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_unrecoverable_error() => {
+                // reconnects for the second attempt
+                self.connection = None;
+                redis::cmd("PUBLISH")
+                    .arg(&channel)
+                    .arg(message)
+                    .query::<i64>(self.connection()?)
+                    .map(|_| ())
+                    .inspect_err(|_| self.connection = None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<T: ?Sized + Serialize> Publisher<T> for RedisPublisher<T> {
+    fn publish(&mut self, change: &Change<'_, T>) {
+        let res = match change.to_json(self.payload) {
+            Ok(message) => self.send(&message).map_err(Box::<dyn Error>::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            (self.on_error)(e.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Serialize, Debug)]
+    struct Seats(u32);
+
+    impl Guard for Seats {
+        fn finish(&mut self) {
+            assert!(self.0 <= 3, "overbooked");
+        }
+    }
+
+    type Commands = Arc<Mutex<Vec<Vec<String>>>>;
+
+    /// reads a RESP array of bulk strings
+    fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).ok()?;
+            let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).ok()?;
+            arg.truncate(len);
+            args.push(String::from_utf8(arg).ok()?);
+        }
+        Some(args)
+    }
+
+    /// fake server answering `PUBLISH` with one subscriber. The connections
+    /// are closed after each `PUBLISH` when `drop_connections` is set
+    fn server(drop_connections: bool) -> (String, Commands) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let commands = Commands::default();
+
+        let recorded = commands.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let recorded = recorded.clone();
+                thread::spawn(move || {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    while let Some(args) = read_command(&mut reader) {
+                        if args[0] != "PUBLISH" {
+                            stream.write_all(b"+OK\r\n").unwrap();
+                            continue;
+                        }
+                        recorded.lock().unwrap().push(args);
+                        stream.write_all(b":1\r\n").unwrap();
+                        if drop_connections {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (url, commands)
+    }
+
+    #[test]
+    fn publish() {
+        let (url, commands) = server(false);
+        let client = Client::open(url).unwrap();
+
+        let mut seats = MutGuard::new(Seats(0));
+        seats.set_label("seats");
+        seats.publish_to(RedisPublisher::new(client.clone(), "seats"));
+        seats.publish_to(RedisPublisher::new(client, "seats-snapshots").payload(Payload::Snapshot));
+
+        seats.guard().0 += 2;
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| seats.guard().0 += 2));
+        assert!(res.is_err());
+
+        let commands = commands.lock().unwrap();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0][1], "seats");
+        let event: serde_json::Value = serde_json::from_str(&commands[0][2]).unwrap();
+        assert_eq!(event["guard_id"], seats.id().as_u64());
+        assert_eq!(event["sequence"], 1);
+        assert_eq!(event["label"], "seats");
+        assert_eq!(event["value"], 2);
+        assert_eq!(commands[1][1..], ["seats-snapshots", "2"]);
+    }
+
+    #[test]
+    fn reconnect() {
+        let (url, commands) = server(true);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+
+        let mut seats = MutGuard::new(Seats(0));
+        {
+            let errors = errors.clone();
+            let publisher = RedisPublisher::new(Client::open(url).unwrap(), "seats")
+                .payload(Payload::Snapshot)
+                .on_error(move |e| errors.lock().unwrap().push(e.to_string()));
+            seats.publish_to(publisher);
+        }
+
+        for _ in 0..3 {
+            seats.guard().0 += 1;
+        }
+
+        let published: Vec<_> = commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|c| c[2].clone())
+            .collect();
+        assert_eq!(published, vec!["1", "2", "3"]);
+        assert!(errors.lock().unwrap().is_empty());
+
+        // nothing listens anymore
+        let mut seats = MutGuard::new(Seats(0));
+        {
+            let errors = errors.clone();
+            let client = Client::open("redis://127.0.0.1:1/").unwrap();
+            let publisher = RedisPublisher::new(client, "seats")
+                .on_error(move |e| errors.lock().unwrap().push(e.to_string()));
+            seats.publish_to(publisher);
+        }
+        seats.guard().0 += 1;
+        assert_eq!(seats.0, 1);
+        assert_eq!(errors.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+//! RCU style publication of guarded state, built on `arc-swap`
+//!
+//! *Note*: this module requires the `arc-swap` feature.
+//!
+//! a single `Publisher` owns the value and mutates it through guarded
+//! borrows. Once a borrow is released and `Guard::finish` succeeded, an
+//! immutable copy of the value is published, that any number of `Reader`
+//! handles can load without locking. Readers never see a state that did
+//! not pass the checks.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::rcu::*;
+//! use std::thread;
+//!
+//! # fn main() {
+//! #[derive(Clone, Debug)]
+//! struct Config {
+//!   workers: u8,
+//! }
+//!
+//! impl Guard for Config {
+//!   fn finish(&mut self) {
+//!     assert!(self.workers > 0, "at least one worker is needed");
+//!   }
+//! }
+//!
+//! let mut config = Publisher::new(Config { workers: 1 });
+//! let reader = config.reader();
+//!
+//! config.guard().workers = 4;
+//!
+//! let handle = thread::spawn(move || reader.load().workers);
+//! assert_eq!(handle.join().unwrap(), 4);
+//! # }
+//! ```
+use std::ops::{Deref, DerefMut, Drop};
+use std::sync::Arc;
+
+use arc_swap::{ArcSwap, Guard as LoadGuard};
+
+use super::hold::Unwinding;
+use super::{run_guard, Guard};
+
+/// single writer for a value that is published to `Reader`s after every
+/// successful guarded mutation
+pub struct Publisher<T: Guard + Clone> {
+    inner: T,
+    published: Arc<ArcSwap<T>>,
+}
+
+impl<T: Guard + Clone> Publisher<T> {
+    /// the value is published right away, without running the checks, like
+    /// `MutGuard::new()`
+    pub fn new(inner: T) -> Publisher<T> {
+        let published = Arc::new(ArcSwap::from_pointee(inner.clone()));
+        Publisher { inner, published }
+    }
+
+    /// creates a new handle to the published snapshots
+    pub fn reader(&self) -> Reader<T> {
+        Reader {
+            published: self.published.clone(),
+        }
+    }
+
+    /// mutable borrow of the value. When it is dropped, the value is checked
+    /// then published
+    pub fn guard(&mut self) -> PublishBorrow<'_, T> {
+        PublishBorrow {
+            publisher: self,
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    /// returns the value, consuming the publisher. Readers keep the last
+    /// published snapshot
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Guard + Clone> Deref for Publisher<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// mutable borrow of a `Publisher`'s value
+pub struct PublishBorrow<'a, T: 'a + Guard + Clone> {
+    publisher: &'a mut Publisher<T>,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Guard + Clone> Deref for PublishBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.publisher.inner
+    }
+}
+
+impl<'a, T: Guard + Clone> DerefMut for PublishBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.publisher.inner
+    }
+}
+
+impl<'a, T: Guard + Clone> Drop for PublishBorrow<'a, T> {
+    fn drop(&mut self) {
+        // if the checks panic, or a panic interrupted the mutation, the
+        // previous snapshot stays published
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(&mut self.publisher.inner);
+        self.publisher
+            .published
+            .store(Arc::new(self.publisher.inner.clone()));
+    }
+}
+
+/// lock-free access to the last value published by a `Publisher`
+pub struct Reader<T> {
+    published: Arc<ArcSwap<T>>,
+}
+
+impl<T> Reader<T> {
+    /// current snapshot, for short lived accesses
+    pub fn load(&self) -> LoadGuard<Arc<T>> {
+        self.published.load()
+    }
+
+    /// current snapshot, that can be kept around
+    pub fn load_full(&self) -> Arc<T> {
+        self.published.load_full()
+    }
+}
+
+impl<T> Clone for Reader<T> {
+    fn clone(&self) -> Reader<T> {
+        Reader {
+            published: self.published.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Even(u32);
+
+    impl Guard for Even {
+        fn finish(&mut self) {
+            assert!(self.0.is_multiple_of(2), "value must be even: {}", self.0);
+        }
+    }
+
+    #[test]
+    fn publish() {
+        let mut publisher = Publisher::new(Even(0));
+        let reader = publisher.reader();
+        let before = reader.load_full();
+
+        publisher.guard().0 += 2;
+        assert_eq!(reader.load().0, 2);
+        assert_eq!(before.0, 0);
+        assert_eq!(reader.clone().load_full().0, 2);
+    }
+
+    #[test]
+    fn failed_check_is_not_published() {
+        let mut publisher = Publisher::new(Even(0));
+        let reader = publisher.reader();
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            publisher.guard().0 += 1;
+        }));
+        assert!(res.is_err());
+        assert_eq!(reader.load().0, 0);
+    }
+
+    #[test]
+    fn interrupted_change_is_not_published() {
+        let mut publisher = Publisher::new(Even(0));
+        let reader = publisher.reader();
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let mut p = publisher.guard();
+            p.0 += 1;
+            panic!("interrupted");
+        }));
+        assert!(res.is_err());
+        assert_eq!(reader.load().0, 0);
+    }
+}
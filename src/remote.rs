@@ -0,0 +1,872 @@
+//! Guarded state persisted in remote storage
+//!
+//! *Note*: this module requires the `persist` feature.
+//!
+//! object stores cannot hold the advisory locks used by
+//! `persist::PersistentMutGuard`, and are reached through asynchronous
+//! IO. An `AsyncPersistentMutGuard` keeps its snapshot in an
+//! `AsyncStorage` instead, and relies on versions (like HTTP `ETag`s):
+//! every mutation loads the latest snapshot, applies the change, checks the
+//! element, then stores it only if nobody else stored a new version in the
+//! meantime. On a conflict, it starts again from the new version.
+//!
+//! With the `object-storage` feature, `HttpStorage` stores the snapshot as
+//! an object on an HTTP server supporting conditional requests, like an S3
+//! compatible store.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # #[macro_use]
+//! # extern crate serde_derive;
+//! # use mut_guard::*;
+//! # use mut_guard::remote::*;
+//! use std::future::{self, Future};
+//! use std::io;
+//! use std::pin::pin;
+//! use std::sync::{Arc, Mutex};
+//! use std::task::{Context, Poll, Waker};
+//!
+//! /// in memory, so every future is immediately ready
+//! #[derive(Clone, Default)]
+//! struct Memory(Arc<Mutex<Option<(Vec<u8>, u64)>>>);
+//!
+//! impl AsyncStorage for Memory {
+//!   fn load(&self) -> StorageFuture<Option<(Vec<u8>, Version)>> {
+//!     let stored = self.0.lock().unwrap().clone();
+//!     Box::pin(future::ready(Ok(stored.map(|(data, v)| (data, Version::new(v.to_string()))))))
+//!   }
+//!
+//!   fn store(&self, snapshot: Vec<u8>, expected: Option<&Version>) -> StorageFuture<Stored> {
+//!     let mut stored = self.0.lock().unwrap();
+//!     let current = stored.as_ref().map(|(_, v)| *v).unwrap_or(0);
+//!     let res = match expected {
+//!       Some(expected) if expected.as_str() != current.to_string() => Stored::Conflict,
+//!       _ => {
+//!         *stored = Some((snapshot, current + 1));
+//!         Stored::Done(Version::new((current + 1).to_string()))
+//!       }
+//!     };
+//!     Box::pin(future::ready(Ok(res)))
+//!   }
+//! }
+//!
+//! #[derive(Serialize, Deserialize, Debug)]
+//! struct Quota {
+//!   used: u32,
+//! }
+//!
+//! impl Guard for Quota {
+//!   fn finish(&mut self) {
+//!     assert!(self.used <= 10, "quota exceeded");
+//!   }
+//! }
+//!
+//! // `.await` in an async function
+//! fn block_on<F: Future>(f: F) -> F::Output {
+//!   let mut f = pin!(f);
+//!   match f.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+//!     Poll::Ready(output) => output,
+//!     Poll::Pending => unreachable!(),
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let storage = Memory::default();
+//! let mut quota = block_on(AsyncPersistentMutGuard::create(storage.clone(), Quota { used: 0 })).unwrap();
+//! let mut other = block_on(AsyncPersistentMutGuard::<Quota, _>::open(storage)).unwrap();
+//!
+//! block_on(quota.update(|q| q.used += 2)).unwrap();
+//! // starts from the version stored by `quota`
+//! block_on(other.update(|q| q.used += 3)).unwrap();
+//! assert_eq!(other.used, 5);
+//! # }
+//! ```
+use std::future::Future;
+use std::io;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::persist::{validate, PersistError};
+use super::{run_guard, Guard};
+
+/// asynchronous operation of an `AsyncStorage`. It owns what it needs, so it
+/// does not borrow the storage
+pub type StorageFuture<T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send>>;
+
+/// opaque version of a stored snapshot, like an HTTP `ETag`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Version(String);
+
+impl Version {
+    pub fn new<S: Into<String>>(version: S) -> Version {
+        Version(version.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// result of `AsyncStorage::store()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stored {
+    /// the snapshot was stored, with this version
+    Done(Version),
+    /// the stored version was not the expected one, nothing was written
+    Conflict,
+}
+
+/// asynchronous variant of `persist::Storage`, for remote storage that
+/// cannot be locked
+pub trait AsyncStorage {
+    /// returns the snapshot and its version, or `None` if nothing was stored
+    fn load(&self) -> StorageFuture<Option<(Vec<u8>, Version)>>;
+
+    /// replaces the snapshot if its version is still `expected`, or
+    /// unconditionally if `expected` is `None`. Readers must see either the
+    /// old or the new snapshot, never a partial write
+    fn store(&self, snapshot: Vec<u8>, expected: Option<&Version>) -> StorageFuture<Stored>;
+}
+
+/// guarded element persisted in an `AsyncStorage`
+///
+/// the element can be read through `Deref`, as of the last load or
+/// mutation
+pub struct AsyncPersistentMutGuard<T, S: AsyncStorage> {
+    inner: T,
+    storage: S,
+    version: Option<Version>,
+    max_retries: usize,
+}
+
+impl<T, S> AsyncPersistentMutGuard<T, S>
+where
+    T: Guard + Serialize + DeserializeOwned,
+    S: AsyncStorage,
+{
+    /// checks `inner`, then stores it, replacing what `storage` contained
+    pub fn create(storage: S, mut inner: T) -> Create<T, S> {
+        let store = validate(&mut inner)
+            .and_then(|()| Ok(serde_json::to_vec(&inner)?))
+            .map(|snapshot| storage.store(snapshot, None));
+
+        Create {
+            state: Some((inner, storage)),
+            store: Some(store),
+        }
+    }
+
+    /// loads and checks the element stored in `storage`
+    pub fn open(storage: S) -> Open<T, S> {
+        Open {
+            load: storage.load(),
+            storage: Some(storage),
+            element: std::marker::PhantomData,
+        }
+    }
+
+    /// how many times `update()` starts again after a conflict before
+    /// returning `PersistError::Conflict`. Defaults to 5
+    pub fn max_retries(&mut self, retries: usize) {
+        self.max_retries = retries;
+    }
+
+    /// loads the latest snapshot, applies `f` to it and checks the element,
+    /// then stores it if no other writer stored a new version in the
+    /// meantime. If one did, `f` is applied again to the new version, so it
+    /// must not have side effects
+    ///
+    /// A failed check panics, like with `MutGuard`, and nothing is stored
+    pub fn update<R, F: FnMut(&mut T) -> R>(&mut self, f: F) -> Update<'_, T, S, R, F> {
+        let load = self.storage.load();
+        Update {
+            guard: self,
+            f,
+            state: UpdateState::Loading(load),
+            retries: 0,
+            result: None,
+        }
+    }
+
+    /// version of the snapshot, as of the last load or mutation
+    pub fn version(&self) -> Option<&Version> {
+        self.version.as_ref()
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// returns the element, consuming the AsyncPersistentMutGuard
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T, S: AsyncStorage> Deref for AsyncPersistentMutGuard<T, S> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+/// decodes, fixes up and checks a snapshot
+fn decode<T: Guard + DeserializeOwned>(snapshot: &[u8]) -> Result<T, PersistError> {
+    let mut inner: T = serde_json::from_slice(snapshot)?;
+    validate(&mut inner)?;
+    Ok(inner)
+}
+
+macro_rules! ready {
+    ($e:expr) => {
+        match $e {
+            Poll::Ready(res) => res,
+            Poll::Pending => return Poll::Pending,
+        }
+    };
+}
+
+/// future returned by `AsyncPersistentMutGuard::create()`
+pub struct Create<T, S: AsyncStorage> {
+    state: Option<(T, S)>,
+    store: Option<Result<StorageFuture<Stored>, PersistError>>,
+}
+
+// only the boxed storage future is polled
+impl<T, S: AsyncStorage> Unpin for Create<T, S> {}
+
+impl<T, S: AsyncStorage> Future for Create<T, S> {
+    type Output = Result<AsyncPersistentMutGuard<T, S>, PersistError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let store = match this.store {
+            Some(Ok(ref mut store)) => store,
+            Some(Err(_)) => return Poll::Ready(Err(this.store.take().unwrap().err().unwrap())),
+            None => panic!("Create polled after completion"),
+        };
+
+        let version = match ready!(store.as_mut().poll(cx))? {
+            Stored::Done(version) => version,
+            Stored::Conflict => return Poll::Ready(Err(PersistError::Conflict)),
+        };
+        let (inner, storage) = this.state.take().expect("Create polled after completion");
+        Poll::Ready(Ok(AsyncPersistentMutGuard {
+            inner,
+            storage,
+            version: Some(version),
+            max_retries: 5,
+        }))
+    }
+}
+
+/// future returned by `AsyncPersistentMutGuard::open()`
+pub struct Open<T, S: AsyncStorage> {
+    load: StorageFuture<Option<(Vec<u8>, Version)>>,
+    storage: Option<S>,
+    element: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<T, S: AsyncStorage> Unpin for Open<T, S> {}
+
+impl<T, S> Future for Open<T, S>
+where
+    T: Guard + DeserializeOwned,
+    S: AsyncStorage,
+{
+    type Output = Result<AsyncPersistentMutGuard<T, S>, PersistError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (snapshot, version) =
+            ready!(self.load.as_mut().poll(cx))?.ok_or(PersistError::Missing)?;
+        let storage = self.storage.take().expect("Open polled after completion");
+        Poll::Ready(Ok(AsyncPersistentMutGuard {
+            inner: decode(&snapshot)?,
+            storage,
+            version: Some(version),
+            max_retries: 5,
+        }))
+    }
+}
+
+enum UpdateState {
+    Loading(StorageFuture<Option<(Vec<u8>, Version)>>),
+    Storing(StorageFuture<Stored>),
+}
+
+/// future returned by `AsyncPersistentMutGuard::update()`
+pub struct Update<'a, T: 'a, S: 'a + AsyncStorage, R, F> {
+    guard: &'a mut AsyncPersistentMutGuard<T, S>,
+    f: F,
+    state: UpdateState,
+    retries: usize,
+    result: Option<R>,
+}
+
+impl<'a, T, S: AsyncStorage, R, F> Unpin for Update<'a, T, S, R, F> {}
+
+impl<'a, T, S, R, F> Future for Update<'a, T, S, R, F>
+where
+    T: Guard + Serialize + DeserializeOwned,
+    S: AsyncStorage,
+    F: FnMut(&mut T) -> R,
+{
+    type Output = Result<R, PersistError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            let next = match this.state {
+                UpdateState::Loading(ref mut load) => {
+                    let (snapshot, version) =
+                        ready!(load.as_mut().poll(cx))?.ok_or(PersistError::Missing)?;
+                    let guard = &mut *this.guard;
+                    guard.inner = decode(&snapshot)?;
+                    guard.version = Some(version);
+
+                    this.result = Some((this.f)(&mut guard.inner));
+                    run_guard(&mut guard.inner);
+                    let snapshot = serde_json::to_vec(&guard.inner)?;
+                    UpdateState::Storing(guard.storage.store(snapshot, guard.version.as_ref()))
+                }
+                UpdateState::Storing(ref mut store) => match ready!(store.as_mut().poll(cx))? {
+                    Stored::Done(version) => {
+                        this.guard.version = Some(version);
+                        let result = this.result.take().expect("Update polled after completion");
+                        return Poll::Ready(Ok(result));
+                    }
+                    Stored::Conflict => {
+                        if this.retries == this.guard.max_retries {
+                            return Poll::Ready(Err(PersistError::Conflict));
+                        }
+                        this.retries += 1;
+                        UpdateState::Loading(this.guard.storage.load())
+                    }
+                },
+            };
+            this.state = next;
+        }
+    }
+}
+
+#[cfg(feature = "object-storage")]
+pub use self::http::HttpStorage;
+
+#[cfg(feature = "object-storage")]
+mod http {
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{AsyncStorage, StorageFuture, Stored, Version};
+
+    /// *Note*: this requires the `object-storage` feature.
+    ///
+    /// `AsyncStorage` keeping the snapshot as an object on an HTTP server,
+    /// like an S3 compatible store, using `ETag`s for versions and
+    /// `If-Match` for conditional writes.
+    ///
+    /// This is a minimal HTTP/1.1 client meant as an example backend: it
+    /// only supports plain `http://` URLs, and does not sign requests.
+    /// Authentication can use static headers (`header()`), or a local proxy
+    /// adding TLS and signatures. Requests run on tokio's blocking thread
+    /// pool, so they must be started in the context of a tokio runtime
+    ///
+    /// ```rust,no_run,edition2021
+    /// # extern crate mut_guard;
+    /// # extern crate tokio;
+    /// # use mut_guard::*;
+    /// # use mut_guard::remote::*;
+    /// # #[derive(serde::Serialize, serde::Deserialize)]
+    /// # struct Config { replicas: u32 }
+    /// # impl Guard for Config { fn finish(&mut self) {} }
+    /// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    /// let storage = HttpStorage::new("http://127.0.0.1:9000/bucket/config.json")?
+    ///   .header("Authorization", "Bearer secret");
+    ///
+    /// let mut config = AsyncPersistentMutGuard::<Config, _>::open(storage).await?;
+    /// config.update(|c| c.replicas += 1).await?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {}
+    /// ```
+    #[derive(Clone, Debug)]
+    pub struct HttpStorage {
+        config: Arc<Config>,
+    }
+
+    #[derive(Clone, Debug)]
+    struct Config {
+        /// `host:port`
+        host: String,
+        path: String,
+        headers: Vec<(String, String)>,
+        timeout: Duration,
+    }
+
+    impl HttpStorage {
+        /// stores the snapshot at `url`, of the form `http://host[:port]/path`
+        pub fn new(url: &str) -> io::Result<HttpStorage> {
+            let rest = url.strip_prefix("http://").ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only http:// URLs are supported",
+                )
+            })?;
+            let (host, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, "/"),
+            };
+            let host = if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:80", host)
+            };
+
+            Ok(HttpStorage {
+                config: Arc::new(Config {
+                    host,
+                    path: path.to_string(),
+                    headers: Vec::new(),
+                    timeout: Duration::from_secs(30),
+                }),
+            })
+        }
+
+        /// adds a header to every request
+        pub fn header(mut self, name: &str, value: &str) -> HttpStorage {
+            Arc::make_mut(&mut self.config)
+                .headers
+                .push((name.to_string(), value.to_string()));
+            self
+        }
+
+        /// maximum time spent connecting, sending or receiving. Defaults to
+        /// 30 seconds
+        pub fn timeout(mut self, timeout: Duration) -> HttpStorage {
+            Arc::make_mut(&mut self.config).timeout = timeout;
+            self
+        }
+    }
+
+    impl AsyncStorage for HttpStorage {
+        fn load(&self) -> StorageFuture<Option<(Vec<u8>, Version)>> {
+            let config = self.config.clone();
+            blocking(move || {
+                let response = request(&config, "GET", &[], &[])?;
+                match response.status {
+                    200 => {
+                        let version = response.version()?;
+                        Ok(Some((response.body, version)))
+                    }
+                    404 => Ok(None),
+                    status => Err(unexpected(status)),
+                }
+            })
+        }
+
+        fn store(&self, snapshot: Vec<u8>, expected: Option<&Version>) -> StorageFuture<Stored> {
+            let config = self.config.clone();
+            let expected = expected.cloned();
+            blocking(move || {
+                let condition: Vec<(&str, &str)> = match expected {
+                    Some(ref version) => vec![("If-Match", version.as_str())],
+                    None => Vec::new(),
+                };
+                let response = request(&config, "PUT", &condition, &snapshot)?;
+                match response.status {
+                    200 | 201 | 204 => Ok(Stored::Done(response.version()?)),
+                    409 | 412 => Ok(Stored::Conflict),
+                    status => Err(unexpected(status)),
+                }
+            })
+        }
+    }
+
+    /// runs `f` on tokio's blocking thread pool
+    fn blocking<T, F>(f: F) -> StorageFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        Box::pin(Blocking(tokio::task::spawn_blocking(f)))
+    }
+
+    struct Blocking<T>(tokio::task::JoinHandle<io::Result<T>>);
+
+    impl<T> std::future::Future for Blocking<T> {
+        type Output = io::Result<T>;
+
+        fn poll(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<T>> {
+            std::pin::Pin::new(&mut self.0)
+                .poll(cx)
+                .map(|res| res.unwrap_or_else(|e| Err(io::Error::other(e))))
+        }
+    }
+
+    struct Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Response {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+
+        fn version(&self) -> io::Result<Version> {
+            self.header("ETag")
+                .map(Version::new)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing ETag header"))
+        }
+    }
+
+    fn unexpected(status: u16) -> io::Error {
+        io::Error::other(format!("unexpected HTTP status {}", status))
+    }
+
+    fn request(
+        config: &Config,
+        method: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let stream = TcpStream::connect(&config.host)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        stream.set_write_timeout(Some(config.timeout))?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            config.path,
+            config.host,
+            body.len()
+        );
+        let configured = config.headers.iter().map(|(n, v)| (n.as_str(), v.as_str()));
+        for (name, value) in headers.iter().cloned().chain(configured) {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+
+        let mut writer = &stream;
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(body)?;
+        writer.flush()?;
+
+        read_response(BufReader::new(&stream))
+    }
+
+    fn read_response<R: BufRead>(mut reader: R) -> io::Result<Response> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| invalid("invalid HTTP status line"))?;
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| invalid("invalid HTTP header"))?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        let mut response = Response {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        if response
+            .header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+        {
+            response.body = read_chunked(&mut reader)?;
+        } else if let Some(length) = response.header("Content-Length") {
+            let length: usize = length
+                .parse()
+                .map_err(|_| invalid("invalid Content-Length"))?;
+            response.body = vec![0; length];
+            reader.read_exact(&mut response.body)?;
+        } else {
+            reader.read_to_end(&mut response.body)?;
+        }
+        Ok(response)
+    }
+
+    fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or("");
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            // the CRLF after the chunk, or the end of the trailers
+            line.clear();
+            reader.read_line(&mut line)?;
+            if size == 0 {
+                return Ok(body);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::super::AsyncPersistentMutGuard;
+        use super::*;
+        use serde::{Deserialize, Serialize};
+        use std::io::{Cursor, Read};
+        use std::net::TcpListener;
+        use std::thread;
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Replicas(u32);
+
+        impl crate::Guard for Replicas {
+            fn finish(&mut self) {
+                assert!(self.0 <= 5, "too many replicas");
+            }
+        }
+
+        /// single object server, with conditional writes
+        fn serve(listener: TcpListener, requests: usize) {
+            let mut object: Option<(Vec<u8>, u32)> = None;
+            for stream in listener.incoming().take(requests) {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let method = line.split_whitespace().next().unwrap().to_string();
+
+                let (mut length, mut condition) = (0, None);
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let header = line.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(": ").unwrap();
+                    match name {
+                        "Content-Length" => length = value.parse().unwrap(),
+                        "If-Match" => condition = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+
+                let current = object.as_ref().map(|(_, v)| format!("\"{}\"", v));
+                let (status, etag, body) = match (method.as_str(), object.as_ref()) {
+                    ("GET", Some((data, _))) => ("200 OK", current, data.clone()),
+                    ("GET", None) => ("404 Not Found", None, Vec::new()),
+                    _ if condition.is_some() && condition != current => {
+                        ("412 Precondition Failed", None, Vec::new())
+                    }
+                    _ => {
+                        let version = object.as_ref().map(|(_, v)| v + 1).unwrap_or(1);
+                        object = Some((body, version));
+                        ("200 OK", Some(format!("\"{}\"", version)), Vec::new())
+                    }
+                };
+
+                let mut writer = &stream;
+                write!(
+                    writer,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                if let Some(etag) = etag {
+                    write!(writer, "ETag: {}\r\n", etag).unwrap();
+                }
+                writer.write_all(b"\r\n").unwrap();
+                writer.write_all(&body).unwrap();
+            }
+        }
+
+        #[test]
+        fn http() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!(
+                "http://{}/bucket/replicas.json",
+                listener.local_addr().unwrap()
+            );
+            let server = thread::spawn(move || serve(listener, 5));
+
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let _runtime = rt.enter();
+            let storage = HttpStorage::new(&url).unwrap();
+            assert!(rt.block_on(storage.load()).unwrap().is_none());
+
+            let create = AsyncPersistentMutGuard::create(storage.clone(), Replicas(1));
+            let mut replicas = rt.block_on(create).unwrap();
+            assert_eq!(replicas.version(), Some(&Version::new("\"1\"")));
+
+            let stale = Version::new("\"0\"");
+            let res = rt.block_on(storage.store(b"5".to_vec(), Some(&stale)));
+            assert_eq!(res.unwrap(), Stored::Conflict);
+
+            rt.block_on(replicas.update(|r| r.0 += 2)).unwrap();
+            assert_eq!(replicas.version(), Some(&Version::new("\"2\"")));
+            assert_eq!(*replicas, Replicas(3));
+            server.join().unwrap();
+        }
+
+        #[test]
+        fn chunked_response() {
+            let raw = "HTTP/1.1 200 OK\r\nETag: \"1\"\r\nTransfer-Encoding: chunked\r\n\r\n\
+                       3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+            let response = read_response(Cursor::new(raw)).unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.version().unwrap(), Version::new("\"1\""));
+            assert_eq!(response.body, b"abcde");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::future;
+    use std::sync::{Arc, Mutex};
+    use std::task::Waker;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Balance(i64);
+
+    impl Guard for Balance {
+        fn finish(&mut self) {
+            assert!(self.0 >= 0, "negative balance: {}", self.0);
+        }
+    }
+
+    /// snapshot and version
+    type Object = Option<(Vec<u8>, u64)>;
+
+    /// in memory storage. `interfere` stores a new snapshot before the next
+    /// `store()` calls, as another writer would
+    #[derive(Clone, Default)]
+    struct Memory {
+        stored: Arc<Mutex<Object>>,
+        interfere: Arc<Mutex<usize>>,
+    }
+
+    impl AsyncStorage for Memory {
+        fn load(&self) -> StorageFuture<Option<(Vec<u8>, Version)>> {
+            let stored = self.stored.lock().unwrap().clone();
+            let stored = stored.map(|(data, v)| (data, Version::new(v.to_string())));
+            Box::pin(future::ready(Ok(stored)))
+        }
+
+        fn store(&self, snapshot: Vec<u8>, expected: Option<&Version>) -> StorageFuture<Stored> {
+            let mut stored = self.stored.lock().unwrap();
+            let mut current = stored.as_ref().map(|(_, v)| *v).unwrap_or(0);
+
+            let mut interfere = self.interfere.lock().unwrap();
+            if *interfere > 0 {
+                *interfere -= 1;
+                current += 1;
+                *stored = Some((b"100".to_vec(), current));
+            }
+
+            let res = match expected {
+                Some(expected) if expected.as_str() != current.to_string() => Stored::Conflict,
+                _ => {
+                    *stored = Some((snapshot, current + 1));
+                    Stored::Done(Version::new((current + 1).to_string()))
+                }
+            };
+            Box::pin(future::ready(Ok(res)))
+        }
+    }
+
+    fn block_on<F: Future + Unpin>(mut f: F) -> F::Output {
+        match Pin::new(&mut f).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the memory storage should always be ready"),
+        }
+    }
+
+    #[test]
+    fn conflict() {
+        let storage = Memory::default();
+        let mut balance = block_on(AsyncPersistentMutGuard::create(
+            storage.clone(),
+            Balance(10),
+        ))
+        .unwrap();
+        assert_eq!(balance.version(), Some(&Version::new("1")));
+
+        *storage.interfere.lock().unwrap() = 2;
+        let mut calls = 0;
+        let res = block_on(balance.update(|b| {
+            calls += 1;
+            b.0 -= 5;
+            b.0
+        }));
+        // applied again on the snapshots stored by the other writer
+        assert_eq!(res.unwrap(), 95);
+        assert_eq!(calls, 3);
+        assert_eq!(*balance, Balance(95));
+
+        *storage.interfere.lock().unwrap() = 10;
+        balance.max_retries(1);
+        assert!(matches!(
+            block_on(balance.update(|b| b.0 -= 5)),
+            Err(PersistError::Conflict)
+        ));
+
+        let reopened = block_on(AsyncPersistentMutGuard::<Balance, _>::open(storage)).unwrap();
+        assert_eq!(*reopened, Balance(100));
+    }
+
+    #[test]
+    fn invalid() {
+        let storage = Memory::default();
+        assert!(matches!(
+            block_on(AsyncPersistentMutGuard::<Balance, _>::open(storage.clone())),
+            Err(PersistError::Missing)
+        ));
+        assert!(matches!(
+            block_on(AsyncPersistentMutGuard::create(
+                storage.clone(),
+                Balance(-1)
+            )),
+            Err(PersistError::Violation(_))
+        ));
+        assert!(storage.stored.lock().unwrap().is_none());
+    }
+}
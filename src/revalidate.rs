@@ -0,0 +1,168 @@
+//! Periodic background checks
+//!
+//! checks normally run when a mutable borrow ends, so they miss state
+//! corrupted by other means: unsafe code, FFI, or another process writing
+//! to shared memory. A `Revalidator` runs the checks of a shared value from
+//! a background thread at a fixed period, and reports failures to a
+//! handler instead of panicking.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::revalidate::Revalidator;
+//! # use mut_guard::sync::GuardedMutex;
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! #[derive(Debug)]
+//! struct Config {
+//!   workers: u32,
+//! }
+//!
+//! impl Guard for Config {
+//!   fn finish(&mut self) {
+//!     assert!(self.workers > 0, "no workers");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let config = Arc::new(GuardedMutex::new(Config { workers: 4 }));
+//!
+//! let revalidator = Revalidator::spawn(config.clone(), Duration::from_secs(10), |violation| {
+//!   eprintln!("config corrupted: {}", violation);
+//! });
+//!
+//! config.lock().unwrap().workers = 8;
+//! revalidator.stop();
+//! # }
+//! ```
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::violation::Violation;
+use super::{Guard, ARMED};
+
+/// shared values whose checks can run from another thread
+pub trait Revalidate: Send + Sync {
+    /// runs `Guard::finish()` and `Guard::finish_slow()` on the current
+    /// value, returning the failure instead of panicking. `normalize()` is
+    /// not called, and the value is not modified
+    fn revalidate(&self) -> Result<(), Violation>;
+}
+
+/// runs the checks of `value`, catching a failure
+pub(crate) fn check<T: Guard + ?Sized>(value: &mut T) -> Result<(), Violation> {
+    if !ARMED {
+        return Ok(());
+    }
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        value.finish();
+        value.finish_slow();
+    }))
+    .map_err(|payload| Violation::from_panic(payload.as_ref()))
+}
+
+/// background thread running the checks of a value periodically. The
+/// thread stops when this is dropped
+pub struct Revalidator {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Revalidator {
+    /// checks `target` every `period`, and calls `handler` with each
+    /// failure. The first check happens after one period
+    pub fn spawn<R, H>(target: Arc<R>, period: Duration, mut handler: H) -> Revalidator
+    where
+        R: 'static + Revalidate + ?Sized,
+        H: 'static + Send + FnMut(&Violation),
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || loop {
+            match stopped.recv_timeout(period) {
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(violation) = target.revalidate() {
+                        handler(&violation);
+                    }
+                }
+                _ => return,
+            }
+        });
+
+        Revalidator {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// stops the thread, and waits for it to finish its current check
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            // a panicking handler already reported its panic
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Revalidator {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use sync::GuardedMutex;
+
+    #[derive(Debug)]
+    struct Shared {
+        corrupted: Arc<AtomicBool>,
+    }
+
+    impl Guard for Shared {
+        fn finish(&mut self) {
+            assert!(!self.corrupted.load(Ordering::SeqCst), "corrupted value");
+        }
+    }
+
+    #[test]
+    fn reports_corruption() {
+        let corrupted = Arc::new(AtomicBool::new(false));
+        let value = Arc::new(GuardedMutex::new(Shared {
+            corrupted: corrupted.clone(),
+        }));
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = reports.clone();
+        let revalidator = Revalidator::spawn(value.clone(), Duration::from_millis(1), move |v| {
+            r.lock().unwrap().push(v.message().to_string());
+        });
+
+        thread::sleep(Duration::from_millis(20));
+        assert!(reports.lock().unwrap().is_empty());
+
+        corrupted.store(true, Ordering::SeqCst);
+        while reports.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        revalidator.stop();
+
+        assert_eq!(reports.lock().unwrap()[0], "corrupted value");
+        // the checks ran without poisoning the mutex
+        corrupted.store(false, Ordering::SeqCst);
+        assert!(value.lock().is_ok());
+    }
+}
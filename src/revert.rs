@@ -0,0 +1,277 @@
+//! Rejecting changes instead of panicking
+//!
+//! some invariants are better treated as "reject this change" than as
+//! "crash the program". A type implementing `Veto` decides after every
+//! mutable borrow whether the change is kept, or reverted to a snapshot
+//! taken when the borrow started.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::revert::*;
+//! use std::ops::ControlFlow;
+//!
+//! # fn main() {
+//! #[derive(Clone, Debug)]
+//! struct LessThan20(pub u8);
+//!
+//! impl Veto for LessThan20 {
+//!   fn decide(&mut self) -> ControlFlow<Revert> {
+//!     if self.0 <= 20 {
+//!       ControlFlow::Continue(())
+//!     } else {
+//!       ControlFlow::Break(Revert)
+//!     }
+//!   }
+//! }
+//!
+//! let mut val = MutGuard::new(LessThan20(0));
+//!
+//! val.guard_or_revert().0 = 10;
+//! assert_eq!(val.0, 10);
+//!
+//! // the change is rejected when the borrow is dropped
+//! val.guard_or_revert().0 = 30;
+//! assert_eq!(val.0, 10);
+//!
+//! // commit() reports the decision
+//! let mut borrow = val.guard_or_revert();
+//! borrow.0 = 40;
+//! assert_eq!(borrow.commit(), Err(Revert));
+//! assert_eq!(val.0, 10);
+//! # }
+//! ```
+use std::ops::{ControlFlow, Deref, DerefMut, Drop};
+use std::panic::Location;
+
+use super::hold::Unwinding;
+use super::{MutGuard, ARMED};
+
+/// decision returned by `Veto::decide` to restore the state from before
+/// the mutable borrow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Revert;
+
+/// Specifies a method that will be called after every time an element
+/// protected by a `MutGuard` is borrowed through `guard_or_revert()`,
+/// to decide if the change is kept
+pub trait Veto: Clone {
+    fn decide(&mut self) -> ControlFlow<Revert>;
+}
+
+impl<T: Veto> MutGuard<T> {
+    /// call this method to get mutable access to the underlying element.
+    /// the element is cloned first, so the change can be reverted
+    #[track_caller]
+    pub fn guard_or_revert(&mut self) -> RevertBorrow<'_, T> {
+        let snapshot = if ARMED {
+            Some(self.inner.clone())
+        } else {
+            None
+        };
+        RevertBorrow {
+            inner: self,
+            snapshot,
+            reverted: false,
+            location: Location::caller(),
+            unwinding: Unwinding::start(),
+        }
+    }
+}
+
+/// Structure returned by `MutGuard::guard_or_revert()`. when this is
+/// dropped, it will call `Veto::decide()` and restore the snapshot if
+/// the change was rejected. Deferred callbacks, publishers and
+/// subscribers only see kept changes
+pub struct RevertBorrow<'a, T: 'a + Veto> {
+    inner: &'a mut MutGuard<T>,
+    snapshot: Option<T>,
+    reverted: bool,
+    location: &'static Location<'static>,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Veto> RevertBorrow<'a, T> {
+    /// ends the borrow now, returning `Err(Revert)` if the change was rejected
+    pub fn commit(mut self) -> Result<(), Revert> {
+        self.decide()
+    }
+
+    fn decide(&mut self) -> Result<(), Revert> {
+        // already decided by `commit()`, or not armed
+        let snapshot = match self.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None if self.reverted => return Err(Revert),
+            None => return Ok(()),
+        };
+
+        match self.inner.inner.decide() {
+            ControlFlow::Continue(()) => Ok(()),
+            ControlFlow::Break(revert) => {
+                self.inner.inner = snapshot;
+                self.reverted = true;
+                Err(revert)
+            }
+        }
+    }
+}
+
+impl<'a, T: Veto> Deref for RevertBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.inner
+    }
+}
+
+impl<'a, T: Veto> DerefMut for RevertBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner.inner
+    }
+}
+
+impl<'a, T: Veto> Drop for RevertBorrow<'a, T> {
+    fn drop(&mut self) {
+        // a change interrupted by a panic is reverted without asking, and
+        // deferred callbacks wait for the next borrow
+        if self.unwinding.interrupted() {
+            if let Some(snapshot) = self.snapshot.take() {
+                self.inner.inner = snapshot;
+            }
+            return;
+        }
+        // deferred callbacks also wait for a kept change
+        if self.decide().is_err() {
+            return;
+        }
+        self.inner.run_deferred();
+        self.inner.publish(self.location);
+        #[cfg(feature = "tokio")]
+        self.inner.notify_changed();
+        #[cfg(feature = "tokio")]
+        self.inner.broadcast_change(self.location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug)]
+    struct Accounts(Vec<i32>);
+
+    impl Veto for Accounts {
+        fn decide(&mut self) -> ControlFlow<Revert> {
+            if self.0.iter().any(|v| *v < 0) {
+                ControlFlow::Break(Revert)
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+    }
+
+    #[test]
+    fn revert_rejected_change() {
+        let mut accounts = MutGuard::new(Accounts(vec![10, 0]));
+
+        {
+            let mut a = accounts.guard_or_revert();
+            a.0[0] -= 5;
+            a.0[1] += 5;
+        }
+        assert_eq!(accounts.0, vec![5, 5]);
+
+        {
+            let mut a = accounts.guard_or_revert();
+            a.0[0] -= 20;
+            a.0[1] += 20;
+        }
+        assert_eq!(accounts.0, vec![5, 5]);
+    }
+
+    #[test]
+    fn commit() {
+        let mut accounts = MutGuard::new(Accounts(vec![10, 0]));
+
+        let mut a = accounts.guard_or_revert();
+        a.0[1] += 1;
+        assert_eq!(a.commit(), Ok(()));
+
+        let mut a = accounts.guard_or_revert();
+        a.0[1] -= 2;
+        assert_eq!(a.commit(), Err(Revert));
+
+        assert_eq!(accounts.0, vec![10, 1]);
+    }
+
+    #[test]
+    fn interrupted_change() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut accounts = MutGuard::new(Accounts(vec![10, 0]));
+        accounts.defer(|a: &mut Accounts| a.0.push(0));
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut a = accounts.guard_or_revert();
+            a.0[0] -= 5;
+            panic!("interrupted");
+        }));
+        assert!(res.is_err());
+        assert_eq!(accounts.0, vec![10, 0]);
+
+        // the deferred callback runs after the next borrow
+        accounts.guard_or_revert().0[1] += 1;
+        assert_eq!(accounts.0, vec![10, 1, 0]);
+    }
+
+    #[test]
+    fn kept_changes_only() {
+        use publish::Change;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let mut accounts = MutGuard::new(Accounts(vec![10, 0]));
+        let published = Arc::new(AtomicUsize::new(0));
+        let counter = published.clone();
+        accounts.publish_to(move |_: &Change<'_, Accounts>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        accounts.defer(|a: &mut Accounts| a.0.push(0));
+
+        accounts.guard_or_revert().0[0] -= 20;
+        assert_eq!(accounts.0, vec![10, 0]);
+        assert_eq!(published.load(Ordering::SeqCst), 0);
+
+        let mut a = accounts.guard_or_revert();
+        a.0[0] -= 20;
+        assert_eq!(a.commit(), Err(Revert));
+        assert_eq!(accounts.0, vec![10, 0]);
+        assert_eq!(published.load(Ordering::SeqCst), 0);
+
+        let mut a = accounts.guard_or_revert();
+        a.0[0] -= 5;
+        assert_eq!(a.commit(), Ok(()));
+        assert_eq!(accounts.0, vec![5, 0, 0]);
+        assert_eq!(published.load(Ordering::SeqCst), 1);
+
+        accounts.guard_or_revert().0[1] += 5;
+        assert_eq!(accounts.0, vec![5, 5, 0]);
+        assert_eq!(published.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn broadcast_kept_changes() {
+        let mut accounts = MutGuard::new(Accounts(vec![10, 0]));
+        let mut events = accounts.broadcast_changes(4);
+
+        accounts.guard_or_revert().0[0] -= 20;
+        assert!(events.try_recv().is_err());
+
+        let line = line!() + 1;
+        accounts.guard_or_revert().0[0] -= 5;
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.location().line(), line);
+        assert!(events.try_recv().is_err());
+    }
+}
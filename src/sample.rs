@@ -0,0 +1,191 @@
+//! Checking a sample of the borrows
+//!
+//! on hot paths, checking the element after every single mutation can cost
+//! too much. A `MutGuard` can be configured to only check one borrow out
+//! of N, either deterministically with `sample_every()`, or randomly with
+//! `sample_randomly()`. `Guard::normalize()` still runs after every borrow,
+//! and `validate_now()` checks the element whatever the sampling.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! #
+//! # fn main() {
+//! let mut hits = MutGuard::wrap(Vec::new(), |v: &mut Vec<u64>| {
+//!   assert!(v.windows(2).all(|w| w[0] <= w[1]), "unsorted hits");
+//! });
+//! hits.sample_randomly(100);
+//!
+//! for i in 0..1000 {
+//!   hits.guard().push(i);
+//! }
+//!
+//! hits.validate_now();
+//! # }
+//! ```
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::num::NonZeroU64;
+use std::panic::Location;
+
+use super::{Guard, MutGuard, WrappedGuard, ARMED};
+
+/// which borrows are checked
+pub(crate) enum Sampling {
+    /// every `n`th borrow
+    Every { n: NonZeroU64, borrows: u64 },
+    /// each borrow with a probability of `1 / n`, with a xorshift generator
+    Random { n: NonZeroU64, state: u64 },
+}
+
+impl Sampling {
+    /// returns true if the current borrow should be checked
+    fn tick(&mut self) -> bool {
+        match *self {
+            Sampling::Every { n, ref mut borrows } => {
+                *borrows += 1;
+                if *borrows == n.get() {
+                    *borrows = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+            Sampling::Random { n, ref mut state } => {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                (*state).is_multiple_of(n.get())
+            }
+        }
+    }
+}
+
+fn rate(n: u64) -> NonZeroU64 {
+    NonZeroU64::new(n).expect("the sampling rate must be 1 or more")
+}
+
+impl<T> MutGuard<T> {
+    /// only checks every `n`th mutable borrow
+    ///
+    /// panics if `n` is 0
+    pub fn sample_every(&mut self, n: u64) {
+        self.settings.sample = Some(Sampling::Every {
+            n: rate(n),
+            borrows: 0,
+        });
+    }
+
+    /// checks each mutable borrow with a probability of `1 / n`
+    ///
+    /// panics if `n` is 0
+    pub fn sample_randomly(&mut self, n: u64) {
+        // xorshift needs a non zero state
+        let seed = RandomState::new().build_hasher().finish() | 1;
+        self.settings.sample = Some(Sampling::Random {
+            n: rate(n),
+            state: seed,
+        });
+    }
+
+    /// checks every mutable borrow again
+    pub fn sample_all(&mut self) {
+        self.settings.sample = None;
+    }
+
+    /// returns true if the current borrow should be checked
+    pub(crate) fn sampled(&mut self) -> bool {
+        match self.settings.sample {
+            Some(ref mut sampling) => sampling.tick(),
+            None => true,
+        }
+    }
+}
+
+impl<T: Guard> MutGuard<T> {
+    /// normalizes and checks the element now, even if sampling would skip
+    /// the next borrow
+    #[track_caller]
+    pub fn validate_now(&mut self) {
+        self.inner.normalize();
+        if ARMED {
+            self.timed_finish(Location::caller(), None);
+        }
+    }
+}
+
+impl<T, F> WrappedGuard<T, F> {
+    /// see `MutGuard::sample_every()`
+    pub fn sample_every(&mut self, n: u64) {
+        self.guard.sample_every(n);
+    }
+
+    /// see `MutGuard::sample_randomly()`
+    pub fn sample_randomly(&mut self, n: u64) {
+        self.guard.sample_randomly(n);
+    }
+
+    /// see `MutGuard::sample_all()`
+    pub fn sample_all(&mut self) {
+        self.guard.sample_all();
+    }
+}
+
+impl<T, F: FnMut(&mut T)> WrappedGuard<T, F> {
+    /// see `MutGuard::validate_now()`
+    #[track_caller]
+    pub fn validate_now(&mut self) {
+        self.guard.validate_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn deterministic() {
+        let checks = ::std::cell::Cell::new(0);
+        let mut v = MutGuard::wrap(0u32, |_| checks.set(checks.get() + 1));
+        v.sample_every(4);
+
+        for _ in 0..10 {
+            *v.guard() += 1;
+        }
+        assert_eq!(checks.get(), 2);
+
+        v.validate_now();
+        assert_eq!(checks.get(), 3);
+
+        v.sample_all();
+        *v.guard() += 1;
+        assert_eq!(checks.get(), 4);
+    }
+
+    #[test]
+    fn random() {
+        let checks = ::std::cell::Cell::new(0);
+        let mut v = MutGuard::wrap(0u32, |_| checks.set(checks.get() + 1));
+        v.sample_randomly(10);
+
+        for _ in 0..10_000 {
+            *v.guard() += 1;
+        }
+        assert!(
+            checks.get() > 500 && checks.get() < 2000,
+            "{}",
+            checks.get()
+        );
+    }
+
+    #[test]
+    fn validate_now_panics() {
+        let mut v = MutGuard::wrap(0u32, |v| assert!(*v < 5, "too large"));
+        v.sample_every(1000);
+
+        *v.guard() = 10;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| v.validate_now()));
+        assert!(res.is_err());
+    }
+}
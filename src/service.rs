@@ -0,0 +1,422 @@
+//! Guarded application state for web services
+//!
+//! *Note*: this module requires the `axum` or `actix` feature.
+//!
+//! `GuardedState` (with axum) and `GuardedData` (with actix-web) share an
+//! element implementing `Guard` between the handlers of a service, in a
+//! `GuardedRwLock` behind an `Arc`: handlers read it concurrently, and the
+//! element is checked every time a write lock is released.
+//!
+//! With axum, `GuardedState` is an extractor: a handler takes it as an
+//! argument, and it is cloned from the router's state, either directly or
+//! from a field of the application state through `FromRef`.
+//!
+//! With actix-web, `GuardedData` is registered with `App::app_data()`, like
+//! a `web::Data`, and extracted by handlers. `guard()` gives mutable access,
+//! checked when the returned borrow is dropped at the end of the handler.
+#[cfg(feature = "axum")]
+use std::convert::Infallible;
+use std::future;
+#[cfg(feature = "axum")]
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::Arc;
+
+#[cfg(feature = "actix")]
+use actix_web::dev::Payload;
+#[cfg(feature = "actix")]
+use actix_web::error::ErrorInternalServerError;
+#[cfg(feature = "actix")]
+use actix_web::{web, FromRequest, HttpRequest};
+#[cfg(feature = "axum")]
+use axum::extract::{FromRef, FromRequestParts};
+#[cfg(feature = "axum")]
+use axum::http::request::Parts;
+
+use super::sync::GuardedRwLock;
+#[cfg(feature = "actix")]
+use super::sync::GuardedRwLockWriteGuard;
+use super::Guard;
+
+#[cfg(feature = "axum")]
+/// an element implementing `Guard`, shared between the handlers of a
+/// service. Cloning it gives access to the same element
+///
+/// the lock is reached through `Deref`: `read()` for shared access, and
+/// `write()` or `upgradeable_read()` to modify the element
+///
+/// ```rust,edition2021
+/// # extern crate axum;
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::service::GuardedState;
+/// use axum::extract::FromRef;
+/// use axum::routing::{get, post};
+/// use axum::Router;
+///
+/// #[derive(Debug)]
+/// struct Limits {
+///   max_connections: u32,
+///   reserved: u32,
+/// }
+///
+/// impl Guard for Limits {
+///   fn finish(&mut self) {
+///     assert!(self.reserved <= self.max_connections, "too many reserved connections");
+///   }
+/// }
+///
+/// #[derive(Clone)]
+/// struct App {
+///   limits: GuardedState<Limits>,
+///   name: String,
+/// }
+///
+/// impl FromRef<App> for GuardedState<Limits> {
+///   fn from_ref(app: &App) -> GuardedState<Limits> {
+///     app.limits.clone()
+///   }
+/// }
+///
+/// async fn max_connections(limits: GuardedState<Limits>) -> String {
+///   limits.read().unwrap().max_connections.to_string()
+/// }
+///
+/// async fn reserve(limits: GuardedState<Limits>) {
+///   limits.write().unwrap().reserved += 1;
+/// }
+///
+/// # fn main() {
+/// let app = App {
+///   limits: GuardedState::new(Limits { max_connections: 10, reserved: 0 }),
+///   name: "proxy".to_string(),
+/// };
+///
+/// let router: Router = Router::new()
+///   .route("/limits", get(max_connections))
+///   .route("/reserve", post(reserve))
+///   .with_state(app);
+/// # }
+/// ```
+pub struct GuardedState<T: Guard> {
+    lock: Arc<GuardedRwLock<T>>,
+}
+
+#[cfg(feature = "axum")]
+impl<T: Guard> GuardedState<T> {
+    pub fn new(value: T) -> GuardedState<T> {
+        GuardedState {
+            lock: Arc::new(GuardedRwLock::new(value)),
+        }
+    }
+
+    /// the lock shared by the clones of this state
+    pub fn as_arc(&self) -> &Arc<GuardedRwLock<T>> {
+        &self.lock
+    }
+}
+
+#[cfg(feature = "axum")]
+impl<T: Guard> Clone for GuardedState<T> {
+    fn clone(&self) -> GuardedState<T> {
+        GuardedState {
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "axum")]
+impl<T: Guard> From<Arc<GuardedRwLock<T>>> for GuardedState<T> {
+    fn from(lock: Arc<GuardedRwLock<T>>) -> GuardedState<T> {
+        GuardedState { lock }
+    }
+}
+
+#[cfg(feature = "axum")]
+impl<T: Guard> Deref for GuardedState<T> {
+    type Target = GuardedRwLock<T>;
+
+    fn deref(&self) -> &GuardedRwLock<T> {
+        &self.lock
+    }
+}
+
+#[cfg(feature = "axum")]
+impl<T, S> FromRequestParts<S> for GuardedState<T>
+where
+    T: Guard + Send + Sync,
+    S: Send + Sync,
+    GuardedState<T>: FromRef<S>,
+{
+    type Rejection = Infallible;
+
+    fn from_request_parts(
+        _parts: &mut Parts,
+        state: &S,
+    ) -> impl Future<Output = Result<GuardedState<T>, Infallible>> + Send {
+        future::ready(Ok(GuardedState::from_ref(state)))
+    }
+}
+
+/// an element implementing `Guard`, shared between the handlers of an
+/// actix-web service, and registered with `App::app_data()`. Cloning it
+/// gives access to the same element
+///
+/// the lock is reached through `Deref`, like with `GuardedState`
+///
+/// ```rust,no_run,edition2021
+/// # extern crate actix_web;
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::service::GuardedData;
+/// use actix_web::{web, App, HttpServer};
+///
+/// #[derive(Debug)]
+/// struct Inventory {
+///   stock: u32,
+///   reserved: u32,
+/// }
+///
+/// impl Guard for Inventory {
+///   fn finish(&mut self) {
+///     assert!(self.reserved <= self.stock, "reserved more than the stock");
+///   }
+/// }
+///
+/// async fn reserve(inventory: GuardedData<Inventory>) -> actix_web::Result<String> {
+///   let mut inventory = inventory.guard()?;
+///   inventory.reserved += 1;
+///   Ok(inventory.reserved.to_string())
+/// }
+///
+/// # async fn run() -> std::io::Result<()> {
+/// let inventory = GuardedData::new(Inventory { stock: 10, reserved: 0 });
+///
+/// HttpServer::new(move || {
+///   App::new()
+///     .app_data(inventory.clone())
+///     .route("/reserve", web::post().to(reserve))
+/// })
+/// .bind(("127.0.0.1", 8080))?
+/// .run()
+/// .await
+/// # }
+/// # fn main() {}
+/// ```
+#[cfg(feature = "actix")]
+pub struct GuardedData<T: Guard> {
+    data: web::Data<GuardedRwLock<T>>,
+}
+
+#[cfg(feature = "actix")]
+impl<T: Guard> GuardedData<T> {
+    pub fn new(value: T) -> GuardedData<T> {
+        GuardedData {
+            data: web::Data::new(GuardedRwLock::new(value)),
+        }
+    }
+
+    /// mutable access to the element, checked when the returned borrow is
+    /// dropped. If a previous check failed, the lock is poisoned, and this
+    /// returns an internal server error until the element is fixed with
+    /// `guard_poisoned()`
+    pub fn guard(&self) -> actix_web::Result<GuardedRwLockWriteGuard<'_, T>> {
+        match self.data.write() {
+            Ok(guard) => Ok(guard),
+            Err(poisoned) => {
+                // the element was not modified here, and the check would fail
+                // again
+                poisoned.into_inner().release_unchecked();
+                Err(ErrorInternalServerError("the guarded state is poisoned"))
+            }
+        }
+    }
+
+    /// like `guard()`, but also gives access to the element when the lock is
+    /// poisoned, to fix it. Once the checks pass when the returned borrow is
+    /// dropped, `guard()` succeeds again. See `GuardedRwLock::repair()`
+    pub fn guard_poisoned(&self) -> GuardedRwLockWriteGuard<'_, T> {
+        self.data.repair()
+    }
+
+    /// the `web::Data` shared by the clones of this state
+    pub fn as_data(&self) -> &web::Data<GuardedRwLock<T>> {
+        &self.data
+    }
+}
+
+#[cfg(feature = "actix")]
+impl<T: Guard> Clone for GuardedData<T> {
+    fn clone(&self) -> GuardedData<T> {
+        GuardedData {
+            data: self.data.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
+impl<T: Guard> From<web::Data<GuardedRwLock<T>>> for GuardedData<T> {
+    fn from(data: web::Data<GuardedRwLock<T>>) -> GuardedData<T> {
+        GuardedData { data }
+    }
+}
+
+#[cfg(feature = "actix")]
+impl<T: Guard> From<Arc<GuardedRwLock<T>>> for GuardedData<T> {
+    fn from(lock: Arc<GuardedRwLock<T>>) -> GuardedData<T> {
+        GuardedData {
+            data: web::Data::from(lock),
+        }
+    }
+}
+
+#[cfg(feature = "actix")]
+impl<T: Guard> Deref for GuardedData<T> {
+    type Target = GuardedRwLock<T>;
+
+    fn deref(&self) -> &GuardedRwLock<T> {
+        &self.data
+    }
+}
+
+/// extracted from a `GuardedData<T>` or a `web::Data<GuardedRwLock<T>>`
+/// registered with `App::app_data()`
+#[cfg(feature = "actix")]
+impl<T: 'static + Guard> FromRequest for GuardedData<T> {
+    type Error = actix_web::Error;
+    type Future = future::Ready<actix_web::Result<GuardedData<T>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let data = match req.app_data::<GuardedData<T>>() {
+            Some(data) => Some(data.clone()),
+            None => req
+                .app_data::<web::Data<GuardedRwLock<T>>>()
+                .map(|data| GuardedData::from(data.clone())),
+        };
+        future::ready(data.ok_or_else(|| {
+            ErrorInternalServerError(format!(
+                "GuardedData<{}> is not registered with App::app_data()",
+                ::std::any::type_name::<T>()
+            ))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "actix")]
+    use actix_web::test::TestRequest;
+    #[cfg(feature = "axum")]
+    use axum::http::Request;
+    #[cfg(feature = "axum")]
+    use axum::routing::get;
+    #[cfg(feature = "axum")]
+    use axum::Router;
+    use std::future::Future;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    #[derive(Debug)]
+    struct Quota {
+        used: u32,
+        max: u32,
+    }
+
+    impl Guard for Quota {
+        fn finish(&mut self) {
+            assert!(self.used <= self.max, "quota exceeded");
+        }
+    }
+
+    /// the extractors are ready immediately
+    fn ready<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the extractor should be ready"),
+        }
+    }
+
+    #[cfg(feature = "axum")]
+    #[derive(Clone)]
+    struct App {
+        quota: GuardedState<Quota>,
+    }
+
+    #[cfg(feature = "axum")]
+    impl FromRef<App> for GuardedState<Quota> {
+        fn from_ref(app: &App) -> GuardedState<Quota> {
+            app.quota.clone()
+        }
+    }
+
+    #[cfg(feature = "axum")]
+    fn extract(app: &App) -> GuardedState<Quota> {
+        let (mut parts, _) = Request::builder().body(()).unwrap().into_parts();
+        match ready(GuardedState::from_request_parts(&mut parts, app)) {
+            Ok(state) => state,
+            Err(e) => match e {},
+        }
+    }
+
+    #[cfg(feature = "axum")]
+    #[test]
+    fn extract_from_app() {
+        let app = App {
+            quota: GuardedState::new(Quota { used: 0, max: 2 }),
+        };
+        // the extractor is accepted by handlers
+        fn used(quota: GuardedState<Quota>) -> future::Ready<String> {
+            future::ready(quota.read().unwrap().used.to_string())
+        }
+        let _router: Router = Router::new().route("/", get(used)).with_state(app.clone());
+
+        let quota = extract(&app);
+        quota.write().unwrap().used += 2;
+        assert_eq!(app.quota.read().unwrap().used, 2);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            extract(&app).write().unwrap().used += 1
+        }));
+        assert!(res.is_err());
+        // a failed check poisons the lock, like any panic
+        assert!(app.quota.read().is_err());
+    }
+
+    #[cfg(feature = "actix")]
+    #[test]
+    fn extract_from_app_data() {
+        let quota = GuardedData::new(Quota { used: 0, max: 2 });
+        let req = TestRequest::default()
+            .app_data(quota.clone())
+            .to_http_request();
+        let extracted = ready(GuardedData::<Quota>::extract(&req)).unwrap();
+        extracted.guard().unwrap().used += 1;
+        assert_eq!(quota.read().unwrap().used, 1);
+
+        // a plain `web::Data` works too
+        let req = TestRequest::default()
+            .app_data(quota.as_data().clone())
+            .to_http_request();
+        let extracted = ready(GuardedData::<Quota>::extract(&req)).unwrap();
+        assert_eq!(extracted.read().unwrap().used, 1);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| extracted.guard().unwrap().used += 2));
+        assert!(res.is_err());
+        let error = quota.guard().err().unwrap();
+        assert_eq!(error.to_string(), "the guarded state is poisoned");
+
+        // still poisoned after a failed repair
+        let res = panic::catch_unwind(AssertUnwindSafe(|| quota.guard_poisoned().used += 1));
+        assert!(res.is_err());
+        assert!(quota.guard().is_err());
+        quota.guard_poisoned().used = 0;
+        quota.guard().unwrap().used += 2;
+        assert_eq!(quota.read().unwrap().used, 2);
+
+        let req = TestRequest::default().to_http_request();
+        let error = ready(GuardedData::<Quota>::extract(&req)).err().unwrap();
+        assert!(error.to_string().contains("is not registered"));
+    }
+}
@@ -0,0 +1,262 @@
+//! Guarded values in shared memory
+//!
+//! *Note*: this module requires the `memmap` feature.
+//!
+//! a `SharedRegion` maps a file holding a single `#[repr(C)]` value, so
+//! that several processes can share it. The value is checked after every
+//! mutable borrow, and `flush()` writes the changes back to the file
+//! (like `msync`).
+//!
+//! The region does not synchronize processes: if several of them write to
+//! it, they must coordinate, for example with a lock file or atomics
+//! stored in the value. Since the file can change under the process's
+//! feet, `create` and `open` are `unsafe`, like `memmap2::MmapMut::map_mut`.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::shm::*;
+//! #
+//! #[repr(C)]
+//! #[derive(Clone, Copy, Debug)]
+//! struct Stats {
+//!   requests: u64,
+//!   errors: u64,
+//! }
+//!
+//! unsafe impl Plain for Stats {}
+//!
+//! impl Guard for Stats {
+//!   fn finish(&mut self) {
+//!     assert!(self.errors <= self.requests, "more errors than requests");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! # let path = std::env::temp_dir().join(format!("mutguard-shm-doc-{}", std::process::id()));
+//! let mut stats = unsafe { SharedRegion::create(&path, Stats { requests: 0, errors: 0 }) }.unwrap();
+//! stats.guard().requests += 1;
+//! stats.flush().unwrap();
+//!
+//! // as seen by another process
+//! let other = unsafe { SharedRegion::<Stats>::open(&path) }.unwrap();
+//! assert_eq!(other.requests, 1);
+//! # std::fs::remove_file(&path).unwrap();
+//! # }
+//! ```
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut, Drop};
+use std::path::Path;
+
+use memmap2::MmapMut;
+
+use super::hold::Unwinding;
+use super::revalidate::{self, Revalidate};
+use super::violation::Violation;
+use super::{run_guard, Guard};
+
+/// types that can be stored in a `SharedRegion`
+///
+/// # Safety
+///
+/// the type must be `#[repr(C)]` (or `#[repr(transparent)]`), contain no
+/// pointers or references, and every bit pattern must be a valid value,
+/// since the mapped file can contain anything
+pub unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u16 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl Plain for i8 {}
+unsafe impl Plain for i16 {}
+unsafe impl Plain for i32 {}
+unsafe impl Plain for i64 {}
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+/// guarded value stored in a memory mapped file
+pub struct SharedRegion<T: Plain + Guard> {
+    map: MmapMut,
+    _file: File,
+    value: PhantomData<T>,
+}
+
+impl<T: Plain + Guard> SharedRegion<T> {
+    /// creates (or truncates) the file at `path` and stores `value` in it
+    ///
+    /// # Safety
+    ///
+    /// while a reference to the value is alive, nothing else (another
+    /// region on the same file, another process, or a file handle) may write
+    /// to the file
+    pub unsafe fn create<P: AsRef<Path>>(path: P, value: T) -> io::Result<SharedRegion<T>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(mem::size_of::<T>() as u64)?;
+
+        let mut region: SharedRegion<T> = unsafe { SharedRegion::map(file)? };
+        *region.value_mut() = value;
+        region.flush()?;
+        Ok(region)
+    }
+
+    /// maps an existing file, which must have the size of `T`. The value is
+    /// not checked
+    ///
+    /// # Safety
+    ///
+    /// same as `create`: while a reference to the value is alive, nothing
+    /// else may write to the file
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> io::Result<SharedRegion<T>> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() != mem::size_of::<T>() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the file size does not match the size of the shared value",
+            ));
+        }
+
+        unsafe { SharedRegion::map(file) }
+    }
+
+    /// # Safety
+    ///
+    /// the caller upholds the contract of `create` and `open`
+    unsafe fn map(file: File) -> io::Result<SharedRegion<T>> {
+        // the caller guarantees the file is not written to while references
+        // are alive, and its size was checked. Mappings are page aligned,
+        // which covers `T`'s alignment
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(SharedRegion {
+            map,
+            _file: file,
+            value: PhantomData,
+        })
+    }
+
+    fn value_mut(&mut self) -> &mut T {
+        unsafe { &mut *(self.map.as_mut_ptr() as *mut T) }
+    }
+
+    /// call this method to get mutable access to the shared value. It is
+    /// checked when the returned borrow is dropped
+    pub fn guard(&mut self) -> RegionBorrow<'_, T> {
+        RegionBorrow {
+            region: self,
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    /// writes modified pages to the file, waiting until it is done
+    pub fn flush(&self) -> io::Result<()> {
+        self.map.flush()
+    }
+
+    /// starts writing modified pages to the file, without waiting
+    pub fn flush_async(&self) -> io::Result<()> {
+        self.map.flush_async()
+    }
+}
+
+impl<T: Plain + Guard + Send + Sync> Revalidate for SharedRegion<T> {
+    /// checks a copy of the value, which another process may have changed
+    fn revalidate(&self) -> Result<(), Violation> {
+        let mut value = **self;
+        revalidate::check(&mut value)
+    }
+}
+
+impl<T: Plain + Guard> Deref for SharedRegion<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(self.map.as_ptr() as *const T) }
+    }
+}
+
+/// mutable borrow of a `SharedRegion`'s value
+pub struct RegionBorrow<'a, T: 'a + Plain + Guard> {
+    region: &'a mut SharedRegion<T>,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Plain + Guard> Deref for RegionBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.region
+    }
+}
+
+impl<'a, T: Plain + Guard> DerefMut for RegionBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.region.value_mut()
+    }
+}
+
+impl<'a, T: Plain + Guard> Drop for RegionBorrow<'a, T> {
+    fn drop(&mut self) {
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(self.region.value_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug)]
+    struct Range {
+        start: u32,
+        end: u32,
+    }
+
+    unsafe impl Plain for Range {}
+
+    impl Guard for Range {
+        fn finish(&mut self) {
+            assert!(self.start <= self.end, "invalid range");
+        }
+    }
+
+    #[test]
+    fn shared_region() {
+        let path = env::temp_dir().join(format!("mutguard-shm-{}", process::id()));
+        // `b` is only read once `a`'s borrow is dropped
+        let mut a = unsafe { SharedRegion::create(&path, Range { start: 0, end: 10 }) }.unwrap();
+        let b = unsafe { SharedRegion::<Range>::open(&path) }.unwrap();
+
+        a.guard().end = 20;
+        a.flush().unwrap();
+        assert_eq!(b.end, 20);
+
+        drop((a, b));
+
+        fs::write(&path, b"abc").unwrap();
+        assert!(unsafe { SharedRegion::<Range>::open(&path) }.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid range")]
+    fn violation() {
+        let path = env::temp_dir().join(format!("mutguard-shm-violation-{}", process::id()));
+        let mut region =
+            unsafe { SharedRegion::create(&path, Range { start: 0, end: 10 }) }.unwrap();
+        fs::remove_file(&path).unwrap();
+        region.guard().start = 11;
+    }
+}
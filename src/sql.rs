@@ -0,0 +1,207 @@
+//! Mirroring guarded state to Postgres with `sqlx`
+//!
+//! a type deriving `TrackFields` and `SqlRow` can be borrowed with
+//! `MutGuard::guard_tracked()`, then written back with an `UPDATE` setting
+//! only the columns of the fields that changed.
+//!
+//! ```rust,no_run,edition2021
+//! # extern crate mut_guard;
+//! # extern crate sqlx;
+//! # use mut_guard::*;
+//! # use mut_guard::sql::Row;
+//! #
+//! #[derive(Debug, TrackFields, SqlRow)]
+//! #[sql(table = "accounts")]
+//! struct Account {
+//!   #[sql(key)]
+//!   id: i64,
+//!   balance: i64,
+//!   #[sql(column = "display_name")]
+//!   name: String,
+//! }
+//!
+//! impl Guard for Account {
+//!   fn finish(&mut self) {
+//!     assert!(self.balance >= 0, "negative balance");
+//!   }
+//! }
+//!
+//! # async fn run(pool: sqlx::PgPool) -> Result<(), sqlx::Error> {
+//! let mut account = MutGuard::new(Account { id: 1, balance: 10, name: "alice".into() });
+//!
+//! let changed = {
+//!   let mut a = account.guard_tracked();
+//!   *a.balance_mut() -= 4;
+//!   *a.changed()
+//! };
+//!
+//! // UPDATE "accounts" SET "balance" = $1 WHERE "id" = $2
+//! if let Some(update) = account.update(&changed) {
+//!   update.query().execute(&pool).await?;
+//! }
+//! # Ok(())
+//! # }
+//! # fn main() {}
+//! ```
+use std::fmt::Write;
+
+use sqlx::postgres::{PgArguments, Postgres};
+use sqlx::query::Query;
+
+use super::dirty::{FieldSet, Fields};
+
+/// query built by `Row::update()`
+pub type PgQuery<'q> = Query<'q, Postgres, PgArguments>;
+
+/// type stored as a row of a Postgres table, one column per field.
+/// Implemented with `#[derive(SqlRow)]`
+pub trait Row: Fields {
+    const TABLE: &'static str;
+    /// column of each field, in the order of `Fields::FIELDS`
+    const COLUMNS: &'static [&'static str];
+    /// index of the primary key field
+    const KEY: usize;
+
+    /// binds the value of the field at `index` to the next parameter of
+    /// `query`
+    fn bind_field<'q>(&'q self, index: usize, query: PgQuery<'q>) -> PgQuery<'q>;
+
+    /// `UPDATE` statement for the `changed` fields, or `None` if no column
+    /// changed. It is also `None` if the key changed: the statement would
+    /// select the row by its new key, and overwrite another row
+    fn update(&self, changed: &FieldSet) -> Option<Update<'_, Self>>
+    where
+        Self: Sized,
+    {
+        if changed.is_empty() || changed.contains_index(Self::KEY) {
+            return None;
+        }
+
+        let mut sql = format!("UPDATE {} SET ", quoted(Self::TABLE));
+        let mut param = 0;
+        for index in 0..Self::COLUMNS.len() {
+            if changed.contains_index(index) {
+                param += 1;
+                if param > 1 {
+                    sql.push_str(", ");
+                }
+                let _ = write!(sql, "{} = ${}", quoted(Self::COLUMNS[index]), param);
+            }
+        }
+        let _ = write!(
+            sql,
+            " WHERE {} = ${}",
+            quoted(Self::COLUMNS[Self::KEY]),
+            param + 1
+        );
+
+        Some(Update {
+            row: self,
+            sql,
+            changed: *changed,
+        })
+    }
+}
+
+/// quotes an identifier
+fn quoted(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// `UPDATE` statement for a row, returned by `Row::update()`
+pub struct Update<'a, T: 'a + Row> {
+    row: &'a T,
+    sql: String,
+    changed: FieldSet,
+}
+
+impl<'a, T: Row> Update<'a, T> {
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// the statement, with the changed values and the key bound
+    pub fn query(&self) -> PgQuery<'_> {
+        let mut query = sqlx::query(&self.sql);
+        for index in 0..T::COLUMNS.len() {
+            if self.changed.contains_index(index) {
+                query = self.row.bind_field(index, query);
+            }
+        }
+        self.row.bind_field(T::KEY, query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Guard, MutGuard, SqlRow, TrackFields};
+
+    #[derive(Debug, TrackFields, SqlRow)]
+    #[sql(table = "items")]
+    struct Item {
+        #[sql(key)]
+        id: i32,
+        stock: i32,
+        #[sql(column = "label")]
+        name: String,
+        price: i64,
+    }
+
+    impl Guard for Item {
+        fn finish(&mut self) {
+            assert!(self.stock >= 0);
+        }
+    }
+
+    #[test]
+    fn update_changed_columns() {
+        let mut item = MutGuard::new(Item {
+            id: 1,
+            stock: 3,
+            name: "pen".to_string(),
+            price: 2,
+        });
+
+        let changed = {
+            let mut i = item.guard_tracked();
+            *i.stock_mut() -= 1;
+            i.name_mut().push('s');
+            *i.changed()
+        };
+        assert_eq!(item.name, "pens");
+
+        let update = item.update(&changed).unwrap();
+        assert_eq!(
+            update.sql(),
+            "UPDATE \"items\" SET \"stock\" = $1, \"label\" = $2 WHERE \"id\" = $3"
+        );
+        let _ = update.query();
+
+        let unchanged = {
+            let mut i = item.guard_tracked();
+            *i.id_mut() = 1;
+            *i.changed()
+        };
+        assert!(item.update(&unchanged).is_none());
+        assert_eq!(item.price, 2);
+    }
+
+    #[test]
+    fn update_changed_key() {
+        let mut item = MutGuard::new(Item {
+            id: 1,
+            stock: 3,
+            name: "pen".to_string(),
+            price: 2,
+        });
+
+        let changed = {
+            let mut i = item.guard_tracked();
+            *i.id_mut() = 2;
+            *i.stock_mut() -= 1;
+            *i.changed()
+        };
+        assert!(item.update(&changed).is_none());
+    }
+}
@@ -0,0 +1,185 @@
+//! Guards with a stable address
+//!
+//! *Note*: this module requires the `stable-deref` feature.
+//!
+//! Self-referential structures (`owning_ref`, `yoke`, zero-copy caches)
+//! need an owner implementing `stable_deref_trait::StableDeref`: the
+//! element it dereferences to must not move when the owner moves.
+//! `MutGuard<Box<T>>` cannot implement it, since it dereferences to the
+//! `Box`, stored inline in the `MutGuard`. `StableGuard<P>` holds a
+//! `MutGuard` around a `StableDeref` pointer (`Box`, `Vec`, `String`...),
+//! dereferences to the pointed element and checks it after each mutable
+//! borrow, so it implements `StableDeref` itself.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # extern crate stable_deref_trait;
+//! # use mut_guard::*;
+//! # use mut_guard::stable::StableGuard;
+//! use stable_deref_trait::StableDeref;
+//!
+//! #[derive(Debug)]
+//! struct Frame(Vec<u8>);
+//!
+//! impl Guard for Frame {
+//!   fn finish(&mut self) {
+//!     assert!(self.0.len() <= 1500, "frame above the MTU");
+//!   }
+//! }
+//!
+//! // what self-referential structures require from their owner
+//! fn owner<P: StableDeref>(owner: P) -> P {
+//!   owner
+//! }
+//!
+//! # fn main() {
+//! let mut frame = StableGuard::new(Box::new(Frame(vec![])));
+//! frame.guard().0.extend_from_slice(b"hello");
+//!
+//! let address: *const Frame = &*frame;
+//! let moved = owner(frame);
+//! assert_eq!(address, &*moved as *const Frame);
+//! # }
+//! ```
+use std::ops::{Deref, DerefMut};
+
+use stable_deref_trait::StableDeref;
+
+use super::{Guard, GuardId, MutGuard, MutGuardBorrow};
+
+/// stores a `StableDeref` pointer, and checks the element it points to
+/// after every time it is mutably borrowed through `guard()`
+pub struct StableGuard<P> {
+    guard: MutGuard<Stable<P>>,
+}
+
+struct Stable<P>(P);
+
+impl<P: DerefMut> Guard for Stable<P>
+where
+    P::Target: Guard,
+{
+    fn normalize(&mut self) {
+        self.0.normalize();
+    }
+
+    fn finish(&mut self) {
+        self.0.finish();
+    }
+
+    fn finish_slow(&mut self) {
+        self.0.finish_slow();
+    }
+}
+
+impl<P: StableDeref + DerefMut> StableGuard<P>
+where
+    P::Target: Guard,
+{
+    pub fn new(inner: P) -> StableGuard<P> {
+        StableGuard {
+            guard: MutGuard::new(Stable(inner)),
+        }
+    }
+
+    /// call this method to get mutable access to the pointed element
+    #[track_caller]
+    pub fn guard(&mut self) -> StableBorrow<'_, P> {
+        StableBorrow {
+            inner: self.guard.guard(),
+        }
+    }
+
+    /// see `MutGuard::set_label()`
+    pub fn set_label<S: Into<String>>(&mut self, label: S) {
+        self.guard.set_label(label);
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.guard.label()
+    }
+
+    /// see `MutGuard::id()`
+    pub fn id(&self) -> GuardId {
+        self.guard.id()
+    }
+
+    /// returns the pointer, consuming the StableGuard
+    pub fn into_inner(self) -> P {
+        self.guard.into_inner().0
+    }
+}
+
+impl<P: StableDeref> Deref for StableGuard<P> {
+    type Target = P::Target;
+
+    fn deref(&self) -> &P::Target {
+        &self.guard.0
+    }
+}
+
+// the pointed element is not stored in the `MutGuard`, and only changes
+// through `guard()`, which needs `&mut self`
+unsafe impl<P: StableDeref> StableDeref for StableGuard<P> {}
+
+/// Structure returned by `StableGuard::guard()`. When this is dropped, the
+/// pointed element is checked
+pub struct StableBorrow<'a, P: 'a + DerefMut>
+where
+    P::Target: Guard,
+{
+    inner: MutGuardBorrow<'a, Stable<P>>,
+}
+
+impl<'a, P: DerefMut> Deref for StableBorrow<'a, P>
+where
+    P::Target: Guard,
+{
+    type Target = P::Target;
+
+    fn deref(&self) -> &P::Target {
+        &self.inner.0
+    }
+}
+
+impl<'a, P: DerefMut> DerefMut for StableBorrow<'a, P>
+where
+    P::Target: Guard,
+{
+    fn deref_mut(&mut self) -> &mut P::Target {
+        &mut self.inner.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[derive(Debug)]
+    struct Utf8(Vec<u8>);
+
+    impl Guard for Utf8 {
+        fn finish(&mut self) {
+            assert!(std::str::from_utf8(&self.0).is_ok(), "invalid UTF-8");
+        }
+    }
+
+    #[test]
+    fn stable_address() {
+        let mut text = StableGuard::new(Box::new(Utf8(b"caf".to_vec())));
+        text.guard().0.extend_from_slice("é".as_bytes());
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| text.guard().0.push(0xff)));
+        assert!(res.is_err());
+        text.guard().0.pop();
+
+        let address: *const Utf8 = &*text;
+        let moved = vec![text];
+        assert_eq!(address, &*moved[0] as *const Utf8);
+        assert_eq!(
+            moved.into_iter().next().unwrap().into_inner().0,
+            "café".as_bytes()
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! Timing of the checks
+//!
+//! once `MutGuard::track_stats()` was called, the duration of every
+//! `Guard::finish()` call is recorded in a histogram, returned by
+//! `MutGuard::stats()`. This shows which invariant checks become hot spots
+//! as the data grows.
+//!
+//! `MutGuard::on_slow_finish()` reports every single call slower than a
+//! threshold instead, with the guard's label and where the borrow was
+//! acquired. This catches accidentally quadratic checks early.
+//!
+//! With the `metrics` feature, durations are also reported to the `metrics`
+//! facade, as the `mut_guard.finish_seconds` histogram with `type` and
+//! `guard_id` labels.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! #
+//! #[derive(Debug)]
+//! struct Sorted(Vec<u32>);
+//!
+//! impl Guard for Sorted {
+//!   fn finish(&mut self) {
+//!     assert!(self.0.windows(2).all(|w| w[0] <= w[1]), "not sorted");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut val = MutGuard::new(Sorted(Vec::new()));
+//! val.track_stats();
+//!
+//! for i in 0..10 {
+//!   val.guard().0.push(i);
+//! }
+//!
+//! let stats = val.stats().unwrap();
+//! assert_eq!(stats.count(), 10);
+//! assert!(stats.max() >= stats.mean());
+//! # }
+//! ```
+use std::fmt;
+use std::panic::Location;
+use std::time::Duration;
+
+use super::{GuardId, MutGuard, WrappedGuard};
+
+/// number of histogram buckets. Bucket `i` counts durations under `2^i`
+/// microseconds, the last one counts everything else
+const BUCKETS: usize = 24;
+
+/// histogram of `Guard::finish()` durations
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FinishStats {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    buckets: [u64; BUCKETS],
+}
+
+impl FinishStats {
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        if elapsed > self.max {
+            self.max = elapsed;
+        }
+        self.buckets[bucket(elapsed)] += 1;
+    }
+
+    /// number of recorded calls
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+        }
+    }
+
+    /// upper bound of the bucket containing the `p`th percentile (`p` between
+    /// 0 and 100), or `None` if nothing was recorded. The last bucket has no
+    /// upper bound, so the maximum is returned instead
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = ((p.clamp(0.0, 100.0) / 100.0) * self.count as f64)
+            .ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(upper_bound(i).unwrap_or(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// `(upper bound, count)` for each bucket, with `None` as the upper bound
+    /// of the last one
+    pub fn histogram(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (upper_bound(i), *n))
+    }
+}
+
+/// sends a duration to the `metrics` facade
+#[cfg(feature = "metrics")]
+pub(crate) fn report<T: ?Sized>(id: GuardId, elapsed: Duration) {
+    metrics::histogram!(
+        "mut_guard.finish_seconds",
+        "type" => ::std::any::type_name::<T>(),
+        "guard_id" => id.to_string()
+    )
+    .record(elapsed.as_secs_f64());
+}
+
+fn bucket(elapsed: Duration) -> usize {
+    let micros = elapsed.as_micros();
+    let i = (128 - micros.leading_zeros()) as usize;
+    i.min(BUCKETS - 1)
+}
+
+fn upper_bound(bucket: usize) -> Option<Duration> {
+    if bucket == BUCKETS - 1 {
+        None
+    } else {
+        Some(Duration::from_micros(1 << bucket))
+    }
+}
+
+/// describes a `Guard::finish()` call that took longer than the configured
+/// threshold
+#[derive(Debug)]
+pub struct SlowFinish<'a> {
+    id: GuardId,
+    label: Option<&'a str>,
+    location: &'static Location<'static>,
+    elapsed: Duration,
+    threshold: Duration,
+}
+
+impl<'a> SlowFinish<'a> {
+    pub fn guard_id(&self) -> GuardId {
+        self.id
+    }
+
+    /// label set with `MutGuard::set_label()`
+    pub fn label(&self) -> Option<&'a str> {
+        self.label
+    }
+
+    /// where the checked borrow was acquired
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+}
+
+impl<'a> fmt::Display for SlowFinish<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guard::finish()")?;
+        if let Some(label) = self.label {
+            write!(f, " for {}", label)?;
+        }
+        write!(f, " (guard {})", self.id)?;
+        write!(
+            f,
+            " took {:?} (threshold: {:?}), for the borrow acquired at {}",
+            self.elapsed, self.threshold, self.location
+        )
+    }
+}
+
+pub(crate) struct SlowCheck {
+    threshold: Duration,
+    handler: Box<dyn Fn(&SlowFinish<'_>) + Send + Sync>,
+}
+
+impl SlowCheck {
+    pub(crate) fn check(
+        &self,
+        id: GuardId,
+        label: Option<&str>,
+        location: &'static Location<'static>,
+        elapsed: Duration,
+    ) {
+        if elapsed > self.threshold {
+            (self.handler)(&SlowFinish {
+                id,
+                label,
+                location,
+                elapsed,
+                threshold: self.threshold,
+            });
+        }
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// starts recording how long `Guard::finish()` takes
+    pub fn track_stats(&mut self) {
+        if self.settings.stats.is_none() {
+            self.settings.stats = Some(FinishStats::default());
+        }
+    }
+
+    /// durations recorded since `track_stats()` was called
+    pub fn stats(&self) -> Option<&FinishStats> {
+        self.settings.stats.as_ref()
+    }
+
+    /// calls `handler` every time a `Guard::finish()` call takes longer than
+    /// `threshold`
+    pub fn on_slow_finish<F>(&mut self, threshold: Duration, handler: F)
+    where
+        F: 'static + Send + Sync + Fn(&SlowFinish<'_>),
+    {
+        self.settings.slow = Some(SlowCheck {
+            threshold,
+            handler: Box::new(handler),
+        });
+    }
+
+    /// prints a warning on stderr every time a `Guard::finish()` call takes
+    /// longer than `threshold`
+    pub fn warn_on_slow_finish(&mut self, threshold: Duration) {
+        self.on_slow_finish(threshold, |slow| eprintln!("warning: {}", slow));
+    }
+}
+
+impl<T, F> WrappedGuard<T, F> {
+    /// see `MutGuard::track_stats()`
+    pub fn track_stats(&mut self) {
+        self.guard.track_stats();
+    }
+
+    /// see `MutGuard::stats()`
+    pub fn stats(&self) -> Option<&FinishStats> {
+        self.guard.stats()
+    }
+
+    /// see `MutGuard::on_slow_finish()`
+    pub fn on_slow_finish<H>(&mut self, threshold: Duration, handler: H)
+    where
+        H: 'static + Send + Sync + Fn(&SlowFinish<'_>),
+    {
+        self.guard.on_slow_finish(threshold, handler);
+    }
+
+    /// see `MutGuard::warn_on_slow_finish()`
+    pub fn warn_on_slow_finish(&mut self, threshold: Duration) {
+        self.guard.warn_on_slow_finish(threshold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use Guard;
+
+    #[derive(Debug)]
+    struct Slow(u64);
+
+    impl Guard for Slow {
+        fn finish(&mut self) {
+            thread::sleep(Duration::from_millis(self.0));
+        }
+    }
+
+    #[test]
+    fn slow_finish() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let mut val = MutGuard::new(Slow(0));
+        val.set_label("slow one");
+
+        let r = reports.clone();
+        val.on_slow_finish(Duration::from_millis(10), move |slow| {
+            r.lock().unwrap().push((
+                slow.guard_id(),
+                slow.label().map(String::from),
+                slow.location().line(),
+            ));
+        });
+
+        val.guard().0 = 20;
+        let line = line!() - 1;
+        val.guard().0 = 0;
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(
+            *reports,
+            vec![(val.id(), Some("slow one".to_string()), line)]
+        );
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(bucket(Duration::from_nanos(500)), 0);
+        assert_eq!(bucket(Duration::from_micros(1)), 1);
+        assert_eq!(bucket(Duration::from_micros(3)), 2);
+        assert_eq!(bucket(Duration::from_secs(3600)), BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentile() {
+        let mut stats = FinishStats::default();
+        assert_eq!(stats.percentile(50.0), None);
+
+        for _ in 0..9 {
+            stats.record(Duration::from_micros(3));
+        }
+        stats.record(Duration::from_millis(2));
+
+        assert_eq!(stats.count(), 10);
+        assert_eq!(stats.max(), Duration::from_millis(2));
+        assert_eq!(stats.percentile(50.0), Some(Duration::from_micros(4)));
+        assert_eq!(stats.percentile(100.0), Some(Duration::from_micros(2048)));
+        assert_eq!(stats.histogram().map(|(_, n)| n).sum::<u64>(), 10);
+    }
+
+    #[test]
+    fn mean_of_many_calls() {
+        let stats = FinishStats {
+            count: 1 << 32,
+            total: Duration::from_micros(3 << 32),
+            ..FinishStats::default()
+        };
+        assert_eq!(stats.mean(), Duration::from_micros(3));
+    }
+}
@@ -0,0 +1,152 @@
+//! Closure-only mutation
+//!
+//! `MutGuard::guard()` checks the element when the returned borrow is
+//! dropped. Leaking the borrow with `mem::forget()` skips the checks, and
+//! leaves the invalid element in the guard. `StrictMutGuard` has no borrow
+//! to leak: the element is only mutated through `update()`, which checks it
+//! once the closure returns. If the closure panics, the element is checked
+//! before the panic resumes, and a failed check replaces that panic.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::strict::StrictMutGuard;
+//! # use std::panic::{self, AssertUnwindSafe};
+//! #[derive(Debug)]
+//! struct Permissions {
+//!   admin: bool,
+//!   roles: Vec<String>,
+//! }
+//!
+//! impl Guard for Permissions {
+//!   fn finish(&mut self) {
+//!     assert_eq!(self.admin, self.roles.iter().any(|r| r == "admin"), "admin flag out of sync");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut permissions = StrictMutGuard::new(Permissions { admin: false, roles: vec![] });
+//! permissions.update(|p| {
+//!   p.roles.push("admin".to_string());
+//!   p.admin = true;
+//! });
+//!
+//! let res = panic::catch_unwind(AssertUnwindSafe(|| {
+//!   permissions.update(|p| p.roles.clear())
+//! }));
+//! assert!(res.is_err());
+//! # }
+//! ```
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe, Location};
+
+use super::{Guard, GuardId, MutGuard};
+
+/// stores an inner element that must implement the `Guard` trait, and
+/// only gives mutable access to it through `update()`
+pub struct StrictMutGuard<T> {
+    guard: MutGuard<T>,
+}
+
+impl<T: Guard> StrictMutGuard<T> {
+    pub fn new(inner: T) -> StrictMutGuard<T> {
+        StrictMutGuard {
+            guard: MutGuard::new(inner),
+        }
+    }
+
+    /// calls `f` with mutable access to the element, then checks it like at
+    /// the end of `MutGuard::guard()`, and returns the result of `f`
+    #[track_caller]
+    pub fn update<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        let mut borrow = self.guard.borrow_at(Location::caller());
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut borrow))) {
+            Ok(res) => res,
+            Err(payload) => {
+                // checked outside of the unwinding, so a failed check
+                // panics instead of aborting
+                drop(borrow);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// see `MutGuard::set_label()`
+    pub fn set_label<S: Into<String>>(&mut self, label: S) {
+        self.guard.set_label(label);
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.guard.label()
+    }
+
+    /// see `MutGuard::id()`
+    pub fn id(&self) -> GuardId {
+        self.guard.id()
+    }
+
+    /// returns the wrapped element, consuming the StrictMutGuard
+    pub fn into_inner(self) -> T {
+        self.guard.into_inner()
+    }
+}
+
+impl<T> Deref for StrictMutGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Balance(i64);
+
+    impl Guard for Balance {
+        fn finish(&mut self) {
+            assert!(self.0 >= 0, "negative balance");
+        }
+    }
+
+    fn message(payload: Box<dyn std::any::Any + Send>) -> String {
+        match payload.downcast::<String>() {
+            Ok(s) => *s,
+            Err(payload) => payload.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn checked_after_panic() {
+        let mut balance = StrictMutGuard::new(Balance(10));
+        assert_eq!(
+            balance.update(|b| {
+                b.0 -= 4;
+                b.0
+            }),
+            6
+        );
+
+        // the closure's panic resumes once the element was checked
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            balance.update(|b| {
+                b.0 -= 1;
+                panic!("transfer failed");
+            })
+        }));
+        assert_eq!(message(res.unwrap_err()), "transfer failed");
+        assert_eq!(balance.0, 5);
+
+        // a failed check replaces it
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            balance.update(|b| {
+                b.0 -= 10;
+                panic!("transfer failed");
+            })
+        }));
+        assert_eq!(message(res.unwrap_err()), "negative balance");
+    }
+}
@@ -0,0 +1,857 @@
+//! Guarded state shared between threads
+//!
+//! `GuardedMutex` is a mutex whose value is checked every time a lock is
+//! released. Threads can also wait until the value reaches some state with
+//! `GuardedMutex::wait_until`, without managing a `Condvar` themselves.
+//!
+//! `GuardedRwLock` is a reader-writer lock whose value is checked when a
+//! write lock is released. It also supports upgradeable reads: the value is
+//! read first, and only checked if the read lock was upgraded to a write
+//! lock.
+//!
+//! `SeqGuard` is a seqlock: writers mutate a copy of the value, check it,
+//! then publish it while bumping a sequence counter. Readers never lock,
+//! they copy the value and retry if a write happened in the meantime. This
+//! is meant for small, `Copy` values that are read much more often than
+//! they are written.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::sync::*;
+//! use std::sync::Arc;
+//! use std::thread;
+//!
+//! # fn main() {
+//! #[derive(Clone, Copy, Debug)]
+//! struct Range {
+//!   start: u32,
+//!   end: u32,
+//! }
+//!
+//! impl Guard for Range {
+//!   fn finish(&mut self) {
+//!     assert!(self.start <= self.end, "invalid range: {:?}", self);
+//!   }
+//! }
+//!
+//! let range = Arc::new(SeqGuard::new(Range { start: 0, end: 10 }));
+//!
+//! let writer = {
+//!   let range = range.clone();
+//!   thread::spawn(move || {
+//!     let mut r = range.guard();
+//!     r.end = 20;
+//!     r.start = 15;
+//!   })
+//! };
+//!
+//! let Range { start, end } = range.read();
+//! assert!(start <= end);
+//!
+//! writer.join().unwrap();
+//! assert_eq!(range.read().start, 15);
+//! # }
+//! ```
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut, Drop};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::sync::{
+    Condvar, LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    TryLockError, TryLockResult,
+};
+
+use super::hold::Unwinding;
+use super::revalidate::{self, Revalidate};
+use super::violation::Violation;
+use super::{run_guard, Guard};
+
+/// mutex whose value is checked every time a lock is released, and that
+/// wakes up waiting threads after each mutation
+///
+/// ```rust
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::sync::*;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// # fn main() {
+/// #[derive(Debug)]
+/// struct Queue(Vec<u32>);
+///
+/// impl Guard for Queue {
+///   fn finish(&mut self) {
+///     assert!(self.0.len() <= 16, "the queue is full");
+///   }
+/// }
+///
+/// let queue = Arc::new(GuardedMutex::new(Queue(Vec::new())));
+///
+/// let producer = {
+///   let queue = queue.clone();
+///   thread::spawn(move || {
+///     for i in 0..4 {
+///       queue.lock().unwrap().0.push(i);
+///     }
+///   })
+/// };
+///
+/// let mut q = queue.wait_until(|q| q.0.len() == 4).unwrap();
+/// q.0.clear();
+/// # drop(q);
+/// # producer.join().unwrap();
+/// # }
+/// ```
+pub struct GuardedMutex<T: Guard> {
+    inner: Mutex<T>,
+    changed: Condvar,
+}
+
+impl<T: Guard> GuardedMutex<T> {
+    pub fn new(value: T) -> GuardedMutex<T> {
+        GuardedMutex {
+            inner: Mutex::new(value),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// locks the mutex. The value is checked when the returned guard is
+    /// dropped. Like `Mutex::lock()`, this fails if a thread panicked while
+    /// holding the lock, which includes failed checks. The guard in the
+    /// `PoisonError` is checked too, so it can be used to repair the value
+    pub fn lock(&self) -> LockResult<GuardedMutexGuard<'_, T>> {
+        self.wrap(self.inner.lock())
+    }
+
+    /// like `lock()`, but returns `TryLockError::WouldBlock` instead of
+    /// waiting if the mutex is already locked
+    pub fn try_lock(&self) -> TryLockResult<GuardedMutexGuard<'_, T>> {
+        match self.inner.try_lock() {
+            Ok(lock) => Ok(self.guarded(lock)),
+            Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
+                self.guarded(e.into_inner()),
+            ))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// blocks until `pred` returns true for the value, then returns the lock.
+    /// `pred` is called again after each guarded mutation
+    pub fn wait_until<P>(&self, mut pred: P) -> LockResult<GuardedMutexGuard<'_, T>>
+    where
+        P: FnMut(&T) -> bool,
+    {
+        let lock = match self.inner.lock() {
+            Ok(lock) => lock,
+            Err(e) => return self.wrap(Err(e)),
+        };
+        self.wrap(self.changed.wait_while(lock, |value| !pred(value)))
+    }
+
+    /// returns the value, consuming the mutex
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+
+    fn wrap<'a>(
+        &'a self,
+        lock: LockResult<MutexGuard<'a, T>>,
+    ) -> LockResult<GuardedMutexGuard<'a, T>> {
+        match lock {
+            Ok(lock) => Ok(self.guarded(lock)),
+            Err(e) => Err(PoisonError::new(self.guarded(e.into_inner()))),
+        }
+    }
+
+    fn guarded<'a>(&'a self, lock: MutexGuard<'a, T>) -> GuardedMutexGuard<'a, T> {
+        GuardedMutexGuard {
+            mutex: self,
+            lock: Some(lock),
+            unwinding: Unwinding::start(),
+        }
+    }
+}
+
+impl<T: Guard + Send> Revalidate for GuardedMutex<T> {
+    fn revalidate(&self) -> Result<(), Violation> {
+        let mut lock = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        revalidate::check(&mut *lock)
+    }
+}
+
+/// lock on a `GuardedMutex`
+pub struct GuardedMutexGuard<'a, T: 'a + Guard> {
+    mutex: &'a GuardedMutex<T>,
+    lock: Option<MutexGuard<'a, T>>,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Guard> Deref for GuardedMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.lock.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: Guard> DerefMut for GuardedMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.lock.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: Guard> Drop for GuardedMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // declared first so waiters are woken up after the lock is
+        // released, even if the checks panic
+        let _notify = Notify(&self.mutex.changed);
+        if let Some(mut lock) = self.lock.take() {
+            // a panic interrupted the mutation, the mutex is poisoned
+            if !self.unwinding.interrupted() {
+                run_guard(&mut *lock);
+            }
+        }
+    }
+}
+
+impl<'a, T: Guard + fmt::Debug> fmt::Debug for GuardedMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+struct Notify<'a>(&'a Condvar);
+
+impl<'a> Drop for Notify<'a> {
+    fn drop(&mut self) {
+        self.0.notify_all();
+    }
+}
+
+/// reader-writer lock whose value is checked every time a write lock is
+/// released
+///
+/// ```rust
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::sync::*;
+/// #[derive(Debug)]
+/// struct Limits(Vec<u32>);
+///
+/// impl Guard for Limits {
+///   fn finish(&mut self) {
+///     assert!(self.0.iter().all(|l| *l < 10), "limit too high");
+///   }
+/// }
+///
+/// # fn main() {
+/// let limits = GuardedRwLock::new(Limits(vec![1, 5]));
+///
+/// // only pays for the checks if the value needs to change
+/// let read = limits.upgradeable_read().unwrap();
+/// if !read.0.contains(&3) {
+///   let mut write = read.upgrade().unwrap();
+///   write.0.push(3);
+/// }
+/// # }
+/// ```
+pub struct GuardedRwLock<T: Guard> {
+    inner: RwLock<T>,
+    /// held by writers and upgradeable readers, so that an upgradeable
+    /// reader can release its read lock then take the write lock without
+    /// another writer coming in between
+    upgrade: Mutex<()>,
+}
+
+impl<T: Guard> GuardedRwLock<T> {
+    pub fn new(value: T) -> GuardedRwLock<T> {
+        GuardedRwLock {
+            inner: RwLock::new(value),
+            upgrade: Mutex::new(()),
+        }
+    }
+
+    /// shared read access, without checks
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.inner.read()
+    }
+
+    /// exclusive access. The value is checked when the returned guard is
+    /// dropped. Like `RwLock::write()`, this fails if a thread panicked
+    /// while holding the write lock, which includes failed checks
+    pub fn write(&self) -> LockResult<GuardedRwLockWriteGuard<'_, T>> {
+        let upgrade = self.lock_upgrade();
+        wrap_write(self.inner.write(), upgrade)
+    }
+
+    /// exclusive access even if the lock is poisoned, to fix the value after
+    /// a failed check. The value is checked when the returned guard is
+    /// dropped, and once the checks pass, the lock is not poisoned anymore
+    pub fn repair(&self) -> GuardedRwLockWriteGuard<'_, T> {
+        let upgrade = self.lock_upgrade();
+        let lock = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        let mut guard = write_guard(lock, upgrade);
+        guard.repair = Some(&self.inner);
+        guard
+    }
+
+    /// shared read access that can be upgraded to a write lock. Only one
+    /// upgradeable read or write lock can be held at a time, but plain
+    /// readers are not blocked. The value is only checked if the lock is
+    /// upgraded
+    pub fn upgradeable_read(&self) -> LockResult<UpgradeableReadGuard<'_, T>> {
+        let upgrade = self.lock_upgrade();
+        match self.inner.read() {
+            Ok(read) => Ok(UpgradeableReadGuard {
+                lock: self,
+                read,
+                upgrade,
+            }),
+            Err(e) => Err(PoisonError::new(UpgradeableReadGuard {
+                lock: self,
+                read: e.into_inner(),
+                upgrade,
+            })),
+        }
+    }
+
+    /// like `read()`, without waiting if a writer holds the lock
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        self.inner.try_read()
+    }
+
+    /// like `write()`, but returns `TryLockError::WouldBlock` instead of
+    /// waiting if the lock is held
+    pub fn try_write(&self) -> TryLockResult<GuardedRwLockWriteGuard<'_, T>> {
+        let upgrade = self.try_lock_upgrade()?;
+        match self.inner.try_write() {
+            Ok(lock) => Ok(write_guard(lock, upgrade)),
+            Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
+                write_guard(e.into_inner(), upgrade),
+            ))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// like `upgradeable_read()`, without waiting if a writer or another
+    /// upgradeable reader holds the lock
+    pub fn try_upgradeable_read(&self) -> TryLockResult<UpgradeableReadGuard<'_, T>> {
+        let upgrade = self.try_lock_upgrade()?;
+        match self.inner.try_read() {
+            Ok(read) => Ok(UpgradeableReadGuard {
+                lock: self,
+                read,
+                upgrade,
+            }),
+            Err(TryLockError::Poisoned(e)) => Err(TryLockError::Poisoned(PoisonError::new(
+                UpgradeableReadGuard {
+                    lock: self,
+                    read: e.into_inner(),
+                    upgrade,
+                },
+            ))),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// returns the value, consuming the lock
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+
+    fn try_lock_upgrade<U>(&self) -> Result<MutexGuard<'_, ()>, TryLockError<U>> {
+        match self.upgrade.try_lock() {
+            Ok(upgrade) => Ok(upgrade),
+            Err(TryLockError::Poisoned(e)) => Ok(e.into_inner()),
+            Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    fn lock_upgrade(&self) -> MutexGuard<'_, ()> {
+        // the mutex protects no data, a panic while holding it changes nothing
+        self.upgrade.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: Guard + Send + Sync> Revalidate for GuardedRwLock<T> {
+    /// takes the write lock, since checks need mutable access
+    fn revalidate(&self) -> Result<(), Violation> {
+        let mut lock = self.inner.write().unwrap_or_else(PoisonError::into_inner);
+        revalidate::check(&mut *lock)
+    }
+}
+
+fn wrap_write<'a, T: Guard>(
+    lock: LockResult<RwLockWriteGuard<'a, T>>,
+    upgrade: MutexGuard<'a, ()>,
+) -> LockResult<GuardedRwLockWriteGuard<'a, T>> {
+    match lock {
+        Ok(lock) => Ok(write_guard(lock, upgrade)),
+        Err(e) => Err(PoisonError::new(write_guard(e.into_inner(), upgrade))),
+    }
+}
+
+fn write_guard<'a, T: Guard>(
+    lock: RwLockWriteGuard<'a, T>,
+    upgrade: MutexGuard<'a, ()>,
+) -> GuardedRwLockWriteGuard<'a, T> {
+    GuardedRwLockWriteGuard {
+        lock: Some(lock),
+        _upgrade: upgrade,
+        unwinding: Unwinding::start(),
+        repair: None,
+    }
+}
+
+/// write lock on a `GuardedRwLock`, checking the value when dropped
+pub struct GuardedRwLockWriteGuard<'a, T: 'a + Guard> {
+    lock: Option<RwLockWriteGuard<'a, T>>,
+    _upgrade: MutexGuard<'a, ()>,
+    unwinding: Unwinding,
+    /// set by `GuardedRwLock::repair()`
+    repair: Option<&'a RwLock<T>>,
+}
+
+impl<'a, T: Guard> Deref for GuardedRwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.lock.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: Guard> DerefMut for GuardedRwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.lock.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: Guard> GuardedRwLockWriteGuard<'a, T> {
+    /// releases the lock without checking the value
+    #[cfg(feature = "actix")]
+    pub(crate) fn release_unchecked(mut self) {
+        self.lock = None;
+    }
+}
+
+impl<'a, T: Guard> Drop for GuardedRwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(mut lock) = self.lock.take() {
+            if !self.unwinding.interrupted() {
+                run_guard(&mut *lock);
+                if let Some(inner) = self.repair {
+                    inner.clear_poison();
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T: Guard + fmt::Debug> fmt::Debug for GuardedRwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// read lock on a `GuardedRwLock` that can be upgraded to a write lock.
+/// Dropping it does not check the value
+pub struct UpgradeableReadGuard<'a, T: 'a + Guard> {
+    lock: &'a GuardedRwLock<T>,
+    read: RwLockReadGuard<'a, T>,
+    upgrade: MutexGuard<'a, ()>,
+}
+
+impl<'a, T: Guard> UpgradeableReadGuard<'a, T> {
+    /// releases the read lock and takes the write lock. Other writers cannot
+    /// modify the value in between
+    pub fn upgrade(self) -> LockResult<GuardedRwLockWriteGuard<'a, T>> {
+        let UpgradeableReadGuard {
+            lock,
+            read,
+            upgrade,
+        } = self;
+        drop(read);
+        wrap_write(lock.inner.write(), upgrade)
+    }
+}
+
+impl<'a, T: Guard> Deref for UpgradeableReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.read
+    }
+}
+
+impl<'a, T: Guard + fmt::Debug> fmt::Debug for UpgradeableReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// seqlock protected value, checked before every publication
+pub struct SeqGuard<T: Copy + Guard> {
+    seq: AtomicUsize,
+    value: UnsafeCell<T>,
+    writer: Mutex<()>,
+}
+
+// writers are serialized by the mutex. Readers copy the value into a
+// `MaybeUninit<T>` while a writer may be storing it, and only assume it is
+// initialized once the sequence shows no write happened meanwhile, so a torn
+// copy is never used as a `T`. The copy itself still races with the writer:
+// like every seqlock that does not store the value in atomic words, this
+// relies on `T` being plain data: `Copy`, so it has no `Drop` and no
+// interior mutability. `T: Send` since readers get their own copy on other
+// threads
+unsafe impl<T: Copy + Guard + Send> Sync for SeqGuard<T> {}
+
+impl<T: Copy + Guard + Send> Revalidate for SeqGuard<T> {
+    /// checks a copy of the published value
+    fn revalidate(&self) -> Result<(), Violation> {
+        revalidate::check(&mut self.read())
+    }
+}
+
+impl<T: Copy + Guard> SeqGuard<T> {
+    pub fn new(value: T) -> SeqGuard<T> {
+        SeqGuard {
+            seq: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// copies the current value, without locking
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 == 1 {
+                ::std::hint::spin_loop();
+                continue;
+            }
+
+            // a write may happen concurrently, the copy is discarded if so,
+            // before it is used as a `T`
+            let value = unsafe { ptr::read_volatile(self.value.get() as *const MaybeUninit<T>) };
+            fence(Ordering::Acquire);
+
+            if self.seq.load(Ordering::Relaxed) == before {
+                // no write happened during the copy, so it is a full value
+                return unsafe { value.assume_init() };
+            }
+        }
+    }
+
+    /// number of values published since creation
+    pub fn version(&self) -> usize {
+        self.seq.load(Ordering::Acquire) / 2
+    }
+
+    /// mutable borrow of a copy of the value. When it is dropped, the copy
+    /// is checked then published. Writers wait for each other
+    pub fn guard(&self) -> SeqBorrow<'_, T> {
+        let lock = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let value = unsafe { *self.value.get() };
+        SeqBorrow {
+            seq: self,
+            value,
+            _lock: lock,
+            unwinding: Unwinding::start(),
+        }
+    }
+
+    /// returns the value, consuming the SeqGuard
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn publish(&self, value: T) {
+        self.seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(self.value.get(), value) };
+        self.seq.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// writer's copy of a `SeqGuard` value
+pub struct SeqBorrow<'a, T: 'a + Copy + Guard> {
+    seq: &'a SeqGuard<T>,
+    value: T,
+    _lock: MutexGuard<'a, ()>,
+    unwinding: Unwinding,
+}
+
+impl<'a, T: Copy + Guard> Deref for SeqBorrow<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'a, T: Copy + Guard> DerefMut for SeqBorrow<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<'a, T: Copy + Guard> Drop for SeqBorrow<'a, T> {
+    fn drop(&mut self) {
+        // if the checks panic, or a panic interrupted the mutation, readers
+        // keep seeing the previous value
+        if self.unwinding.interrupted() {
+            return;
+        }
+        run_guard(&mut self.value);
+        self.seq.publish(self.value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[derive(Clone, Copy, Debug)]
+    struct Pair {
+        a: u64,
+        b: u64,
+    }
+
+    impl Guard for Pair {
+        fn finish(&mut self) {
+            assert_eq!(self.a, self.b, "pair members differ");
+        }
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        let pair = Arc::new(SeqGuard::new(Pair { a: 0, b: 0 }));
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let pair = pair.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut p = pair.guard();
+                        p.a += 1;
+                        p.b += 1;
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..1000 {
+            let p = pair.read();
+            assert_eq!(p.a, p.b);
+        }
+
+        for w in writers {
+            w.join().unwrap();
+        }
+        assert_eq!(pair.read().a, 2000);
+        assert_eq!(pair.version(), 2000);
+    }
+
+    #[test]
+    fn failed_check_is_not_published() {
+        let pair = SeqGuard::new(Pair { a: 1, b: 1 });
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            pair.guard().a = 2;
+        }));
+        assert!(res.is_err());
+        assert_eq!(pair.read().a, 1);
+        assert_eq!(pair.version(), 0);
+
+        pair.guard().b = 1;
+        assert_eq!(pair.version(), 1);
+    }
+
+    #[test]
+    fn wait_until() {
+        let pair = Arc::new(GuardedMutex::new(Pair { a: 0, b: 0 }));
+
+        let writer = {
+            let pair = pair.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let mut p = pair.lock().unwrap();
+                    p.a += 1;
+                    p.b += 1;
+                }
+            })
+        };
+
+        let p = pair.wait_until(|p| p.a == 10).unwrap();
+        assert_eq!(p.b, 10);
+        drop(p);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn mutex_poisoned_by_violation() {
+        let pair = GuardedMutex::new(Pair { a: 0, b: 0 });
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            pair.lock().unwrap().a = 1;
+        }));
+        assert!(res.is_err());
+
+        // the value can be repaired through the poisoned lock, it is
+        // checked again when released
+        let mut p = pair.lock().unwrap_err().into_inner();
+        assert_eq!(p.a, 1);
+        p.a = 0;
+        drop(p);
+        assert_eq!(pair.into_inner().unwrap_err().into_inner().a, 0);
+    }
+
+    #[test]
+    fn unchecked_while_unwinding() {
+        let pair = GuardedMutex::new(Pair { a: 0, b: 0 });
+
+        // checking the half-updated pair would panic while panicking
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let mut p = pair.lock().unwrap();
+            p.a = 1;
+            panic!("interrupted");
+        }));
+        assert_eq!(
+            res.unwrap_err().downcast_ref::<&str>(),
+            Some(&"interrupted")
+        );
+        assert_eq!(pair.into_inner().unwrap_err().into_inner().a, 1);
+    }
+
+    #[test]
+    fn seq_interrupted_write() {
+        let pair = SeqGuard::new(Pair { a: 1, b: 1 });
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let mut p = pair.guard();
+            p.a = 2;
+            panic!("interrupted");
+        }));
+        assert!(res.is_err());
+        assert_eq!(pair.read().a, 1);
+        assert_eq!(pair.version(), 0);
+    }
+
+    #[test]
+    fn seq_write_in_drop() {
+        struct Cleanup<'a>(&'a SeqGuard<Pair>);
+
+        impl<'a> Drop for Cleanup<'a> {
+            fn drop(&mut self) {
+                // borrows acquired while unwinding are still checked
+                let invalid = catch_unwind(AssertUnwindSafe(|| self.0.guard().a = 7));
+                assert!(invalid.is_err());
+                let mut p = self.0.guard();
+                p.a = 3;
+                p.b = 3;
+            }
+        }
+
+        let pair = SeqGuard::new(Pair { a: 1, b: 1 });
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            let _cleanup = Cleanup(&pair);
+            panic!("unrelated");
+        }));
+        assert!(res.is_err());
+        assert_eq!(pair.read().a, 3);
+        assert_eq!(pair.version(), 1);
+    }
+
+    #[test]
+    fn upgradeable_read() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug)]
+        struct Counted<'a> {
+            value: u32,
+            checks: &'a AtomicUsize,
+        }
+
+        impl<'a> Guard for Counted<'a> {
+            fn finish(&mut self) {
+                self.checks.fetch_add(1, Ordering::SeqCst);
+                assert!(self.value < 10, "value too large");
+            }
+        }
+
+        let checks = AtomicUsize::new(0);
+        let lock = GuardedRwLock::new(Counted {
+            value: 1,
+            checks: &checks,
+        });
+
+        {
+            let read = lock.upgradeable_read().unwrap();
+            assert_eq!(read.value, 1);
+            assert_eq!(lock.read().unwrap().value, 1);
+        }
+        assert_eq!(checks.load(Ordering::SeqCst), 0);
+
+        lock.upgradeable_read().unwrap().upgrade().unwrap().value = 2;
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        lock.write().unwrap().value = 3;
+        assert_eq!(checks.load(Ordering::SeqCst), 2);
+
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            lock.write().unwrap().value = 20;
+        }));
+        assert!(res.is_err());
+        let mut repaired = lock.write().unwrap_err().into_inner();
+        repaired.value = 4;
+        drop(repaired);
+        assert_eq!(lock.read().unwrap_err().into_inner().value, 4);
+
+        // fixed with repair(), once the checks pass
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            lock.write().unwrap_err().into_inner().value = 20;
+        }));
+        assert!(res.is_err());
+        let res = catch_unwind(AssertUnwindSafe(|| lock.repair().value += 1));
+        assert!(res.is_err());
+        assert!(lock.read().is_err());
+        lock.repair().value = 5;
+        assert_eq!(lock.write().unwrap().value, 5);
+    }
+
+    #[test]
+    fn try_lock() {
+        let pair = GuardedMutex::new(Pair { a: 0, b: 0 });
+        {
+            let _p = pair.try_lock().unwrap();
+            assert!(matches!(pair.try_lock(), Err(TryLockError::WouldBlock)));
+        }
+        pair.try_lock().unwrap().a = 0;
+
+        let lock = GuardedRwLock::new(Pair { a: 0, b: 0 });
+        {
+            let _read = lock.try_upgradeable_read().unwrap();
+            assert!(lock.try_read().is_ok());
+            assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+            assert!(matches!(
+                lock.try_upgradeable_read(),
+                Err(TryLockError::WouldBlock)
+            ));
+        }
+        {
+            let _read = lock.try_read().unwrap();
+            assert!(matches!(lock.try_write(), Err(TryLockError::WouldBlock)));
+        }
+        let mut write = lock.try_write().unwrap();
+        write.a += 1;
+        write.b += 1;
+    }
+}
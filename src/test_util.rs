@@ -0,0 +1,498 @@
+//! Testing helpers
+//!
+//! *Note*: this module requires the `test-util` feature.
+//!
+//! testing that bad mutations are caught with `#[should_panic]` is coarse:
+//! the test stops at the first violation, and can only check the panic
+//! message. `ViolationRecorder` wraps a `Guard` implementation, and records
+//! the panics of `Guard::finish` as `Violation`s instead.
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate mut_guard;
+//! use mut_guard::*;
+//! use mut_guard::test_util::*;
+//!
+//! #[derive(Debug)]
+//! struct LessThan20(pub u8);
+//!
+//! impl Guard for LessThan20 {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 <= 20, "invariant failed, internal value is too large: {}", self.0);
+//!   }
+//! }
+//!
+//! fn main() {
+//!   let mut val = MutGuard::new(ViolationRecorder::new(LessThan20(0)));
+//!
+//!   val.guard().0 = 10;
+//!   assert!(val.violations().is_empty());
+//!
+//!   val.guard().0 = 30;
+//!   assert_violation!(val, "internal value is too large: 30");
+//! }
+//! ```
+use std::fmt::Debug;
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+
+use super::budget::{Budget, Progress};
+use super::command::Command;
+use super::dirty::{FieldSet, Fields};
+use super::violation::Violation;
+use super::{Guard, MutGuard};
+
+/// `Guard` adapter recording the panics of the wrapped element's checks
+/// (`Guard::finish` and its variants) as violations
+#[derive(Debug)]
+pub struct ViolationRecorder<T: Guard> {
+    inner: T,
+    violations: Vec<Violation>,
+}
+
+impl<T: Guard> ViolationRecorder<T> {
+    pub fn new(inner: T) -> ViolationRecorder<T> {
+        ViolationRecorder {
+            inner,
+            violations: Vec::new(),
+        }
+    }
+
+    /// returns the violations recorded so far, in order
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// returns the recorded violations, and clears the list
+    pub fn take_violations(&mut self) -> Vec<Violation> {
+        ::std::mem::take(&mut self.violations)
+    }
+
+    /// returns the wrapped element, consuming the ViolationRecorder
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Guard> Guard for ViolationRecorder<T> {
+    fn normalize(&mut self) {
+        self.inner.normalize();
+    }
+
+    fn finish(&mut self) {
+        self.record(T::finish);
+    }
+
+    fn finish_incremental(&mut self, changed: &FieldSet) {
+        self.record(|inner| inner.finish_incremental(changed));
+    }
+
+    fn finish_slow(&mut self) {
+        self.record(T::finish_slow);
+    }
+
+    fn finish_within(&mut self, budget: &Budget) -> Progress {
+        // a check that panicked did not stop early
+        self.record(|inner| inner.finish_within(budget))
+            .unwrap_or(Progress::Done)
+    }
+}
+
+/// so that `MutGuard::guard_tracked()` works through the recorder
+impl<T: Guard + Fields> Fields for ViolationRecorder<T> {
+    const FIELDS: &'static [&'static str] = T::FIELDS;
+}
+
+impl<T: Guard> ViolationRecorder<T> {
+    fn record<R, F: FnOnce(&mut T) -> R>(&mut self, check: F) -> Option<R> {
+        let inner = &mut self.inner;
+        match panic::catch_unwind(AssertUnwindSafe(|| check(inner))) {
+            Ok(res) => Some(res),
+            Err(payload) => {
+                self.violations
+                    .push(Violation::from_panic(payload.as_ref()));
+                None
+            }
+        }
+    }
+}
+
+impl<T: Guard> Deref for ViolationRecorder<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Guard> DerefMut for ViolationRecorder<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// `Guard` hook called on a `MockGuard`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    Normalize,
+    Finish,
+    FinishIncremental,
+    FinishSlow,
+    FinishWithin,
+}
+
+/// `Guard` test double: it records every hook call, and can be scripted
+/// to panic on the Nth check (a call to `Guard::finish`,
+/// `Guard::finish_incremental` or `Guard::finish_within`)
+///
+/// ```rust
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::test_util::*;
+/// #
+/// # fn main() {
+/// let mut val = MutGuard::new(MockGuard::new(vec![1]));
+///
+/// val.guard().push(2);
+/// val.guard().push(3);
+///
+/// assert_eq!(val.finish_calls(), 2);
+/// assert_eq!(
+///   val.calls(),
+///   &[
+///     Hook::Normalize, Hook::Finish, Hook::FinishSlow,
+///     Hook::Normalize, Hook::Finish, Hook::FinishSlow,
+///   ][..]
+/// );
+/// assert_eq!(**val, vec![1, 2, 3]);
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MockGuard<T = ()> {
+    inner: T,
+    calls: Vec<Hook>,
+    finish_calls: usize,
+    fail_on: Option<usize>,
+}
+
+impl<T> MockGuard<T> {
+    pub fn new(inner: T) -> MockGuard<T> {
+        MockGuard {
+            inner,
+            calls: Vec::new(),
+            finish_calls: 0,
+            fail_on: None,
+        }
+    }
+
+    /// makes the `n`th check panic (starting at 1)
+    pub fn fail_on(mut self, n: usize) -> MockGuard<T> {
+        self.fail_on = Some(n);
+        self
+    }
+
+    /// returns the hooks called so far, in order
+    pub fn calls(&self) -> &[Hook] {
+        &self.calls
+    }
+
+    /// number of checks so far, by `Guard::finish`,
+    /// `Guard::finish_incremental` or `Guard::finish_within`
+    pub fn finish_calls(&self) -> usize {
+        self.finish_calls
+    }
+
+    /// returns the wrapped element, consuming the MockGuard
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> Guard for MockGuard<T> {
+    fn normalize(&mut self) {
+        self.calls.push(Hook::Normalize);
+    }
+
+    fn finish(&mut self) {
+        self.check(Hook::Finish);
+    }
+
+    fn finish_incremental(&mut self, _changed: &FieldSet) {
+        self.check(Hook::FinishIncremental);
+    }
+
+    fn finish_slow(&mut self) {
+        self.calls.push(Hook::FinishSlow);
+    }
+
+    fn finish_within(&mut self, _budget: &Budget) -> Progress {
+        self.check(Hook::FinishWithin);
+        Progress::Done
+    }
+}
+
+impl<T: Fields> Fields for MockGuard<T> {
+    const FIELDS: &'static [&'static str] = T::FIELDS;
+}
+
+impl<T> MockGuard<T> {
+    fn check(&mut self, hook: Hook) {
+        self.calls.push(hook);
+        self.finish_calls += 1;
+        if self.fail_on == Some(self.finish_calls) {
+            panic!("MockGuard: scripted failure on call {}", self.finish_calls);
+        }
+    }
+}
+
+impl<T> Deref for MockGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for MockGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+/// returned by `try_replay()` when a command broke an invariant
+#[derive(Debug)]
+pub struct ReplayFailure<C> {
+    /// index of the failing command in the log
+    pub step: usize,
+    pub command: C,
+    pub violation: Violation,
+}
+
+/// applies every command of a recorded log (like `CommandLog::commands()`)
+/// to `initial` through a `MutGuard`, checking the invariants after each one.
+///
+/// Returns the final state, or the first command that broke an invariant
+#[allow(clippy::result_large_err)]
+pub fn try_replay<T, C, I>(initial: T, commands: I) -> Result<T, ReplayFailure<C>>
+where
+    T: Guard,
+    C: Command<T>,
+    I: IntoIterator<Item = C>,
+{
+    let mut state = MutGuard::new(initial);
+
+    for (step, command) in commands.into_iter().enumerate() {
+        let guard = &mut state;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| guard.apply_command(&command)));
+        if let Err(payload) = res {
+            return Err(ReplayFailure {
+                step,
+                command,
+                violation: Violation::from_panic(payload.as_ref()),
+            });
+        }
+    }
+
+    Ok(state.into_inner())
+}
+
+/// like `try_replay()`, but panics with the failing step and command
+///
+/// ```rust,should_panic
+/// # extern crate mut_guard;
+/// # use mut_guard::*;
+/// # use mut_guard::command::Command;
+/// # use mut_guard::test_util::*;
+/// #
+/// # fn main() {
+/// struct Balance(i32);
+///
+/// impl Guard for Balance {
+///   fn finish(&mut self) {
+///     assert!(self.0 >= 0, "negative balance");
+///   }
+/// }
+///
+/// #[derive(Debug)]
+/// struct Withdraw(i32);
+///
+/// impl Command<Balance> for Withdraw {
+///   fn apply(&self, target: &mut Balance) {
+///     target.0 -= self.0;
+///   }
+/// }
+///
+/// // panics with "step 2 (Withdraw(10)) broke an invariant: negative balance"
+/// replay(Balance(10), vec![Withdraw(1), Withdraw(4), Withdraw(10)]);
+/// # }
+/// ```
+pub fn replay<T, C, I>(initial: T, commands: I) -> T
+where
+    T: Guard,
+    C: Command<T> + Debug,
+    I: IntoIterator<Item = C>,
+{
+    match try_replay(initial, commands) {
+        Ok(state) => state,
+        Err(failure) => panic!(
+            "step {} ({:?}) broke an invariant: {}",
+            failure.step,
+            failure.command,
+            failure.violation.message()
+        ),
+    }
+}
+
+/// asserts that a `ViolationRecorder` recorded a violation. With a second
+/// argument, the message of the last violation must contain it
+#[macro_export]
+macro_rules! assert_violation {
+    ($recorder:expr) => {
+        assert!(
+            !$recorder.violations().is_empty(),
+            "expected an invariant violation, none was recorded"
+        );
+    };
+    ($recorder:expr, $pattern:expr) => {
+        match $recorder.violations().last() {
+            Some(v) => assert!(
+                v.message().contains($pattern),
+                "expected an invariant violation containing {:?}, got {:?}",
+                $pattern,
+                v.message()
+            ),
+            None => panic!(
+                "expected an invariant violation containing {:?}, none was recorded",
+                $pattern
+            ),
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Positive(i32);
+
+    impl Guard for Positive {
+        fn finish(&mut self) {
+            assert!(self.0 > 0, "value should be positive, got {}", self.0);
+        }
+    }
+
+    #[test]
+    fn record() {
+        let mut val = MutGuard::new(ViolationRecorder::new(Positive(1)));
+
+        val.guard().0 = -1;
+        val.guard().0 = 2;
+        val.guard().0 = 0;
+
+        assert_eq!(val.violations().len(), 2);
+        assert_violation!(val, "got 0");
+        assert_eq!(
+            val.violations()[0].message(),
+            "value should be positive, got -1"
+        );
+
+        let violations = {
+            let mut v = val.guard();
+            v.0 = 5;
+            v.take_violations()
+        };
+        assert_eq!(violations.len(), 2);
+        assert!(val.violations().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "MockGuard: scripted failure on call 3")]
+    fn mock_fail_on() {
+        let mut val = MutGuard::new(MockGuard::new(()).fail_on(3));
+
+        val.guard();
+        val.guard();
+        assert_eq!(val.finish_calls(), 2);
+        val.guard();
+    }
+
+    #[test]
+    fn mock_in_recorder() {
+        let mut val = MutGuard::new(ViolationRecorder::new(MockGuard::new(0).fail_on(2)));
+
+        for i in 0..3 {
+            ***val.guard() = i;
+        }
+
+        assert_eq!(val.finish_calls(), 3);
+        assert_violation!(val, "call 2");
+    }
+
+    #[derive(Debug, Default)]
+    struct Pair {
+        a: i32,
+        b: i32,
+    }
+
+    impl Fields for Pair {
+        const FIELDS: &'static [&'static str] = &["a", "b"];
+    }
+
+    #[test]
+    fn mock_hooks() {
+        use std::time::Duration;
+
+        let mut val = MutGuard::new(ViolationRecorder::new(
+            MockGuard::new(Pair::default()).fail_on(2),
+        ));
+
+        val.guard_tracked().mark(0);
+        val.with_budget(Duration::from_secs(1));
+        val.guard().b = 1;
+
+        assert_eq!(
+            val.calls(),
+            &[
+                Hook::Normalize,
+                Hook::FinishIncremental,
+                Hook::FinishSlow,
+                Hook::Normalize,
+                Hook::FinishWithin,
+                Hook::FinishSlow,
+            ][..]
+        );
+        assert_violation!(val, "call 2");
+        assert_eq!(val.a + val.b, 1);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Set(i32);
+
+    impl Command<Positive> for Set {
+        fn apply(&self, target: &mut Positive) {
+            target.0 = self.0;
+        }
+    }
+
+    #[test]
+    fn replay_log() {
+        let state = replay(Positive(1), vec![Set(3), Set(2)]);
+        assert_eq!(state.0, 2);
+
+        let failure = try_replay(Positive(1), vec![Set(3), Set(-2), Set(4)]).unwrap_err();
+        assert_eq!(failure.step, 1);
+        assert_eq!(failure.command, Set(-2));
+        assert_eq!(
+            failure.violation.message(),
+            "value should be positive, got -2"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "none was recorded")]
+    fn no_violation() {
+        let val = MutGuard::new(ViolationRecorder::new(Positive(1)));
+        assert_violation!(val);
+    }
+}
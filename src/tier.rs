@@ -0,0 +1,160 @@
+//! Running expensive checks occasionally
+//!
+//! `Guard::finish_slow()` holds the checks too expensive to run after every
+//! mutation. By default it runs right after `Guard::finish()`, but a
+//! `MutGuard` can be configured with `slow_checks_every()` to only run it
+//! on every Nth borrow, while `check_slow()` runs it on demand.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! #
+//! #[derive(Debug)]
+//! struct Index {
+//!   keys: Vec<u32>,
+//! }
+//!
+//! impl Guard for Index {
+//!   fn finish(&mut self) {
+//!     assert!(self.keys.len() < 1000, "too many keys");
+//!   }
+//!
+//!   fn finish_slow(&mut self) {
+//!     let mut sorted = self.keys.clone();
+//!     sorted.sort();
+//!     sorted.dedup();
+//!     assert_eq!(sorted.len(), self.keys.len(), "duplicate keys");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut index = MutGuard::new(Index { keys: vec![] });
+//! index.slow_checks_every(100);
+//!
+//! for i in 0..10 {
+//!   index.guard().keys.push(i);
+//! }
+//!
+//! // before a snapshot, for example
+//! index.check_slow();
+//! # }
+//! ```
+use std::num::NonZeroU64;
+use std::panic::Location;
+
+use super::{Guard, MutGuard, ARMED};
+
+/// counts borrows to decide when `Guard::finish_slow()` runs
+pub(crate) struct Schedule {
+    every: NonZeroU64,
+    borrows: u64,
+}
+
+impl Schedule {
+    /// counts a borrow, and returns true if the slow checks should run
+    pub(crate) fn tick(&mut self) -> bool {
+        self.borrows += 1;
+        if self.borrows == self.every.get() {
+            self.borrows = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// only runs `Guard::finish_slow()` on every `n`th mutable borrow,
+    /// instead of every borrow. `Guard::finish()` still runs every time
+    ///
+    /// panics if `n` is 0
+    pub fn slow_checks_every(&mut self, n: u64) {
+        let every = NonZeroU64::new(n).expect("slow checks must run every 1 or more borrows");
+        self.settings.tier = Some(Schedule { every, borrows: 0 });
+    }
+
+    /// runs `Guard::finish_slow()` after every mutable borrow again
+    pub fn slow_checks_always(&mut self) {
+        self.settings.tier = None;
+    }
+
+    /// returns true if the next borrow will run `Guard::finish_slow()`
+    pub(crate) fn slow_checks_due(&mut self) -> bool {
+        match self.settings.tier {
+            Some(ref mut schedule) => schedule.tick(),
+            None => true,
+        }
+    }
+}
+
+impl<T: Guard> MutGuard<T> {
+    /// checks the element with `Guard::finish()` and `Guard::finish_slow()`
+    /// now, whatever `slow_checks_every()` was set to, and restarts the count
+    /// of borrows until the next slow checks
+    #[track_caller]
+    pub fn check_slow(&mut self) {
+        if !ARMED {
+            return;
+        }
+        if let Some(ref mut schedule) = self.settings.tier {
+            schedule.borrows = 0;
+        }
+        let location = Location::caller();
+        self.finish_with(location, |inner| {
+            inner.finish();
+            inner.finish_slow();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[derive(Debug, Default)]
+    struct Counted {
+        value: u32,
+        fast: u32,
+        slow: u32,
+    }
+
+    impl Guard for Counted {
+        fn finish(&mut self) {
+            self.fast += 1;
+        }
+
+        fn finish_slow(&mut self) {
+            self.slow += 1;
+            assert!(self.value < 10, "value too large");
+        }
+    }
+
+    #[test]
+    fn every_nth_borrow() {
+        let mut counted = MutGuard::new(Counted::default());
+        counted.slow_checks_every(3);
+
+        for _ in 0..7 {
+            counted.guard().value += 1;
+        }
+        assert_eq!((counted.fast, counted.slow), (7, 2));
+
+        counted.check_slow();
+        assert_eq!((counted.fast, counted.slow), (8, 3));
+
+        counted.slow_checks_always();
+        counted.guard().value = 2;
+        assert_eq!(counted.slow, 4);
+    }
+
+    #[test]
+    fn explicit_slow_check() {
+        let mut counted = MutGuard::new(Counted::default());
+        counted.slow_checks_every(1000);
+
+        counted.guard().value = 20;
+        let res = panic::catch_unwind(AssertUnwindSafe(|| counted.check_slow()));
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,798 @@
+//! Invariant violations
+//!
+//! a `Violation` describes a failed check, for code that reports broken
+//! invariants instead of panicking.
+//!
+//! `DumpOnViolation` adds the `Debug` rendering of the element to the panic
+//! message of a failed check, to see the state around the broken invariant.
+//!
+//! ```rust,should_panic
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::violation::DumpOnViolation;
+//! #
+//! #[derive(Debug)]
+//! struct Range {
+//!   start: u32,
+//!   end: u32,
+//! }
+//!
+//! impl Guard for Range {
+//!   fn finish(&mut self) {
+//!     assert!(self.start <= self.end, "invalid range");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut range = MutGuard::new(DumpOnViolation::new(Range { start: 0, end: 10 }));
+//!
+//! // panics with "invalid range\nstate: Range { start: 20, end: 10 }"
+//! range.guard().start = 20;
+//! # }
+//! ```
+//!
+//! `MutGuard::on_violation()` handles failed checks without panicking: the
+//! handler gets the `Violation`, and the borrow ends normally, skipping the
+//! `defer()` callbacks and the publishers. The guard counts the violations
+//! it handled in `violation_count()`, and keeps the most recent one in
+//! `last_violation()`, so violations stay visible even when nothing
+//! crashes. With the `metrics` feature, each of them also increments the
+//! `mut_guard.violations` counter, with `type`, `guard_id` and `severity`
+//! labels.
+//!
+//! Not every broken invariant justifies stopping the program: a check added
+//! with `MutGuard::add_check()` can be given the `Severity::Warning` with
+//! `set_check_severity()`. When it fails, the violation is reported, to the
+//! `on_violation()` handler if there is one or on stderr otherwise (and to
+//! the JSON sink), and the borrow ends normally, as if the check passed.
+//! Checks with the default `Severity::Error` panic, or go to the handler.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! #
+//! #[derive(Debug)]
+//! struct Percent(u8);
+//!
+//! impl Guard for Percent {
+//!   fn finish(&mut self) {
+//!     assert!(self.0 <= 100, "more than 100%");
+//!   }
+//! }
+//!
+//! # fn main() {
+//! let mut progress = MutGuard::new(Percent(0));
+//! progress.on_violation(|violation| eprintln!("warning: {}", violation));
+//!
+//! progress.guard().0 = 120;
+//! assert_eq!(progress.violation_count(), 1);
+//! assert_eq!(progress.last_violation().unwrap().message(), "more than 100%");
+//! # }
+//! ```
+//!
+//! A check on the whole element only says that something is wrong. With the
+//! `derive` feature, `#[derive(CheckFields)]` implements `CheckFields` with
+//! a check per field, marked with `#[check(...)]`, and `Guard::finish()`
+//! calls `check_fields()`. A failed check panics with a message starting
+//! with the path of the field, like `accounts[2].balance: negative balance`,
+//! and the path is also stored in `Violation::field()`.
+//!
+//! With the `serde` feature, violations can be serialized, and
+//! `set_json_sink()` makes every failed `MutGuard` check write a JSON line
+//! (with the guard's label and id, and where the borrow was acquired) to a
+//! sink, before the panic continues. This gives structured reports to
+//! aggregate failures across fuzzing or CI runs.
+use std::any::Any;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::{self, Debug};
+#[cfg(feature = "serde")]
+use std::io::{self, Write};
+use std::num::NonZeroU64;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "serde")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "serde")]
+use std::sync::Mutex;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{hold, Guard, GuardId, MutGuard, TryWrappedGuard, WrappedGuard};
+
+/// how serious a broken invariant is
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Severity {
+    /// reported to the handler set with `MutGuard::on_violation()`, or on
+    /// stderr, then the borrow ends normally
+    Warning,
+    /// panics, or is passed to the handler set with `on_violation()`
+    #[default]
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    fn is_error(&self) -> bool {
+        *self == Severity::Error
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// describes a failed invariant check
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Violation {
+    message: String,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    label: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    // ids start at 1
+    guard_id: Option<NonZeroU64>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    location: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    field: Option<String>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Severity::is_error")
+    )]
+    severity: Severity,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    // boxed, to keep `Result<_, Violation>` small
+    #[allow(clippy::box_collection)]
+    backtrace: Option<Box<String>>,
+}
+
+impl Violation {
+    pub fn new<S: Into<String>>(message: S) -> Violation {
+        Violation {
+            message: message.into(),
+            label: None,
+            guard_id: None,
+            location: None,
+            field: None,
+            severity: Severity::Error,
+            backtrace: None,
+        }
+    }
+
+    /// names the guard where the check failed
+    pub fn with_label<S: Into<String>>(mut self, label: S) -> Violation {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// identifies the guard where the check failed
+    pub fn with_guard_id(mut self, id: GuardId) -> Violation {
+        self.guard_id = NonZeroU64::new(id.as_u64());
+        self
+    }
+
+    /// where the checked borrow was acquired, usually `file:line:column`
+    pub fn with_location<S: Into<String>>(mut self, location: S) -> Violation {
+        self.location = Some(location.into());
+        self
+    }
+
+    /// path of the field that broke the invariant, like
+    /// `accounts[2].balance`
+    pub fn with_field<S: Into<String>>(mut self, field: S) -> Violation {
+        self.field = Some(field.into());
+        self
+    }
+
+    /// `Severity::Error` by default
+    pub fn with_severity(mut self, severity: Severity) -> Violation {
+        self.severity = severity;
+        self
+    }
+
+    /// the rendered backtrace of the place the checked borrow was acquired
+    pub fn with_backtrace<S: Into<String>>(mut self, backtrace: S) -> Violation {
+        self.backtrace = Some(Box::new(backtrace.into()));
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn guard_id(&self) -> Option<GuardId> {
+        self.guard_id.map(|id| GuardId(id.get()))
+    }
+
+    pub fn location(&self) -> Option<&str> {
+        self.location.as_deref()
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// backtrace of the checked borrow, captured like
+    /// `MutGuardBorrow::backtrace()`
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_deref().map(String::as_str)
+    }
+
+    /// writes the violation as a single line of JSON
+    #[cfg(feature = "serde")]
+    pub fn write_json<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let mut line = serde_json::to_vec(self).map_err(io::Error::other)?;
+        line.push(b'\n');
+        w.write_all(&line)?;
+        w.flush()
+    }
+
+    /// builds a violation from the payload of a caught panic, with the
+    /// field set by `fail_field()` if the panic came from there, and the
+    /// backtrace of the borrow being checked
+    pub(crate) fn from_panic(payload: &(dyn Any + Send)) -> Violation {
+        let mut violation = if let Some(s) = payload.downcast_ref::<&str>() {
+            Violation::new(*s)
+        } else if let Some(s) = payload.downcast_ref::<String>() {
+            Violation::new(s.clone())
+        } else {
+            Violation::new("panicked with a non string payload")
+        };
+        violation.backtrace = hold::checked_backtrace().map(Box::new);
+        // the panic can be caught and built again by the inner layers
+        let field = FAILED_FIELD.with(|failed| match *failed.borrow() {
+            Some((ref field, ref message)) if *message == violation.message => Some(field.clone()),
+            _ => None,
+        });
+        match field {
+            Some(field) => violation.with_field(field),
+            None => violation,
+        }
+    }
+
+    /// panics with the message of this violation, without calling the
+    /// panic hook again, and keeps its field for `from_panic()`
+    pub(crate) fn resume(self) -> ! {
+        if let Some(field) = self.field {
+            keep_field(field, &self.message);
+        }
+        panic::resume_unwind(Box::new(self.message))
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "invariant warning: {}", self.message),
+            Severity::Error => write!(f, "invariant violation: {}", self.message),
+        }
+    }
+}
+
+impl Error for Violation {}
+
+#[cfg(feature = "serde")]
+static SINK_SET: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "serde")]
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// from now on, every failed `MutGuard` check is written to `sink` as a JSON
+/// line. This replaces the previous sink
+#[cfg(feature = "serde")]
+pub fn set_json_sink<W: Write + Send + 'static>(sink: W) {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(sink));
+    SINK_SET.store(true, Ordering::Release);
+}
+
+/// stops writing failed checks to the JSON sink
+#[cfg(feature = "serde")]
+pub fn clear_json_sink() {
+    SINK_SET.store(false, Ordering::Release);
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// calls `check`, and writes a violation to the JSON sink if it panics,
+/// before resuming the panic
+#[cfg(feature = "serde")]
+pub(crate) fn finish_reported<T: ?Sized, F: FnOnce(&mut T)>(
+    inner: &mut T,
+    check: F,
+    id: GuardId,
+    label: Option<&str>,
+    location: &'static Location<'static>,
+) {
+    if !SINK_SET.load(Ordering::Acquire) {
+        check(inner);
+        return;
+    }
+
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| check(inner))) {
+        let mut violation = Violation::from_panic(payload.as_ref())
+            .with_guard_id(id)
+            .with_location(location.to_string());
+        if let Some(label) = label {
+            violation = violation.with_label(label);
+        }
+
+        write_to_sink(&violation);
+        panic::resume_unwind(payload);
+    }
+}
+
+/// writes `violation` to the JSON sink, if one was set
+#[cfg(feature = "serde")]
+pub(crate) fn write_to_sink(violation: &Violation) {
+    if !SINK_SET.load(Ordering::Acquire) {
+        return;
+    }
+    if let Some(ref mut sink) = *SINK.lock().unwrap_or_else(|e| e.into_inner()) {
+        // the original panic matters more than a failed report
+        let _ = violation.write_json(sink);
+    }
+}
+
+thread_local! {
+    /// the field path and message of the last `fail_field()` panic, until
+    /// `Violation::from_panic()` sees the same message
+    static FAILED_FIELD: RefCell<Option<(String, String)>> = const { RefCell::new(None) };
+}
+
+/// panics because the field at `path` broke an invariant, see `CheckFields`
+pub(crate) fn fail_field(path: &FieldPath<'_>, message: &str) -> ! {
+    let field = path.to_string();
+    let message = format!("{}: {}", field, message);
+    keep_field(field, &message);
+    panic!("{}", message)
+}
+
+/// the next panic with `message` comes from `field`
+fn keep_field(field: String, message: &str) {
+    FAILED_FIELD.with(|failed| *failed.borrow_mut() = Some((field, message.to_string())));
+}
+
+/// path of a field from the checked element, built while
+/// `CheckFields::check_fields_at()` goes down the element
+#[derive(Clone, Copy, Debug)]
+pub enum FieldPath<'a> {
+    /// the checked element
+    Root,
+    /// a field of the parent
+    Field(&'a FieldPath<'a>, &'static str),
+    /// an element of the parent collection
+    Index(&'a FieldPath<'a>, usize),
+}
+
+impl<'a> FieldPath<'a> {
+    pub fn field(&'a self, name: &'static str) -> FieldPath<'a> {
+        FieldPath::Field(self, name)
+    }
+
+    pub fn index(&'a self, index: usize) -> FieldPath<'a> {
+        FieldPath::Index(self, index)
+    }
+}
+
+impl<'a> fmt::Display for FieldPath<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldPath::Root => Ok(()),
+            FieldPath::Field(&FieldPath::Root, name) => f.write_str(name),
+            FieldPath::Field(parent, name) => write!(f, "{}.{}", parent, name),
+            FieldPath::Index(parent, index) => write!(f, "{}[{}]", parent, index),
+        }
+    }
+}
+
+/// checks of each field, generated by `#[derive(CheckFields)]`, to call from
+/// `Guard::finish()`. A failed check panics with the path of the field in
+/// the message, also kept in the `Violation` built from the panic
+pub trait CheckFields {
+    /// checks the fields, `path` leading to this element
+    fn check_fields_at(&self, path: &FieldPath<'_>);
+
+    fn check_fields(&self) {
+        self.check_fields_at(&FieldPath::Root);
+    }
+}
+
+/// set with `MutGuard::on_violation()`
+pub(crate) struct Handler {
+    handler: Box<dyn Fn(&Violation) + Send + Sync>,
+    count: u64,
+    last: Option<Violation>,
+}
+
+impl Handler {
+    /// builds the violation from the payload of the failed check, counts it
+    /// then calls the handler
+    #[cfg_attr(not(feature = "metrics"), allow(clippy::extra_unused_type_parameters))]
+    pub(crate) fn handle<T: ?Sized>(
+        &mut self,
+        payload: &(dyn Any + Send),
+        id: GuardId,
+        label: Option<&str>,
+        location: &'static Location<'static>,
+    ) {
+        let mut violation = Violation::from_panic(payload)
+            .with_guard_id(id)
+            .with_location(location.to_string());
+        if let Some(label) = label {
+            violation = violation.with_label(label);
+        }
+        self.report::<T>(violation);
+    }
+
+    /// counts `violation`, then calls the handler
+    #[cfg_attr(not(feature = "metrics"), allow(clippy::extra_unused_type_parameters))]
+    pub(crate) fn report<T: ?Sized>(&mut self, violation: Violation) {
+        self.count += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!(
+            "mut_guard.violations",
+            "type" => ::std::any::type_name::<T>(),
+            "guard_id" => violation.guard_id().map(|id| id.to_string()).unwrap_or_default(),
+            "severity" => violation.severity().as_str()
+        )
+        .increment(1);
+        (self.handler)(&violation);
+        self.last = Some(violation);
+    }
+}
+
+/// reports the warnings of the checks added with `set_check_severity()`,
+/// to the handler if there is one, or on stderr
+pub(crate) fn report_warnings<T: ?Sized>(
+    handler: Option<&mut Handler>,
+    warnings: Vec<Violation>,
+    id: GuardId,
+    label: Option<&str>,
+    location: &'static Location<'static>,
+) {
+    let mut handler = handler;
+    for warning in warnings {
+        let mut warning = warning
+            .with_guard_id(id)
+            .with_location(location.to_string());
+        if let Some(label) = label {
+            warning = warning.with_label(label);
+        }
+        #[cfg(feature = "serde")]
+        write_to_sink(&warning);
+        match handler {
+            Some(ref mut handler) => handler.report::<T>(warning),
+            None => eprintln!("warning: {}", warning),
+        }
+    }
+}
+
+impl<T> MutGuard<T> {
+    /// calls `handler` when a check fails instead of panicking. The borrow
+    /// then ends without running the `defer()` callbacks (they wait for the
+    /// next borrow) and the publishers, and the element is left as it is.
+    /// This replaces the previous handler, and keeps the counters
+    ///
+    /// the panic hook still runs for the failed check, and `validate_now()`
+    /// still panics
+    pub fn on_violation<F>(&mut self, handler: F)
+    where
+        F: 'static + Send + Sync + Fn(&Violation),
+    {
+        let (count, last) = match self.settings.violations.take() {
+            Some(previous) => (previous.count, previous.last),
+            None => (0, None),
+        };
+        self.settings.violations = Some(Handler {
+            handler: Box::new(handler),
+            count,
+            last,
+        });
+    }
+
+    /// removes the handler set with `on_violation()`: failed checks panic
+    /// again, and the counters are reset
+    pub fn panic_on_violation(&mut self) {
+        self.settings.violations = None;
+    }
+
+    /// number of violations handled since `on_violation()` was called,
+    /// warnings included
+    pub fn violation_count(&self) -> u64 {
+        self.settings.violations.as_ref().map_or(0, |h| h.count)
+    }
+
+    /// the most recent violation handled since `on_violation()` was called,
+    /// warnings included
+    pub fn last_violation(&self) -> Option<&Violation> {
+        self.settings
+            .violations
+            .as_ref()
+            .and_then(|h| h.last.as_ref())
+    }
+}
+
+impl<T, F> WrappedGuard<T, F> {
+    /// see `MutGuard::on_violation()`
+    pub fn on_violation<H>(&mut self, handler: H)
+    where
+        H: 'static + Send + Sync + Fn(&Violation),
+    {
+        self.guard.on_violation(handler);
+    }
+
+    /// see `MutGuard::panic_on_violation()`
+    pub fn panic_on_violation(&mut self) {
+        self.guard.panic_on_violation();
+    }
+
+    /// see `MutGuard::violation_count()`
+    pub fn violation_count(&self) -> u64 {
+        self.guard.violation_count()
+    }
+
+    /// see `MutGuard::last_violation()`
+    pub fn last_violation(&self) -> Option<&Violation> {
+        self.guard.last_violation()
+    }
+}
+
+impl<T, F, E> TryWrappedGuard<T, F, E> {
+    /// see `MutGuard::on_violation()`. The handler is also called when the
+    /// function fails
+    pub fn on_violation<H>(&mut self, handler: H)
+    where
+        H: 'static + Send + Sync + Fn(&Violation),
+    {
+        self.guard.on_violation(handler);
+    }
+
+    /// removes the handler set with `on_violation()`, and resets the
+    /// counters. Failures of the function are then only returned
+    pub fn panic_on_violation(&mut self) {
+        self.guard.panic_on_violation();
+    }
+
+    /// see `MutGuard::violation_count()`
+    pub fn violation_count(&self) -> u64 {
+        self.guard.violation_count()
+    }
+
+    /// see `MutGuard::last_violation()`
+    pub fn last_violation(&self) -> Option<&Violation> {
+        self.guard.last_violation()
+    }
+}
+
+/// default length of the `Debug` rendering in `DumpOnViolation` messages
+const DEFAULT_MAX_LEN: usize = 1024;
+
+/// guard adapter adding the `Debug` rendering of the element (truncated to
+/// a maximum length) to the panic message when `Guard::finish()` fails
+pub struct DumpOnViolation<T> {
+    inner: T,
+    max_len: usize,
+}
+
+impl<T: Guard + Debug> DumpOnViolation<T> {
+    pub fn new(inner: T) -> DumpOnViolation<T> {
+        DumpOnViolation::with_max_len(inner, DEFAULT_MAX_LEN)
+    }
+
+    /// the rendering is cut after `max_len` characters
+    pub fn with_max_len(inner: T, max_len: usize) -> DumpOnViolation<T> {
+        DumpOnViolation { inner, max_len }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+fn truncated<T: Debug>(value: &T, max_len: usize) -> String {
+    let mut dump = format!("{:?}", value);
+    if let Some((index, _)) = dump.char_indices().nth(max_len) {
+        dump.truncate(index);
+        dump.push_str("...");
+    }
+    dump
+}
+
+impl<T: Guard + Debug> Guard for DumpOnViolation<T> {
+    fn normalize(&mut self) {
+        self.inner.normalize();
+    }
+
+    fn finish(&mut self) {
+        self.dump_on_panic(T::finish);
+    }
+
+    fn finish_slow(&mut self) {
+        self.dump_on_panic(T::finish_slow);
+    }
+}
+
+impl<T: Guard + Debug> DumpOnViolation<T> {
+    fn dump_on_panic<F: FnOnce(&mut T)>(&mut self, check: F) {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| check(&mut self.inner)));
+        if let Err(payload) = res {
+            let violation = Violation::from_panic(payload.as_ref());
+            let message = format!(
+                "{}\nstate: {}",
+                violation.message(),
+                truncated(&self.inner, self.max_len)
+            );
+            if let Some(field) = violation.field {
+                keep_field(field, &message);
+            }
+            panic!("{}", message);
+        }
+    }
+}
+
+impl<T> Deref for DumpOnViolation<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for DumpOnViolation<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Debug> Debug for DumpOnViolation<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    struct Names(Vec<&'static str>);
+
+    impl Guard for Names {
+        fn finish(&mut self) {
+            assert!(self.0.len() <= 2, "too many names");
+        }
+    }
+
+    #[test]
+    fn truncate() {
+        assert_eq!(truncated(&"abc", 10), "\"abc\"");
+        assert_eq!(truncated(&"abcdef", 4), "\"abc...");
+        assert_eq!(truncated(&"ééé", 2), "\"é...");
+    }
+
+    #[test]
+    #[should_panic(expected = "too many names\nstate: Names([\"a\", \"b\", \"c\"])")]
+    fn dump() {
+        let mut names = MutGuard::new(DumpOnViolation::new(Names(vec!["a"])));
+        names.guard().0.push("b");
+        names.guard().0.push("c");
+    }
+
+    #[test]
+    fn handled() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut names = MutGuard::new(Names(vec![]));
+        names.set_label("names");
+        {
+            let seen = seen.clone();
+            names.on_violation(move |v| seen.lock().unwrap().push(v.message().to_string()));
+        }
+        let published = Arc::new(Mutex::new(0));
+        {
+            let published = published.clone();
+            names.publish_to(move |_: &::publish::Change<Names>| *published.lock().unwrap() += 1);
+        }
+
+        names.guard().0.extend(&["a", "b"]);
+        assert_eq!(names.violation_count(), 0);
+        assert!(names.last_violation().is_none());
+
+        names.guard().0.push("c");
+        names.guard().0.push("d");
+        assert_eq!(names.0.len(), 4);
+        assert_eq!(names.violation_count(), 2);
+        let last = names.last_violation().unwrap();
+        assert_eq!(last.message(), "too many names");
+        assert_eq!(last.label(), Some("names"));
+        assert_eq!(last.guard_id(), Some(names.id()));
+        assert_eq!(*seen.lock().unwrap(), vec!["too many names"; 2]);
+        // only the valid state was published
+        assert_eq!(*published.lock().unwrap(), 1);
+
+        names.panic_on_violation();
+        assert_eq!(names.violation_count(), 0);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| names.guard().0.push("e")));
+        assert!(res.is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_sink() {
+        use std::sync::Arc;
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        set_json_sink(out.clone());
+
+        let mut names = MutGuard::new(Names(vec![]));
+        names.set_label("names");
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            names.guard().0.extend(&["a", "b", "c"]);
+        }));
+        let line = line!() - 2;
+        clear_json_sink();
+        assert!(res.is_err());
+
+        // other tests may fail checks at the same time
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let reported: Vec<Violation> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .filter(|v: &Violation| v.label() == Some("names"))
+            .collect();
+
+        assert_eq!(reported.len(), 1);
+        assert_eq!(reported[0].message(), "too many names");
+        assert_eq!(reported[0].guard_id(), Some(names.id()));
+        let location = format!("{}:{}:", file!(), line);
+        assert!(reported[0].location().unwrap().starts_with(&location));
+    }
+}
@@ -0,0 +1,122 @@
+//! Reactive guarded state for web frontends
+//!
+//! *Note*: this module requires the `web` feature.
+//!
+//! `GuardedSignal` stores a value implementing the `Guard` trait in a
+//! reactive signal (the signal type used by leptos). Every update runs
+//! `Guard::finish`, then notifies the framework so that what depends on
+//! the signal is rendered again.
+//!
+//! ```rust
+//! # extern crate mut_guard;
+//! # use mut_guard::*;
+//! # use mut_guard::web::*;
+//! #
+//! # fn main() {
+//! #[derive(Clone, Debug)]
+//! struct Cart {
+//!   items: Vec<String>,
+//! }
+//!
+//! impl Guard for Cart {
+//!   fn finish(&mut self) {
+//!     assert!(self.items.len() <= 10, "the cart is full");
+//!   }
+//! }
+//!
+//! let cart = GuardedSignal::new(Cart { items: Vec::new() });
+//!
+//! cart.update(|c| c.items.push("book".to_string()));
+//! assert_eq!(cart.with(|c| c.items.len()), 1);
+//! # }
+//! ```
+use reactive_graph::signal::{ReadSignal, RwSignal};
+use reactive_graph::traits::{Get, Update, With};
+
+use super::{run_guard, Guard};
+
+/// reactive signal storing an element that must implement the `Guard`
+/// trait, and that can only be modified through `update()`
+pub struct GuardedSignal<T: 'static + Guard + Send + Sync> {
+    signal: RwSignal<T>,
+}
+
+impl<T: 'static + Guard + Send + Sync> GuardedSignal<T> {
+    pub fn new(value: T) -> GuardedSignal<T> {
+        GuardedSignal {
+            signal: RwSignal::new(value),
+        }
+    }
+
+    /// modifies the element with `f`, calls `Guard::finish`, then notifies
+    /// the subscribers of the signal
+    pub fn update<U, F: FnOnce(&mut T) -> U>(&self, f: F) -> U {
+        let mut res = None;
+        self.signal.update(|value| {
+            res = Some(f(value));
+            run_guard(value);
+        });
+        res.expect("the signal was disposed")
+    }
+
+    /// subscribes to the signal, then calls `f` on the element
+    pub fn with<U, F: FnOnce(&T) -> U>(&self, f: F) -> U {
+        self.signal.with(f)
+    }
+
+    /// subscribes to the signal, then returns a clone of the element
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        self.signal.get()
+    }
+
+    /// returns a read only signal that can be given to components
+    pub fn read_only(&self) -> ReadSignal<T> {
+        self.signal.read_only()
+    }
+}
+
+impl<T: 'static + Guard + Send + Sync> Clone for GuardedSignal<T> {
+    fn clone(&self) -> GuardedSignal<T> {
+        *self
+    }
+}
+
+impl<T: 'static + Guard + Send + Sync> Copy for GuardedSignal<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Score(u32);
+
+    impl Guard for Score {
+        fn normalize(&mut self) {
+            if self.0 > 100 {
+                self.0 = 100;
+            }
+        }
+
+        fn finish(&mut self) {}
+    }
+
+    #[test]
+    fn update() {
+        let score = GuardedSignal::new(Score(0));
+        let read = score.read_only();
+
+        score.update(|s| s.0 += 10);
+        assert_eq!(read.get(), Score(10));
+
+        let previous = score.update(|s| {
+            let previous = s.0;
+            s.0 += 200;
+            previous
+        });
+        assert_eq!(previous, 10);
+        assert_eq!(score.get(), Score(100));
+    }
+}